@@ -0,0 +1,74 @@
+use std::fs;
+use std::path::Path;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use dns_server_rust::{
+    buffer::BytePacketBuffer, name::Name, packet::Packet, record::DnsRecord, view::PacketView,
+};
+
+fn corpus() -> Vec<(String, Vec<u8>)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let mut messages: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            (name, fs::read(&path).unwrap())
+        })
+        .collect();
+    messages.sort();
+    messages
+}
+
+fn full_parse(c: &mut Criterion) {
+    for (name, bytes) in corpus() {
+        let mut group = c.benchmark_group(format!("parse/{}", name));
+        group.bench_function("Packet::from_buffer", |b| {
+            b.iter(|| {
+                let mut buffer = BytePacketBuffer::from_bytes(black_box(&bytes)).unwrap();
+                Packet::from_buffer(&mut buffer).unwrap()
+            })
+        });
+        group.bench_function("PacketView", |b| {
+            b.iter(|| {
+                let view = PacketView::new(black_box(&bytes)).unwrap();
+                let questions = view.questions().filter(|q| q.is_ok()).count();
+                let records = view.records().filter(|r| r.is_ok()).count();
+                questions + records
+            })
+        });
+        group.finish();
+    }
+}
+
+// The cache-lookup shape: does the message answer a given name?
+fn find_answer(c: &mut Criterion) {
+    let bytes =
+        fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus/cname_chain.bin"))
+            .unwrap();
+    let target: Name = "github.com".parse().unwrap();
+    let mut group = c.benchmark_group("find_answer");
+    group.bench_function("Packet::from_buffer", |b| {
+        b.iter(|| {
+            let mut buffer = BytePacketBuffer::from_bytes(black_box(&bytes)).unwrap();
+            let packet = Packet::from_buffer(&mut buffer).unwrap();
+            packet.answers.iter().any(|record| match record {
+                DnsRecord::A { domain, .. } => *domain == target,
+                _ => false,
+            })
+        })
+    });
+    group.bench_function("PacketView", |b| {
+        b.iter(|| {
+            let view = PacketView::new(black_box(&bytes)).unwrap();
+            view.answers()
+                .filter_map(|record| record.ok())
+                .any(|record| record.name.eq_name(&target))
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, full_parse, find_answer);
+criterion_main!(benches);
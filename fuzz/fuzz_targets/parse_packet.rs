@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use dns_server_rust::{buffer::BytePacketBuffer, packet::Packet, PACKET_BUFFER_SIZE};
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() > PACKET_BUFFER_SIZE {
+        return;
+    }
+    let mut buffer = BytePacketBuffer::from_bytes(data).unwrap();
+    let _ = Packet::from_buffer(&mut buffer);
+});
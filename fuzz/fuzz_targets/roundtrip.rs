@@ -0,0 +1,35 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use dns_server_rust::{buffer::BytePacketBuffer, packet::Packet, PACKET_BUFFER_SIZE};
+
+fn encode(packet: &mut Packet) -> Option<Vec<u8>> {
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).ok()?;
+    Some(buffer.buffer[..buffer.pos()].to_vec())
+}
+
+fn decode(data: &[u8]) -> Option<Packet> {
+    let mut buffer = BytePacketBuffer::from_bytes(data).unwrap();
+    Packet::from_buffer(&mut buffer).ok()
+}
+
+// parse -> write -> parse must succeed, and writing the reparsed packet must
+// give the same bytes again.
+fuzz_target!(|data: &[u8]| {
+    if data.len() > PACKET_BUFFER_SIZE {
+        return;
+    }
+    let mut packet = match decode(data) {
+        Some(packet) => packet,
+        None => return,
+    };
+    let first = match encode(&mut packet) {
+        Some(bytes) => bytes,
+        None => return,
+    };
+
+    let mut reparsed = decode(&first).expect("written packet does not parse");
+    let second = encode(&mut reparsed).expect("reparsed packet does not write");
+    assert_eq!(first, second);
+});
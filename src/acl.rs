@@ -0,0 +1,291 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    cidr::Cidr,
+    config::{AclConfig, TransportAclConfig},
+    context::Transport,
+    name::Name,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// A BIND-style address match list: entries are tried in order and the first
+/// one containing the client decides; a client matching nothing is denied.
+/// Entries are CIDRs, optionally negated with `!`, or `any`, `none` and
+/// `localhost`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AddressList {
+    entries: Vec<(bool, Cidr)>,
+}
+
+impl AddressList {
+    pub fn any() -> Self {
+        "any".parse().unwrap()
+    }
+
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Loopback and private (RFC 1918 / RFC 4193) addresses.
+    pub fn local() -> Self {
+        "localhost 10.0.0.0/8 172.16.0.0/12 192.168.0.0/16 fc00::/7"
+            .parse()
+            .unwrap()
+    }
+
+    pub fn allows(&self, client: IpAddr) -> bool {
+        self.entries
+            .iter()
+            .find(|(_, cidr)| cidr.contains(client))
+            .map(|(allow, _)| *allow)
+            .unwrap_or(false)
+    }
+
+    fn push(&mut self, entry: &str) -> Result<()> {
+        let (allow, entry) = match entry.strip_prefix('!') {
+            Some(entry) => (false, entry.trim_start()),
+            None => (true, entry),
+        };
+        let cidrs = match entry {
+            "any" => vec!["0.0.0.0/0".parse()?, "::/0".parse()?],
+            "none" => vec![],
+            "localhost" => vec!["127.0.0.0/8".parse()?, "::1/128".parse()?],
+            cidr => vec![cidr.parse()?],
+        };
+        self.entries
+            .extend(cidrs.into_iter().map(|cidr| (allow, cidr)));
+        Ok(())
+    }
+}
+
+/// Whitespace-separated entries, as in `"!192.0.2.66 192.0.2.0/24"`.
+impl FromStr for AddressList {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut list = AddressList::default();
+        for entry in s.split_whitespace() {
+            list.push(entry)?;
+        }
+        Ok(list)
+    }
+}
+
+impl<'de> Deserialize<'de> for AddressList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let entries = Vec::<String>::deserialize(deserializer)?;
+        let mut list = AddressList::default();
+        for entry in entries {
+            list.push(entry.trim()).map_err(serde::de::Error::custom)?;
+        }
+        Ok(list)
+    }
+}
+
+struct TransportAcl {
+    query: AddressList,
+    recursion: AddressList,
+}
+
+impl TransportAcl {
+    fn new(config: &AclConfig, overrides: &Option<TransportAclConfig>) -> Self {
+        let overrides = overrides.clone().unwrap_or_default();
+        Self {
+            query: overrides.query.unwrap_or_else(|| config.query.clone()),
+            recursion: overrides
+                .recursion
+                .unwrap_or_else(|| config.recursion.clone()),
+        }
+    }
+}
+
+struct ZoneAcl {
+    zone: Name,
+    allow_query: Option<AddressList>,
+    allow_transfer: AddressList,
+    allow_update: AddressList,
+    allow_transfer_keys: Vec<Name>,
+    allow_update_keys: Vec<Name>,
+}
+
+/// Who may query, recurse, transfer and update, by client address and, for
+/// transfers and updates, by TSIG key.
+pub struct Acl {
+    udp: TransportAcl,
+    tcp: TransportAcl,
+    zones: Vec<ZoneAcl>,
+}
+
+impl Acl {
+    pub fn new(config: &AclConfig) -> Self {
+        let mut zones: Vec<ZoneAcl> = config
+            .zones
+            .iter()
+            .map(|zone| ZoneAcl {
+                zone: zone.zone.clone(),
+                allow_query: zone.allow_query.clone(),
+                allow_transfer: zone.allow_transfer.clone(),
+                allow_update: zone.allow_update.clone(),
+                allow_transfer_keys: zone.allow_transfer_keys.clone(),
+                allow_update_keys: zone.allow_update_keys.clone(),
+            })
+            .collect();
+        // Most specific zone first, so the first match is the closest one.
+        zones.sort_by_key(|zone| std::cmp::Reverse(zone.zone.label_count()));
+
+        Self {
+            udp: TransportAcl::new(config, &config.udp),
+            tcp: TransportAcl::new(config, &config.tcp),
+            zones,
+        }
+    }
+
+    fn transport(&self, transport: Transport) -> &TransportAcl {
+        match transport {
+            Transport::Udp => &self.udp,
+            // The TCP lists cover the encrypted transports too: like TCP,
+            // they only answer clients that completed a handshake.
+            Transport::Tcp | Transport::Tls | Transport::Https | Transport::Quic => &self.tcp,
+        }
+    }
+
+    fn zone(&self, qname: &Name) -> Option<&ZoneAcl> {
+        self.zones
+            .iter()
+            .find(|zone| qname.is_subdomain_of(&zone.zone))
+    }
+
+    /// A zone's `allow_query` replaces the transport's query list for names
+    /// in that zone.
+    pub fn may_query(&self, client: IpAddr, transport: Transport, qname: &Name) -> bool {
+        match self.zone(qname).and_then(|zone| zone.allow_query.as_ref()) {
+            Some(list) => list.allows(client),
+            None => self.transport(transport).query.allows(client),
+        }
+    }
+
+    pub fn may_recurse(&self, client: IpAddr, transport: Transport) -> bool {
+        self.transport(transport).recursion.allows(client)
+    }
+
+    /// Zone transfers of `zone`; denied unless the zone lists the client or
+    /// the key the request is signed with.
+    pub fn may_transfer(&self, client: IpAddr, key: Option<&Name>, zone: &Name) -> bool {
+        self.zone(zone)
+            .map(|acl| {
+                key.is_some_and(|key| acl.allow_transfer_keys.contains(key))
+                    || acl.allow_transfer.allows(client)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Dynamic updates to `zone`; denied unless the zone lists the client or
+    /// the key the request is signed with.
+    pub fn may_update(&self, client: IpAddr, key: Option<&Name>, zone: &Name) -> bool {
+        self.zone(zone)
+            .map(|acl| {
+                key.is_some_and(|key| acl.allow_update_keys.contains(key))
+                    || acl.allow_update.allows(client)
+            })
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    fn acl(toml: &str) -> Acl {
+        Acl::new(&Config::parse(toml).unwrap().acl)
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let list: AddressList = "!192.0.2.66 192.0.2.0/24".parse().unwrap();
+        assert!(list.allows(ip("192.0.2.1")));
+        assert!(!list.allows(ip("192.0.2.66")));
+        assert!(!list.allows(ip("198.51.100.1")));
+
+        assert!(AddressList::any().allows(ip("2001:db8::1")));
+        assert!(!AddressList::none().allows(ip("127.0.0.1")));
+        assert!(AddressList::local().allows(ip("::1")));
+        assert!(AddressList::local().allows(ip("192.168.1.20")));
+        assert!(!AddressList::local().allows(ip("8.8.8.8")));
+        assert!("bogus".parse::<AddressList>().is_err());
+    }
+
+    #[test]
+    fn defaults_close_the_open_resolver() {
+        let acl = acl("");
+        assert!(acl.may_query(ip("203.0.113.5"), Transport::Udp, &name("example.com")));
+        assert!(!acl.may_recurse(ip("203.0.113.5"), Transport::Udp));
+        assert!(acl.may_recurse(ip("127.0.0.1"), Transport::Tcp));
+        assert!(acl.may_recurse(ip("10.1.2.3"), Transport::Udp));
+        assert!(!acl.may_transfer(ip("127.0.0.1"), None, &name("example.com")));
+        assert!(!acl.may_update(ip("127.0.0.1"), None, &name("example.com")));
+    }
+
+    #[test]
+    fn transports_have_their_own_lists() {
+        let acl = acl(r#"
+            [acl]
+            query = ["any"]
+            recursion = ["192.0.2.0/24"]
+
+            [acl.udp]
+            query = ["192.0.2.0/24"]
+
+            [acl.tcp]
+            recursion = ["any"]
+            "#);
+        assert!(!acl.may_query(ip("198.51.100.1"), Transport::Udp, &name("a.example")));
+        assert!(acl.may_query(ip("198.51.100.1"), Transport::Tcp, &name("a.example")));
+        assert!(!acl.may_recurse(ip("198.51.100.1"), Transport::Udp));
+        assert!(acl.may_recurse(ip("198.51.100.1"), Transport::Tcp));
+    }
+
+    #[test]
+    fn zone_lists_apply_to_names_in_the_zone() {
+        let acl = acl(r#"
+            [[acl.zones]]
+            zone = "example.com"
+            allow_query = ["192.0.2.0/24"]
+            allow_transfer = ["192.0.2.53"]
+            allow_transfer_keys = ["xfr-key"]
+
+            [[acl.zones]]
+            zone = "public.example.com"
+            allow_query = ["any"]
+            allow_update = ["192.0.2.10"]
+            "#);
+        let outsider = ip("198.51.100.1");
+        assert!(!acl.may_query(outsider, Transport::Udp, &name("www.Example.com")));
+        assert!(acl.may_query(outsider, Transport::Udp, &name("www.public.example.com")));
+        assert!(acl.may_query(outsider, Transport::Udp, &name("example.org")));
+        assert!(acl.may_query(ip("192.0.2.7"), Transport::Udp, &name("example.com")));
+
+        assert!(acl.may_transfer(ip("192.0.2.53"), None, &name("example.com")));
+        assert!(!acl.may_transfer(ip("192.0.2.7"), None, &name("example.com")));
+        let key = name("XFR-Key");
+        assert!(acl.may_transfer(outsider, Some(&key), &name("example.com")));
+        assert!(!acl.may_transfer(outsider, Some(&key), &name("public.example.com")));
+        assert!(!acl.may_transfer(outsider, Some(&name("other-key")), &name("example.com")));
+        assert!(acl.may_update(ip("192.0.2.10"), None, &name("public.example.com")));
+        assert!(!acl.may_update(ip("192.0.2.10"), None, &name("example.com")));
+        assert!(!acl.may_update(outsider, Some(&key), &name("example.com")));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::PACKET_BUFFER_SIZE;
+use crate::{name::Name, PACKET_BUFFER_SIZE};
 
 type Error = Box<dyn std::error::Error>;
 type Result<T> = std::result::Result<T, Error>;
@@ -7,6 +7,14 @@ type Result<T> = std::result::Result<T, Error>;
 pub struct BytePacketBuffer {
     pub buffer: [u8; PACKET_BUFFER_SIZE],
     pub pos: usize,
+    len: usize,
+    names: Vec<(Vec<Vec<u8>>, usize)>,
+}
+
+impl Default for BytePacketBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BytePacketBuffer {
@@ -14,25 +22,63 @@ impl BytePacketBuffer {
         Self {
             buffer: [0; PACKET_BUFFER_SIZE],
             pos: 0,
+            len: 0,
+            names: Vec::new(),
         }
     }
 
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let mut buffer = Self::new();
+        if data.len() > PACKET_BUFFER_SIZE {
+            return Err("Message exceeds buffer size.".into());
+        }
+        buffer.buffer[..data.len()].copy_from_slice(data);
+        buffer.len = data.len();
+        Ok(buffer)
+    }
+
     pub fn pos(&self) -> usize {
         self.pos
     }
 
+    /// Number of valid message bytes: what was received, or what has been
+    /// written so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Marks the first `len` bytes of `buffer` as the message, e.g. after
+    /// filling it with `recv_from`.
+    pub fn set_len(&mut self, len: usize) -> Result<()> {
+        if len > PACKET_BUFFER_SIZE {
+            return Err("Message exceeds buffer size.".into());
+        }
+        self.len = len;
+        Ok(())
+    }
+
     pub fn step(&mut self, steps: usize) -> Result<()> {
+        if self.pos > self.len || steps > self.len - self.pos {
+            return Err("End of buffer.".into());
+        }
         self.pos += steps;
         Ok(())
     }
 
     pub fn seek(&mut self, pos: usize) -> Result<()> {
+        if pos > PACKET_BUFFER_SIZE {
+            return Err("End of buffer.".into());
+        }
         self.pos = pos;
         Ok(())
     }
 
     pub fn read(&mut self) -> Result<u8> {
-        if self.pos >= PACKET_BUFFER_SIZE {
+        if self.pos >= self.len {
             return Err("End of buffer.".into());
         }
         let res = self.buffer[self.pos];
@@ -57,6 +103,7 @@ impl BytePacketBuffer {
         }
         self.buffer[self.pos] = val;
         self.pos += 1;
+        self.len = self.len.max(self.pos);
         Ok(())
     }
     pub fn write_u8(&mut self, val: u8) -> Result<()> {
@@ -72,32 +119,32 @@ impl BytePacketBuffer {
         self.write(((val >> 24) & 0xFF) as u8)?;
         self.write(((val >> 16) & 0xFF) as u8)?;
         self.write(((val >> 8) & 0xFF) as u8)?;
-        self.write(((val >> 0) & 0xFF) as u8)?;
+        self.write((val & 0xFF) as u8)?;
         Ok(())
     }
 
     pub fn get(&mut self, pos: usize) -> Result<u8> {
-        if pos >= PACKET_BUFFER_SIZE {
+        if pos >= self.len {
             return Err("End of buffer.".into());
         }
         Ok(self.buffer[pos])
     }
 
     pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= PACKET_BUFFER_SIZE {
-            return Err("".into());
+        if start > self.len || len > self.len - start {
+            return Err("End of buffer.".into());
         }
         Ok(&self.buffer[start..start + len])
     }
 
-    pub fn read_qname(&mut self, outstr: &mut String) -> Result<()> {
+    pub fn read_qname(&mut self, outname: &mut Name) -> Result<()> {
         let mut pos = self.pos;
 
         let mut jumped = false;
         let max_jumps = 5;
         let mut jumps_performed = 0;
 
-        let mut delim = "";
+        let mut name = Name::root();
         loop {
             if jumps_performed > max_jumps {
                 return Err(format!("Limit of {} jumps excceeded", max_jumps).into());
@@ -110,7 +157,7 @@ impl BytePacketBuffer {
                     self.seek(pos + 2)?;
                 }
                 let b2 = self.get(pos + 1)? as u16;
-                let offset = (((len as u16) ^ 0xc0) << 0) | b2;
+                let offset = (((len as u16) ^ 0xc0) << 8) | b2;
                 pos = offset as usize;
                 jumped = true;
                 jumps_performed += 1;
@@ -120,10 +167,8 @@ impl BytePacketBuffer {
                 if len == 0 {
                     break;
                 }
-                outstr.push_str(delim);
-                let str_buffer = self.get_range(pos, len as usize)?;
-                outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
-                delim = ".";
+                let label = self.get_range(pos, len as usize)?;
+                name.push_label(label.to_vec())?;
                 pos += len as usize;
             }
         }
@@ -131,17 +176,44 @@ impl BytePacketBuffer {
         if !jumped {
             self.seek(pos)?;
         }
+        *outname = name;
         Ok(())
     }
 
-    pub fn write_qname(&mut self, qname: &str) -> Result<()> {
-        for label in qname.split('.') {
-            let len = label.len();
-            if len > 0x3f {
-                return Err("Single lable exceeds 63 characlets of length".into());
+    pub fn write_qname(&mut self, qname: &Name) -> Result<()> {
+        let labels: Vec<&[u8]> = qname.labels().collect();
+        for i in 0..labels.len() {
+            // Point back at the first place this suffix was written, the way
+            // other servers compress, so parsed messages re-encode identically.
+            let suffix = &labels[i..];
+            if let Some(&(_, offset)) = self
+                .names
+                .iter()
+                .find(|(name, _)| name.iter().eq(suffix.iter()))
+            {
+                self.write_u16(0xC000 | offset as u16)?;
+                return Ok(());
+            }
+            if self.pos <= 0x3FFF {
+                self.names
+                    .push((suffix.iter().map(|l| l.to_vec()).collect(), self.pos));
+            }
+
+            let label = labels[i];
+            self.write_u8(label.len() as u8)?;
+            for b in label {
+                self.write_u8(*b)?;
             }
-            self.write_u8(len as u8)?;
-            for b in label.as_bytes() {
+        }
+        self.write_u8(0)?;
+        Ok(())
+    }
+
+    /// Writes `qname` without compression, as some RDATA requires.
+    pub fn write_qname_uncompressed(&mut self, qname: &Name) -> Result<()> {
+        for label in qname.labels() {
+            self.write_u8(label.len() as u8)?;
+            for b in label {
                 self.write_u8(*b)?;
             }
         }
@@ -150,6 +222,9 @@ impl BytePacketBuffer {
     }
 
     fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        if pos >= self.len {
+            return Err("End of buffer.".into());
+        }
         self.buffer[pos] = val;
         Ok(())
     }
@@ -160,3 +235,83 @@ impl BytePacketBuffer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_may_end_at_buffer_end() {
+        let mut data = [0; PACKET_BUFFER_SIZE];
+        data[PACKET_BUFFER_SIZE - 1] = 7;
+        let mut buffer = BytePacketBuffer::from_bytes(&data).unwrap();
+        let range = buffer.get_range(PACKET_BUFFER_SIZE - 4, 4).unwrap();
+        assert_eq!(range, &[0, 0, 0, 7]);
+        assert!(buffer.get_range(PACKET_BUFFER_SIZE - 4, 5).is_err());
+        assert!(buffer.get_range(usize::MAX, 2).is_err());
+    }
+
+    #[test]
+    fn step_and_seek_stay_in_bounds() {
+        let mut buffer = BytePacketBuffer::from_bytes(&[0; PACKET_BUFFER_SIZE]).unwrap();
+        buffer.step(PACKET_BUFFER_SIZE).unwrap();
+        assert!(buffer.step(1).is_err());
+        assert!(buffer.seek(PACKET_BUFFER_SIZE + 1).is_err());
+        assert_eq!(buffer.pos(), PACKET_BUFFER_SIZE);
+    }
+
+    #[test]
+    fn set_u16_out_of_bounds_is_an_error() {
+        let mut buffer = BytePacketBuffer::from_bytes(&[0; PACKET_BUFFER_SIZE]).unwrap();
+        assert!(buffer.set_u16(PACKET_BUFFER_SIZE - 1, 0xFFFF).is_err());
+        assert!(buffer.set_u16(PACKET_BUFFER_SIZE, 0xFFFF).is_err());
+        buffer.set_u16(PACKET_BUFFER_SIZE - 2, 0x1234).unwrap();
+        assert_eq!(buffer.buffer[PACKET_BUFFER_SIZE - 2..], [0x12, 0x34]);
+    }
+
+    #[test]
+    fn compressed_name_beyond_first_256_bytes() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.seek(0x1A0).unwrap();
+        buffer.write_qname(&"example.com".parse().unwrap()).unwrap();
+        buffer.write_u8(3).unwrap();
+        for b in b"www" {
+            buffer.write_u8(*b).unwrap();
+        }
+        buffer.write_u16(0xC1A0).unwrap();
+
+        buffer.seek(0x1A0 + 13).unwrap();
+        let mut name = Name::root();
+        buffer.read_qname(&mut name).unwrap();
+        assert_eq!(name.to_string(), "www.example.com");
+        assert_eq!(buffer.pos(), 0x1A0 + 13 + 6);
+    }
+
+    #[test]
+    fn reads_stop_at_message_length() {
+        let mut buffer = BytePacketBuffer::from_bytes(&[0x12, 0x34, 0x56]).unwrap();
+        assert_eq!(buffer.read_u16().unwrap(), 0x1234);
+        assert!(buffer.read_u16().is_err());
+        assert!(buffer.get(3).is_err());
+        assert!(buffer.get_range(1, 3).is_err());
+        assert!(buffer.step(2).is_err());
+    }
+
+    #[test]
+    fn patching_is_limited_to_written_bytes() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_u16(0).unwrap();
+        assert_eq!(buffer.len(), 2);
+        buffer.set_u16(0, 0xABCD).unwrap();
+        assert!(buffer.set_u16(1, 0xABCD).is_err());
+    }
+
+    #[test]
+    fn pointer_loop_is_an_error() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_u16(0xC000).unwrap();
+        buffer.seek(0).unwrap();
+        let mut name = Name::root();
+        assert!(buffer.read_qname(&mut name).is_err());
+    }
+}
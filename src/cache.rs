@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::{
+    config::CacheConfig,
+    enums::{QueryType, ResultCode},
+    name::Name,
+    packet::Packet,
+    record::DnsRecord,
+};
+
+struct Entry {
+    packet: Packet,
+    inserted: Instant,
+    expires: Instant,
+}
+
+/// Resolved responses keyed by question, kept for the smallest TTL they
+/// carry. Names compare case-insensitively, so `WWW.example.com` and
+/// `www.example.com` share an entry.
+pub struct Cache {
+    capacity: usize,
+    max_ttl: u32,
+    negative_ttl: u32,
+    entries: HashMap<(Name, QueryType), Entry>,
+}
+
+impl Cache {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            capacity: config.capacity,
+            max_ttl: config.max_ttl,
+            negative_ttl: config.negative_ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns a copy of the cached response with its TTLs counted down by
+    /// the time it has spent in the cache.
+    pub fn get(&mut self, qname: &Name, qtype: QueryType, now: Instant) -> Option<Packet> {
+        let key = (qname.clone(), qtype);
+        let entry = self.entries.get(&key)?;
+        if entry.expires <= now {
+            self.entries.remove(&key);
+            return None;
+        }
+
+        let elapsed = now.duration_since(entry.inserted).as_secs() as u32;
+        let mut packet = entry.packet.clone();
+        for record in records_mut(&mut packet) {
+            record.set_ttl(record.ttl().saturating_sub(elapsed));
+        }
+        Some(packet)
+    }
+
+    /// Stores `packet` as the answer to `qname`/`qtype` and returns how many
+    /// entries had to be evicted to make room for it.
+    pub fn insert(
+        &mut self,
+        qname: &Name,
+        qtype: QueryType,
+        packet: &Packet,
+        now: Instant,
+    ) -> usize {
+        let ttl = match self.ttl_of(packet) {
+            Some(ttl) if ttl > 0 && self.capacity > 0 => ttl,
+            _ => return 0,
+        };
+
+        let key = (qname.clone(), qtype);
+        let mut evicted = 0;
+        if !self.entries.contains_key(&key) {
+            self.entries.retain(|_, entry| entry.expires > now);
+            while self.entries.len() >= self.capacity {
+                let soonest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(key, _)| key.clone());
+                if let Some(soonest) = soonest {
+                    self.entries.remove(&soonest);
+                    evicted += 1;
+                }
+            }
+        }
+
+        self.entries.insert(
+            key,
+            Entry {
+                packet: packet.clone(),
+                inserted: now,
+                expires: now + Duration::from_secs(ttl as u64),
+            },
+        );
+        evicted
+    }
+
+    /// Only definite answers are cached: NOERROR with records, or a negative
+    /// answer (NXDOMAIN / NODATA) for at most `negative_ttl`.
+    fn ttl_of(&self, packet: &Packet) -> Option<u32> {
+        let code = packet.header.responce_code;
+        if code != ResultCode::NOERROR && code != ResultCode::NXDOMAIN {
+            return None;
+        }
+
+        let min_ttl = packet
+            .answers
+            .iter()
+            .chain(packet.authorities.iter())
+            .chain(packet.resources.iter())
+            .map(DnsRecord::ttl)
+            .min();
+
+        let ttl = if packet.answers.is_empty() {
+            min_ttl.unwrap_or(self.negative_ttl).min(self.negative_ttl)
+        } else {
+            min_ttl?
+        };
+        Some(ttl.min(self.max_ttl))
+    }
+}
+
+fn records_mut(packet: &mut Packet) -> impl Iterator<Item = &mut DnsRecord> {
+    packet
+        .answers
+        .iter_mut()
+        .chain(packet.authorities.iter_mut())
+        .chain(packet.resources.iter_mut())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    fn cache(capacity: usize) -> Cache {
+        Cache::new(&CacheConfig {
+            capacity,
+            ..CacheConfig::default()
+        })
+    }
+
+    fn answer(qname: &str, ttl: u32) -> Packet {
+        let mut packet = Packet::new();
+        packet.answers.push(DnsRecord::A {
+            domain: name(qname),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl,
+        });
+        packet
+    }
+
+    #[test]
+    fn ttls_count_down_until_expiry() {
+        let mut cache = cache(10);
+        let now = Instant::now();
+        cache.insert(
+            &name("example.com"),
+            QueryType::A,
+            &answer("example.com", 30),
+            now,
+        );
+
+        let hit = cache
+            .get(
+                &name("EXAMPLE.com"),
+                QueryType::A,
+                now + Duration::from_secs(10),
+            )
+            .unwrap();
+        assert_eq!(hit.answers[0].ttl(), 20);
+        assert!(cache
+            .get(&name("example.com"), QueryType::AAAA, now)
+            .is_none());
+        assert!(cache
+            .get(
+                &name("example.com"),
+                QueryType::A,
+                now + Duration::from_secs(30)
+            )
+            .is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn failures_are_not_cached() {
+        let mut cache = cache(10);
+        let mut packet = answer("example.com", 30);
+        packet.header.responce_code = ResultCode::SERVFAIL;
+        cache.insert(&name("example.com"), QueryType::A, &packet, Instant::now());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn negative_answers_use_negative_ttl() {
+        let mut cache = cache(10);
+        let now = Instant::now();
+        let mut packet = Packet::new();
+        packet.header.responce_code = ResultCode::NXDOMAIN;
+        cache.insert(&name("nope.example"), QueryType::A, &packet, now);
+
+        let later = now + Duration::from_secs(CacheConfig::default().negative_ttl as u64);
+        assert!(cache
+            .get(&name("nope.example"), QueryType::A, now)
+            .is_some());
+        assert!(cache
+            .get(&name("nope.example"), QueryType::A, later)
+            .is_none());
+    }
+
+    #[test]
+    fn full_cache_evicts_soonest_expiry() {
+        let mut cache = cache(2);
+        let now = Instant::now();
+        cache.insert(
+            &name("a.example"),
+            QueryType::A,
+            &answer("a.example", 100),
+            now,
+        );
+        cache.insert(
+            &name("b.example"),
+            QueryType::A,
+            &answer("b.example", 10),
+            now,
+        );
+        let evicted = cache.insert(
+            &name("c.example"),
+            QueryType::A,
+            &answer("c.example", 50),
+            now,
+        );
+
+        assert_eq!(evicted, 1);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&name("b.example"), QueryType::A, now).is_none());
+        assert!(cache.get(&name("a.example"), QueryType::A, now).is_some());
+    }
+}
@@ -0,0 +1,591 @@
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::{acl::AddressList, cidr::Cidr, name::Name};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// Server configuration, read from the TOML file given on the command line.
+/// Every key is optional.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: SocketAddr,
+    /// How long to wait for each upstream server before giving up on it.
+    pub upstream_timeout_ms: u64,
+    /// `env_logger` filter for diagnostics on stderr, e.g. `"info"` or
+    /// `"dns_server_rust=debug"`. `RUST_LOG` is applied on top of it.
+    pub log_level: String,
+    pub cache: CacheConfig,
+    pub query_log: QueryLogConfig,
+    pub dnstap: Option<DnstapConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub cookies: CookieConfig,
+    pub acl: AclConfig,
+    /// TSIG keys shared with other servers, named wherever requests are
+    /// signed or checked.
+    pub keys: Vec<KeyConfig>,
+    /// Checked in order before resolution; the first list naming the
+    /// query decides what happens to it.
+    pub blocklists: Vec<BlocklistConfig>,
+    /// How often blocklist files are checked for changes; 0 never reloads.
+    pub blocklist_reload_secs: u64,
+    pub local: LocalConfig,
+    /// Zones this server is authoritative for.
+    pub zones: Vec<ZoneConfig>,
+    /// How often primary zone files are checked for changes; 0 never
+    /// reloads.
+    pub zone_reload_secs: u64,
+    /// Names resolved by asking other servers instead of from the root.
+    pub forwarders: Vec<ForwarderConfig>,
+    /// Split-horizon views, tried in order. Clients matching none of them
+    /// use the top-level zones, local records, forwarders and cache.
+    pub views: Vec<ViewConfig>,
+    pub tls: Option<TlsConfig>,
+    pub https: Option<HttpsConfig>,
+    pub quic: Option<TlsConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 8053)),
+            upstream_timeout_ms: 2000,
+            log_level: "info".to_string(),
+            cache: CacheConfig::default(),
+            query_log: QueryLogConfig::default(),
+            dnstap: None,
+            metrics: None,
+            rate_limit: None,
+            cookies: CookieConfig::default(),
+            acl: AclConfig::default(),
+            keys: Vec::new(),
+            blocklists: Vec::new(),
+            blocklist_reload_secs: 30,
+            local: LocalConfig::default(),
+            zones: Vec::new(),
+            zone_reload_secs: 30,
+            forwarders: Vec::new(),
+            views: Vec::new(),
+            tls: None,
+            https: None,
+            quic: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read config {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e).into())
+    }
+
+    pub fn parse(text: &str) -> Result<Config> {
+        Ok(toml::from_str(text)?)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Maximum number of cached responses; 0 disables the cache.
+    pub capacity: usize,
+    pub max_ttl: u32,
+    /// Lifetime of cached negative answers that carry no TTL of their own.
+    pub negative_ttl: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            max_ttl: 86_400,
+            negative_ttl: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueryLogConfig {
+    /// Where query log lines go; no sinks means no query log.
+    pub sinks: Vec<QueryLogSink>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum QueryLogSink {
+    Stdout,
+    File {
+        path: PathBuf,
+        /// Rotate once the file reaches this many bytes.
+        #[serde(default = "default_max_size")]
+        max_size: u64,
+        /// Number of rotated files (`path.1` .. `path.N`) to keep.
+        #[serde(default = "default_keep")]
+        keep: usize,
+    },
+    Syslog {
+        /// Local syslog socket; ignored when `server` is set.
+        #[serde(default = "default_syslog_socket")]
+        socket: PathBuf,
+        /// Remote syslog server, sent to over UDP.
+        #[serde(default)]
+        server: Option<SocketAddr>,
+        #[serde(default = "default_facility")]
+        facility: String,
+    },
+}
+
+/// dnstap output over Frame Streams, to exactly one of `socket` (a reader
+/// such as `fstrm_capture` listening on a Unix socket) or `file`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnstapConfig {
+    pub socket: Option<PathBuf>,
+    pub file: Option<PathBuf>,
+    /// Server identity in each frame; defaults to the hostname.
+    pub identity: Option<String>,
+    pub version: String,
+    /// Log CLIENT_QUERY / CLIENT_RESPONSE.
+    pub client: bool,
+    /// Log RESOLVER_QUERY / RESOLVER_RESPONSE.
+    pub resolver: bool,
+}
+
+impl Default for DnstapConfig {
+    fn default() -> Self {
+        Self {
+            socket: None,
+            file: None,
+            identity: None,
+            version: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            client: true,
+            resolver: true,
+        }
+    }
+}
+
+/// Prometheus endpoint, served as `http://<listen>/metrics`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub listen: SocketAddr,
+}
+
+/// DNS over TLS (RFC 7858), and over QUIC (RFC 9250) on the same port
+/// number over UDP.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    #[serde(default = "default_tls_listen")]
+    pub listen: SocketAddr,
+    /// PEM certificate chain, leaf first.
+    pub cert: PathBuf,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1).
+    pub key: PathBuf,
+    /// Connections with nothing in flight are closed after this long.
+    #[serde(default = "default_tls_idle_timeout")]
+    pub idle_timeout_secs: u64,
+}
+
+/// DNS over HTTPS (RFC 8484) at `/dns-query`, over HTTP/2 or HTTP/1.1.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpsConfig {
+    #[serde(default = "default_https_listen")]
+    pub listen: SocketAddr,
+    /// PEM certificate chain, leaf first.
+    pub cert: PathBuf,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1).
+    pub key: PathBuf,
+}
+
+/// Response Rate Limiting for UDP, modelled on BIND's `rate-limit`. A rate
+/// of 0 leaves that kind of response unlimited.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub responses_per_second: u32,
+    pub nxdomains_per_second: u32,
+    pub errors_per_second: u32,
+    /// Seconds of over-limit traffic remembered against a netblock.
+    pub window: u32,
+    /// Every `slip`th limited response is sent truncated instead of being
+    /// dropped; 0 drops them all.
+    pub slip: u32,
+    pub ipv4_prefix_length: u8,
+    pub ipv6_prefix_length: u8,
+    /// Clients that are never limited.
+    pub exempt: Vec<Cidr>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            responses_per_second: 5,
+            nxdomains_per_second: 5,
+            errors_per_second: 5,
+            window: 15,
+            slip: 2,
+            ipv4_prefix_length: 24,
+            ipv6_prefix_length: 56,
+            exempt: Vec::new(),
+        }
+    }
+}
+
+/// DNS Cookies (RFC 7873): answered with server cookies, and sent to
+/// upstream servers when resolving.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CookieConfig {
+    pub enabled: bool,
+    /// How often the secret behind server cookies is replaced. Cookies made
+    /// with the one before stay valid until the next replacement.
+    pub secret_rotation_secs: u64,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            secret_rotation_secs: 86_400,
+        }
+    }
+}
+
+/// Address match lists (see `AddressList`) deciding who is served.
+/// Clients that may not query, or that need recursion they may not use,
+/// are answered REFUSED.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclConfig {
+    pub query: AddressList,
+    pub recursion: AddressList,
+    /// Replace `query` / `recursion` for one transport.
+    pub udp: Option<TransportAclConfig>,
+    pub tcp: Option<TransportAclConfig>,
+    pub zones: Vec<ZoneAclConfig>,
+}
+
+impl Default for AclConfig {
+    fn default() -> Self {
+        Self {
+            query: AddressList::any(),
+            recursion: AddressList::local(),
+            udp: None,
+            tcp: None,
+            zones: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportAclConfig {
+    pub query: Option<AddressList>,
+    pub recursion: Option<AddressList>,
+}
+
+/// Lists for one zone and the names below it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneAclConfig {
+    pub zone: Name,
+    #[serde(default)]
+    pub allow_query: Option<AddressList>,
+    #[serde(default)]
+    pub allow_transfer: AddressList,
+    #[serde(default)]
+    pub allow_update: AddressList,
+    /// Requests signed with one of these TSIG keys may transfer or update
+    /// the zone from any address.
+    #[serde(default)]
+    pub allow_transfer_keys: Vec<Name>,
+    #[serde(default)]
+    pub allow_update_keys: Vec<Name>,
+}
+
+/// A TSIG key (RFC 8945).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyConfig {
+    pub name: Name,
+    pub algorithm: TsigAlgorithm,
+    /// The shared secret, base64 encoded.
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha384,
+    HmacSha512,
+}
+
+/// A list of names to block, in one of the formats below.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlocklistConfig {
+    pub path: PathBuf,
+    pub format: BlocklistFormat,
+    /// Applied to every name in `hosts` and `domains` lists; RPZ entries
+    /// carry their own action.
+    #[serde(default)]
+    pub action: PolicyActionKind,
+    /// Addresses for `sinkhole`; unset means `0.0.0.0` and `::`.
+    #[serde(default)]
+    pub sinkhole_ipv4: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub sinkhole_ipv6: Option<Ipv6Addr>,
+    /// Target for `cname`, e.g. a walled-garden page.
+    #[serde(default)]
+    pub redirect: Option<Name>,
+    /// TTL of synthesized answers.
+    #[serde(default = "default_policy_ttl")]
+    pub ttl: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlocklistFormat {
+    /// `/etc/hosts` style; the addresses are ignored.
+    Hosts,
+    /// One name per line, `*.example.com` for everything below it.
+    Domains,
+    /// A Response Policy Zone in master file format (QNAME triggers only).
+    Rpz,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyActionKind {
+    #[default]
+    Nxdomain,
+    Nodata,
+    Sinkhole,
+    Cname,
+}
+
+/// Names answered from local data before recursion.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalConfig {
+    /// An `/etc/hosts`-style file.
+    pub hosts_file: Option<PathBuf>,
+    /// Records in master file syntax, e.g. `"build.local A 10.1.2.3"`.
+    /// Names are absolute even without a trailing dot.
+    pub records: Vec<String>,
+    /// TTL of records that do not give their own.
+    pub ttl: u32,
+    /// Answer PTR queries for every local address that has no PTR record.
+    pub synthesize_ptr: bool,
+}
+
+impl Default for LocalConfig {
+    fn default() -> Self {
+        Self {
+            hosts_file: None,
+            records: Vec::new(),
+            ttl: 3600,
+            synthesize_ptr: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneConfig {
+    pub name: Name,
+    /// Master file holding the zone. A secondary zone saves each transfer
+    /// here and starts from it after a restart.
+    pub file: PathBuf,
+    /// Makes this a secondary zone, transferred from these servers.
+    #[serde(default)]
+    pub primaries: Vec<SocketAddr>,
+    /// Servers told about each new serial besides the zone's name servers.
+    #[serde(default)]
+    pub also_notify: Vec<SocketAddr>,
+    /// TSIG key that signs the transfers of a secondary zone and the
+    /// NOTIFY messages of a primary one. A secondary zone only takes
+    /// NOTIFY signed with it.
+    #[serde(default)]
+    pub key: Option<Name>,
+}
+
+/// Queries for `zone` and the names below it are sent, with recursion
+/// desired, to `servers` in turn.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwarderConfig {
+    pub zone: Name,
+    pub servers: Vec<UpstreamConfig>,
+}
+
+/// An upstream server: `address:port` for plain UDP, a `tls://host[:port]`,
+/// `quic://host[:port]` or `https://host[:port]/path` URL, or a table with
+/// the URL and its options.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(from = "UpstreamEntry")]
+pub struct UpstreamConfig {
+    pub url: String,
+    /// Addresses to connect to when the URL names its host, so that no
+    /// plaintext lookup is needed to reach it.
+    pub bootstrap: Vec<IpAddr>,
+    /// Base64 SHA-256 digests of acceptable certificate public keys
+    /// (RFC 7858 SPKI pins). When given, they replace hostname and chain
+    /// verification.
+    pub spki: Vec<String>,
+    /// PEM certificates to trust instead of the built-in web roots.
+    pub ca: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UpstreamEntry {
+    Url(String),
+    Table(UpstreamTable),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamTable {
+    url: String,
+    #[serde(default)]
+    bootstrap: Vec<IpAddr>,
+    #[serde(default)]
+    spki: Vec<String>,
+    ca: Option<PathBuf>,
+}
+
+impl From<UpstreamEntry> for UpstreamConfig {
+    fn from(entry: UpstreamEntry) -> Self {
+        match entry {
+            UpstreamEntry::Url(url) => UpstreamConfig {
+                url,
+                bootstrap: Vec::new(),
+                spki: Vec::new(),
+                ca: None,
+            },
+            UpstreamEntry::Table(table) => UpstreamConfig {
+                url: table.url,
+                bootstrap: table.bootstrap,
+                spki: table.spki,
+                ca: table.ca,
+            },
+        }
+    }
+}
+
+/// A view serves the clients it matches from its own data and cache.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ViewConfig {
+    pub name: String,
+    /// Clients whose source address is allowed by this list.
+    #[serde(default)]
+    pub match_clients: AddressList,
+    /// Clients whose request is signed with one of these TSIG keys.
+    #[serde(default)]
+    pub match_keys: Vec<Name>,
+    #[serde(default)]
+    pub zones: Vec<ZoneConfig>,
+    #[serde(default)]
+    pub forwarders: Vec<ForwarderConfig>,
+    #[serde(default)]
+    pub local: LocalConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+}
+
+fn default_policy_ttl() -> u32 {
+    60
+}
+
+fn default_tls_listen() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 853))
+}
+
+fn default_https_listen() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 443))
+}
+
+fn default_tls_idle_timeout() -> u64 {
+    30
+}
+
+fn default_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+fn default_syslog_socket() -> PathBuf {
+    PathBuf::from("/dev/log")
+}
+
+fn default_facility() -> String {
+    "daemon".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.listen, SocketAddr::from(([0, 0, 0, 0], 8053)));
+        assert_eq!(config.log_level, "info");
+        assert!(config.query_log.sinks.is_empty());
+    }
+
+    #[test]
+    fn query_log_sinks() {
+        let config = Config::parse(
+            r#"
+            log_level = "debug"
+
+            [[query_log.sinks]]
+            type = "stdout"
+
+            [[query_log.sinks]]
+            type = "file"
+            path = "/var/log/dns/query.log"
+            keep = 3
+
+            [[query_log.sinks]]
+            type = "syslog"
+            server = "192.0.2.10:514"
+            facility = "local0"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.log_level, "debug");
+        assert_eq!(config.query_log.sinks.len(), 3);
+        match &config.query_log.sinks[1] {
+            QueryLogSink::File { max_size, keep, .. } => {
+                assert_eq!(*max_size, default_max_size());
+                assert_eq!(*keep, 3);
+            }
+            sink => panic!("unexpected sink {:?}", sink),
+        }
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(Config::parse("listne = \"127.0.0.1:53\"").is_err());
+        assert!(Config::parse("[[query_log.sinks]]\ntype = \"kafka\"").is_err());
+    }
+}
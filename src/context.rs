@@ -0,0 +1,105 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use serde::Serialize;
+
+use crate::{
+    acl::Acl,
+    config::Config,
+    cookie::Cookies,
+    dnstap::Dnstap,
+    horizon::{self, View},
+    metrics::Metrics,
+    name::Name,
+    notify::Notifier,
+    policy::Policy,
+    querylog::QueryLog,
+    rrl::RateLimiter,
+    tsig::Keyring,
+    upstream::Pool,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// State shared by every listener for the lifetime of the server.
+pub struct ServerContext {
+    pub config: Config,
+    pub acl: Acl,
+    pub query_log: QueryLog,
+    pub dnstap: Option<Dnstap>,
+    pub metrics: Metrics,
+    pub rate_limiter: Option<RateLimiter>,
+    pub policy: Policy,
+    /// Never empty: the last view is the default one.
+    pub views: Vec<View>,
+    /// Connections to encrypted upstreams, shared by all views.
+    pub upstreams: Pool,
+    pub notifier: Notifier,
+    pub keys: Keyring,
+    pub cookies: Option<Cookies>,
+}
+
+impl ServerContext {
+    pub fn new(config: Config) -> Result<Self> {
+        let query_log = QueryLog::new(&config.query_log)?;
+        let dnstap = config.dnstap.as_ref().map(Dnstap::new).transpose()?;
+        let rate_limiter = config.rate_limit.as_ref().map(RateLimiter::new);
+        let policy = Policy::new(&config.blocklists)?;
+        let keys = Keyring::new(&config.keys)?;
+        let views = horizon::views(&config, &keys)?;
+        let cookies = if config.cookies.enabled {
+            Some(Cookies::new(&config.cookies)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            acl: Acl::new(&config.acl),
+            config,
+            query_log,
+            dnstap,
+            metrics: Metrics::new(),
+            rate_limiter,
+            policy,
+            views,
+            upstreams: Pool::default(),
+            notifier: Notifier::default(),
+            keys,
+            cookies,
+        })
+    }
+
+    /// The first view matching the client.
+    pub fn view(&self, client: IpAddr, key: Option<&Name>) -> &View {
+        self.views
+            .iter()
+            .find(|view| view.matches(client, key))
+            .unwrap_or_else(|| self.views.last().unwrap())
+    }
+}
+
+/// How a query reached the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Udp,
+    Tcp,
+    /// DNS over TLS.
+    Tls,
+    /// DNS over HTTPS.
+    Https,
+    /// DNS over QUIC.
+    Quic,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Udp => write!(f, "udp"),
+            Transport::Tcp => write!(f, "tcp"),
+            Transport::Tls => write!(f, "tls"),
+            Transport::Https => write!(f, "https"),
+            Transport::Quic => write!(f, "quic"),
+        }
+    }
+}
@@ -0,0 +1,498 @@
+//! DNS Cookies (RFC 7873): a client cookie that a response has to echo,
+//! and a server cookie, made from the client cookie and address, that only
+//! a client which has heard from this server before can send. Server
+//! cookies are the interoperable kind of RFC 9018.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::{
+    buffer::BytePacketBuffer, config::CookieConfig, enums::QueryType, header::Header, name::Name,
+    packet::Packet, question::Question, record::DnsRecord, PACKET_BUFFER_SIZE,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+const OPTION_COOKIE: u16 = 10;
+const CLIENT_COOKIE_LEN: usize = 8;
+const SERVER_COOKIE_VERSION: u8 = 1;
+/// Server cookies are taken for an hour after they are made, and from up
+/// to five minutes in the future for servers whose clocks run ahead.
+const MAX_AGE: u32 = 3600;
+const MAX_AHEAD: u32 = 300;
+/// Upstream servers whose server cookies are remembered at once; the rest
+/// are forgotten when there are more.
+const MAX_SERVERS: usize = 10_000;
+
+/// The COOKIE option: the client's cookie and, once the client has one
+/// from the server, the server's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    pub client: [u8; CLIENT_COOKIE_LEN],
+    pub server: Vec<u8>,
+}
+
+impl Cookie {
+    /// Reads the option data, which is a client cookie alone or followed
+    /// by a server cookie of 8 to 32 bytes.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() != CLIENT_COOKIE_LEN && !(16..=40).contains(&data.len()) {
+            return Err(format!("COOKIE option of {} bytes", data.len()).into());
+        }
+        let (client, server) = data.split_at(CLIENT_COOKIE_LEN);
+        Ok(Cookie {
+            client: client.try_into().unwrap(),
+            server: server.to_vec(),
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.client.to_vec();
+        data.extend_from_slice(&self.server);
+        data
+    }
+}
+
+/// What this server acts on in the OPT record of a request.
+#[derive(Debug, Clone, Default)]
+pub struct Edns {
+    pub cookie: Option<Cookie>,
+}
+
+/// Reads the OPT record of a request, if it has one. The records before it
+/// only have to be well-framed, so requests this server answers without
+/// parsing them are not turned away here. A malformed COOKIE is an error.
+pub fn read_edns(message: &[u8]) -> Result<Option<Edns>> {
+    let mut buffer = BytePacketBuffer::from_bytes(message)?;
+    let mut header = Header::new();
+    header.read(&mut buffer)?;
+    for _ in 0..header.questions {
+        Question::new(Name::root(), QueryType::UNKNOWN(0)).read(&mut buffer)?;
+    }
+    let records = usize::from(header.answers)
+        + usize::from(header.authoritative_entries)
+        + usize::from(header.resource_entries);
+    for _ in 0..records {
+        let start = buffer.pos();
+        let mut name = Name::root();
+        buffer.read_qname(&mut name)?;
+        let rtype = buffer.read_u16()?;
+        // Class and TTL.
+        buffer.step(6)?;
+        let len = buffer.read_u16()?;
+        buffer.step(usize::from(len))?;
+        if rtype == QueryType::OPT.to_num() {
+            buffer.seek(start)?;
+            let opt = DnsRecord::read(&mut buffer)?;
+            return Ok(Some(Edns {
+                cookie: cookie_of(&opt)?,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// The COOKIE option of an OPT record.
+fn cookie_of(opt: &DnsRecord) -> Result<Option<Cookie>> {
+    match opt {
+        DnsRecord::OPT { options, .. } => options
+            .iter()
+            .find(|(code, _)| *code == OPTION_COOKIE)
+            .map(|(_, data)| Cookie::parse(data))
+            .transpose(),
+        _ => Ok(None),
+    }
+}
+
+/// An OPT record offering messages as large as this server takes, with
+/// `cookie` if there is one.
+pub fn opt(cookie: Option<&Cookie>) -> DnsRecord {
+    DnsRecord::OPT {
+        domain: Name::root(),
+        payload_size: PACKET_BUFFER_SIZE as u16,
+        extended_rcode: 0,
+        version: 0,
+        flags: 0,
+        options: cookie
+            .map(|cookie| (OPTION_COOKIE, cookie.to_bytes()))
+            .into_iter()
+            .collect(),
+    }
+}
+
+/// The secrets behind both kinds of cookie this server makes: server
+/// cookies for its clients, and client cookies for the servers it asks.
+pub struct Cookies {
+    /// Seconds between replacements of the server secret; 0 never
+    /// replaces it.
+    rotation: u64,
+    secrets: Mutex<Secrets>,
+    client_secret: [u8; 16],
+    /// The server cookie each upstream server last sent.
+    learned: Mutex<HashMap<IpAddr, Vec<u8>>>,
+}
+
+struct Secrets {
+    current: [u8; 16],
+    /// The secret before the current one, whose cookies are still valid.
+    previous: Option<[u8; 16]>,
+    /// When the current secret replaced it, in seconds since the epoch.
+    since: u64,
+}
+
+impl Cookies {
+    pub fn new(config: &CookieConfig) -> Result<Self> {
+        Ok(Cookies {
+            rotation: config.secret_rotation_secs,
+            secrets: Mutex::new(Secrets {
+                current: random_secret()?,
+                previous: None,
+                since: secs(SystemTime::now()),
+            }),
+            client_secret: random_secret()?,
+            learned: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether `cookie` carries a server cookie this server made for
+    /// `client`, with the current secret or the one before it, that has
+    /// not expired.
+    pub fn is_valid(&self, cookie: &Cookie, client: IpAddr, now: SystemTime) -> bool {
+        let server = &cookie.server;
+        if server.len() != 16 || server[..4] != [SERVER_COOKIE_VERSION, 0, 0, 0] {
+            return false;
+        }
+        let timestamp = u32::from_be_bytes(server[4..8].try_into().unwrap());
+        let now = secs(now);
+        let age = (now as u32).wrapping_sub(timestamp);
+        if age > MAX_AGE && age.wrapping_neg() > MAX_AHEAD {
+            return false;
+        }
+        let secrets = self.secrets(now);
+        [Some(secrets.0), secrets.1]
+            .iter()
+            .flatten()
+            .any(|secret| server_cookie(secret, &cookie.client, timestamp, client) == server[..])
+    }
+
+    /// The cookie for the response to a request carrying `cookie`: the
+    /// client cookie with a fresh server cookie.
+    pub fn respond(&self, cookie: &Cookie, client: IpAddr, now: SystemTime) -> Cookie {
+        let now = secs(now);
+        let (secret, _) = self.secrets(now);
+        Cookie {
+            client: cookie.client,
+            server: server_cookie(&secret, &cookie.client, now as u32, client),
+        }
+    }
+
+    /// The cookie to send to `server`: a client cookie of its own, and the
+    /// server cookie it last sent.
+    pub fn client_cookie(&self, server: IpAddr) -> Cookie {
+        Cookie {
+            client: siphash(&self.client_secret, &octets(server)),
+            server: self
+                .learned
+                .lock()
+                .unwrap()
+                .get(&server)
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Checks a response from `server` to a query that carried `sent`, and
+    /// remembers the server cookie in it. A response that does not echo the
+    /// client cookie is taken for a spoofed one, as is one without a cookie
+    /// from a server that has sent one before.
+    pub fn check_response(&self, server: IpAddr, sent: &Cookie, response: &Packet) -> Result<()> {
+        let cookie = match response.opt().map(cookie_of).transpose()?.flatten() {
+            Some(cookie) => cookie,
+            None if sent.server.is_empty() => return Ok(()),
+            None => return Err(format!("response from {} has no cookie", server).into()),
+        };
+        if cookie.client != sent.client {
+            return Err(format!("response from {} has the wrong client cookie", server).into());
+        }
+        if !cookie.server.is_empty() {
+            let mut learned = self.learned.lock().unwrap();
+            if learned.len() >= MAX_SERVERS && !learned.contains_key(&server) {
+                learned.clear();
+            }
+            learned.insert(server, cookie.server);
+        }
+        Ok(())
+    }
+
+    /// The current secret and the one before it, replacing the current one
+    /// first if it is due.
+    fn secrets(&self, now: u64) -> ([u8; 16], Option<[u8; 16]>) {
+        let mut secrets = self.secrets.lock().unwrap();
+        let elapsed = now.saturating_sub(secrets.since);
+        if self.rotation > 0 && elapsed >= self.rotation {
+            match random_secret() {
+                Ok(secret) => {
+                    // Cookies from two secrets back have outlived theirs.
+                    secrets.previous =
+                        Some(secrets.current).filter(|_| elapsed < 2 * self.rotation);
+                    secrets.current = secret;
+                    secrets.since = now;
+                }
+                Err(e) => log::warn!("cannot replace the server cookie secret: {}", e),
+            }
+        }
+        (secrets.current, secrets.previous)
+    }
+}
+
+/// A server cookie (RFC 9018 section 4): version, three reserved bytes,
+/// the time it was made and a hash binding it to the client.
+fn server_cookie(
+    secret: &[u8; 16],
+    client_cookie: &[u8; CLIENT_COOKIE_LEN],
+    timestamp: u32,
+    client: IpAddr,
+) -> Vec<u8> {
+    let mut cookie = vec![SERVER_COOKIE_VERSION, 0, 0, 0];
+    cookie.extend_from_slice(&timestamp.to_be_bytes());
+    let mut data = client_cookie.to_vec();
+    data.extend_from_slice(&cookie);
+    data.extend_from_slice(&octets(client));
+    cookie.extend_from_slice(&siphash(secret, &data));
+    cookie
+}
+
+fn octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+/// SipHash-2-4, with the output in its usual little-endian byte order.
+fn siphash(key: &[u8; 16], data: &[u8]) -> [u8; 8] {
+    let k0 = u64::from_le_bytes(key[..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(key[8..].try_into().unwrap());
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+    let compress = |v: &mut [u64; 4], m: u64| {
+        v[3] ^= m;
+        sip_round(v);
+        sip_round(v);
+        v[0] ^= m;
+    };
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        compress(&mut v, u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    let mut last = [0; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = data.len() as u8;
+    compress(&mut v, u64::from_le_bytes(last));
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    (v[0] ^ v[1] ^ v[2] ^ v[3]).to_le_bytes()
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+fn random_secret() -> Result<[u8; 16]> {
+    let mut secret = [0; 16];
+    SystemRandom::new()
+        .fill(&mut secret)
+        .map_err(|_| "no random numbers for a cookie secret")?;
+    Ok(secret)
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn server_cookies_match_rfc_9018() {
+        // Appendix A.1, and A.3 from a server with another secret.
+        let secret: [u8; 16] = hex("e5e973e5a6b2a43f48e7dc849e37bfcf").try_into().unwrap();
+        let client: [u8; 8] = hex("2464c4abcf10c957").try_into().unwrap();
+        assert_eq!(
+            server_cookie(
+                &secret,
+                &client,
+                1559731985,
+                "198.51.100.100".parse().unwrap()
+            ),
+            hex("010000005cf79f111f8130c3eee29480")
+        );
+        let secret: [u8; 16] = hex("dd3bdf9344b678b185a6f5cb60fca715").try_into().unwrap();
+        let client: [u8; 8] = hex("22681ab97d52c298").try_into().unwrap();
+        assert_eq!(
+            server_cookie(
+                &secret,
+                &client,
+                1559741817,
+                "2001:db8:220:1:59de:d0f4:8769:82b8".parse().unwrap()
+            ),
+            hex("010000005cf7c57926556bd0934c72f8")
+        );
+    }
+
+    #[test]
+    fn server_cookies_are_checked_and_rotate() {
+        let cookies = Cookies::new(&CookieConfig {
+            enabled: true,
+            secret_rotation_secs: 60,
+        })
+        .unwrap();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let now = SystemTime::now();
+        let sent = Cookie::parse(&hex("2464c4abcf10c957")).unwrap();
+        let cookie = cookies.respond(&sent, client, now);
+        assert_eq!(cookie.client, sent.client);
+        assert_eq!(Cookie::parse(&cookie.to_bytes()).unwrap(), cookie);
+        assert!(!cookies.is_valid(&sent, client, now));
+        assert!(cookies.is_valid(&cookie, client, now));
+        assert!(!cookies.is_valid(&cookie, "192.0.2.2".parse().unwrap(), now));
+        let mut forged = cookie.clone();
+        forged.server[15] ^= 1;
+        assert!(!cookies.is_valid(&forged, client, now));
+
+        // Made with the secret before the current one, then two before.
+        assert!(cookies.is_valid(&cookie, client, now + Duration::from_secs(61)));
+        let later = cookies.respond(&sent, client, now + Duration::from_secs(61));
+        assert!(!cookies.is_valid(&cookie, client, now + Duration::from_secs(122)));
+        assert!(cookies.is_valid(&later, client, now + Duration::from_secs(122)));
+    }
+
+    #[test]
+    fn server_cookies_expire() {
+        let cookies = Cookies::new(&CookieConfig {
+            enabled: true,
+            secret_rotation_secs: 0,
+        })
+        .unwrap();
+        let client: IpAddr = "2001:db8::1".parse().unwrap();
+        let sent = Cookie::parse(&hex("22681ab97d52c298")).unwrap();
+        let cookie = cookies.respond(&sent, client, at(1_000_000));
+        assert!(cookies.is_valid(&cookie, client, at(1_000_000 + 3600)));
+        assert!(!cookies.is_valid(&cookie, client, at(1_000_000 + 3601)));
+        assert!(cookies.is_valid(&cookie, client, at(1_000_000 - 300)));
+        assert!(!cookies.is_valid(&cookie, client, at(1_000_000 - 301)));
+    }
+
+    #[test]
+    fn responses_have_to_echo_the_client_cookie() {
+        let cookies = Cookies::new(&CookieConfig::default()).unwrap();
+        let server: IpAddr = "198.51.100.53".parse().unwrap();
+        let sent = cookies.client_cookie(server);
+        assert!(sent.server.is_empty());
+        assert_eq!(cookies.client_cookie(server), sent);
+        assert_ne!(
+            cookies
+                .client_cookie("198.51.100.54".parse().unwrap())
+                .client,
+            sent.client
+        );
+
+        let response = |cookie: Option<Cookie>| {
+            let mut packet = Packet::new();
+            packet.resources.push(opt(cookie.as_ref()));
+            packet
+        };
+        // Servers without cookies are believed until they send one.
+        assert!(cookies
+            .check_response(server, &sent, &Packet::new())
+            .is_ok());
+        assert!(cookies
+            .check_response(server, &sent, &response(None))
+            .is_ok());
+        let spoofed = Cookie {
+            client: [0; 8],
+            server: vec![1; 16],
+        };
+        assert!(cookies
+            .check_response(server, &sent, &response(Some(spoofed)))
+            .is_err());
+        let answered = Cookie {
+            client: sent.client,
+            server: vec![1; 16],
+        };
+        cookies
+            .check_response(server, &sent, &response(Some(answered.clone())))
+            .unwrap();
+
+        let sent = cookies.client_cookie(server);
+        assert_eq!(sent, answered);
+        assert!(cookies
+            .check_response(server, &sent, &Packet::new())
+            .is_err());
+    }
+
+    #[test]
+    fn malformed_cookies_are_errors() {
+        let request = |data: &[u8]| {
+            let mut packet = Packet::new();
+            packet.resources.push(DnsRecord::OPT {
+                domain: Name::root(),
+                payload_size: 1232,
+                extended_rcode: 0,
+                version: 0,
+                flags: 0,
+                options: vec![(3, Vec::new()), (OPTION_COOKIE, data.to_vec())],
+            });
+            let mut buffer = BytePacketBuffer::new();
+            packet.write(&mut buffer).unwrap();
+            read_edns(&buffer.buffer[..buffer.pos()])
+        };
+        assert_eq!(
+            request(&[7; 8]).unwrap().unwrap().cookie.unwrap().client,
+            [7; 8]
+        );
+        assert_eq!(
+            request(&[7; 40]).unwrap().unwrap().cookie.unwrap().server,
+            [7; 32]
+        );
+        for len in [0, 7, 9, 15, 41] {
+            assert!(request(&vec![7; len]).is_err(), "{} bytes", len);
+        }
+        assert!(read_edns(&[0; 12]).unwrap().is_none());
+    }
+}
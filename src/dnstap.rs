@@ -0,0 +1,479 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{config::DnstapConfig, context::Transport};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// Frames waiting for the writer thread. Beyond this, frames are dropped
+/// rather than slowing down queries.
+const QUEUE_SIZE: usize = 4096;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Frame Streams control frame types and fields.
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FINISH: u32 = 0x05;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 0x01;
+
+/// `Message.Type` from dnstap.proto.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    ResolverQuery = 3,
+    ResolverResponse = 4,
+    ClientQuery = 5,
+    ClientResponse = 6,
+}
+
+impl MessageType {
+    fn is_query(self) -> bool {
+        matches!(self, MessageType::ResolverQuery | MessageType::ClientQuery)
+    }
+}
+
+/// One DNS message as seen on the wire. `query_address` is the side that
+/// sent the query: the client for CLIENT_*, this server for RESOLVER_*.
+#[derive(Debug, Clone)]
+pub struct DnstapMessage<'a> {
+    pub kind: MessageType,
+    pub transport: Transport,
+    pub query_address: SocketAddr,
+    pub response_address: SocketAddr,
+    pub query_time: SystemTime,
+    pub response_time: Option<SystemTime>,
+    pub message: &'a [u8],
+}
+
+/// Sends dnstap frames to a Frame Streams reader on a Unix socket, or
+/// writes them to a file, from a background thread.
+pub struct Dnstap {
+    identity: Vec<u8>,
+    version: Vec<u8>,
+    client: bool,
+    resolver: bool,
+    sender: Option<SyncSender<Vec<u8>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Dnstap {
+    pub fn new(config: &DnstapConfig) -> Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let writer = match (&config.socket, &config.file) {
+            (Some(path), None) => {
+                let path = path.clone();
+                thread::Builder::new()
+                    .name("dnstap".to_string())
+                    .spawn(move || write_socket(&path, receiver))?
+            }
+            (None, Some(path)) => {
+                // A Frame Streams file holds a single stream, so an old
+                // capture is replaced rather than appended to.
+                let file = File::create(path)
+                    .map_err(|e| format!("Cannot create dnstap file {}: {}", path.display(), e))?;
+                let path = path.clone();
+                thread::Builder::new()
+                    .name("dnstap".to_string())
+                    .spawn(move || {
+                        if let Err(e) = write_stream(BufWriter::new(file), &receiver) {
+                            log::error!("dnstap {}: {}", path.display(), e);
+                        }
+                    })?
+            }
+            _ => return Err("dnstap needs exactly one of socket or file".into()),
+        };
+
+        let identity = match &config.identity {
+            Some(identity) => identity.clone(),
+            None => hostname(),
+        };
+        Ok(Self {
+            identity: identity.into_bytes(),
+            version: config.version.clone().into_bytes(),
+            client: config.client,
+            resolver: config.resolver,
+            sender: Some(sender),
+            writer: Some(writer),
+        })
+    }
+
+    pub fn log(&self, message: &DnstapMessage) {
+        let wanted = match message.kind {
+            MessageType::ClientQuery | MessageType::ClientResponse => self.client,
+            MessageType::ResolverQuery | MessageType::ResolverResponse => self.resolver,
+        };
+        if !wanted {
+            return;
+        }
+
+        let frame = encode(&self.identity, &self.version, message);
+        if let Some(sender) = &self.sender {
+            match sender.try_send(frame) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => log::debug!("dnstap queue full, frame dropped"),
+                Err(TrySendError::Disconnected(_)) => {}
+            }
+        }
+    }
+}
+
+/// Closing the queue lets the writer send STOP and finish the stream.
+impl Drop for Dnstap {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default()
+}
+
+fn write_socket(path: &Path, receiver: Receiver<Vec<u8>>) {
+    loop {
+        let result = UnixStream::connect(path)
+            .map_err(Error::from)
+            .and_then(|mut stream| {
+                handshake(&mut stream)?;
+                write_stream(BufWriter::new(&stream), &receiver)?;
+                read_control(&mut stream, CONTROL_FINISH)
+            });
+        match result {
+            Ok(()) => return,
+            Err(e) => log::warn!("dnstap {}: {}", path.display(), e),
+        }
+
+        // Nobody is listening: drop frames until it is time to retry, and
+        // stop once the server shuts down.
+        let retry = Instant::now() + RECONNECT_DELAY;
+        loop {
+            let wait = retry.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(wait) {
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+}
+
+/// Bidirectional Frame Streams: READY, wait for ACCEPT, then the stream.
+fn handshake(stream: &mut UnixStream) -> Result<()> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.write_all(&control_frame(CONTROL_READY))?;
+    read_control(stream, CONTROL_ACCEPT)?;
+    Ok(())
+}
+
+/// Writes START, every frame until the queue closes, then STOP.
+fn write_stream<W: Write>(mut out: W, receiver: &Receiver<Vec<u8>>) -> Result<()> {
+    out.write_all(&control_frame(CONTROL_START))?;
+    out.flush()?;
+    while let Ok(frame) = receiver.recv() {
+        write_frame(&mut out, &frame)?;
+        while let Ok(frame) = receiver.try_recv() {
+            write_frame(&mut out, &frame)?;
+        }
+        out.flush()?;
+    }
+    out.write_all(&control_frame(CONTROL_STOP))?;
+    out.flush()?;
+    Ok(())
+}
+
+fn write_frame<W: Write>(out: &mut W, frame: &[u8]) -> io::Result<()> {
+    out.write_all(&(frame.len() as u32).to_be_bytes())?;
+    out.write_all(frame)
+}
+
+fn control_frame(control_type: u32) -> Vec<u8> {
+    let mut control = control_type.to_be_bytes().to_vec();
+    if control_type != CONTROL_STOP && control_type != CONTROL_FINISH {
+        control.extend_from_slice(&CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        control.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        control.extend_from_slice(CONTENT_TYPE);
+    }
+
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend_from_slice(&(control.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&control);
+    frame
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+/// Reads one control frame and checks its type, and that an ACCEPT lists
+/// our content type.
+fn read_control<R: Read>(input: &mut R, expected: u32) -> Result<()> {
+    if read_u32(input)? != 0 {
+        return Err("expected a control frame".into());
+    }
+    let len = read_u32(input)? as usize;
+    if !(4..=512).contains(&len) {
+        return Err(format!("bad control frame length {}", len).into());
+    }
+    let mut control = vec![0; len];
+    input.read_exact(&mut control)?;
+
+    let control_type = u32::from_be_bytes([control[0], control[1], control[2], control[3]]);
+    if control_type != expected {
+        return Err(format!("expected control frame {}, got {}", expected, control_type).into());
+    }
+    if expected != CONTROL_ACCEPT {
+        return Ok(());
+    }
+
+    let mut fields = &control[4..];
+    while fields.len() >= 8 {
+        let field = u32::from_be_bytes([fields[0], fields[1], fields[2], fields[3]]);
+        let field_len = u32::from_be_bytes([fields[4], fields[5], fields[6], fields[7]]) as usize;
+        let value = fields
+            .get(8..8 + field_len)
+            .ok_or("truncated control field")?;
+        if field == CONTROL_FIELD_CONTENT_TYPE && value == CONTENT_TYPE {
+            return Ok(());
+        }
+        fields = &fields[8 + field_len..];
+    }
+    Err("reader does not accept protobuf:dnstap.Dnstap".into())
+}
+
+// Protobuf encoding of the few dnstap.proto fields we fill in.
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_uint(out: &mut Vec<u8>, field: u32, value: u64) {
+    put_varint(out, (field as u64) << 3);
+    put_varint(out, value);
+}
+
+fn put_fixed32(out: &mut Vec<u8>, field: u32, value: u32) {
+    put_varint(out, (field as u64) << 3 | 5);
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, field: u32, value: &[u8]) {
+    put_varint(out, (field as u64) << 3 | 2);
+    put_varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+fn put_time(out: &mut Vec<u8>, sec_field: u32, time: SystemTime) {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    put_uint(out, sec_field, since.as_secs());
+    put_fixed32(out, sec_field + 1, since.subsec_nanos());
+}
+
+fn put_address(out: &mut Vec<u8>, field: u32, addr: &SocketAddr) {
+    match addr {
+        SocketAddr::V4(addr) => put_bytes(out, field, &addr.ip().octets()),
+        SocketAddr::V6(addr) => put_bytes(out, field, &addr.ip().octets()),
+    }
+}
+
+/// `Message.SocketProtocol` from dnstap.proto.
+fn socket_protocol(transport: Transport) -> u64 {
+    match transport {
+        Transport::Udp => 1,
+        Transport::Tcp => 2,
+        Transport::Tls => 3,
+        Transport::Https => 4,
+        Transport::Quic => 7,
+    }
+}
+
+fn encode(identity: &[u8], version: &[u8], message: &DnstapMessage) -> Vec<u8> {
+    let mut inner = Vec::new();
+    put_uint(&mut inner, 1, message.kind as u64);
+    let family = if message.query_address.is_ipv4() {
+        1
+    } else {
+        2
+    };
+    put_uint(&mut inner, 2, family);
+    put_uint(&mut inner, 3, socket_protocol(message.transport));
+    put_address(&mut inner, 4, &message.query_address);
+    put_address(&mut inner, 5, &message.response_address);
+    put_uint(&mut inner, 6, message.query_address.port() as u64);
+    put_uint(&mut inner, 7, message.response_address.port() as u64);
+    put_time(&mut inner, 8, message.query_time);
+    if message.kind.is_query() {
+        put_bytes(&mut inner, 10, message.message);
+    } else {
+        if let Some(time) = message.response_time {
+            put_time(&mut inner, 12, time);
+        }
+        put_bytes(&mut inner, 14, message.message);
+    }
+
+    let mut frame = Vec::new();
+    if !identity.is_empty() {
+        put_bytes(&mut frame, 1, identity);
+    }
+    put_bytes(&mut frame, 2, version);
+    put_bytes(&mut frame, 14, &inner);
+    // Dnstap.Type MESSAGE
+    put_uint(&mut frame, 15, 1);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn config(socket: Option<PathBuf>, file: Option<PathBuf>) -> DnstapConfig {
+        DnstapConfig {
+            socket,
+            file,
+            identity: Some("ns1".to_string()),
+            ..DnstapConfig::default()
+        }
+    }
+
+    fn message(kind: MessageType) -> DnstapMessage<'static> {
+        DnstapMessage {
+            kind,
+            transport: Transport::Udp,
+            query_address: "192.0.2.1:5353".parse().unwrap(),
+            response_address: "192.0.2.53:53".parse().unwrap(),
+            query_time: UNIX_EPOCH + Duration::new(1_700_000_000, 5),
+            response_time: Some(UNIX_EPOCH + Duration::new(1_700_000_001, 0)),
+            message: b"\x12\x34",
+        }
+    }
+
+    /// Splits a Frame Streams capture into control types and data frames.
+    fn read_stream<R: Read>(input: &mut R) -> (Vec<u32>, Vec<Vec<u8>>) {
+        let mut controls = Vec::new();
+        let mut frames = Vec::new();
+        while let Ok(len) = read_u32(input) {
+            if len == 0 {
+                let len = read_u32(input).unwrap() as usize;
+                let mut control = vec![0; len];
+                input.read_exact(&mut control).unwrap();
+                let control_type =
+                    u32::from_be_bytes([control[0], control[1], control[2], control[3]]);
+                controls.push(control_type);
+                if control_type == CONTROL_STOP {
+                    break;
+                }
+            } else {
+                let mut frame = vec![0; len as usize];
+                input.read_exact(&mut frame).unwrap();
+                frames.push(frame);
+            }
+        }
+        (controls, frames)
+    }
+
+    #[test]
+    fn encodes_client_query() {
+        let frame = encode(b"ns1", b"0.1", &message(MessageType::ClientQuery));
+        let mut expected = vec![0x0A, 3, b'n', b's', b'1', 0x12, 3, b'0', b'.', b'1'];
+        expected.extend_from_slice(&[0x72, 38]);
+        expected.extend_from_slice(&[0x08, 5, 0x10, 1, 0x18, 1]);
+        expected.extend_from_slice(&[0x22, 4, 192, 0, 2, 1, 0x2A, 4, 192, 0, 2, 53]);
+        expected.extend_from_slice(&[0x30, 0xE9, 0x29, 0x38, 53]);
+        expected.extend_from_slice(&[0x40, 0x80, 0xE2, 0xCF, 0xAA, 0x06, 0x4D, 5, 0, 0, 0]);
+        expected.extend_from_slice(&[0x52, 2, 0x12, 0x34]);
+        expected.extend_from_slice(&[0x78, 1]);
+        assert_eq!(frame, expected);
+    }
+
+    #[test]
+    fn file_capture_is_a_frame_stream() {
+        let path = temp_path("dnstap-file");
+        let dnstap = Dnstap::new(&config(None, Some(path.clone()))).unwrap();
+        dnstap.log(&message(MessageType::ClientQuery));
+        dnstap.log(&message(MessageType::ClientResponse));
+        drop(dnstap);
+
+        let mut file = File::open(&path).unwrap();
+        let (controls, frames) = read_stream(&mut file);
+        assert_eq!(controls, vec![CONTROL_START, CONTROL_STOP]);
+        assert_eq!(frames.len(), 2);
+        let version = DnstapConfig::default().version;
+        assert_eq!(
+            frames[1],
+            encode(
+                b"ns1",
+                version.as_bytes(),
+                &message(MessageType::ClientResponse)
+            )
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn socket_output_does_the_handshake() {
+        let path = temp_path("dnstap-socket");
+        let listener = UnixListener::bind(&path).unwrap();
+        let reader = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_control(&mut stream, CONTROL_READY).unwrap();
+            let mut accept = control_frame(CONTROL_START);
+            accept[11] = CONTROL_ACCEPT as u8;
+            stream.write_all(&accept).unwrap();
+            let stream_data = read_stream(&mut stream);
+            stream.write_all(&control_frame(CONTROL_FINISH)).unwrap();
+            stream_data
+        });
+
+        let dnstap = Dnstap::new(&config(Some(path.clone()), None)).unwrap();
+        dnstap.log(&message(MessageType::ResolverQuery));
+        drop(dnstap);
+
+        let (controls, frames) = reader.join().unwrap();
+        assert_eq!(controls, vec![CONTROL_START, CONTROL_STOP]);
+        assert_eq!(frames.len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn filtered_kinds_are_not_sent() {
+        let path = temp_path("dnstap-filter");
+        let mut config = config(None, Some(path.clone()));
+        config.resolver = false;
+        let dnstap = Dnstap::new(&config).unwrap();
+        dnstap.log(&message(MessageType::ResolverQuery));
+        dnstap.log(&message(MessageType::ClientQuery));
+        drop(dnstap);
+
+        let (_, frames) = read_stream(&mut File::open(&path).unwrap());
+        assert_eq!(frames.len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,706 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::engine::{general_purpose, DecodePaddingMode, GeneralPurpose};
+use base64::Engine;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{ALLOW, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use rustls::ServerConfig;
+use serde_json::{json, Value};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    buffer::BytePacketBuffer,
+    context::{ServerContext, Transport},
+    enums::{QueryType, ResultCode},
+    handler::{self, Client},
+    name::Name,
+    packet::Packet,
+    question::Question,
+    record::{opt_data, DnsRecord},
+    PACKET_BUFFER_SIZE,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+pub const PATH: &str = "/dns-query";
+const DNS_MESSAGE: &str = "application/dns-message";
+const DNS_JSON: &str = "application/dns-json";
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const TYPE_TXT: u16 = 16;
+
+/// RFC 8484 leaves the padding off, but accept clients that add it.
+const BASE64URL: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::URL_SAFE,
+    general_purpose::NO_PAD.with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// A DoH request once the HTTP framing is stripped off.
+#[derive(Debug, PartialEq)]
+enum Query {
+    /// A DNS message, from the `dns` parameter or a POST body.
+    Message(Vec<u8>),
+    /// The JSON API's `name` and `type` parameters.
+    Json { name: Name, qtype: QueryType },
+}
+
+/// Accepts DNS-over-HTTPS connections, speaking HTTP/2 or HTTP/1.1 as the
+/// client negotiates. Runs its own runtime and only returns if that fails.
+pub fn serve(listener: TcpListener, tls: Arc<ServerConfig>, ctx: Arc<ServerContext>) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_name("doh")
+        .build()?;
+    let local = listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+    runtime.block_on(async move {
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        let acceptor = TlsAcceptor::from(tls);
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("https accept: {}", e);
+                    continue;
+                }
+            };
+            let client = Client {
+                addr,
+                local,
+                transport: Transport::Https,
+            };
+            let acceptor = acceptor.clone();
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let stream =
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(e)) => return log::debug!("https handshake with {}: {}", addr, e),
+                        Err(_) => return log::debug!("https handshake with {} timed out", addr),
+                    };
+                let service = service_fn(move |request| handle(ctx.clone(), client, request));
+                if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    log::debug!("https connection from {}: {}", addr, e);
+                }
+            });
+        }
+    })
+}
+
+async fn handle(
+    ctx: Arc<ServerContext>,
+    client: Client,
+    request: Request<Incoming>,
+) -> std::result::Result<Response<Full<Bytes>>, Infallible> {
+    let query = match parse_request(request).await {
+        Ok(query) => query,
+        Err(response) => return Ok(response),
+    };
+    // Resolution blocks on upstream sockets, so keep it off the runtime.
+    let response = tokio::task::spawn_blocking(move || respond(&ctx, client, query))
+        .await
+        .unwrap_or_else(|_| plain(StatusCode::INTERNAL_SERVER_ERROR, "internal error"));
+    Ok(response)
+}
+
+/// Unwraps the query from an HTTP request, or gives the error response to
+/// send instead.
+async fn parse_request(
+    request: Request<Incoming>,
+) -> std::result::Result<Query, Response<Full<Bytes>>> {
+    if request.uri().path() != PATH {
+        return Err(plain(StatusCode::NOT_FOUND, "not found"));
+    }
+    match *request.method() {
+        Method::GET => parse_get(request.uri().query().unwrap_or(""))
+            .map_err(|e| plain(StatusCode::BAD_REQUEST, &e.to_string())),
+        Method::POST => {
+            let content_type = request
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("");
+            if !content_type.eq_ignore_ascii_case(DNS_MESSAGE) {
+                return Err(plain(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "expected application/dns-message",
+                ));
+            }
+            let body = Limited::new(request.into_body(), PACKET_BUFFER_SIZE)
+                .collect()
+                .await
+                .map_err(|_| plain(StatusCode::PAYLOAD_TOO_LARGE, "message too large"))?;
+            Ok(Query::Message(body.to_bytes().to_vec()))
+        }
+        _ => {
+            let mut response = plain(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+            response
+                .headers_mut()
+                .insert(ALLOW, "GET, POST".parse().unwrap());
+            Err(response)
+        }
+    }
+}
+
+/// `?dns=<base64url message>`, or the JSON API's `?name=<name>&type=<type>`.
+fn parse_get(query: &str) -> Result<Query> {
+    let params: HashMap<&str, &str> = query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .collect();
+    if let Some(dns) = params.get("dns") {
+        let message = BASE64URL
+            .decode(dns)
+            .map_err(|e| format!("Invalid dns parameter: {}", e))?;
+        return Ok(Query::Message(message));
+    }
+    let name = params.get("name").ok_or("Missing dns or name parameter")?;
+    let name = percent_decode(name)?;
+    let name = if name.is_ascii() {
+        name.parse()?
+    } else {
+        Name::from_unicode(&name)?
+    };
+    let qtype = match params.get("type") {
+        Some(qtype) => qtype.parse()?,
+        None => QueryType::A,
+    };
+    Ok(Query::Json { name, qtype })
+}
+
+fn percent_decode(text: &str) -> Result<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let byte = text
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| format!("Invalid escape in {:?}", text))?;
+                decoded.push(byte);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+/// Answers the query through the normal pipeline and wraps the result in
+/// the matching content type.
+fn respond(ctx: &ServerContext, client: Client, query: Query) -> Response<Full<Bytes>> {
+    let json = matches!(query, Query::Json { .. });
+    let message = match query {
+        Query::Message(message) => message,
+        Query::Json { name, qtype } => {
+            let mut packet = Packet::new();
+            packet.header.recursion_desired = true;
+            packet.questions.push(Question::new(name, qtype));
+            let mut buffer = BytePacketBuffer::new();
+            if let Err(e) = packet.write(&mut buffer) {
+                return plain(StatusCode::BAD_REQUEST, &e.to_string());
+            }
+            buffer.buffer[..buffer.pos()].to_vec()
+        }
+    };
+    let valid = BytePacketBuffer::from_bytes(&message)
+        .and_then(|mut buffer| Packet::from_buffer(&mut buffer));
+    match valid {
+        Ok(packet) if !packet.header.responce => {}
+        Ok(_) => return plain(StatusCode::BAD_REQUEST, "not a query"),
+        Err(e) => return plain(StatusCode::BAD_REQUEST, &e.to_string()),
+    }
+
+    let data = BytePacketBuffer::from_bytes(&message)
+        .and_then(|mut buffer| handler::answer(ctx, &mut buffer, client));
+    let data = match data {
+        Ok(Some(data)) => data,
+        // A blocklist chose to drop the query, which HTTP cannot express.
+        Ok(None) => return plain(StatusCode::FORBIDDEN, "query refused"),
+        Err(e) => {
+            log::warn!("https query from {}: {}", client.addr, e);
+            return plain(StatusCode::INTERNAL_SERVER_ERROR, "internal error");
+        }
+    };
+    let packet = match BytePacketBuffer::from_bytes(&data)
+        .and_then(|mut buffer| Packet::from_buffer(&mut buffer))
+    {
+        Ok(packet) => packet,
+        Err(e) => {
+            log::warn!("https response to {}: {}", client.addr, e);
+            return plain(StatusCode::INTERNAL_SERVER_ERROR, "internal error");
+        }
+    };
+
+    let (content_type, body) = if json {
+        (DNS_JSON, Bytes::from(to_json(&packet).to_string()))
+    } else {
+        (DNS_MESSAGE, Bytes::from(data))
+    };
+    Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .header(CACHE_CONTROL, format!("max-age={}", max_age(&packet)))
+        .body(Full::new(body))
+        .unwrap()
+}
+
+/// RFC 8484 section 5.1: the response may be cached for as long as its
+/// shortest-lived record.
+fn max_age(packet: &Packet) -> u32 {
+    packet
+        .answers
+        .iter()
+        .chain(&packet.authorities)
+        .chain(&packet.resources)
+        .filter(|record| record.qtype() != QueryType::OPT)
+        .map(DnsRecord::ttl)
+        .min()
+        .unwrap_or(0)
+}
+
+fn plain(status: StatusCode, text: &str) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(Full::new(Bytes::from(format!("{}\n", text))))
+        .unwrap()
+}
+
+/// The JSON API format popularised by Google and Cloudflare.
+fn to_json(packet: &Packet) -> Value {
+    let records = |records: &[DnsRecord]| -> Vec<Value> {
+        records
+            .iter()
+            .filter(|record| record.qtype() != QueryType::OPT)
+            .map(|record| {
+                json!({
+                    "name": fqdn(record.domain()),
+                    "type": record.qtype().to_num(),
+                    "TTL": record.ttl(),
+                    "data": rdata(record),
+                })
+            })
+            .collect()
+    };
+    let header = &packet.header;
+    let mut value = json!({
+        "Status": header.responce_code.to_num(),
+        "TC": header.truncated_message,
+        "RD": header.recursion_desired,
+        "RA": header.recursion_available,
+        "AD": header.authed_data,
+        "CD": header.checking_disabled,
+        "Question": packet
+            .questions
+            .iter()
+            .map(|question| json!({"name": fqdn(&question.name), "type": question.qtype.to_num()}))
+            .collect::<Vec<_>>(),
+    });
+    for (key, section) in [
+        ("Answer", &packet.answers),
+        ("Authority", &packet.authorities),
+        ("Additional", &packet.resources),
+    ] {
+        let section = records(section);
+        if !section.is_empty() {
+            value[key] = Value::from(section);
+        }
+    }
+    value
+}
+
+fn fqdn(name: &Name) -> String {
+    if name.is_root() {
+        ".".to_string()
+    } else {
+        format!("{}.", name)
+    }
+}
+
+/// RDATA in presentation format, or the RFC 3597 generic form for types
+/// without one.
+fn rdata(record: &DnsRecord) -> String {
+    match record {
+        DnsRecord::A { addr, .. } => addr.to_string(),
+        DnsRecord::AAAA { addr, .. } => addr.to_string(),
+        DnsRecord::NS { host, .. }
+        | DnsRecord::CNAME { host, .. }
+        | DnsRecord::PTR { host, .. } => fqdn(host),
+        DnsRecord::MX { priority, host, .. } => format!("{} {}", priority, fqdn(host)),
+        DnsRecord::SOA {
+            mname,
+            rname,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+            ..
+        } => format!(
+            "{} {} {} {} {} {} {}",
+            fqdn(mname),
+            fqdn(rname),
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum
+        ),
+        DnsRecord::TSIG {
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+            ..
+        } => format!(
+            "{} {} {} {} {} {} {} {} {}",
+            fqdn(algorithm),
+            time_signed,
+            fudge,
+            mac.len(),
+            general_purpose::STANDARD.encode(mac),
+            original_id,
+            ResultCode::from_num(*error as u8),
+            other.len(),
+            general_purpose::STANDARD.encode(other)
+        )
+        .trim_end()
+        .to_string(),
+        DnsRecord::OPT { options, .. } => generic(&opt_data(options)),
+        DnsRecord::UNKNOWN { qtype, data, .. } => {
+            txt(*qtype, data).unwrap_or_else(|| generic(data))
+        }
+    }
+}
+
+fn generic(data: &[u8]) -> String {
+    let hex: String = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("\\# {} {}", data.len(), hex).trim_end().to_string()
+}
+
+/// TXT character strings, each quoted, if `data` is well-formed TXT RDATA.
+fn txt(qtype: u16, data: &[u8]) -> Option<String> {
+    if qtype != TYPE_TXT {
+        return None;
+    }
+    let mut strings = Vec::new();
+    let mut rest = data;
+    while let Some((&len, tail)) = rest.split_first() {
+        let text = tail.get(..len as usize)?;
+        rest = &tail[len as usize..];
+        let mut quoted = String::from("\"");
+        for &byte in text {
+            match byte {
+                b'"' | b'\\' => {
+                    quoted.push('\\');
+                    quoted.push(byte as char);
+                }
+                0x20..=0x7e => quoted.push(byte as char),
+                _ => quoted.push_str(&format!("\\{:03}", byte)),
+            }
+        }
+        quoted.push('"');
+        strings.push(quoted);
+    }
+    Some(strings.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, enums::ResultCode, tls};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use rustls_pki_types::{CertificateDer, ServerName};
+    use std::convert::TryFrom;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    fn start_server() -> (SocketAddr, Arc<ClientConfig>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("doh-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+        let config = Config::parse(
+            r#"
+            [acl]
+            recursion = []
+
+            [local]
+            records = ["a.test 120 A 192.0.2.1", "a.test 60 TXT \"v=1\""]
+            "#,
+        )
+        .unwrap();
+        let tls = tls::server_config(
+            &dir.join("cert.pem"),
+            &dir.join("key.pem"),
+            &[b"h2", b"http/1.1"],
+        )
+        .unwrap();
+        let ctx = Arc::new(ServerContext::new(config).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || serve(listener, tls, ctx).unwrap());
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(cert.cert.der().to_vec()))
+            .unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let client = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        (addr, Arc::new(client))
+    }
+
+    fn query(id: u16, qname: &str, qtype: QueryType) -> Vec<u8> {
+        let mut packet = Packet::new();
+        packet.header.id = id;
+        packet.header.recursion_desired = true;
+        packet.questions.push(Question::new(name(qname), qtype));
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.buffer[..buffer.pos()].to_vec()
+    }
+
+    fn parse(data: &[u8]) -> Packet {
+        Packet::from_buffer(&mut BytePacketBuffer::from_bytes(data).unwrap()).unwrap()
+    }
+
+    /// Sends one HTTP/1.1 request and returns the status line, headers and
+    /// body of the response.
+    fn http1(
+        addr: SocketAddr,
+        client: &Arc<ClientConfig>,
+        head: &str,
+        body: &[u8],
+    ) -> (String, Vec<u8>) {
+        let mut config = (**client).clone();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let conn =
+            ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap())
+                .unwrap();
+        let mut stream = StreamOwned::new(conn, TcpStream::connect(addr).unwrap());
+        let mut request = format!("{}\r\nHost: localhost\r\nConnection: close\r\n", head);
+        if !body.is_empty() {
+            request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+
+        let mut response = Vec::new();
+        let mut chunk = [0; 1024];
+        let end = loop {
+            if let Some(end) = response.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end;
+            }
+            let len = stream.read(&mut chunk).unwrap();
+            response.extend_from_slice(&chunk[..len]);
+        };
+        let head = String::from_utf8(response[..end].to_vec()).unwrap();
+        let length: usize = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut body = response[end + 4..].to_vec();
+        while body.len() < length {
+            let len = stream.read(&mut chunk).unwrap();
+            body.extend_from_slice(&chunk[..len]);
+        }
+        (head, body)
+    }
+
+    #[test]
+    fn get_parameters() {
+        let message = query(0, "a.test", QueryType::A);
+        let encoded = general_purpose::URL_SAFE_NO_PAD.encode(&message);
+        assert_eq!(
+            parse_get(&format!("ct=x&dns={}", encoded)).unwrap(),
+            Query::Message(message.clone())
+        );
+        let padded = general_purpose::URL_SAFE.encode(&message);
+        assert_eq!(
+            parse_get(&format!("dns={}", padded)).unwrap(),
+            Query::Message(message)
+        );
+        assert_eq!(
+            parse_get("name=b%C3%BCcher.example&type=aaaa").unwrap(),
+            Query::Json {
+                name: name("xn--bcher-kva.example"),
+                qtype: QueryType::AAAA
+            }
+        );
+        assert_eq!(
+            parse_get("name=a.test&type=65").unwrap(),
+            Query::Json {
+                name: name("a.test"),
+                qtype: QueryType::UNKNOWN(65)
+            }
+        );
+        assert!(parse_get("").is_err());
+        assert!(parse_get("dns=***").is_err());
+        assert!(parse_get("name=a.test&type=BOGUS").is_err());
+    }
+
+    #[test]
+    fn json_and_cache_lifetime() {
+        let mut packet = Packet::new();
+        packet.header.responce = true;
+        packet
+            .questions
+            .push(Question::new(name("a.test"), QueryType::MX));
+        packet.answers.push(DnsRecord::MX {
+            domain: name("a.test"),
+            priority: 10,
+            host: name("mx.a.test"),
+            ttl: 300,
+        });
+        packet.answers.push(DnsRecord::UNKNOWN {
+            domain: name("a.test"),
+            qtype: TYPE_TXT,
+            data: b"\x05hello\x03\"x\"".to_vec(),
+            ttl: 30,
+        });
+        packet.resources.push(DnsRecord::OPT {
+            domain: Name::root(),
+            payload_size: 512,
+            extended_rcode: 0,
+            version: 0,
+            flags: 0,
+            options: Vec::new(),
+        });
+        assert_eq!(max_age(&packet), 30);
+        assert_eq!(
+            to_json(&packet),
+            json!({
+                "Status": 0, "TC": false, "RD": false, "RA": false, "AD": false, "CD": false,
+                "Question": [{"name": "a.test.", "type": 15}],
+                "Answer": [
+                    {"name": "a.test.", "type": 15, "TTL": 300, "data": "10 mx.a.test."},
+                    {"name": "a.test.", "type": 16, "TTL": 30, "data": "\"hello\" \"\\\"x\\\"\""},
+                ],
+            })
+        );
+        assert_eq!(max_age(&Packet::new()), 0);
+    }
+
+    #[test]
+    fn http1_wire_and_json() {
+        let (addr, client) = start_server();
+
+        let message = query(0, "a.test", QueryType::A);
+        let path = format!("GET {}?dns={} HTTP/1.1", PATH, BASE64URL.encode(&message));
+        let (head, body) = http1(addr, &client, &path, &[]);
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+        assert!(head.contains("content-type: application/dns-message"));
+        assert!(head.contains("cache-control: max-age=120"));
+        let response = parse(&body);
+        assert_eq!(response.header.responce_code, ResultCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+
+        let message = query(9, "other.test", QueryType::A);
+        let head_line = format!(
+            "POST {} HTTP/1.1\r\nContent-Type: application/dns-message",
+            PATH
+        );
+        let (head, body) = http1(addr, &client, &head_line, &message);
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+        let response = parse(&body);
+        assert_eq!(response.header.id, 9);
+        assert_eq!(response.header.responce_code, ResultCode::REFUSED);
+        assert!(head.contains("cache-control: max-age=0"));
+
+        let path = format!("GET {}?name=a.test&type=TXT HTTP/1.1", PATH);
+        let (head, body) = http1(addr, &client, &path, &[]);
+        assert!(head.contains("content-type: application/dns-json"));
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["Status"], 0);
+        assert_eq!(value["Answer"][0]["data"], "\"v=1\"");
+
+        let head_line = format!("POST {} HTTP/1.1\r\nContent-Type: text/plain", PATH);
+        let (head, _) = http1(addr, &client, &head_line, b"x");
+        assert!(head.starts_with("HTTP/1.1 415"), "{}", head);
+        let (head, _) = http1(addr, &client, "GET / HTTP/1.1", &[]);
+        assert!(head.starts_with("HTTP/1.1 404"), "{}", head);
+        let (head, _) = http1(addr, &client, &format!("PUT {} HTTP/1.1", PATH), b"x");
+        assert!(head.starts_with("HTTP/1.1 405"), "{}", head);
+        let (head, _) = http1(
+            addr,
+            &client,
+            &format!("GET {}?dns=AAAA HTTP/1.1", PATH),
+            &[],
+        );
+        assert!(head.starts_with("HTTP/1.1 400"), "{}", head);
+    }
+
+    #[test]
+    fn http2_post() {
+        let (addr, client) = start_server();
+        let mut config = (*client).clone();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let (status, version, body) = runtime.block_on(async {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let name = ServerName::try_from("localhost").unwrap();
+            let stream = connector.connect(name, stream).await.unwrap();
+            assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+            let (mut sender, conn) =
+                hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                    .await
+                    .unwrap();
+            tokio::spawn(conn);
+            let request = Request::post(format!("https://localhost{}", PATH))
+                .header(CONTENT_TYPE, DNS_MESSAGE)
+                .body(Full::new(Bytes::from(query(3, "a.test", QueryType::A))))
+                .unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            let status = response.status();
+            let version = response.version();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, version, body)
+        });
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(version, hyper::Version::HTTP_2);
+        let response = parse(&body);
+        assert_eq!(response.header.id, 3);
+        assert_eq!(response.answers.len(), 1);
+    }
+}
@@ -1,45 +1,106 @@
+use std::fmt;
+use std::str::FromStr;
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ResultCode {
-    NOERROR = 0,
-    FORMERR = 1,
-    SERVFAIL = 2,
-    NXDOMAIN = 3,
-    NOTIMP = 4,
-    REFUSED = 5,
+    UNKNOWN(u8),
+    NOERROR,
+    FORMERR,
+    SERVFAIL,
+    NXDOMAIN,
+    NOTIMP,
+    REFUSED,
+    YXDOMAIN,
+    YXRRSET,
+    NXRRSET,
+    NOTAUTH,
+    NOTZONE,
+    /// Extended codes (RFC 8945) for a failed TSIG check, which only fit in
+    /// the TSIG record; the header says NOTAUTH.
+    BADSIG,
+    BADKEY,
+    BADTIME,
+    /// Sent with a fresh server cookie (RFC 7873), in the upper bits of
+    /// the OPT record and the header together.
+    BADCOOKIE,
 }
 
 impl ResultCode {
+    pub fn to_num(self) -> u8 {
+        match self {
+            ResultCode::UNKNOWN(x) => x,
+            ResultCode::NOERROR => 0,
+            ResultCode::FORMERR => 1,
+            ResultCode::SERVFAIL => 2,
+            ResultCode::NXDOMAIN => 3,
+            ResultCode::NOTIMP => 4,
+            ResultCode::REFUSED => 5,
+            ResultCode::YXDOMAIN => 6,
+            ResultCode::YXRRSET => 7,
+            ResultCode::NXRRSET => 8,
+            ResultCode::NOTAUTH => 9,
+            ResultCode::NOTZONE => 10,
+            ResultCode::BADSIG => 16,
+            ResultCode::BADKEY => 17,
+            ResultCode::BADTIME => 18,
+            ResultCode::BADCOOKIE => 23,
+        }
+    }
     pub fn from_num(num: u8) -> ResultCode {
         match num {
+            0 => ResultCode::NOERROR,
             1 => ResultCode::FORMERR,
             2 => ResultCode::SERVFAIL,
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
-            0 | _ => ResultCode::NOERROR,
+            6 => ResultCode::YXDOMAIN,
+            7 => ResultCode::YXRRSET,
+            8 => ResultCode::NXRRSET,
+            9 => ResultCode::NOTAUTH,
+            10 => ResultCode::NOTZONE,
+            16 => ResultCode::BADSIG,
+            17 => ResultCode::BADKEY,
+            18 => ResultCode::BADTIME,
+            23 => ResultCode::BADCOOKIE,
+            _ => ResultCode::UNKNOWN(num),
         }
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum QueryType {
     UNKNOWN(u16),
     A,
     NS,
     CNAME,
+    SOA,
+    PTR,
     MX,
     AAAA,
+    OPT,
+    TSIG,
+    IXFR,
+    AXFR,
 }
 
 impl QueryType {
-    pub fn to_num(&self) -> u16 {
-        match *self {
+    pub fn to_num(self) -> u16 {
+        match self {
             QueryType::UNKNOWN(x) => x,
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
             QueryType::MX => 15,
             QueryType::AAAA => 28,
+            QueryType::OPT => 41,
+            QueryType::TSIG => 250,
+            QueryType::IXFR => 251,
+            QueryType::AXFR => 252,
         }
     }
     pub fn from_num(num: u16) -> QueryType {
@@ -47,9 +108,102 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
             28 => QueryType::AAAA,
+            41 => QueryType::OPT,
+            250 => QueryType::TSIG,
+            251 => QueryType::IXFR,
+            252 => QueryType::AXFR,
             _ => QueryType::UNKNOWN(num),
         }
     }
 }
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpCode {
+    UNKNOWN(u8),
+    QUERY,
+    IQUERY,
+    STATUS,
+    NOTIFY,
+    UPDATE,
+}
+
+impl OpCode {
+    pub fn to_num(self) -> u8 {
+        match self {
+            OpCode::UNKNOWN(x) => x,
+            OpCode::QUERY => 0,
+            OpCode::IQUERY => 1,
+            OpCode::STATUS => 2,
+            OpCode::NOTIFY => 4,
+            OpCode::UPDATE => 5,
+        }
+    }
+    pub fn from_num(num: u8) -> OpCode {
+        match num {
+            0 => OpCode::QUERY,
+            1 => OpCode::IQUERY,
+            2 => OpCode::STATUS,
+            4 => OpCode::NOTIFY,
+            5 => OpCode::UPDATE,
+            _ => OpCode::UNKNOWN(num),
+        }
+    }
+}
+
+/// Mnemonic, or `RCODEn` for codes without one.
+impl fmt::Display for ResultCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ResultCode::UNKNOWN(x) => write!(f, "RCODE{}", x),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+/// Mnemonic, or `TYPEn` for types without one (RFC 3597).
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            QueryType::UNKNOWN(x) => write!(f, "TYPE{}", x),
+            qtype => write!(f, "{:?}", qtype),
+        }
+    }
+}
+
+/// A mnemonic, `TYPEn` or a bare type number, in any case.
+impl FromStr for QueryType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+        let known = [
+            QueryType::A,
+            QueryType::NS,
+            QueryType::CNAME,
+            QueryType::SOA,
+            QueryType::PTR,
+            QueryType::MX,
+            QueryType::AAAA,
+            QueryType::IXFR,
+            QueryType::AXFR,
+        ];
+        if let Some(qtype) = known.iter().find(|qtype| qtype.to_string() == upper) {
+            return Ok(*qtype);
+        }
+        // TXT records are carried as raw RDATA.
+        if upper == "TXT" {
+            return Ok(QueryType::UNKNOWN(16));
+        }
+        upper
+            .strip_prefix("TYPE")
+            .unwrap_or(&upper)
+            .parse()
+            .map(QueryType::from_num)
+            .map_err(|_| format!("Unknown record type {:?}", s))
+    }
+}
@@ -1,92 +1,616 @@
-use std::net::{Ipv4Addr, UdpSocket};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::{
     buffer::BytePacketBuffer,
-    enums::{QueryType, ResultCode},
+    context::{ServerContext, Transport},
+    cookie,
+    dnstap::{DnstapMessage, MessageType},
+    enums::{OpCode, QueryType, ResultCode},
+    header::Header,
+    horizon::View,
+    name::Name,
     packet::Packet,
+    policy::{self, Hit},
+    querylog::QueryLogEntry,
     question::Question,
+    record::DnsRecord,
+    rrl::{self, Action},
+    secondary, transfer,
+    tsig::{self, Signature},
+    update,
+    upstream::{self, Upstream},
+    PACKET_BUFFER_SIZE,
 };
 
 type Error = Box<dyn std::error::Error>;
 type Result<T> = std::result::Result<T, Error>;
 
-pub fn handle_query(socket: &UdpSocket) -> Result<()> {
+const CLASS_IN: u16 = 1;
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What it took to answer one query, for the query log and metrics.
+#[derive(Debug, Default)]
+pub struct LookupStats {
+    pub cache_hit: bool,
+    pub upstreams: Vec<SocketAddr>,
+    /// Name server lookups currently nested inside the query, and the most
+    /// there were at once.
+    pub depth: usize,
+    pub max_depth: usize,
+    /// The blocklist entry that decided the answer.
+    pub policy: Option<Hit>,
+}
+
+/// Who sent a query, and how.
+#[derive(Debug, Clone, Copy)]
+pub struct Client {
+    pub addr: SocketAddr,
+    pub local: SocketAddr,
+    pub transport: Transport,
+}
+
+pub fn handle_query(ctx: &ServerContext, socket: &UdpSocket) -> Result<()> {
     let mut req_buffer = BytePacketBuffer::new();
-    let (_, src) = socket.recv_from(&mut req_buffer.buffer)?;
+    let (len, src) = socket.recv_from(&mut req_buffer.buffer)?;
+    req_buffer.set_len(len)?;
 
-    let mut request = Packet::from_buffer(&mut req_buffer)?;
+    let client = Client {
+        addr: src,
+        local: socket.local_addr()?,
+        transport: Transport::Udp,
+    };
+    if let Some(data) = answer(ctx, &mut req_buffer, client)? {
+        socket.send_to(&data, src)?;
+    }
+    Ok(())
+}
 
-    let mut packet = Packet::new();
-    packet.header.id = request.header.id;
-    packet.header.recursion_desired = true;
-    packet.header.recursion_available = true;
-    packet.header.responce = true;
+/// Serves one TCP connection (RFC 7766): two-byte length-prefixed messages
+/// answered in order, until the client closes it or leaves it idle.
+pub fn handle_tcp_connection(ctx: &ServerContext, mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    let client = Client {
+        addr: stream.peer_addr()?,
+        local: stream.local_addr()?,
+        transport: Transport::Tcp,
+    };
+
+    loop {
+        let mut len = [0; 2];
+        match stream.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if is_closed_or_idle(&e) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let len = u16::from_be_bytes(len) as usize;
+        if len > PACKET_BUFFER_SIZE {
+            return Err(format!("{} byte message from {} is too large", len, client.addr).into());
+        }
+
+        let mut req_buffer = BytePacketBuffer::new();
+        stream.read_exact(&mut req_buffer.buffer[..len])?;
+        req_buffer.set_len(len)?;
+
+        for data in respond(ctx, &mut req_buffer, client)? {
+            let mut message = (data.len() as u16).to_be_bytes().to_vec();
+            message.extend_from_slice(&data);
+            stream.write_all(&message)?;
+        }
+    }
+}
 
-    if let Some(question) = request.questions.pop() {
-        println!("rcvd query : {:?}", question);
+/// Every response to one request on a stream transport: the run of messages
+/// of a zone transfer, otherwise at most one.
+pub fn respond(
+    ctx: &ServerContext,
+    req_buffer: &mut BytePacketBuffer,
+    client: Client,
+) -> Result<Vec<Vec<u8>>> {
+    match transfer::request(req_buffer) {
+        Some(request) => transfer::serve(ctx, req_buffer, &request, client),
+        None => Ok(answer(ctx, req_buffer, client)?.into_iter().collect()),
+    }
+}
+
+fn is_closed_or_idle(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
 
-        if let Ok(result) = recursice_lookup(&question.name, question.qtype) {
-            packet.questions.push(question.clone());
-            packet.header.responce_code = result.header.responce_code;
+/// Builds the encoded response to one request, or `None` if it gets no
+/// reply, and records it in dnstap, metrics and the query log.
+pub fn answer(
+    ctx: &ServerContext,
+    req_buffer: &mut BytePacketBuffer,
+    client: Client,
+) -> Result<Option<Vec<u8>>> {
+    let start = Instant::now();
+    let query_time = SystemTime::now();
+    if let Some(dnstap) = &ctx.dnstap {
+        dnstap.log(&DnstapMessage {
+            kind: MessageType::ClientQuery,
+            transport: client.transport,
+            query_address: client.addr,
+            response_address: client.local,
+            query_time,
+            response_time: None,
+            message: &req_buffer.buffer[..req_buffer.len()],
+        });
+    }
 
-            for q in result.answers {
-                println!("Answer : {:?}", q);
-                packet.answers.push(q);
+    // Responses and messages too short to hold a header get no reply at all,
+    // so that traffic arriving on this port cannot start a reflection loop.
+    let ip = client.addr.ip();
+    let message = &req_buffer.buffer[..req_buffer.len()];
+    let mut malformed = false;
+    let signature = tsig::verify_request(&ctx.keys, message, query_time).unwrap_or_else(|e| {
+        log::debug!("bad TSIG record from {}: {}", client.addr, e);
+        malformed = true;
+        None
+    });
+    let edns = cookie::read_edns(message).unwrap_or_else(|e| {
+        log::debug!("bad OPT record from {}: {}", client.addr, e);
+        malformed = true;
+        None
+    });
+    let cookie = edns.as_ref().and_then(|edns| edns.cookie.as_ref());
+    let valid_cookie = match (&ctx.cookies, cookie) {
+        (Some(cookies), Some(cookie)) => cookies.is_valid(cookie, ip, query_time),
+        _ => false,
+    };
+    let key = signature.as_ref().and_then(Signature::key);
+    let view = ctx.view(ip, key);
+    let may_recurse = ctx.acl.may_recurse(ip, client.transport);
+    let mut stats = LookupStats::default();
+    let packet = if malformed {
+        rejected(req_buffer, ResultCode::FORMERR)
+    } else if signature.is_some() && key.is_none() {
+        rejected(req_buffer, ResultCode::NOTAUTH)
+    } else {
+        secondary::notify(ctx, req_buffer, ip, key)
+            .or_else(|| update::respond(ctx, view, req_buffer, ip, key))
+    };
+    let packet = match packet {
+        Some(packet) => Some(packet),
+        None => build_response(req_buffer, |qname, qtype| {
+            if !ctx.acl.may_query(ip, client.transport, qname) {
+                log::debug!("query for {} from {} refused", qname, client.addr);
+                return Ok(refused());
             }
-            for q in result.authorities {
-                println!("Authority : {:?}", q);
-                packet.authorities.push(q);
+            if matches!(qtype, QueryType::AXFR | QueryType::IXFR) {
+                return Ok(transfer::single_message(ctx, view, ip, key, qname, qtype));
             }
-            for q in result.resources {
-                println!("Resource : {:?}", q);
-                packet.resources.push(q);
+            if let Some(zone) = view.zones.find(qname) {
+                return Ok(zone.lookup(qname, qtype));
+            }
+            if let Some(mut packet) = view.local.lookup(qname, qtype) {
+                if may_recurse {
+                    chase_cname(ctx, view, &mut packet, qtype, &mut stats)?;
+                }
+                return Ok(packet);
+            }
+            if !may_recurse {
+                log::debug!("recursion for {} from {} refused", qname, client.addr);
+                return Ok(refused());
+            }
+            match ctx.policy.check(qname) {
+                Some(hit) => apply_policy(ctx, view, hit, qname, qtype, &mut stats),
+                None => resolve(ctx, view, qname, qtype, &mut stats),
+            }
+        }),
+    };
+    let dropped = matches!(&stats.policy, Some(hit) if hit.action == policy::Action::Drop);
+    let mut packet = match packet {
+        Some(packet) if !dropped => packet,
+        _ => {
+            log::debug!(
+                "dropped {} byte message from {}",
+                req_buffer.len(),
+                client.addr
+            );
+            return Ok(None);
+        }
+    };
+    packet.header.recursion_available = may_recurse;
+
+    // A spoofed source cannot send back the server cookie made for it, so
+    // clients that do are not limited.
+    let limited = (&ctx.rate_limiter, client.transport, valid_cookie);
+    if let (Some(rate_limiter), Transport::Udp, false) = limited {
+        match rate_limiter.check(ip, &packet, Instant::now()) {
+            Action::Send => {}
+            Action::Drop => {
+                log::debug!("rate limited response to {} dropped", client.addr);
+                ctx.metrics.rate_limited("drop");
+                return Ok(None);
+            }
+            Action::Slip => {
+                log::debug!("rate limited response to {} slipped", client.addr);
+                ctx.metrics.rate_limited("slip");
+                if ctx.cookies.is_some() && cookie.is_some() {
+                    rrl::bad_cookie(&mut packet);
+                } else {
+                    rrl::truncate(&mut packet);
+                }
             }
-        } else {
-            packet.header.responce_code = ResultCode::SERVFAIL;
         }
-    } else {
-        packet.header.responce_code = ResultCode::FORMERR;
+    }
+    if let Some(edns) = &edns {
+        let cookie = ctx
+            .cookies
+            .as_ref()
+            .zip(edns.cookie.as_ref())
+            .map(|(cookies, cookie)| cookies.respond(cookie, ip, query_time));
+        packet.resources.push(cookie::opt(cookie.as_ref()));
     }
 
     let mut res_buffer = BytePacketBuffer::new();
     packet.write(&mut res_buffer)?;
+    let mut data = res_buffer.get_range(0, res_buffer.pos())?.to_vec();
+    if let Some(signature) = &signature {
+        data = tsig::sign_response(signature, &data, SystemTime::now())?;
+    }
 
-    let len = res_buffer.pos();
-    let data = res_buffer.get_range(0, len)?;
+    if let Some(dnstap) = &ctx.dnstap {
+        dnstap.log(&DnstapMessage {
+            kind: MessageType::ClientResponse,
+            transport: client.transport,
+            query_address: client.addr,
+            response_address: client.local,
+            query_time,
+            response_time: Some(SystemTime::now()),
+            message: &data,
+        });
+    }
+    log::trace!("response to {}: {:#?}", client.addr, packet);
 
-    socket.send_to(data, src)?;
-    println!("{:#?}", packet);
+    let latency = start.elapsed();
+    let rcode = packet.header.responce_code.to_string();
+    let qtype = packet.questions.first().map(|q| q.qtype.to_string());
+    ctx.metrics
+        .query(qtype.as_deref(), &rcode, client.transport, latency);
+
+    if ctx.query_log.is_enabled() {
+        let mut entry = QueryLogEntry::new(client.addr, client.transport, rcode);
+        entry.view = Some(view.name.clone());
+        entry.qname = packet.questions.first().map(|q| q.name.to_string());
+        entry.qtype = qtype;
+        entry.answers = packet.answers.len();
+        entry.cache_hit = stats.cache_hit;
+        entry.upstreams = stats.upstreams;
+        entry.policy = stats.policy.map(|hit| hit.to_string());
+        entry.set_latency(latency);
+        ctx.query_log.log(&entry);
+    }
+    Ok(Some(data))
+}
+
+/// Answers a query that matched a blocklist. Every hit is logged, including
+/// passthru and drop.
+fn apply_policy(
+    ctx: &ServerContext,
+    view: &View,
+    hit: Hit,
+    qname: &Name,
+    qtype: QueryType,
+    stats: &mut LookupStats,
+) -> Result<Packet> {
+    log::info!("policy hit for {} {}: {}", qname, qtype, hit);
+    ctx.metrics.policy_hit(&hit.list, &hit.action.to_string());
+    let action = hit.action.clone();
+    stats.policy = Some(hit);
+
+    match action {
+        policy::Action::Passthru => resolve(ctx, view, qname, qtype, stats),
+        policy::Action::Drop => Ok(Packet::new()),
+        policy::Action::Redirect(_) => {
+            let mut packet = policy::respond(stats.policy.as_ref().unwrap(), qname, qtype);
+            chase_cname(ctx, view, &mut packet, qtype, stats)?;
+            Ok(packet)
+        }
+        _ => Ok(policy::respond(
+            stats.policy.as_ref().unwrap(),
+            qname,
+            qtype,
+        )),
+    }
+}
+
+/// Completes an answer that ends in a CNAME by looking up its target, in
+/// local data if it is there and by resolving it otherwise.
+fn chase_cname(
+    ctx: &ServerContext,
+    view: &View,
+    packet: &mut Packet,
+    qtype: QueryType,
+    stats: &mut LookupStats,
+) -> Result<()> {
+    let target = match packet.answers.last() {
+        Some(DnsRecord::CNAME { host, .. }) if qtype != QueryType::CNAME => host.clone(),
+        _ => return Ok(()),
+    };
+    let chased = match view.local.lookup(&target, qtype) {
+        Some(chased) => chased,
+        None => resolve(ctx, view, &target, qtype, stats)?,
+    };
+    packet.header.responce_code = chased.header.responce_code;
+    packet.answers.extend(chased.answers);
     Ok(())
 }
 
-fn lookup(qname: &str, qtype: QueryType, server: (Ipv4Addr, u16)) -> Result<Packet> {
-    let socket = UdpSocket::bind(("0.0.0.0", 43210))?;
+/// The answer to a request turned away before it is looked at: `rcode`
+/// and the question, if there is one.
+fn rejected(req_buffer: &BytePacketBuffer, rcode: ResultCode) -> Option<Packet> {
+    let mut buffer = BytePacketBuffer::from_bytes(&req_buffer.buffer[..req_buffer.len()]).ok()?;
+    let mut header = Header::new();
+    if header.read(&mut buffer).is_err() || header.responce {
+        return None;
+    }
+    let mut packet = Packet::new();
+    packet.header.id = header.id;
+    packet.header.operation_code = header.operation_code;
+    packet.header.responce = true;
+    packet.header.responce_code = rcode;
+    let mut question = Question::new(Name::root(), QueryType::UNKNOWN(0));
+    if header.questions == 1 && question.read(&mut buffer).is_ok() {
+        packet.questions.push(question);
+    }
+    Some(packet)
+}
+
+fn refused() -> Packet {
+    let mut packet = Packet::new();
+    packet.header.responce_code = ResultCode::REFUSED;
+    packet
+}
+
+/// Answers from the view's cache when possible, otherwise resolves through
+/// a forwarder or from the root and caches the result.
+fn resolve(
+    ctx: &ServerContext,
+    view: &View,
+    qname: &Name,
+    qtype: QueryType,
+    stats: &mut LookupStats,
+) -> Result<Packet> {
+    if let Some(packet) = view.cache.lock().unwrap().get(qname, qtype, Instant::now()) {
+        stats.cache_hit = true;
+        ctx.metrics.cache_hit();
+        return Ok(packet);
+    }
+    ctx.metrics.cache_miss();
+
+    // The upstream's AA bit does not carry over (or into the cache): this
+    // server is not authoritative for what it resolves.
+    let mut response = match view.forwarders(qname) {
+        Some(servers) => forward(ctx, qname, qtype, servers, stats)?,
+        None => {
+            let response = recursice_lookup(ctx, qname, qtype, stats)?;
+            ctx.metrics.recursion_depth(stats.max_depth + 1);
+            response
+        }
+    };
+    response.header.authoritative_answer = false;
+    let evicted = view
+        .cache
+        .lock()
+        .unwrap()
+        .insert(qname, qtype, &response, Instant::now());
+    if evicted > 0 {
+        log::debug!("cache full, evicted {} entries", evicted);
+        ctx.metrics.cache_evictions(evicted);
+    }
+    Ok(response)
+}
+
+fn build_response<F>(req_buffer: &mut BytePacketBuffer, resolve: F) -> Option<Packet>
+where
+    F: FnOnce(&Name, QueryType) -> Result<Packet>,
+{
+    let mut req_header = Header::new();
+    if req_header.read(req_buffer).is_err() || req_header.responce {
+        return None;
+    }
+
+    let mut packet = Packet::new();
+    packet.header.id = req_header.id;
+    packet.header.operation_code = req_header.operation_code;
+    packet.header.recursion_desired = req_header.recursion_desired;
+    packet.header.recursion_available = true;
+    packet.header.responce = true;
+
+    if req_header.operation_code != OpCode::QUERY {
+        packet.header.responce_code = ResultCode::NOTIMP;
+        return Some(packet);
+    }
+
+    // RFC 9619: a QUERY carries exactly one question.
+    if req_header.questions != 1 {
+        packet.header.responce_code = ResultCode::FORMERR;
+        return Some(packet);
+    }
+
+    // Only the question is needed to answer, so the rest of the message is
+    // never parsed and cannot turn a good query into FORMERR.
+    let mut question = Question::new(Name::root(), QueryType::UNKNOWN(0));
+    if question.read(req_buffer).is_err() {
+        packet.header.responce_code = ResultCode::FORMERR;
+        return Some(packet);
+    }
+    packet.questions.push(question.clone());
+
+    // Only the Internet class is resolved.
+    if question.qclass != CLASS_IN {
+        packet.header.responce_code = ResultCode::NOTIMP;
+        return Some(packet);
+    }
+
+    if let Ok(result) = resolve(&question.name, question.qtype) {
+        packet.header.responce_code = result.header.responce_code;
+        packet.header.authoritative_answer = result.header.authoritative_answer;
+        packet.answers = result.answers;
+        packet.authorities = result.authorities;
+        packet.resources = result.resources;
+    } else {
+        packet.header.responce_code = ResultCode::SERVFAIL;
+    }
+
+    Some(packet)
+}
+
+fn lookup(
+    ctx: &ServerContext,
+    qname: &Name,
+    qtype: QueryType,
+    upstream: &Upstream,
+    stats: &mut LookupStats,
+) -> Result<Packet> {
+    let response = lookup_once(ctx, qname, qtype, upstream, stats)?;
+    // The server wants its cookie back, which it has just sent.
+    if response.header.responce_code == ResultCode::BADCOOKIE {
+        log::debug!("{} sent BADCOOKIE, asking again", upstream.addr);
+        return lookup_once(ctx, qname, qtype, upstream, stats);
+    }
+    Ok(response)
+}
+
+/// Sends one query to `upstream` and returns its response, with the OPT
+/// record checked and taken out.
+fn lookup_once(
+    ctx: &ServerContext,
+    qname: &Name,
+    qtype: QueryType,
+    upstream: &Upstream,
+    stats: &mut LookupStats,
+) -> Result<Packet> {
+    let server = upstream.addr;
+    stats.upstreams.push(server);
+    ctx.metrics.upstream_query(server);
+    let timeout = Duration::from_millis(ctx.config.upstream_timeout_ms);
     let mut packet = Packet::new();
 
     packet.header.id = 6666;
     packet.header.questions = 1;
     packet.header.recursion_desired = true;
-    packet
-        .questions
-        .push(Question::new(qname.to_string(), qtype));
+    packet.questions.push(Question::new(qname.clone(), qtype));
+    let cookie = ctx
+        .cookies
+        .as_ref()
+        .map(|cookies| cookies.client_cookie(server.ip()));
+    if let Some(cookie) = &cookie {
+        packet.resources.push(cookie::opt(Some(cookie)));
+    }
 
     let mut req_buffer = BytePacketBuffer::new();
     packet.write(&mut req_buffer)?;
-    socket.send_to(&req_buffer.buffer[0..req_buffer.pos()], server)?;
+    let query = &req_buffer.buffer[0..req_buffer.pos()];
+    let query_time = SystemTime::now();
+    let mut local = None;
+    let sent = |addr: SocketAddr| {
+        local = Some(addr);
+        if let Some(dnstap) = &ctx.dnstap {
+            dnstap.log(&DnstapMessage {
+                kind: MessageType::ResolverQuery,
+                transport: upstream.transport,
+                query_address: addr,
+                response_address: server,
+                query_time,
+                response_time: None,
+                message: query,
+            });
+        }
+    };
+    let response = match upstream.transport {
+        Transport::Udp => {
+            let bind = match server {
+                SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 43210)),
+                SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 43210)),
+            };
+            upstream::udp_exchange(upstream, bind, query, timeout, sent)
+        }
+        _ => ctx.upstreams.exchange(upstream, query, timeout, sent),
+    };
+    let response = match response {
+        Ok(response) => response,
+        Err(e) if is_timeout(&e) => {
+            ctx.metrics.upstream_timeout(server);
+            return Err(format!("Timed out waiting for {}", server).into());
+        }
+        Err(e) => return Err(e),
+    };
+    if let (Some(dnstap), Some(local)) = (&ctx.dnstap, local) {
+        dnstap.log(&DnstapMessage {
+            kind: MessageType::ResolverResponse,
+            transport: upstream.transport,
+            query_address: local,
+            response_address: server,
+            query_time,
+            response_time: Some(SystemTime::now()),
+            message: &response,
+        });
+    }
 
-    let mut res_buffer = BytePacketBuffer::new();
-    socket.recv_from(&mut res_buffer.buffer)?;
+    let mut response = Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&response)?)?;
+    if let (Some(cookies), Some(cookie)) = (&ctx.cookies, &cookie) {
+        cookies.check_response(server.ip(), cookie, &response)?;
+    }
+    response
+        .resources
+        .retain(|record| record.qtype() != QueryType::OPT);
+    Ok(response)
+}
+
+fn is_timeout(e: &Error) -> bool {
+    matches!(
+        e.downcast_ref::<io::Error>().map(io::Error::kind),
+        Some(io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+    )
+}
 
-    Packet::from_buffer(&mut res_buffer)
+/// Asks each forwarder in turn, returning the first definite answer.
+fn forward(
+    ctx: &ServerContext,
+    qname: &Name,
+    qtype: QueryType,
+    servers: &[Upstream],
+    stats: &mut LookupStats,
+) -> Result<Packet> {
+    let mut last: Result<Packet> = Err("No forwarders".into());
+    for server in servers {
+        log::debug!(
+            "forwarding {} {} to {} over {}",
+            qtype,
+            qname,
+            server.addr,
+            server.transport
+        );
+        last = lookup(ctx, qname, qtype, server, stats);
+        if let Ok(response) = &last {
+            let code = response.header.responce_code;
+            if code == ResultCode::NOERROR || code == ResultCode::NXDOMAIN {
+                break;
+            }
+        }
+    }
+    last
 }
 
-fn recursice_lookup(qname: &str, qtype: QueryType) -> Result<Packet> {
+fn recursice_lookup(
+    ctx: &ServerContext,
+    qname: &Name,
+    qtype: QueryType,
+    stats: &mut LookupStats,
+) -> Result<Packet> {
     let mut ns = "198.41.0.4".parse::<Ipv4Addr>()?;
     loop {
-        println!("attemptiong lookup of {:?} {} with ns {}", qtype, qname, ns);
+        log::debug!("attempting lookup of {} {} with ns {}", qtype, qname, ns);
         let ns_copy = ns;
-        let server = (ns_copy, 53);
-        let response = lookup(qname, qtype, server)?;
+        let server = SocketAddr::from((ns_copy, 53));
+        let response = lookup(ctx, qname, qtype, &Upstream::udp(server), stats)?;
         if !response.answers.is_empty() && response.header.responce_code == ResultCode::NOERROR {
             return Ok(response);
         }
@@ -103,7 +627,11 @@ fn recursice_lookup(qname: &str, qtype: QueryType) -> Result<Packet> {
             None => return Ok(response),
         };
 
-        let recursive_response = recursice_lookup(&new_ns_name, QueryType::A)?;
+        stats.depth += 1;
+        stats.max_depth = stats.max_depth.max(stats.depth);
+        let recursive_response = recursice_lookup(ctx, new_ns_name, QueryType::A, stats);
+        stats.depth -= 1;
+        let recursive_response = recursive_response?;
 
         if let Some(new_ns) = recursive_response.get_random_a() {
             ns = new_ns;
@@ -112,3 +640,358 @@ fn recursice_lookup(qname: &str, qtype: QueryType) -> Result<Packet> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_buffer(packet: &mut Packet) -> BytePacketBuffer {
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.seek(0).unwrap();
+        buffer
+    }
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    fn query(qname: &str) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.id = 4242;
+        packet.header.recursion_desired = true;
+        packet
+            .questions
+            .push(Question::new(name(qname), QueryType::A));
+        packet
+    }
+
+    fn unreachable(_: &Name, _: QueryType) -> Result<Packet> {
+        panic!("resolver must not be called");
+    }
+
+    #[test]
+    fn responses_are_dropped() {
+        let mut request = query("google.com");
+        request.header.responce = true;
+        let mut buffer = request_buffer(&mut request);
+        assert!(build_response(&mut buffer, unreachable).is_none());
+    }
+
+    #[test]
+    fn short_datagram_is_dropped() {
+        let mut buffer = BytePacketBuffer::from_bytes(&[0x10, 0x92, 0x01, 0x00]).unwrap();
+        assert!(build_response(&mut buffer, unreachable).is_none());
+    }
+
+    #[test]
+    fn no_question_is_formerr() {
+        let mut request = query("google.com");
+        request.questions.clear();
+        let mut buffer = request_buffer(&mut request);
+        let response = build_response(&mut buffer, unreachable).unwrap();
+        assert_eq!(response.header.responce_code, ResultCode::FORMERR);
+        assert_eq!(response.header.id, 4242);
+        assert!(response.header.responce);
+        assert!(response.questions.is_empty());
+    }
+
+    #[test]
+    fn two_questions_is_formerr() {
+        let mut request = query("google.com");
+        request
+            .questions
+            .push(Question::new(name("example.com"), QueryType::AAAA));
+        let mut buffer = request_buffer(&mut request);
+        let response = build_response(&mut buffer, unreachable).unwrap();
+        assert_eq!(response.header.responce_code, ResultCode::FORMERR);
+        assert!(response.questions.is_empty());
+    }
+
+    #[test]
+    fn other_opcodes_are_notimp() {
+        let mut request = query("google.com");
+        request.header.operation_code = OpCode::STATUS;
+        let mut buffer = request_buffer(&mut request);
+        let response = build_response(&mut buffer, unreachable).unwrap();
+        assert_eq!(response.header.responce_code, ResultCode::NOTIMP);
+        assert_eq!(response.header.operation_code, OpCode::STATUS);
+    }
+
+    #[test]
+    fn question_is_echoed() {
+        let mut request = query("WwW.Example.COM");
+        let mut buffer = request_buffer(&mut request);
+
+        let response = build_response(&mut buffer, |qname, qtype| {
+            assert_eq!(qname.to_string(), "WwW.Example.COM");
+            assert_eq!(qtype, QueryType::A);
+            let mut result = Packet::new();
+            result.answers.push(DnsRecord::A {
+                domain: qname.clone(),
+                addr: Ipv4Addr::new(10, 1, 2, 3),
+                ttl: 60,
+            });
+            Ok(result)
+        })
+        .unwrap();
+
+        assert_eq!(response.header.id, 4242);
+        assert_eq!(response.header.responce_code, ResultCode::NOERROR);
+        assert!(response.header.recursion_desired);
+        assert_eq!(response.questions.len(), 1);
+        assert_eq!(response.questions[0].name.to_string(), "WwW.Example.COM");
+        assert_eq!(response.questions[0].qtype, QueryType::A);
+        assert_eq!(response.questions[0].qclass, CLASS_IN);
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn other_classes_are_notimp() {
+        let mut request = query("version.bind");
+        request.questions[0].qclass = 3;
+        let mut buffer = request_buffer(&mut request);
+        let response = build_response(&mut buffer, unreachable).unwrap();
+        assert_eq!(response.header.responce_code, ResultCode::NOTIMP);
+        assert_eq!(response.questions.len(), 1);
+        assert_eq!(response.questions[0].qclass, 3);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn failed_resolution_is_servfail() {
+        let mut request = query("google.com");
+        let mut buffer = request_buffer(&mut request);
+        let response = build_response(&mut buffer, |_, _| Err("timeout".into())).unwrap();
+        assert_eq!(response.header.responce_code, ResultCode::SERVFAIL);
+        assert_eq!(response.questions.len(), 1);
+    }
+
+    #[test]
+    fn tcp_connection_answers_each_message() {
+        let mut config = crate::config::Config::default();
+        config.acl.recursion = "none".parse().unwrap();
+        let ctx = ServerContext::new(config).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        for id in [1u16, 2] {
+            let mut request = query("example.com");
+            request.header.id = id;
+            let buffer = request_buffer(&mut request);
+            let mut message = (buffer.len() as u16).to_be_bytes().to_vec();
+            message.extend_from_slice(&buffer.buffer[..buffer.len()]);
+            client.write_all(&message).unwrap();
+        }
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        handle_tcp_connection(&ctx, server).unwrap();
+
+        for id in [1u16, 2] {
+            let mut len = [0; 2];
+            client.read_exact(&mut len).unwrap();
+            let mut response = vec![0; u16::from_be_bytes(len) as usize];
+            client.read_exact(&mut response).unwrap();
+            let response =
+                Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&response).unwrap()).unwrap();
+            assert_eq!(response.header.id, id);
+            assert_eq!(response.header.responce_code, ResultCode::REFUSED);
+            assert!(!response.header.recursion_available);
+        }
+        assert_eq!(ctx.metrics.queries("A", "REFUSED", Transport::Tcp), 2);
+    }
+
+    #[test]
+    fn blocklisted_names_are_answered_locally() {
+        let dir = std::env::temp_dir().join(format!("handler-policy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rpz");
+        std::fs::write(
+            &path,
+            "blocked.example CNAME .
+quiet.example CNAME rpz-drop.
+",
+        )
+        .unwrap();
+        let config = crate::config::Config::parse(&format!(
+            "[[blocklists]]\npath = {:?}\nformat = \"rpz\"",
+            path
+        ))
+        .unwrap();
+        let ctx = ServerContext::new(config).unwrap();
+        let client = Client {
+            addr: "127.0.0.1:5300".parse().unwrap(),
+            local: "127.0.0.1:53".parse().unwrap(),
+            transport: Transport::Udp,
+        };
+
+        let mut buffer = request_buffer(&mut query("blocked.example"));
+        let data = answer(&ctx, &mut buffer, client).unwrap().unwrap();
+        let response =
+            Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&data).unwrap()).unwrap();
+        assert_eq!(response.header.responce_code, ResultCode::NXDOMAIN);
+
+        let mut buffer = request_buffer(&mut query("quiet.example"));
+        assert!(answer(&ctx, &mut buffer, client).unwrap().is_none());
+        assert!(ctx.metrics.render().contains("action=\"drop\""));
+    }
+
+    #[test]
+    fn local_records_need_no_recursion() {
+        let mut config =
+            crate::config::Config::parse("[local]\nrecords = [\"build.local A 10.1.2.3\"]")
+                .unwrap();
+        config.acl.recursion = "none".parse().unwrap();
+        let ctx = ServerContext::new(config).unwrap();
+        let client = Client {
+            addr: "192.0.2.1:5300".parse().unwrap(),
+            local: "192.0.2.53:53".parse().unwrap(),
+            transport: Transport::Udp,
+        };
+
+        let mut buffer = request_buffer(&mut query("build.local"));
+        let data = answer(&ctx, &mut buffer, client).unwrap().unwrap();
+        let response =
+            Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&data).unwrap()).unwrap();
+        assert_eq!(response.header.responce_code, ResultCode::NOERROR);
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.answers.len(), 1);
+
+        let mut request = query("3.2.1.10.in-addr.arpa");
+        request.questions[0].qtype = QueryType::PTR;
+        let mut buffer = request_buffer(&mut request);
+        let data = answer(&ctx, &mut buffer, client).unwrap().unwrap();
+        let response =
+            Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&data).unwrap()).unwrap();
+        assert_eq!(
+            response.answers,
+            vec![DnsRecord::PTR {
+                domain: name("3.2.1.10.in-addr.arpa"),
+                host: name("build.local"),
+                ttl: 3600,
+            }]
+        );
+
+        let mut buffer = request_buffer(&mut query("example.com"));
+        let data = answer(&ctx, &mut buffer, client).unwrap().unwrap();
+        let response =
+            Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&data).unwrap()).unwrap();
+        assert_eq!(response.header.responce_code, ResultCode::REFUSED);
+    }
+
+    #[test]
+    fn views_give_clients_their_own_answers() {
+        let dir = std::env::temp_dir().join(format!("handler-views-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("example.com.zone");
+        std::fs::write(
+            &path,
+            "@ 3600 SOA ns1 hostmaster 1 7200 3600 1209600 300\napp A 203.0.113.80\n",
+        )
+        .unwrap();
+        let config = crate::config::Config::parse(&format!(
+            r#"
+            [[zones]]
+            name = "example.com"
+            file = {:?}
+
+            [[views]]
+            name = "internal"
+            match_clients = ["10.0.0.0/8"]
+            local.records = ["app.example.com A 10.0.0.80"]
+            "#,
+            path
+        ))
+        .unwrap();
+        let ctx = ServerContext::new(config).unwrap();
+        let ask = |addr: &str| {
+            let client = Client {
+                addr: addr.parse().unwrap(),
+                local: "192.0.2.53:53".parse().unwrap(),
+                transport: Transport::Udp,
+            };
+            let mut buffer = request_buffer(&mut query("app.example.com"));
+            let data = answer(&ctx, &mut buffer, client).unwrap().unwrap();
+            Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&data).unwrap()).unwrap()
+        };
+
+        let internal = ask("10.1.2.3:5300");
+        assert_eq!(
+            internal.answers[0],
+            DnsRecord::A {
+                domain: name("app.example.com"),
+                addr: Ipv4Addr::new(10, 0, 0, 80),
+                ttl: 3600,
+            }
+        );
+        let external = ask("198.51.100.7:5300");
+        assert!(external.header.authoritative_answer);
+        assert_eq!(
+            external.answers[0],
+            DnsRecord::A {
+                domain: name("app.example.com"),
+                addr: Ipv4Addr::new(203, 0, 113, 80),
+                ttl: 300,
+            }
+        );
+    }
+
+    #[test]
+    fn cookies_lift_rate_limits() {
+        let mut config = crate::config::Config::parse(
+            "[local]\nrecords = [\"build.local A 10.1.2.3\"]\n\
+             [rate_limit]\nresponses_per_second = 1\nslip = 1",
+        )
+        .unwrap();
+        config.acl.recursion = "none".parse().unwrap();
+        let ctx = ServerContext::new(config).unwrap();
+        let client = Client {
+            addr: "192.0.2.1:5300".parse().unwrap(),
+            local: "192.0.2.53:53".parse().unwrap(),
+            transport: Transport::Udp,
+        };
+        let ask = |cookie: Option<&[u8]>| {
+            let mut request = query("build.local");
+            if let Some(data) = cookie {
+                let mut opt = cookie::opt(None);
+                if let DnsRecord::OPT { options, .. } = &mut opt {
+                    options.push((10, data.to_vec()));
+                }
+                request.resources.push(opt);
+            }
+            let mut buffer = request_buffer(&mut request);
+            let data = answer(&ctx, &mut buffer, client).unwrap().unwrap();
+            Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&data).unwrap()).unwrap()
+        };
+        let cookie_of = |response: &Packet| match response.opt() {
+            Some(DnsRecord::OPT { options, .. }) => options[0].1.clone(),
+            _ => panic!("no OPT record"),
+        };
+
+        // Without EDNS, the response has none either.
+        let response = ask(None);
+        assert_eq!(response.header.responce_code, ResultCode::NOERROR);
+        assert!(response.opt().is_none());
+        // Limited, and slipped as BADCOOKIE since it sent a client cookie.
+        let client_cookie = [7; 8];
+        let response = ask(Some(&client_cookie));
+        assert_eq!(response.header.responce_code, ResultCode::BADCOOKIE);
+        assert!(!response.header.truncated_message);
+        assert!(response.answers.is_empty());
+        let cookie = cookie_of(&response);
+        assert_eq!(cookie.len(), 24);
+        assert_eq!(cookie[..8], client_cookie);
+
+        for _ in 0..3 {
+            let response = ask(Some(&cookie));
+            assert_eq!(response.header.responce_code, ResultCode::NOERROR);
+            assert_eq!(response.answers.len(), 1);
+            assert_eq!(cookie_of(&response)[..8], client_cookie);
+        }
+        let response = ask(None);
+        assert!(response.header.truncated_message);
+        let response = ask(Some(&[7; 12]));
+        assert_eq!(response.header.responce_code, ResultCode::FORMERR);
+    }
+}
@@ -1,15 +1,18 @@
-use crate::{buffer::BytePacketBuffer, enums::ResultCode};
+use crate::{
+    buffer::BytePacketBuffer,
+    enums::{OpCode, ResultCode},
+};
 
 type Error = Box<dyn std::error::Error>;
 type Result<T> = std::result::Result<T, Error>;
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Header {
     pub id: u16,
 
     pub recursion_desired: bool,
     pub truncated_message: bool,
     pub authoritative_answer: bool,
-    pub operation_code: u8,
+    pub operation_code: OpCode,
     pub responce: bool,
 
     pub responce_code: ResultCode,
@@ -24,6 +27,12 @@ pub struct Header {
     pub resource_entries: u16,
 }
 
+impl Default for Header {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Header {
     pub fn new() -> Self {
         Self {
@@ -32,7 +41,7 @@ impl Header {
             recursion_desired: false,
             truncated_message: false,
             authoritative_answer: false,
-            operation_code: 0,
+            operation_code: OpCode::QUERY,
             responce: false,
 
             responce_code: ResultCode::NOERROR,
@@ -57,7 +66,7 @@ impl Header {
         self.recursion_desired = (a & (1 << 0)) > 0;
         self.truncated_message = (a & (1 << 1)) > 0;
         self.authoritative_answer = (a & (1 << 2)) > 0;
-        self.operation_code = (a >> 3) & 0x0F;
+        self.operation_code = OpCode::from_num((a >> 3) & 0x0F);
         self.responce = (a & (1 << 7)) > 0;
 
         self.responce_code = ResultCode::from_num(b & 0x0F);
@@ -79,13 +88,13 @@ impl Header {
         buffer.write_u8(
             (self.recursion_desired as u8)
                 | ((self.truncated_message as u8) << 1)
-                | ((self.authed_data as u8) << 2)
-                | (self.operation_code << 3)
-                | ((self.responce as u8) << 7) as u8,
+                | ((self.authoritative_answer as u8) << 2)
+                | (self.operation_code.to_num() << 3)
+                | ((self.responce as u8) << 7),
         )?;
 
         buffer.write_u8(
-            (self.responce_code as u8)
+            (self.responce_code.to_num() & 0x0F)
                 | ((self.checking_disabled as u8) << 4)
                 | ((self.authed_data as u8) << 5)
                 | ((self.z as u8) << 6)
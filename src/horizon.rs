@@ -0,0 +1,183 @@
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use crate::{
+    acl::AddressList,
+    cache::Cache,
+    config::{Config, ViewConfig},
+    local::LocalRecords,
+    name::Name,
+    primary::Primary,
+    secondary::Secondary,
+    tsig::Keyring,
+    upstream::Upstream,
+    zone::Zones,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+pub const DEFAULT_VIEW: &str = "default";
+
+/// What one group of clients sees: its own authoritative zones, local
+/// records, forwarders and cache.
+pub struct View {
+    pub name: String,
+    match_clients: AddressList,
+    match_keys: Vec<Name>,
+    pub zones: Zones,
+    /// The zones among `zones` loaded from master files here.
+    pub primaries: Vec<Primary>,
+    /// The zones among `zones` transferred from elsewhere, including ones
+    /// not served yet or any more.
+    pub secondaries: Vec<Secondary>,
+    pub local: LocalRecords,
+    forwarders: Vec<(Name, Vec<Upstream>)>,
+    pub cache: Mutex<Cache>,
+}
+
+impl View {
+    fn new(config: &ViewConfig, keys: &Keyring) -> Result<Self> {
+        let mut zones = Vec::new();
+        let mut primaries = Vec::new();
+        let mut secondaries = Vec::new();
+        for zone in &config.zones {
+            let key = match &zone.key {
+                Some(name) => Some(
+                    keys.get(name)
+                        .ok_or_else(|| format!("Zone {}: unknown TSIG key {}", zone.name, name))?,
+                ),
+                None => None,
+            };
+            if zone.primaries.is_empty() {
+                let (primary, loaded) = Primary::load(zone, key)?;
+                zones.push(loaded);
+                primaries.push(primary);
+            } else {
+                let (secondary, saved) = Secondary::new(zone, key);
+                zones.extend(saved);
+                secondaries.push(secondary);
+            }
+        }
+        let mut forwarders = Vec::new();
+        for forwarder in &config.forwarders {
+            let mut upstreams = Vec::new();
+            for server in &forwarder.servers {
+                upstreams.extend(Upstream::from_config(server)?);
+            }
+            forwarders.push((forwarder.zone.clone(), upstreams));
+        }
+        // Most specific zone first, so the first match is the closest one.
+        forwarders.sort_by_key(|(zone, _)| std::cmp::Reverse(zone.label_count()));
+        Ok(Self {
+            name: config.name.clone(),
+            match_clients: config.match_clients.clone(),
+            match_keys: config.match_keys.clone(),
+            zones: Zones::new(zones),
+            primaries,
+            secondaries,
+            local: LocalRecords::new(&config.local)?,
+            forwarders,
+            cache: Mutex::new(Cache::new(&config.cache)),
+        })
+    }
+
+    /// A request signed with a listed key matches regardless of where it
+    /// comes from.
+    pub fn matches(&self, client: IpAddr, key: Option<&Name>) -> bool {
+        key.is_some_and(|key| self.match_keys.contains(key)) || self.match_clients.allows(client)
+    }
+
+    /// The servers to forward `qname` to, if a forwarder covers it.
+    pub fn forwarders(&self, qname: &Name) -> Option<&[Upstream]> {
+        self.forwarders
+            .iter()
+            .find(|(zone, _)| qname.is_subdomain_of(zone))
+            .map(|(_, upstreams)| upstreams.as_slice())
+    }
+}
+
+/// The configured views followed by the default one, built from the
+/// top-level settings, which every client matches.
+pub fn views(config: &Config, keys: &Keyring) -> Result<Vec<View>> {
+    let mut views = config
+        .views
+        .iter()
+        .map(|view| View::new(view, keys).map_err(|e| format!("View {}: {}", view.name, e).into()))
+        .collect::<Result<Vec<_>>>()?;
+    let default = ViewConfig {
+        name: DEFAULT_VIEW.to_string(),
+        match_clients: AddressList::any(),
+        match_keys: Vec::new(),
+        zones: config.zones.clone(),
+        forwarders: config.forwarders.clone(),
+        local: config.local.clone(),
+        cache: config.cache.clone(),
+    };
+    views.push(View::new(&default, keys)?);
+    Ok(views)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn views_match_by_address_or_key() {
+        let config = Config::parse(
+            r#"
+            [[forwarders]]
+            zone = "corp.example"
+            servers = ["192.0.2.1:53"]
+
+            [[views]]
+            name = "internal"
+            match_clients = ["10.0.0.0/8"]
+            match_keys = ["internal-key"]
+            local.records = ["app.example.com A 10.0.0.80"]
+
+            [[views.forwarders]]
+            zone = "example.com"
+            servers = ["10.0.0.53:53"]
+
+            [[views.forwarders]]
+            zone = "lab.example.com"
+            servers = ["10.9.0.53:53", "10.9.0.54:53"]
+            "#,
+        )
+        .unwrap();
+        let views = views(&config, &Keyring::default()).unwrap();
+        assert_eq!(views.len(), 2);
+        let internal = &views[0];
+
+        assert!(internal.matches(ip("10.1.2.3"), None));
+        assert!(!internal.matches(ip("198.51.100.7"), None));
+        assert!(internal.matches(ip("198.51.100.7"), Some(&name("Internal-Key"))));
+        assert!(!internal.matches(ip("198.51.100.7"), Some(&name("other"))));
+        assert!(views[1].matches(ip("198.51.100.7"), None));
+
+        assert_eq!(
+            internal
+                .forwarders(&name("x.lab.example.com"))
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            internal.forwarders(&name("www.example.com")).unwrap()[0].addr,
+            "10.0.0.53:53".parse().unwrap()
+        );
+        assert!(internal.forwarders(&name("example.org")).is_none());
+        assert!(views[1].forwarders(&name("a.corp.example")).is_some());
+        assert!(!internal.local.is_empty());
+        assert!(views[1].local.is_empty());
+    }
+}
@@ -0,0 +1,36 @@
+pub mod acl;
+pub mod buffer;
+pub mod cache;
+pub mod cidr;
+pub mod config;
+pub mod context;
+pub mod cookie;
+pub mod dnstap;
+pub mod doh;
+pub mod enums;
+pub mod handler;
+pub mod header;
+pub mod horizon;
+pub mod local;
+pub mod metrics;
+pub mod name;
+pub mod notify;
+pub mod packet;
+pub mod policy;
+pub mod primary;
+pub mod querylog;
+pub mod question;
+pub mod quic;
+pub mod record;
+pub mod rrl;
+pub mod secondary;
+pub mod tls;
+pub mod transfer;
+pub mod tsig;
+pub mod update;
+pub mod upstream;
+pub mod view;
+pub mod zone;
+pub mod zonefile;
+
+pub const PACKET_BUFFER_SIZE: usize = 512;
@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+
+use crate::{
+    config::LocalConfig, enums::QueryType, name::Name, packet::Packet, record::DnsRecord, zonefile,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// CNAMEs followed inside local data before giving up.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Records from a hosts file and the config, answered before recursion.
+/// A name listed here is owned by it: types it has no records for get an
+/// empty NOERROR rather than being looked up upstream.
+#[derive(Debug, Default)]
+pub struct LocalRecords {
+    records: HashMap<Name, Vec<DnsRecord>>,
+}
+
+impl LocalRecords {
+    pub fn new(config: &LocalConfig) -> Result<Self> {
+        let mut local = LocalRecords::default();
+        let mut records = Vec::new();
+        for line in &config.records {
+            let entries = zonefile::parse(line, &Name::root())
+                .map_err(|e| format!("Invalid local record {:?}: {}", line, e))?;
+            for entry in entries {
+                records.push(entry.to_record(config.ttl)?);
+            }
+        }
+        if let Some(path) = &config.hosts_file {
+            let text = fs::read_to_string(path)
+                .map_err(|e| format!("Cannot read hosts file {}: {}", path.display(), e))?;
+            records.extend(hosts(&text, config.ttl)?);
+        }
+
+        for record in &records {
+            local.insert(record.clone());
+        }
+        if config.synthesize_ptr {
+            for record in &records {
+                let (domain, addr, ttl) = match record {
+                    DnsRecord::A { domain, addr, ttl } => (domain, IpAddr::V4(*addr), *ttl),
+                    DnsRecord::AAAA { domain, addr, ttl } => (domain, IpAddr::V6(*addr), *ttl),
+                    _ => continue,
+                };
+                let reverse = Name::reverse(addr);
+                if local
+                    .lookup(&reverse, QueryType::PTR)
+                    .is_none_or(|packet| packet.answers.is_empty())
+                {
+                    local.insert(DnsRecord::PTR {
+                        domain: reverse,
+                        host: domain.clone(),
+                        ttl,
+                    });
+                }
+            }
+        }
+        Ok(local)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    fn insert(&mut self, record: DnsRecord) {
+        let rrset = self.records.entry(record.domain().clone()).or_default();
+        if !rrset.contains(&record) {
+            rrset.push(record);
+        }
+    }
+
+    /// The answer for `qname`, or `None` if it is not a local name. CNAMEs
+    /// are followed as far as the local data goes.
+    pub fn lookup(&self, qname: &Name, qtype: QueryType) -> Option<Packet> {
+        let mut rrset = self.records.get(qname)?;
+        let mut packet = Packet::new();
+        packet.header.authoritative_answer = true;
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            let matching: Vec<&DnsRecord> = rrset
+                .iter()
+                .filter(|record| record.qtype() == qtype)
+                .collect();
+            if !matching.is_empty() || qtype == QueryType::CNAME {
+                packet.answers.extend(matching.into_iter().cloned());
+                break;
+            }
+            let target = rrset.iter().find_map(|record| match record {
+                DnsRecord::CNAME { host, .. } => Some(host),
+                _ => None,
+            });
+            let target = match target {
+                Some(target) => target,
+                None => break,
+            };
+            packet.answers.extend(
+                rrset
+                    .iter()
+                    .filter(|record| matches!(record, DnsRecord::CNAME { .. }))
+                    .cloned(),
+            );
+            rrset = match self.records.get(target) {
+                Some(rrset) => rrset,
+                None => break,
+            };
+        }
+        Some(packet)
+    }
+}
+
+/// Address records for every name on each `/etc/hosts` line, and a PTR for
+/// the first (canonical) one.
+fn hosts(text: &str, ttl: u32) -> Result<Vec<DnsRecord>> {
+    let mut records = Vec::new();
+    for line in text.lines() {
+        let mut fields = line.split('#').next().unwrap_or("").split_whitespace();
+        let addr = match fields.next() {
+            Some(addr) => addr,
+            None => continue,
+        };
+        // Zone indices such as `fe80::1%lo0` cannot be served; skip them.
+        let addr: IpAddr = match addr.parse() {
+            Ok(addr) => addr,
+            Err(_) => {
+                log::warn!("skipping hosts entry with address {:?}", addr);
+                continue;
+            }
+        };
+        for (i, host) in fields.enumerate() {
+            let domain = if host.is_ascii() {
+                host.parse()?
+            } else {
+                Name::from_unicode(host)?
+            };
+            if i == 0 {
+                records.push(DnsRecord::PTR {
+                    domain: Name::reverse(addr),
+                    host: domain.clone(),
+                    ttl,
+                });
+            }
+            records.push(match addr {
+                IpAddr::V4(addr) => DnsRecord::A { domain, addr, ttl },
+                IpAddr::V6(addr) => DnsRecord::AAAA { domain, addr, ttl },
+            });
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, enums::ResultCode};
+    use std::net::Ipv4Addr;
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    fn local(toml: &str) -> LocalRecords {
+        LocalRecords::new(&Config::parse(toml).unwrap().local).unwrap()
+    }
+
+    #[test]
+    fn inline_records_and_reverse_synthesis() {
+        let local = local(
+            r#"
+            [local]
+            records = [
+                "build.local 300 A 10.1.2.3",
+                "build.local AAAA fd00::3",
+                "ci.local CNAME build.local.",
+                "4.3.2.10.in-addr.arpa PTR printer.local.",
+            ]
+            "#,
+        );
+
+        let packet = local.lookup(&name("Build.LOCAL"), QueryType::A).unwrap();
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(
+            packet.answers,
+            vec![DnsRecord::A {
+                domain: name("build.local"),
+                addr: Ipv4Addr::new(10, 1, 2, 3),
+                ttl: 300,
+            }]
+        );
+
+        let packet = local.lookup(&name("ci.local"), QueryType::AAAA).unwrap();
+        assert_eq!(packet.answers.len(), 2);
+        assert_eq!(packet.answers[1].domain(), &name("build.local"));
+
+        let packet = local
+            .lookup(&name("3.2.1.10.in-addr.arpa"), QueryType::PTR)
+            .unwrap();
+        assert_eq!(
+            packet.answers,
+            vec![DnsRecord::PTR {
+                domain: name("3.2.1.10.in-addr.arpa"),
+                host: name("build.local"),
+                ttl: 300,
+            }]
+        );
+        let reverse = Name::reverse("fd00::3".parse().unwrap());
+        assert!(!local
+            .lookup(&reverse, QueryType::PTR)
+            .unwrap()
+            .answers
+            .is_empty());
+
+        // An explicit PTR is not overridden.
+        let packet = local
+            .lookup(&name("4.3.2.10.in-addr.arpa"), QueryType::PTR)
+            .unwrap();
+        assert_eq!(packet.answers.len(), 1);
+
+        let packet = local.lookup(&name("build.local"), QueryType::MX).unwrap();
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.header.responce_code, ResultCode::NOERROR);
+        assert!(local.lookup(&name("other.local"), QueryType::A).is_none());
+    }
+
+    #[test]
+    fn hosts_file() {
+        let records = hosts(
+            "127.0.0.1 localhost\n\
+             10.0.0.5  nas.home nas # storage\n\
+             ::1       localhost ip6-localhost\n\
+             fe80::1%lo0 link-local\n",
+            60,
+        )
+        .unwrap();
+        let mut local = LocalRecords::default();
+        for record in records {
+            local.insert(record);
+        }
+
+        let packet = local.lookup(&name("nas"), QueryType::A).unwrap();
+        assert_eq!(packet.answers.len(), 1);
+        let packet = local.lookup(&name("localhost"), QueryType::AAAA).unwrap();
+        assert_eq!(packet.answers.len(), 1);
+        let packet = local
+            .lookup(&name("5.0.0.10.in-addr.arpa"), QueryType::PTR)
+            .unwrap();
+        assert_eq!(
+            packet.answers,
+            vec![DnsRecord::PTR {
+                domain: name("5.0.0.10.in-addr.arpa"),
+                host: name("nas.home"),
+                ttl: 60,
+            }]
+        );
+        assert!(local.lookup(&name("link-local"), QueryType::AAAA).is_none());
+    }
+}
@@ -1,25 +1,149 @@
-use std::net::UdpSocket;
+use std::env;
+use std::net::{TcpListener, UdpSocket};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-use crate::handler::handle_query;
-mod buffer;
-mod enums;
-mod handler;
-mod header;
-mod packet;
-mod question;
-mod record;
-
-const PACKET_BUFFER_SIZE: usize = 512;
+use dns_server_rust::{
+    config::Config,
+    context::ServerContext,
+    doh,
+    handler::{handle_query, handle_tcp_connection},
+    metrics, primary, quic, secondary, tls,
+};
 
 type Error = Box<dyn std::error::Error>;
 type Result<T> = std::result::Result<T, Error>;
 
 fn main() -> Result<()> {
-    let socket = UdpSocket::bind(("0.0.0.0", 8053))?;
+    let config = match env::args().nth(1) {
+        Some(path) => Config::load(Path::new(&path))?,
+        None => Config::default(),
+    };
+    env_logger::Builder::new()
+        .parse_filters(&config.log_level)
+        .parse_default_env()
+        .init();
+
+    let socket = UdpSocket::bind(config.listen)?;
+    let tcp_listener = TcpListener::bind(config.listen)?;
+    log::info!("listening on {} (udp, tcp)", config.listen);
+    let metrics_listener = match &config.metrics {
+        Some(metrics) => Some(TcpListener::bind(metrics.listen)?),
+        None => None,
+    };
+    let tls_listener = match &config.tls {
+        Some(tls_config) => {
+            let tls = tls::server_config(&tls_config.cert, &tls_config.key, &[b"dot"])?;
+            let listener = TcpListener::bind(tls_config.listen)?;
+            log::info!("listening on {} (tls)", tls_config.listen);
+            Some((listener, tls))
+        }
+        None => None,
+    };
+    let https_listener = match &config.https {
+        Some(https) => {
+            let tls = tls::server_config(&https.cert, &https.key, &[b"h2", b"http/1.1"])?;
+            let listener = TcpListener::bind(https.listen)?;
+            log::info!("listening on {} (https{})", https.listen, doh::PATH);
+            Some((listener, tls))
+        }
+        None => None,
+    };
+    let quic_socket = match &config.quic {
+        Some(quic_config) => {
+            let idle_timeout = Duration::from_secs(quic_config.idle_timeout_secs);
+            let server = quic::server_config(&quic_config.cert, &quic_config.key, idle_timeout)?;
+            let socket = UdpSocket::bind(quic_config.listen)?;
+            log::info!("listening on {} (quic)", quic_config.listen);
+            Some((socket, server))
+        }
+        None => None,
+    };
+    let ctx = Arc::new(ServerContext::new(config)?);
+
+    if let Some(listener) = metrics_listener {
+        log::info!(
+            "serving metrics on http://{}/metrics",
+            listener.local_addr()?
+        );
+        let ctx = ctx.clone();
+        thread::spawn(move || metrics::serve(listener, ctx));
+    }
+
+    {
+        let ctx = ctx.clone();
+        thread::spawn(move || serve_tcp(tcp_listener, ctx));
+    }
+
+    if let Some((listener, tls)) = tls_listener {
+        let ctx = ctx.clone();
+        thread::spawn(move || tls::serve(listener, tls, ctx));
+    }
+
+    if let Some((listener, tls)) = https_listener {
+        let ctx = ctx.clone();
+        thread::spawn(move || {
+            if let Err(e) = doh::serve(listener, tls, ctx) {
+                log::error!("https: {}", e);
+            }
+        });
+    }
+
+    if let Some((socket, server)) = quic_socket {
+        let ctx = ctx.clone();
+        thread::spawn(move || {
+            if let Err(e) = quic::serve(socket, server, ctx) {
+                log::error!("quic: {}", e);
+            }
+        });
+    }
+
+    if ctx.views.iter().any(|view| !view.secondaries.is_empty()) {
+        let ctx = ctx.clone();
+        thread::spawn(move || secondary::run(&ctx));
+    }
+
+    let reload = ctx.config.zone_reload_secs;
+    if reload > 0 && ctx.views.iter().any(|view| !view.primaries.is_empty()) {
+        let ctx = ctx.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(reload));
+            primary::reload_changed(&ctx);
+        });
+    }
+
+    let reload = ctx.config.blocklist_reload_secs;
+    if reload > 0 && !ctx.policy.is_empty() {
+        let ctx = ctx.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(reload));
+            ctx.policy.reload_changed();
+        });
+    }
+
     loop {
-        match handle_query(&socket) {
-            Ok(_) => {}
-            Err(e) => eprintln!("ERROR! :{}", e),
+        if let Err(e) = handle_query(&ctx, &socket) {
+            log::error!("{}", e);
         }
     }
 }
+
+fn serve_tcp(listener: TcpListener, ctx: Arc<ServerContext>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("tcp accept: {}", e);
+                continue;
+            }
+        };
+        let ctx = ctx.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_tcp_connection(&ctx, stream) {
+                log::debug!("tcp: {}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,402 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::context::{ServerContext, Transport};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+const DEPTH_BUCKETS: &[f64] = &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0, 10.0];
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// A counter split by label values, e.g. queries by (qtype, rcode, transport).
+struct CounterVec {
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl CounterVec {
+    fn new(labels: &'static [&'static str]) -> Self {
+        Self {
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn inc(&self, values: &[&str]) {
+        let key = values.iter().map(|v| v.to_string()).collect();
+        *self.values.lock().unwrap().entry(key).or_insert(0) += 1;
+    }
+
+    fn get(&self, values: &[&str]) -> u64 {
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        self.values.lock().unwrap().get(&key).copied().unwrap_or(0)
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "counter");
+        for (values, count) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{} {}", name, label_set(self.labels, values), count);
+        }
+    }
+}
+
+struct HistogramData {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    data: Mutex<HistogramData>,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            data: Mutex::new(HistogramData {
+                counts: vec![0; bounds.len()],
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        let mut data = self.data.lock().unwrap();
+        for (bound, count) in self.bounds.iter().zip(data.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        data.sum += value;
+        data.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+        let data = self.data.lock().unwrap();
+        for (bound, count) in self.bounds.iter().zip(data.counts.iter()) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, data.count);
+        let _ = writeln!(out, "{}_sum {}", name, data.sum);
+        let _ = writeln!(out, "{}_count {}", name, data.count);
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn label_set(labels: &[&str], values: &[String]) -> String {
+    let pairs: Vec<String> = labels
+        .iter()
+        .zip(values)
+        .map(|(label, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", label, value)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+}
+
+/// Server-wide counters and histograms, rendered in the Prometheus text
+/// exposition format.
+pub struct Metrics {
+    queries: CounterVec,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    cache_evictions: AtomicU64,
+    upstream_queries: CounterVec,
+    upstream_timeouts: CounterVec,
+    rate_limited: CounterVec,
+    policy_hits: CounterVec,
+    recursion_depth: Histogram,
+    query_duration: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            queries: CounterVec::new(&["qtype", "rcode", "transport"]),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            cache_evictions: AtomicU64::new(0),
+            upstream_queries: CounterVec::new(&["server"]),
+            upstream_timeouts: CounterVec::new(&["server"]),
+            rate_limited: CounterVec::new(&["action"]),
+            policy_hits: CounterVec::new(&["list", "action"]),
+            recursion_depth: Histogram::new(DEPTH_BUCKETS),
+            query_duration: Histogram::new(LATENCY_BUCKETS),
+        }
+    }
+
+    /// An answered query; `qtype` is `None` when the request had no
+    /// readable question.
+    pub fn query(&self, qtype: Option<&str>, rcode: &str, transport: Transport, latency: Duration) {
+        let transport = transport.to_string();
+        self.queries
+            .inc(&[qtype.unwrap_or("none"), rcode, &transport]);
+        self.query_duration.observe(latency.as_secs_f64());
+    }
+
+    pub fn queries(&self, qtype: &str, rcode: &str, transport: Transport) -> u64 {
+        self.queries.get(&[qtype, rcode, &transport.to_string()])
+    }
+
+    pub fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cache_evictions(&self, count: usize) {
+        self.cache_evictions
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn upstream_query(&self, server: SocketAddr) {
+        self.upstream_queries.inc(&[&server.to_string()]);
+    }
+
+    pub fn upstream_timeout(&self, server: SocketAddr) {
+        self.upstream_timeouts.inc(&[&server.to_string()]);
+    }
+
+    /// A response withheld by rate limiting; `action` is "drop" or "slip".
+    pub fn rate_limited(&self, action: &str) {
+        self.rate_limited.inc(&[action]);
+    }
+
+    pub fn policy_hit(&self, list: &str, action: &str) {
+        self.policy_hits.inc(&[list, action]);
+    }
+
+    /// How deeply `recursice_lookup` had to nest (to resolve name server
+    /// names) to answer one query.
+    pub fn recursion_depth(&self, depth: usize) {
+        self.recursion_depth.observe(depth as f64);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.queries.render(
+            &mut out,
+            "dns_queries_total",
+            "Queries answered, by query type, response code and transport.",
+        );
+        render_counter(
+            &mut out,
+            "dns_cache_hits_total",
+            "Queries answered from the cache.",
+            &self.cache_hits,
+        );
+        render_counter(
+            &mut out,
+            "dns_cache_misses_total",
+            "Queries that had to be resolved.",
+            &self.cache_misses,
+        );
+        render_counter(
+            &mut out,
+            "dns_cache_evictions_total",
+            "Cache entries evicted to make room for new ones.",
+            &self.cache_evictions,
+        );
+        self.upstream_queries.render(
+            &mut out,
+            "dns_upstream_queries_total",
+            "Queries sent to upstream servers.",
+        );
+        self.upstream_timeouts.render(
+            &mut out,
+            "dns_upstream_timeouts_total",
+            "Upstream queries that got no answer in time.",
+        );
+        self.rate_limited.render(
+            &mut out,
+            "dns_rate_limited_total",
+            "Responses dropped or slipped by response rate limiting.",
+        );
+        self.policy_hits.render(
+            &mut out,
+            "dns_policy_hits_total",
+            "Queries that matched a blocklist, by list and action.",
+        );
+        self.recursion_depth.render(
+            &mut out,
+            "dns_recursion_depth",
+            "Nesting depth of recursive resolution per resolved query.",
+        );
+        self.query_duration.render(
+            &mut out,
+            "dns_query_duration_seconds",
+            "Time from receiving a query to sending the response.",
+        );
+        out
+    }
+}
+
+/// Serves `GET /metrics` until the listener fails. Requests are handled one
+/// at a time, which is plenty for a scraper.
+pub fn serve(listener: TcpListener, ctx: Arc<ServerContext>) {
+    for stream in listener.incoming() {
+        let result = stream
+            .map_err(Error::from)
+            .and_then(|stream| respond(stream, &ctx.metrics));
+        if let Err(e) = result {
+            log::debug!("metrics: {}", e);
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+
+    let mut request = Vec::new();
+    let mut chunk = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err("request too large".into());
+        }
+        let len = stream.read(&mut chunk)?;
+        if len == 0 {
+            return Err("connection closed before end of request".into());
+        }
+        request.extend_from_slice(&chunk[..len]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.lines().next().unwrap_or("").split(' ');
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+    let path = path.split('?').next().unwrap_or("");
+
+    let (status, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK", metrics.render()),
+        (_, "/metrics") => ("405 Method Not Allowed", String::new()),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::new();
+        let server: SocketAddr = "198.41.0.4:53".parse().unwrap();
+        metrics.query(
+            Some("A"),
+            "NOERROR",
+            Transport::Udp,
+            Duration::from_millis(3),
+        );
+        metrics.query(
+            Some("A"),
+            "NOERROR",
+            Transport::Udp,
+            Duration::from_millis(30),
+        );
+        metrics.query(None, "FORMERR", Transport::Udp, Duration::from_micros(100));
+        metrics.cache_hit();
+        metrics.cache_evictions(3);
+        metrics.upstream_query(server);
+        metrics.upstream_timeout(server);
+        metrics.recursion_depth(2);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE dns_queries_total counter\n"));
+        assert!(
+            text.contains("dns_queries_total{qtype=\"A\",rcode=\"NOERROR\",transport=\"udp\"} 2\n")
+        );
+        assert!(text
+            .contains("dns_queries_total{qtype=\"none\",rcode=\"FORMERR\",transport=\"udp\"} 1\n"));
+        assert!(text.contains("dns_cache_hits_total 1\n"));
+        assert!(text.contains("dns_cache_misses_total 0\n"));
+        assert!(text.contains("dns_cache_evictions_total 3\n"));
+        assert!(text.contains("dns_upstream_queries_total{server=\"198.41.0.4:53\"} 1\n"));
+        assert!(text.contains("dns_upstream_timeouts_total{server=\"198.41.0.4:53\"} 1\n"));
+        assert!(text.contains("dns_recursion_depth_bucket{le=\"1\"} 0\n"));
+        assert!(text.contains("dns_recursion_depth_bucket{le=\"2\"} 1\n"));
+        assert!(text.contains("dns_query_duration_seconds_bucket{le=\"0.005\"} 2\n"));
+        assert!(text.contains("dns_query_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("dns_query_duration_seconds_count 3\n"));
+        assert_eq!(metrics.queries("A", "NOERROR", Transport::Udp), 2);
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let labels = ["name"];
+        let values = vec!["a\"b\\c\nd".to_string()];
+        assert_eq!(label_set(&labels, &values), "{name=\"a\\\"b\\\\c\\nd\"}");
+    }
+
+    #[test]
+    fn serves_metrics_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Metrics::new();
+        metrics.cache_hit();
+
+        let get = |request: &'static [u8]| {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(request).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            respond(stream, &metrics).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = get(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.ends_with(&metrics.render()));
+
+        let response = get(b"GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = get(b"POST /metrics HTTP/1.1\r\nContent-Length: 0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
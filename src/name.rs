@@ -0,0 +1,503 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use idna::uts46::{AsciiDenyList, DnsLength, Hyphens, Uts46};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+pub const MAX_LABEL_LEN: usize = 63;
+pub const MAX_NAME_LEN: usize = 255;
+
+/// A domain name as the raw bytes of its labels, root label excluded.
+///
+/// Case is preserved as received, but equality, hashing and ordering ignore
+/// ASCII case (RFC 4343). Labels may hold any byte, including `.`.
+#[derive(Clone, Default)]
+pub struct Name {
+    labels: Vec<Vec<u8>>,
+}
+
+impl Name {
+    pub fn root() -> Self {
+        Self { labels: Vec::new() }
+    }
+
+    pub fn from_labels<I, L>(labels: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = L>,
+        L: Into<Vec<u8>>,
+    {
+        let mut name = Self::root();
+        for label in labels {
+            name.push_label(label.into())?;
+        }
+        Ok(name)
+    }
+
+    pub fn labels(&self) -> impl DoubleEndedIterator<Item = &[u8]> + ExactSizeIterator {
+        self.labels.iter().map(|label| label.as_slice())
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Length of the uncompressed wire form, terminating zero included.
+    pub fn wire_len(&self) -> usize {
+        self.labels
+            .iter()
+            .map(|label| label.len() + 1)
+            .sum::<usize>()
+            + 1
+    }
+
+    /// Appends a label on the right, i.e. moves one level closer to the root.
+    pub fn push_label(&mut self, label: Vec<u8>) -> Result<()> {
+        if label.is_empty() {
+            return Err("Empty label inside a name".into());
+        }
+        if label.len() > MAX_LABEL_LEN {
+            return Err("Single lable exceeds 63 characlets of length".into());
+        }
+        if self.wire_len() + label.len() + 1 > MAX_NAME_LEN {
+            return Err("Name exceeds 255 bytes".into());
+        }
+        self.labels.push(label);
+        Ok(())
+    }
+
+    /// The name with its leftmost label removed, or `None` for the root.
+    pub fn parent(&self) -> Option<Name> {
+        if self.is_root() {
+            return None;
+        }
+        Some(Name {
+            labels: self.labels[1..].to_vec(),
+        })
+    }
+
+    /// `label.self`
+    pub fn child(&self, label: &[u8]) -> Result<Name> {
+        let mut name = Name::from_labels(Some(label))?;
+        for label in &self.labels {
+            name.push_label(label.clone())?;
+        }
+        Ok(name)
+    }
+
+    /// True if `self` is `zone` or any name below it.
+    pub fn is_subdomain_of(&self, zone: &Name) -> bool {
+        zone.labels.len() <= self.labels.len()
+            && self
+                .labels()
+                .rev()
+                .zip(zone.labels().rev())
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+
+    /// True if `self` is exactly one label below `parent`.
+    pub fn is_child_of(&self, parent: &Name) -> bool {
+        self.labels.len() == parent.labels.len() + 1 && self.is_subdomain_of(parent)
+    }
+
+    /// Builds a name from user input that may contain Unicode. Labels are
+    /// mapped and normalised per UTS #46 and stored as `xn--` A-labels.
+    pub fn from_unicode(input: &str) -> Result<Name> {
+        let domain = input.strip_suffix('.').unwrap_or(input);
+        if domain.is_empty() {
+            return Ok(Name::root());
+        }
+        match to_ascii(domain) {
+            Ok(ascii) => ascii.parse(),
+            Err(_) => Err(idna_error(input, domain)),
+        }
+    }
+
+    /// Presentation format with valid `xn--` labels shown as Unicode, for
+    /// display only. Anything else is escaped as in `Display`.
+    pub fn to_unicode(&self) -> String {
+        if self.is_root() {
+            return ".".to_string();
+        }
+        self.labels()
+            .map(|label| {
+                let ascii = Name::from_labels(Some(label))
+                    .map(|name| name.to_string())
+                    .unwrap_or_default();
+                if label.len() < 4 || !label[..4].eq_ignore_ascii_case(b"xn--") {
+                    return ascii;
+                }
+                match Uts46::new().to_unicode(label, AsciiDenyList::EMPTY, Hyphens::Allow) {
+                    (unicode, Ok(())) => unicode.into_owned(),
+                    (_, Err(_)) => ascii,
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    /// The `in-addr.arpa` / `ip6.arpa` name that PTR lookups for `addr` use.
+    pub fn reverse(addr: IpAddr) -> Name {
+        let mut labels: Vec<Vec<u8>> = match addr {
+            IpAddr::V4(v4) => v4
+                .octets()
+                .iter()
+                .rev()
+                .map(|octet| octet.to_string().into_bytes())
+                .collect(),
+            IpAddr::V6(v6) => v6
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|octet| [octet & 0x0F, octet >> 4])
+                .map(|nibble| format!("{:x}", nibble).into_bytes())
+                .collect(),
+        };
+        let suffix: &[&[u8]] = match addr {
+            IpAddr::V4(_) => &[b"in-addr", b"arpa"],
+            IpAddr::V6(_) => &[b"ip6", b"arpa"],
+        };
+        labels.extend(suffix.iter().map(|label| label.to_vec()));
+        Name { labels }
+    }
+
+    pub fn to_lowercase(&self) -> Name {
+        Name {
+            labels: self
+                .labels
+                .iter()
+                .map(|label| label.to_ascii_lowercase())
+                .collect(),
+        }
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Self) -> bool {
+        self.labels.len() == other.labels.len()
+            && self
+                .labels()
+                .zip(other.labels())
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+}
+
+impl Eq for Name {}
+
+impl Hash for Name {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.labels.len());
+        for label in &self.labels {
+            state.write_usize(label.len());
+            for b in label {
+                state.write_u8(b.to_ascii_lowercase());
+            }
+        }
+    }
+}
+
+/// Canonical DNSSEC order (RFC 4034 section 6.1): labels are compared from
+/// the root down as lowercased byte strings.
+impl Ord for Name {
+    fn cmp(&self, other: &Self) -> Ordering {
+        for (a, b) in self.labels().rev().zip(other.labels().rev()) {
+            let ordering = a
+                .iter()
+                .map(u8::to_ascii_lowercase)
+                .cmp(b.iter().map(u8::to_ascii_lowercase));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        self.labels.len().cmp(&other.labels.len())
+    }
+}
+
+impl PartialOrd for Name {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Parses presentation format: dot-separated labels with `\.`, `\\` and
+/// `\DDD` escapes. A trailing dot is optional; `.` alone is the root.
+impl FromStr for Name {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Name> {
+        let mut name = Name::root();
+        if s == "." || s.is_empty() {
+            return Ok(name);
+        }
+
+        if !s.is_ascii() {
+            return Err(format!(
+                "Non-ASCII domain name {:?}, use Name::from_unicode for IDNs",
+                s
+            )
+            .into());
+        }
+
+        let bytes = s.as_bytes();
+        let mut label = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'.' => {
+                    name.push_label(std::mem::take(&mut label))?;
+                    if i + 1 == bytes.len() {
+                        return Ok(name);
+                    }
+                }
+                b'\\' => {
+                    let rest = &bytes[i + 1..];
+                    match rest {
+                        [a, b, c, ..] if [a, b, c].iter().all(|d| d.is_ascii_digit()) => {
+                            let value = (a - b'0') as u16 * 100
+                                + (b - b'0') as u16 * 10
+                                + (c - b'0') as u16;
+                            if value > 255 {
+                                return Err(format!("Invalid escape \\{} in {:?}", value, s).into());
+                            }
+                            label.push(value as u8);
+                            i += 3;
+                        }
+                        [c, ..] if !c.is_ascii_digit() => {
+                            label.push(*c);
+                            i += 1;
+                        }
+                        _ => return Err(format!("Dangling escape in {:?}", s).into()),
+                    }
+                }
+                b => label.push(b),
+            }
+            i += 1;
+        }
+        name.push_label(label)?;
+        Ok(name)
+    }
+}
+
+fn to_ascii(domain: &str) -> std::result::Result<String, idna::Errors> {
+    Uts46::new()
+        .to_ascii(
+            domain.as_bytes(),
+            AsciiDenyList::EMPTY,
+            Hyphens::CheckFirstLast,
+            DnsLength::Verify,
+        )
+        .map(|ascii| ascii.into_owned())
+}
+
+// idna only reports that conversion failed, so find the label to blame.
+fn idna_error(input: &str, domain: &str) -> Error {
+    let separators: &[char] = &['.', '\u{3002}', '\u{FF0E}', '\u{FF61}'];
+    for label in domain.split(separators) {
+        if label.is_empty() {
+            return format!("Empty label in domain name {:?}", input).into();
+        }
+        if to_ascii(label).is_err() {
+            return format!("Invalid label {:?} in domain name {:?}", label, input).into();
+        }
+    }
+    format!("Domain name {:?} is too long", input).into()
+}
+
+/// Presentation format without the trailing dot; the root prints as `.`.
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            return f.write_str(".");
+        }
+        for (i, label) in self.labels().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            write_label(f, label)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+/// Names in configuration files may be given in Unicode.
+impl<'de> serde::Deserialize<'de> for Name {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let name = if s.is_ascii() {
+            s.parse()
+        } else {
+            Name::from_unicode(&s)
+        };
+        name.map_err(serde::de::Error::custom)
+    }
+}
+
+pub(crate) fn write_label(f: &mut fmt::Formatter<'_>, label: &[u8]) -> fmt::Result {
+    for &b in label {
+        match b {
+            b'.' | b'\\' | b'"' | b'(' | b')' | b';' | b'@' | b'$' => write!(f, "\\{}", b as char)?,
+            0x21..=0x7E => write!(f, "{}", b as char)?,
+            _ => write!(f, "\\{:03}", b)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    fn hash(name: &Name) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn parses_and_prints_presentation_format() {
+        assert_eq!(name("www.example.com.").label_count(), 3);
+        assert_eq!(name("www.example.com"), name("www.example.com."));
+        assert!(name(".").is_root());
+        assert_eq!(name(".").to_string(), ".");
+
+        let escaped = name(r"a\.b.c\\d.\065\000");
+        let labels: Vec<&[u8]> = escaped.labels().collect();
+        assert_eq!(labels, vec![&b"a.b"[..], b"c\\d", b"A\0"]);
+        assert_eq!(escaped.to_string(), r"a\.b.c\\d.A\000");
+        assert_eq!(name(&escaped.to_string()), escaped);
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        assert!("a..b".parse::<Name>().is_err());
+        assert!(".a".parse::<Name>().is_err());
+        assert!(r"a\".parse::<Name>().is_err());
+        assert!(r"\256".parse::<Name>().is_err());
+        assert!("a".repeat(64).parse::<Name>().is_err());
+        let long = vec!["a".repeat(63); 4].join(".");
+        assert!(long.parse::<Name>().is_err());
+        assert!(long[2..].parse::<Name>().is_ok());
+    }
+
+    #[test]
+    fn compares_case_insensitively_and_preserves_case() {
+        let upper = name("WWW.Example.COM");
+        assert_eq!(upper, name("www.example.com"));
+        assert_eq!(hash(&upper), hash(&name("www.example.com")));
+        assert_eq!(upper.to_string(), "WWW.Example.COM");
+        assert_ne!(name("example.com"), name("example.co"));
+    }
+
+    #[test]
+    fn canonical_order() {
+        // RFC 4034 section 6.1 example.
+        let sorted: Vec<Name> = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            r"zABC.a.EXAMPLE",
+            "z.example",
+            r"\001.z.example",
+            "*.z.example",
+            r"\200.z.example",
+        ]
+        .iter()
+        .map(|s| name(s))
+        .collect();
+        let mut shuffled = sorted.clone();
+        shuffled.reverse();
+        shuffled.sort();
+        assert_eq!(shuffled, sorted);
+    }
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(
+            Name::reverse("192.0.2.1".parse().unwrap()),
+            name("1.2.0.192.in-addr.arpa")
+        );
+        assert_eq!(
+            Name::reverse("2001:db8::1".parse().unwrap()).to_string(),
+            format!("1.{}8.b.d.0.1.0.0.2.ip6.arpa", "0.".repeat(23))
+        );
+    }
+
+    #[test]
+    fn converts_unicode_to_a_labels() {
+        let name = Name::from_unicode("Bücher.example.").unwrap();
+        assert_eq!(name.to_string(), "xn--bcher-kva.example");
+        assert_eq!(name.to_unicode(), "bücher.example");
+
+        let name = Name::from_unicode("www.München。DE").unwrap();
+        assert_eq!(name.to_string(), "www.xn--mnchen-3ya.de");
+        assert_eq!(name.to_unicode(), "www.münchen.de");
+
+        let name = Name::from_unicode("_dmarc.example.com").unwrap();
+        assert_eq!(name.to_string(), "_dmarc.example.com");
+        assert_eq!(Name::from_unicode(".").unwrap(), Name::root());
+    }
+
+    #[test]
+    fn invalid_idns_name_the_label() {
+        let err = Name::from_unicode("ok.-bad-.example").unwrap_err();
+        assert!(err.to_string().contains(r#"label "-bad-""#), "{}", err);
+
+        let err = Name::from_unicode("xn--a.example").unwrap_err();
+        assert!(err.to_string().contains(r#"label "xn--a""#), "{}", err);
+
+        let err = Name::from_unicode("a..example").unwrap_err();
+        assert!(err.to_string().contains("Empty label"), "{}", err);
+
+        let long = vec!["ü".repeat(30); 10].join(".");
+        assert!(Name::from_unicode(&long).is_err());
+    }
+
+    #[test]
+    fn presentation_format_is_ascii_only() {
+        let err = "bücher.example".parse::<Name>().unwrap_err();
+        assert!(err.to_string().contains("from_unicode"));
+        // Raw high bytes from the wire still display and parse via \DDD.
+        let raw = Name::from_labels(vec![vec![0xC3, 0xBC]]).unwrap();
+        assert_eq!(raw.to_string(), r"\195\188");
+        assert_eq!(raw.to_unicode(), r"\195\188");
+        assert_eq!(raw.to_string().parse::<Name>().unwrap(), raw);
+        assert_eq!(name("xn--zz").to_unicode(), "xn--zz");
+    }
+
+    #[test]
+    fn hierarchy_helpers() {
+        let www = name("www.example.com");
+        let example = name("example.com");
+        assert_eq!(www.parent().unwrap(), example);
+        assert_eq!(example.child(b"www").unwrap(), www);
+        assert!(www.is_subdomain_of(&example));
+        assert!(www.is_subdomain_of(&Name::root()));
+        assert!(example.is_subdomain_of(&example));
+        assert!(!name("notexample.com").is_subdomain_of(&example));
+        assert!(www.is_child_of(&example));
+        assert!(!www.is_child_of(&name("com")));
+        assert_eq!(Name::root().parent(), None);
+    }
+}
@@ -0,0 +1,287 @@
+//! Outgoing NOTIFY (RFC 1996): tells the secondaries of a primary zone that
+//! it has a new serial, so they refresh without waiting for their timers.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::{
+    buffer::BytePacketBuffer,
+    enums::{OpCode, QueryType},
+    name::Name,
+    packet::Packet,
+    question::Question,
+    record::DnsRecord,
+    tsig::{Exchange, Key},
+    zone::{self, Zone},
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// Sends before giving up on a secondary, waiting twice as long after each.
+const MAX_ATTEMPTS: u32 = 8;
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+
+/// Sends NOTIFY for each new serial, to each secondary on its own thread,
+/// until the secondary acknowledges it or a newer serial replaces it.
+#[derive(Debug, Default)]
+pub struct Notifier {
+    /// The serial last announced for each zone.
+    latest: Arc<Mutex<HashMap<Name, u32>>>,
+}
+
+impl Notifier {
+    /// Announces the current serial of `zone` to the name servers in its
+    /// apex NS records, apart from the primary named in its SOA, and to
+    /// `also_notify`, signing with `key` if there is one. Returns the
+    /// addresses notified.
+    pub fn notify(
+        &self,
+        zone: &Zone,
+        also_notify: &[SocketAddr],
+        key: Option<Arc<Key>>,
+    ) -> Vec<SocketAddr> {
+        let mut targets = name_servers(zone);
+        for addr in also_notify {
+            if !targets.contains(addr) {
+                targets.push(*addr);
+            }
+        }
+        self.latest
+            .lock()
+            .unwrap()
+            .insert(zone.origin().clone(), zone.serial());
+
+        for target in &targets {
+            let target = *target;
+            let soa = zone.soa().clone();
+            let key = key.clone();
+            let latest = self.latest.clone();
+            thread::spawn(move || send_until_acknowledged(target, soa, key, &latest));
+        }
+        targets
+    }
+}
+
+/// The addresses of the zone's name servers: in-zone ones from their glue,
+/// others from the system resolver.
+fn name_servers(zone: &Zone) -> Vec<SocketAddr> {
+    let primary = match zone.soa() {
+        DnsRecord::SOA { mname, .. } => mname.clone(),
+        _ => unreachable!(),
+    };
+    let hosts = zone
+        .lookup(zone.origin(), QueryType::NS)
+        .answers
+        .into_iter()
+        .filter_map(|record| match record {
+            DnsRecord::NS { host, .. } if host != primary => Some(host),
+            _ => None,
+        });
+
+    let mut addrs = Vec::new();
+    for host in hosts {
+        let glue: Vec<IpAddr> = [QueryType::A, QueryType::AAAA]
+            .iter()
+            .flat_map(|qtype| zone.lookup(&host, *qtype).answers)
+            .filter_map(|record| match record {
+                DnsRecord::A { addr, .. } => Some(IpAddr::V4(addr)),
+                DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(addr)),
+                _ => None,
+            })
+            .collect();
+        let found: Vec<SocketAddr> = if !glue.is_empty() || host.is_subdomain_of(zone.origin()) {
+            glue.into_iter().map(|ip| SocketAddr::new(ip, 53)).collect()
+        } else {
+            match (host.to_string().as_str(), 53).to_socket_addrs() {
+                Ok(found) => found.collect(),
+                Err(e) => {
+                    log::warn!("cannot notify {} for {}: {}", host, zone.origin(), e);
+                    Vec::new()
+                }
+            }
+        };
+        for addr in found {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+    addrs
+}
+
+fn send_until_acknowledged(
+    target: SocketAddr,
+    soa: DnsRecord,
+    key: Option<Arc<Key>>,
+    latest: &Mutex<HashMap<Name, u32>>,
+) {
+    let origin = soa.domain().clone();
+    let serial = match soa {
+        DnsRecord::SOA { serial, .. } => serial,
+        _ => unreachable!(),
+    };
+    let mut wait = FIRST_RETRY;
+    for _ in 0..MAX_ATTEMPTS {
+        let superseded = latest
+            .lock()
+            .unwrap()
+            .get(&origin)
+            .is_some_and(|latest| zone::is_newer_serial(*latest, serial));
+        if superseded {
+            return;
+        }
+        match send(target, &soa, key.clone(), wait) {
+            Ok(()) => {
+                log::info!(
+                    "{} acknowledged NOTIFY for {} serial {}",
+                    target,
+                    origin,
+                    serial
+                );
+                return;
+            }
+            Err(e) => log::debug!("NOTIFY for {} to {}: {}", origin, target, e),
+        }
+        wait = (wait * 2).min(MAX_RETRY);
+    }
+    log::warn!(
+        "{} never acknowledged NOTIFY for {} serial {}",
+        target,
+        origin,
+        serial
+    );
+}
+
+/// Sends one NOTIFY and waits up to `timeout` for its acknowledgement,
+/// which has to be signed if the NOTIFY was.
+fn send(
+    target: SocketAddr,
+    soa: &DnsRecord,
+    key: Option<Arc<Key>>,
+    timeout: Duration,
+) -> Result<()> {
+    let mut id = [0; 2];
+    // An all-zero ID is still a valid one.
+    let _ = SystemRandom::new().fill(&mut id);
+    let mut packet = Packet::new();
+    packet.header.id = u16::from_be_bytes(id);
+    packet.header.operation_code = OpCode::NOTIFY;
+    packet.header.authoritative_answer = true;
+    packet
+        .questions
+        .push(Question::new(soa.domain().clone(), QueryType::SOA));
+    packet.answers.push(soa.clone());
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer)?;
+    let mut message = buffer.get_range(0, buffer.pos())?.to_vec();
+    let mut exchange = match key {
+        Some(key) => {
+            let (exchange, signed) = Exchange::sign(key, &message, SystemTime::now())?;
+            message = signed;
+            Some(exchange)
+        }
+        None => None,
+    };
+
+    let bind = match target {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.connect(target)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.send(&message)?;
+    loop {
+        let mut response = BytePacketBuffer::new();
+        let len = socket.recv(&mut response.buffer)?;
+        response.set_len(len)?;
+        let header = match Packet::from_buffer(&mut response) {
+            Ok(response) => response.header,
+            Err(_) => continue,
+        };
+        if header.responce
+            && header.id == packet.header.id
+            && header.operation_code == OpCode::NOTIFY
+        {
+            if let Some(exchange) = &mut exchange {
+                exchange.verify(&response.buffer[..len], SystemTime::now())?;
+            }
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn notifies_name_servers_until_acknowledged() {
+        let zone = Zone::parse(
+            &name("example.com"),
+            "@ SOA ns1 hostmaster 7 7200 3600 1209600 300\n\
+             @ NS ns1\n@ NS ns2\n@ NS ns3\n\
+             ns1 A 192.0.2.1\nns2 A 192.0.2.2\nns2 AAAA 2001:db8::2\nns3 A 192.0.2.2",
+        )
+        .unwrap();
+        // ns1 is the primary itself.
+        assert_eq!(
+            name_servers(&zone),
+            vec![
+                "192.0.2.2:53".parse().unwrap(),
+                "[2001:db8::2]:53".parse().unwrap()
+            ]
+        );
+
+        // A secondary that only answers the second NOTIFY.
+        let secondary = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = secondary.local_addr().unwrap();
+        let notifier = Notifier::default();
+        let zone = Zone::parse(
+            &name("example.com"),
+            "@ SOA ns1 hostmaster 7 7200 3600 1209600 300\n@ NS ns1\nns1 A 192.0.2.1",
+        )
+        .unwrap();
+        assert_eq!(notifier.notify(&zone, &[addr], None), vec![addr]);
+
+        let mut serials = Vec::new();
+        for attempt in 0..2 {
+            let mut buffer = BytePacketBuffer::new();
+            let (len, from) = secondary.recv_from(&mut buffer.buffer).unwrap();
+            buffer.set_len(len).unwrap();
+            let mut request = Packet::from_buffer(&mut buffer).unwrap();
+            assert_eq!(request.header.operation_code, OpCode::NOTIFY);
+            assert_eq!(request.questions[0].name, name("example.com"));
+            serials.push(match request.answers[0] {
+                DnsRecord::SOA { serial, .. } => serial,
+                _ => panic!("NOTIFY without a SOA"),
+            });
+            if attempt == 1 {
+                request.header.responce = true;
+                request.answers.clear();
+                let mut response = BytePacketBuffer::new();
+                request.write(&mut response).unwrap();
+                secondary
+                    .send_to(response.get_range(0, response.pos()).unwrap(), from)
+                    .unwrap();
+            }
+        }
+        assert_eq!(serials, vec![7, 7]);
+        secondary
+            .set_read_timeout(Some(Duration::from_millis(2500)))
+            .unwrap();
+        let mut buffer = [0; 512];
+        assert!(secondary.recv_from(&mut buffer).is_err());
+    }
+}
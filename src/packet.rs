@@ -1,12 +1,16 @@
 use std::net::Ipv4Addr;
 
 use crate::{buffer::BytePacketBuffer, header::Header, question::Question};
-use crate::{enums::QueryType, record::DnsRecord};
+use crate::{
+    enums::{QueryType, ResultCode},
+    name::Name,
+    record::DnsRecord,
+};
 
 type Error = Box<dyn std::error::Error>;
 type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Packet {
     pub header: Header,
     pub questions: Vec<Question>,
@@ -15,6 +19,12 @@ pub struct Packet {
     pub resources: Vec<DnsRecord>,
 }
 
+impl Default for Packet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Packet {
     pub fn new() -> Self {
         Self {
@@ -28,9 +38,9 @@ impl Packet {
 
     pub fn from_buffer(buffer: &mut BytePacketBuffer) -> Result<Self> {
         let mut result = Self::new();
-        result.header.read(buffer);
+        result.header.read(buffer)?;
         for _ in 0..result.header.questions {
-            let mut question = Question::new("".to_string(), QueryType::UNKNOWN(0));
+            let mut question = Question::new(Name::root(), QueryType::UNKNOWN(0));
             question.read(buffer)?;
             result.questions.push(question);
         }
@@ -50,6 +60,22 @@ impl Packet {
             result.resources.push(record);
         }
 
+        // The OPT record holds the upper bits of an extended RCODE.
+        if let Some(DnsRecord::OPT { extended_rcode, .. }) = result.opt() {
+            let low = result.header.responce_code.to_num();
+            if let Some(code) = extended_rcode.checked_mul(16) {
+                result.header.responce_code = ResultCode::from_num(code | low);
+            }
+        }
+
+        if buffer.pos() != buffer.len() {
+            return Err(format!(
+                "{} bytes of trailing data after the last record",
+                buffer.len() - buffer.pos()
+            )
+            .into());
+        }
+
         Ok(result)
     }
 
@@ -58,6 +84,12 @@ impl Packet {
         self.header.answers = self.answers.len() as u16;
         self.header.authoritative_entries = self.authorities.len() as u16;
         self.header.resource_entries = self.resources.len() as u16;
+        let code = self.header.responce_code.to_num();
+        if let Some(DnsRecord::OPT { extended_rcode, .. }) = self.opt_mut() {
+            if code > 0x0F {
+                *extended_rcode = code >> 4;
+            }
+        }
 
         self.header.write(buffer)?;
 
@@ -77,6 +109,19 @@ impl Packet {
         Ok(())
     }
 
+    /// The EDNS pseudo-record, if the message has one.
+    pub fn opt(&self) -> Option<&DnsRecord> {
+        self.resources
+            .iter()
+            .find(|record| matches!(record, DnsRecord::OPT { .. }))
+    }
+
+    fn opt_mut(&mut self) -> Option<&mut DnsRecord> {
+        self.resources
+            .iter_mut()
+            .find(|record| matches!(record, DnsRecord::OPT { .. }))
+    }
+
     pub fn get_random_a(&self) -> Option<Ipv4Addr> {
         self.answers
             .iter()
@@ -87,17 +132,17 @@ impl Packet {
             .next()
     }
 
-    pub fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+    pub fn get_ns<'a>(&'a self, qname: &'a Name) -> impl Iterator<Item = (&'a Name, &'a Name)> {
         self.authorities
             .iter()
             .filter_map(|record| match record {
-                DnsRecord::NS { domain, host, .. } => Some((domain.as_str(), host.as_str())),
+                DnsRecord::NS { domain, host, .. } => Some((domain, host)),
                 _ => None,
             })
-            .filter(move |(domain, _)| qname.ends_with(*domain))
+            .filter(move |(domain, _)| qname.is_subdomain_of(domain))
     }
 
-    pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
+    pub fn get_resolved_ns(&self, qname: &Name) -> Option<Ipv4Addr> {
         self.get_ns(qname)
             .flat_map(|(_, host)| {
                 self.resources
@@ -107,11 +152,83 @@ impl Packet {
                         _ => None,
                     })
             })
-            .map(|addr| *addr)
+            .copied()
             .next()
     }
 
-    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
+    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a Name) -> Option<&'a Name> {
         self.get_ns(qname).map(|(_, host)| host).next()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // response_packet.txt: google.com A 172.217.175.110
+    const RESPONSE: &[u8] = &[
+        0x2a, 0xba, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x06, 0x67, 0x6f,
+        0x6f, 0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00, 0x01, 0xc0, 0x0c,
+        0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0xbe, 0x00, 0x04, 0xac, 0xd9, 0xaf, 0x6e,
+    ];
+    const RDLENGTH: usize = 39;
+
+    fn parse(bytes: &[u8]) -> Result<Packet> {
+        Packet::from_buffer(&mut BytePacketBuffer::from_bytes(bytes)?)
+    }
+
+    #[test]
+    fn parses_exact_message() {
+        let packet = parse(RESPONSE).unwrap();
+        assert_eq!(packet.answers.len(), 1);
+    }
+
+    #[test]
+    fn zero_padding_is_trailing_data() {
+        let mut padded = [0; crate::PACKET_BUFFER_SIZE];
+        padded[..RESPONSE.len()].copy_from_slice(RESPONSE);
+        assert!(parse(&padded).is_err());
+    }
+
+    #[test]
+    fn rdata_longer_than_declared_is_an_error() {
+        let mut bytes = RESPONSE.to_vec();
+        bytes[RDLENGTH] = 3;
+        assert!(parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn rdata_shorter_than_declared_is_an_error() {
+        let mut bytes = RESPONSE.to_vec();
+        bytes[RDLENGTH] = 5;
+        bytes.push(0);
+        assert!(parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn truncated_record_is_an_error() {
+        assert!(parse(&RESPONSE[..RESPONSE.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn extended_rcode_is_split_with_the_opt_record() {
+        let mut packet = Packet::new();
+        packet.header.responce = true;
+        packet.header.responce_code = ResultCode::BADCOOKIE;
+        packet.resources.push(DnsRecord::OPT {
+            domain: Name::root(),
+            payload_size: 512,
+            extended_rcode: 0,
+            version: 0,
+            flags: 0,
+            options: vec![(10, vec![1; 24])],
+        });
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let bytes = buffer.get_range(0, buffer.pos()).unwrap().to_vec();
+        // YXRRSET in the header, 1 in the upper bits.
+        assert_eq!(bytes[3] & 0x0F, 7);
+        assert_eq!(bytes[17], 1);
+        assert_eq!(parse(&bytes).unwrap(), packet);
+    }
+}
@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use crate::{
+    config::{BlocklistConfig, BlocklistFormat, PolicyActionKind},
+    enums::{QueryType, ResultCode},
+    name::Name,
+    packet::Packet,
+    record::DnsRecord,
+    zonefile,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// What to do with a query that matched a blocklist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Nxdomain,
+    Nodata,
+    /// Answer A/AAAA queries with these addresses, anything else NODATA.
+    Sinkhole(Vec<IpAddr>),
+    /// Answer with a CNAME to this name.
+    Redirect(Name),
+    /// Resolve normally, skipping any later lists (RPZ `rpz-passthru.`).
+    Passthru,
+    /// Send no response (RPZ `rpz-drop.`).
+    Drop,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Nxdomain => write!(f, "nxdomain"),
+            Action::Nodata => write!(f, "nodata"),
+            Action::Sinkhole(_) => write!(f, "sinkhole"),
+            Action::Redirect(_) => write!(f, "cname"),
+            Action::Passthru => write!(f, "passthru"),
+            Action::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+/// A policy decision for one query, for logging.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hit {
+    pub list: String,
+    /// The blocklist entry that matched, e.g. `*.ads.example`.
+    pub trigger: Name,
+    pub action: Action,
+    pub ttl: u32,
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.list, self.trigger, self.action)
+    }
+}
+
+/// One loaded blocklist. Exact names win over wildcards, and the closest
+/// wildcard wins over ones further up.
+struct Blocklist {
+    config: BlocklistConfig,
+    modified: Option<SystemTime>,
+    exact: HashMap<Name, (Action, u32)>,
+    /// Keyed by the name below `*.`, matching strict subdomains of it.
+    wildcard: HashMap<Name, (Action, u32)>,
+}
+
+impl Blocklist {
+    fn load(config: &BlocklistConfig) -> Result<Self> {
+        let modified = modified(&config.path);
+        let text = fs::read_to_string(&config.path)
+            .map_err(|e| format!("Cannot read blocklist {}: {}", config.path.display(), e))?;
+        let mut list = Blocklist {
+            config: config.clone(),
+            modified,
+            exact: HashMap::new(),
+            wildcard: HashMap::new(),
+        };
+        let default = (default_action(config)?, config.ttl);
+        match config.format {
+            BlocklistFormat::Domains => {
+                for line in text.lines() {
+                    let line = line.split('#').next().unwrap_or("").trim();
+                    if !line.is_empty() {
+                        list.insert(trigger(line)?, default.clone());
+                    }
+                }
+            }
+            BlocklistFormat::Hosts => {
+                // The addresses are ignored: every listed name is blocked
+                // with the configured action.
+                for line in text.lines() {
+                    let line = line.split('#').next().unwrap_or("");
+                    for host in line.split_whitespace().skip(1) {
+                        if host != "localhost" {
+                            list.insert(trigger(host)?, default.clone());
+                        }
+                    }
+                }
+            }
+            BlocklistFormat::Rpz => list.load_rpz(&text)?,
+        }
+        log::info!(
+            "loaded blocklist {} ({} names, {} wildcards)",
+            config.path.display(),
+            list.exact.len(),
+            list.wildcard.len()
+        );
+        Ok(list)
+    }
+
+    /// QNAME triggers from a Response Policy Zone. The action is in the
+    /// RDATA: `CNAME .` is NXDOMAIN, `CNAME *.` NODATA, `CNAME rpz-passthru.`
+    /// and `CNAME rpz-drop.` pass or drop, another CNAME redirects, and
+    /// A/AAAA records are answered as given.
+    fn load_rpz(&mut self, text: &str) -> Result<()> {
+        let entries = zonefile::parse(text, &Name::root())?;
+        let origin = entries
+            .iter()
+            .find(|entry| entry.rtype == "SOA")
+            .map(|entry| entry.owner.clone())
+            .unwrap_or_else(Name::root);
+
+        let mut addresses: HashMap<Name, (Vec<IpAddr>, u32)> = HashMap::new();
+        for entry in entries {
+            if entry.rtype == "SOA" || entry.rtype == "NS" {
+                continue;
+            }
+            let owner = match strip_origin(&entry.owner, &origin) {
+                Some(owner) => owner,
+                None => continue,
+            };
+            if owner.labels().any(|label| label.starts_with(b"rpz-")) {
+                log::debug!("skipping unsupported RPZ trigger {}", entry.owner);
+                continue;
+            }
+            let ttl = entry.ttl.unwrap_or(self.config.ttl);
+            let rdata = entry.rdata.first().map(|s| s.as_str()).unwrap_or("");
+
+            let action = match entry.rtype.as_str() {
+                "CNAME" => match rdata {
+                    "." => Action::Nxdomain,
+                    "*." => Action::Nodata,
+                    "rpz-passthru." => Action::Passthru,
+                    "rpz-drop." => Action::Drop,
+                    target => Action::Redirect(zonefile::name(target, &origin)?),
+                },
+                "A" | "AAAA" => {
+                    let addr: IpAddr = rdata
+                        .parse()
+                        .map_err(|_| format!("Invalid address {:?} for {}", rdata, entry.owner))?;
+                    let entry = addresses.entry(owner).or_insert((Vec::new(), ttl));
+                    entry.0.push(addr);
+                    continue;
+                }
+                other => {
+                    log::debug!("skipping RPZ {} record for {}", other, entry.owner);
+                    continue;
+                }
+            };
+            self.insert(owner, (action, ttl));
+        }
+        for (owner, (addrs, ttl)) in addresses {
+            self.insert(owner, (Action::Sinkhole(addrs), ttl));
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, trigger: Name, rule: (Action, u32)) {
+        let first = trigger.labels().next().map(|label| label.to_vec());
+        if first.as_deref() == Some(b"*") {
+            self.wildcard.insert(trigger.parent().unwrap(), rule);
+        } else {
+            self.exact.insert(trigger, rule);
+        }
+    }
+
+    fn lookup(&self, qname: &Name) -> Option<(Name, &(Action, u32))> {
+        if let Some(rule) = self.exact.get(qname) {
+            return Some((qname.clone(), rule));
+        }
+        let mut parent = qname.parent();
+        while let Some(name) = parent {
+            if let Some(rule) = self.wildcard.get(&name) {
+                return Some((name.child(b"*").ok()?, rule));
+            }
+            parent = name.parent();
+        }
+        None
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn trigger(s: &str) -> Result<Name> {
+    let s = s.trim_end_matches('.');
+    if s.is_ascii() {
+        s.parse()
+    } else {
+        Name::from_unicode(s)
+    }
+}
+
+fn strip_origin(name: &Name, origin: &Name) -> Option<Name> {
+    if !name.is_subdomain_of(origin) || name == origin {
+        return None;
+    }
+    let keep = name.label_count() - origin.label_count();
+    Name::from_labels(name.labels().take(keep).map(|l| l.to_vec())).ok()
+}
+
+fn default_action(config: &BlocklistConfig) -> Result<Action> {
+    Ok(match config.action {
+        PolicyActionKind::Nxdomain => Action::Nxdomain,
+        PolicyActionKind::Nodata => Action::Nodata,
+        PolicyActionKind::Sinkhole => Action::Sinkhole(vec![
+            IpAddr::V4(config.sinkhole_ipv4.unwrap_or(Ipv4Addr::UNSPECIFIED)),
+            IpAddr::V6(config.sinkhole_ipv6.unwrap_or(Ipv6Addr::UNSPECIFIED)),
+        ]),
+        PolicyActionKind::Cname => Action::Redirect(
+            config
+                .redirect
+                .clone()
+                .ok_or("A cname blocklist needs a redirect name")?,
+        ),
+    })
+}
+
+/// The configured blocklists, checked in order; the first list with a
+/// match decides. Lists can be swapped out while queries are running.
+pub struct Policy {
+    lists: RwLock<Vec<Arc<Blocklist>>>,
+}
+
+impl Policy {
+    pub fn new(configs: &[BlocklistConfig]) -> Result<Self> {
+        let lists = configs
+            .iter()
+            .map(|config| Blocklist::load(config).map(Arc::new))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            lists: RwLock::new(lists),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lists.read().unwrap().is_empty()
+    }
+
+    pub fn check(&self, qname: &Name) -> Option<Hit> {
+        let lists = self.lists.read().unwrap().clone();
+        lists.iter().find_map(|list| {
+            list.lookup(qname).map(|(trigger, (action, ttl))| Hit {
+                list: list.config.path.display().to_string(),
+                trigger,
+                action: action.clone(),
+                ttl: *ttl,
+            })
+        })
+    }
+
+    /// Reloads every list whose file changed since it was loaded. A list
+    /// that fails to load keeps its previous contents.
+    pub fn reload_changed(&self) -> usize {
+        let current = self.lists.read().unwrap().clone();
+        let mut reloaded = 0;
+        let lists: Vec<Arc<Blocklist>> = current
+            .into_iter()
+            .map(|list| {
+                if modified(&list.config.path) == list.modified {
+                    return list;
+                }
+                match Blocklist::load(&list.config) {
+                    Ok(new) => {
+                        reloaded += 1;
+                        Arc::new(new)
+                    }
+                    Err(e) => {
+                        log::warn!("{}", e);
+                        list
+                    }
+                }
+            })
+            .collect();
+        *self.lists.write().unwrap() = lists;
+        reloaded
+    }
+}
+
+/// The response for a blocked query. `Passthru` and `Drop` are handled by
+/// the caller and never get here.
+pub fn respond(hit: &Hit, qname: &Name, qtype: QueryType) -> Packet {
+    let mut packet = Packet::new();
+    match &hit.action {
+        Action::Nxdomain => packet.header.responce_code = ResultCode::NXDOMAIN,
+        Action::Nodata | Action::Passthru | Action::Drop => {}
+        Action::Sinkhole(addrs) => {
+            for addr in addrs {
+                let record = match (addr, qtype) {
+                    (IpAddr::V4(addr), QueryType::A) => DnsRecord::A {
+                        domain: qname.clone(),
+                        addr: *addr,
+                        ttl: hit.ttl,
+                    },
+                    (IpAddr::V6(addr), QueryType::AAAA) => DnsRecord::AAAA {
+                        domain: qname.clone(),
+                        addr: *addr,
+                        ttl: hit.ttl,
+                    },
+                    _ => continue,
+                };
+                packet.answers.push(record);
+            }
+        }
+        Action::Redirect(target) => packet.answers.push(DnsRecord::CNAME {
+            domain: qname.clone(),
+            host: target.clone(),
+            ttl: hit.ttl,
+        }),
+    }
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    fn temp_file(file: &str, contents: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("policy-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(file);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn policy(toml: &str) -> Policy {
+        Policy::new(&Config::parse(toml).unwrap().blocklists).unwrap()
+    }
+
+    #[test]
+    fn domain_list_matches_exact_and_wildcard() {
+        let path = temp_file(
+            "domains.txt",
+            "# ads\nads.example.com\n*.tracker.example # all of it\n",
+        );
+        let policy = policy(&format!(
+            "[[blocklists]]\npath = {:?}\nformat = \"domains\"",
+            path
+        ));
+
+        let hit = policy.check(&name("ADS.example.com")).unwrap();
+        assert_eq!(hit.action, Action::Nxdomain);
+        assert_eq!(hit.trigger, name("ads.example.com"));
+        assert!(policy.check(&name("www.ads.example.com")).is_none());
+
+        let hit = policy.check(&name("a.b.tracker.example")).unwrap();
+        assert_eq!(hit.trigger, name("*.tracker.example"));
+        assert!(policy.check(&name("tracker.example")).is_none());
+        assert!(policy.check(&name("example.com")).is_none());
+    }
+
+    #[test]
+    fn hosts_list_with_sinkhole() {
+        let path = temp_file(
+            "hosts",
+            "127.0.0.1 localhost\n0.0.0.0 malware.example  phish.example\n",
+        );
+        let policy = policy(&format!(
+            "[[blocklists]]\npath = {:?}\nformat = \"hosts\"\naction = \"sinkhole\"\nsinkhole_ipv4 = \"192.0.2.1\"",
+            path
+        ));
+        assert!(policy.check(&name("localhost")).is_none());
+
+        let hit = policy.check(&name("phish.example")).unwrap();
+        let response = respond(&hit, &name("phish.example"), QueryType::A);
+        assert_eq!(
+            response.answers,
+            vec![DnsRecord::A {
+                domain: name("phish.example"),
+                addr: Ipv4Addr::new(192, 0, 2, 1),
+                ttl: 60,
+            }]
+        );
+        let response = respond(&hit, &name("phish.example"), QueryType::AAAA);
+        assert_eq!(response.answers[0].domain(), &name("phish.example"));
+        let response = respond(&hit, &name("phish.example"), QueryType::MX);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.header.responce_code, ResultCode::NOERROR);
+    }
+
+    #[test]
+    fn rpz_actions() {
+        let path = temp_file(
+            "policy.rpz",
+            r#"
+$TTL 300
+@ SOA localhost. root.localhost. 1 3600 600 86400 60
+  NS localhost.
+bad.example       CNAME .
+*.bad.example     CNAME .
+empty.example     CNAME *.
+ok.bad.example    CNAME rpz-passthru.
+quiet.example     CNAME rpz-drop.
+moved.example     CNAME walled-garden.example.
+sink.example  30  A     10.0.0.1
+sink.example      AAAA  fd00::1
+32.1.2.0.192.rpz-ip CNAME .
+"#,
+        );
+        let policy = policy(&format!(
+            "[[blocklists]]\npath = {:?}\nformat = \"rpz\"",
+            path
+        ));
+        let action = |s: &str| policy.check(&name(s)).map(|hit| hit.action);
+
+        assert_eq!(action("bad.example"), Some(Action::Nxdomain));
+        assert_eq!(action("x.bad.example"), Some(Action::Nxdomain));
+        assert_eq!(action("ok.bad.example"), Some(Action::Passthru));
+        assert_eq!(action("empty.example"), Some(Action::Nodata));
+        assert_eq!(action("quiet.example"), Some(Action::Drop));
+        assert_eq!(
+            action("moved.example"),
+            Some(Action::Redirect(name("walled-garden.example")))
+        );
+        assert_eq!(
+            action("sink.example"),
+            Some(Action::Sinkhole(vec![
+                "10.0.0.1".parse().unwrap(),
+                "fd00::1".parse().unwrap()
+            ]))
+        );
+        assert_eq!(policy.check(&name("sink.example")).unwrap().ttl, 30);
+        assert_eq!(action("other.example"), None);
+    }
+
+    #[test]
+    fn earlier_lists_win_and_changed_files_reload() {
+        let first = temp_file("first.txt", "shared.example\n");
+        let second = temp_file("second.txt", "*.example\n");
+        let policy = policy(&format!(
+            "[[blocklists]]\npath = {:?}\nformat = \"domains\"\naction = \"nodata\"\n\
+             [[blocklists]]\npath = {:?}\nformat = \"domains\"",
+            first, second
+        ));
+        assert_eq!(
+            policy.check(&name("shared.example")).unwrap().action,
+            Action::Nodata
+        );
+        assert_eq!(
+            policy.check(&name("new.example")).unwrap().action,
+            Action::Nxdomain
+        );
+
+        assert_eq!(policy.reload_changed(), 0);
+        fs::write(&first, "shared.example\nnew.example\n").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(10);
+        fs::File::options()
+            .write(true)
+            .open(&first)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(policy.reload_changed(), 1);
+        assert_eq!(
+            policy.check(&name("new.example")).unwrap().action,
+            Action::Nodata
+        );
+    }
+}
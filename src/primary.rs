@@ -0,0 +1,163 @@
+//! Primary zones: loaded from master files and reloaded when a file
+//! changes, with secondaries notified of each new serial.
+
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::{
+    config::ZoneConfig,
+    context::ServerContext,
+    name::Name,
+    tsig::Key,
+    zone::{self, Zone, Zones},
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// A zone this server is the primary for, and where its master file is.
+#[derive(Debug)]
+pub struct Primary {
+    pub origin: Name,
+    file: PathBuf,
+    pub also_notify: Vec<SocketAddr>,
+    /// Signs the NOTIFY messages sent for the zone.
+    pub key: Option<Arc<Key>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl Primary {
+    pub fn load(config: &ZoneConfig, key: Option<Arc<Key>>) -> Result<(Self, Zone)> {
+        let modified = modified(&config.file);
+        let zone = Zone::load(&config.name, &config.file)?;
+        let primary = Primary {
+            origin: config.name.clone(),
+            file: config.file.clone(),
+            also_notify: config.also_notify.clone(),
+            key,
+            modified: Mutex::new(modified),
+        };
+        Ok((primary, zone))
+    }
+
+    /// Reloads the zone if its file changed since it was loaded, and returns
+    /// the new version if it moved the serial forward. A file that fails to
+    /// load leaves the zone as it was.
+    pub fn reload_changed(&self, zones: &Zones) -> Option<Arc<Zone>> {
+        let mut loaded = self.modified.lock().unwrap();
+        let modified = modified(&self.file);
+        if modified == *loaded {
+            return None;
+        }
+        *loaded = modified;
+        let zone = match Zone::load(&self.origin, &self.file) {
+            Ok(zone) => zone,
+            Err(e) => {
+                log::warn!("{}", e);
+                return None;
+            }
+        };
+        let serial = zone.serial();
+        let newer = zones
+            .get(&self.origin)
+            .is_none_or(|old| zone::is_newer_serial(serial, old.serial()));
+        if !newer {
+            log::warn!(
+                "zone {} reloaded without a higher serial than {}; secondaries will not notice",
+                self.origin,
+                serial
+            );
+        }
+        log::info!("reloaded zone {} at serial {}", self.origin, serial);
+        zones.replace(zone);
+        if newer {
+            zones.get(&self.origin)
+        } else {
+            None
+        }
+    }
+}
+
+/// Reloads every changed primary zone in every view and notifies the
+/// secondaries of those with a new serial.
+pub fn reload_changed(ctx: &ServerContext) {
+    for view in &ctx.views {
+        for primary in &view.primaries {
+            if let Some(zone) = primary.reload_changed(&view.zones) {
+                ctx.notifier
+                    .notify(&zone, &primary.also_notify, primary.key.clone());
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{enums::QueryType, record::DnsRecord};
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    #[test]
+    fn changed_files_are_reloaded() {
+        let path = std::env::temp_dir().join(format!("primary-{}.zone", std::process::id()));
+        let write = |serial: u32, addr: &str, age: u64| {
+            fs::write(
+                &path,
+                format!(
+                    "@ SOA ns1 hostmaster {} 7200 3600 1209600 300\nwww A {}",
+                    serial, addr
+                ),
+            )
+            .unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::now() + Duration::from_secs(age))
+                .unwrap();
+        };
+        write(1, "192.0.2.1", 0);
+        let config = ZoneConfig {
+            name: "example.com".parse().unwrap(),
+            file: path.clone(),
+            primaries: Vec::new(),
+            also_notify: Vec::new(),
+            key: None,
+        };
+        let (primary, zone) = Primary::load(&config, None).unwrap();
+        let zones = Zones::new(vec![zone]);
+        assert!(primary.reload_changed(&zones).is_none());
+
+        write(2, "192.0.2.2", 10);
+        assert_eq!(primary.reload_changed(&zones).unwrap().serial(), 2);
+        assert!(primary.reload_changed(&zones).is_none());
+
+        // Reloaded, but with nothing to notify about.
+        write(2, "192.0.2.3", 20);
+        assert!(primary.reload_changed(&zones).is_none());
+        let www = zones
+            .get(&config.name)
+            .unwrap()
+            .lookup(&"www.example.com".parse().unwrap(), QueryType::A);
+        assert_eq!(
+            www.answers[0],
+            DnsRecord::A {
+                domain: "www.example.com".parse().unwrap(),
+                addr: Ipv4Addr::new(192, 0, 2, 3),
+                ttl: 300,
+            }
+        );
+        write(3, "not an address", 30);
+        assert!(primary.reload_changed(&zones).is_none());
+        assert_eq!(zones.get(&config.name).unwrap().serial(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,376 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+
+use crate::{
+    config::{QueryLogConfig, QueryLogSink},
+    context::Transport,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// One answered query, written as a single JSON line.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryLogEntry {
+    pub timestamp: String,
+    pub client_ip: IpAddr,
+    pub client_port: u16,
+    pub transport: Transport,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub view: Option<String>,
+    pub qname: Option<String>,
+    pub qtype: Option<String>,
+    pub rcode: String,
+    pub answers: usize,
+    pub cache_hit: bool,
+    pub upstreams: Vec<SocketAddr>,
+    /// The blocklist hit that decided the answer, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+    pub latency_ms: f64,
+}
+
+impl QueryLogEntry {
+    pub fn new(client: SocketAddr, transport: Transport, rcode: String) -> Self {
+        Self {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            client_ip: client.ip(),
+            client_port: client.port(),
+            transport,
+            view: None,
+            qname: None,
+            qtype: None,
+            rcode,
+            answers: 0,
+            cache_hit: false,
+            upstreams: Vec::new(),
+            policy: None,
+            latency_ms: 0.0,
+        }
+    }
+
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.latency_ms = latency.as_secs_f64() * 1000.0;
+    }
+}
+
+/// Fans query log lines out to the configured sinks. A sink that fails is
+/// reported through `log` and does not affect the query being answered.
+pub struct QueryLog {
+    sinks: Vec<Mutex<Sink>>,
+}
+
+impl QueryLog {
+    pub fn new(config: &QueryLogConfig) -> Result<Self> {
+        let sinks = config
+            .sinks
+            .iter()
+            .map(|sink| Sink::open(sink).map(Mutex::new))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { sinks })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.sinks.is_empty()
+    }
+
+    pub fn log(&self, entry: &QueryLogEntry) {
+        if !self.is_enabled() {
+            return;
+        }
+        let line = match serde_json::to_string(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                log::warn!("cannot serialize query log entry: {}", e);
+                return;
+            }
+        };
+        for sink in &self.sinks {
+            let mut sink = sink.lock().unwrap();
+            if let Err(e) = sink.write_line(&line) {
+                log::warn!("query log {}: {}", sink.describe(), e);
+            }
+        }
+    }
+}
+
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+    Syslog(Syslog),
+}
+
+impl Sink {
+    fn open(config: &QueryLogSink) -> Result<Sink> {
+        Ok(match config {
+            QueryLogSink::Stdout => Sink::Stdout,
+            QueryLogSink::File {
+                path,
+                max_size,
+                keep,
+            } => Sink::File(RotatingFile::open(path, *max_size, *keep)?),
+            QueryLogSink::Syslog {
+                socket,
+                server,
+                facility,
+            } => Sink::Syslog(Syslog::open(socket, *server, facility)?),
+        })
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Sink::Stdout => "stdout".to_string(),
+            Sink::File(file) => file.path.display().to_string(),
+            Sink::Syslog(_) => "syslog".to_string(),
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Sink::Stdout => {
+                let stdout = io::stdout();
+                let mut out = stdout.lock();
+                writeln!(out, "{}", line)?;
+                out.flush()
+            }
+            Sink::File(file) => file.write_line(line),
+            Sink::Syslog(syslog) => syslog.send(line),
+        }
+    }
+}
+
+/// Appends to `path` and, once it would grow past `max_size`, shifts it to
+/// `path.1`, `path.1` to `path.2` and so on, dropping anything past `keep`.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, keep: usize) -> Result<Self> {
+        let file = Self::append(path)
+            .map_err(|e| format!("Cannot open query log {}: {}", path.display(), e))?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn append(path: &Path) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = Self::append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+enum SyslogTarget {
+    Local(UnixDatagram, PathBuf),
+    Remote(UdpSocket, SocketAddr),
+}
+
+/// RFC 5424 messages to the local syslog socket or a remote server over UDP.
+struct Syslog {
+    target: SyslogTarget,
+    facility: u8,
+}
+
+/// Severity "informational".
+const SYSLOG_SEVERITY: u8 = 6;
+
+impl Syslog {
+    fn open(socket: &Path, server: Option<SocketAddr>, facility: &str) -> Result<Self> {
+        let facility = facility_code(facility)
+            .ok_or_else(|| format!("Unknown syslog facility {:?}", facility))?;
+        let target = match server {
+            Some(server) => {
+                let bind: SocketAddr = if server.is_ipv4() {
+                    ([0, 0, 0, 0], 0).into()
+                } else {
+                    ([0u16; 8], 0).into()
+                };
+                SyslogTarget::Remote(UdpSocket::bind(bind)?, server)
+            }
+            None => SyslogTarget::Local(UnixDatagram::unbound()?, socket.to_path_buf()),
+        };
+        Ok(Self { target, facility })
+    }
+
+    fn send(&self, line: &str) -> io::Result<()> {
+        let message = format!(
+            "<{}>1 {} - {} {} query - {}",
+            self.facility * 8 + SYSLOG_SEVERITY,
+            humantime::format_rfc3339_millis(SystemTime::now()),
+            env!("CARGO_PKG_NAME"),
+            std::process::id(),
+            line
+        );
+        match &self.target {
+            SyslogTarget::Local(socket, path) => socket.send_to(message.as_bytes(), path)?,
+            SyslogTarget::Remote(socket, server) => socket.send_to(message.as_bytes(), server)?,
+        };
+        Ok(())
+    }
+}
+
+fn facility_code(name: &str) -> Option<u8> {
+    let code = match name {
+        "kern" => 0,
+        "user" => 1,
+        "mail" => 2,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "lpr" => 6,
+        "news" => 7,
+        "uucp" => 8,
+        "cron" => 9,
+        "authpriv" => 10,
+        "ftp" => 11,
+        "local0" => 16,
+        "local1" => 17,
+        "local2" => 18,
+        "local3" => 19,
+        "local4" => 20,
+        "local5" => 21,
+        "local6" => 22,
+        "local7" => 23,
+        _ => return None,
+    };
+    Some(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entry() -> QueryLogEntry {
+        let mut entry = QueryLogEntry::new(
+            "192.0.2.1:5353".parse().unwrap(),
+            Transport::Udp,
+            "NOERROR".to_string(),
+        );
+        entry.qname = Some("example.com".to_string());
+        entry.qtype = Some("A".to_string());
+        entry
+    }
+
+    #[test]
+    fn entries_are_single_json_lines() {
+        let line = serde_json::to_string(&entry()).unwrap();
+        assert!(!line.contains('\n'));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["client_ip"], "192.0.2.1");
+        assert_eq!(value["client_port"], 5353);
+        assert_eq!(value["transport"], "udp");
+        assert_eq!(value["qname"], "example.com");
+        assert_eq!(value["cache_hit"], false);
+    }
+
+    #[test]
+    fn file_sink_rotates() {
+        let dir = temp_dir("querylog-rotate");
+        let path = dir.join("query.log");
+        let line = serde_json::to_string(&entry()).unwrap();
+        let max_size = 2 * (line.len() as u64 + 1);
+        let log = QueryLog::new(&QueryLogConfig {
+            sinks: vec![QueryLogSink::File {
+                path: path.clone(),
+                max_size,
+                keep: 2,
+            }],
+        })
+        .unwrap();
+
+        for _ in 0..7 {
+            log.log(&entry());
+        }
+
+        let lines = |p: &Path| fs::read_to_string(p).unwrap().lines().count();
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&dir.join("query.log.1")), 2);
+        assert_eq!(lines(&dir.join("query.log.2")), 2);
+        assert!(!dir.join("query.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn syslog_sink_sends_rfc5424() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let log = QueryLog::new(&QueryLogConfig {
+            sinks: vec![QueryLogSink::Syslog {
+                socket: PathBuf::new(),
+                server: Some(server.local_addr().unwrap()),
+                facility: "local3".to_string(),
+            }],
+        })
+        .unwrap();
+        log.log(&entry());
+
+        let mut buf = [0; 1024];
+        let len = server.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..len]).unwrap();
+        assert!(message.starts_with("<158>1 "));
+        assert!(message.contains(" query - {\"timestamp\":"));
+        assert!(message.contains("\"qname\":\"example.com\""));
+    }
+
+    #[test]
+    fn unknown_facility_is_rejected() {
+        assert!(Syslog::open(Path::new("/dev/log"), None, "local9").is_err());
+    }
+}
@@ -1,22 +1,32 @@
-use crate::{buffer::BytePacketBuffer, enums::QueryType};
+use crate::{buffer::BytePacketBuffer, enums::QueryType, name::Name};
 
 type Error = Box<dyn std::error::Error>;
 type Result<T> = std::result::Result<T, Error>;
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Question {
-    pub name: String,
+    pub name: Name,
     pub qtype: QueryType,
+    pub qclass: u16,
 }
 
 impl Question {
-    pub fn new(name: String, qtype: QueryType) -> Self {
-        Self { name, qtype }
+    pub fn new(name: Name, qtype: QueryType) -> Self {
+        Self {
+            name,
+            qtype,
+            qclass: 1,
+        }
+    }
+
+    /// Question for a hostname typed by a user, which may be Unicode.
+    pub fn for_hostname(hostname: &str, qtype: QueryType) -> Result<Self> {
+        Ok(Self::new(Name::from_unicode(hostname)?, qtype))
     }
 
     pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
         buffer.read_qname(&mut self.name)?;
         self.qtype = QueryType::from_num(buffer.read_u16()?);
-        let _ = buffer.read_u16()?; // class
+        self.qclass = buffer.read_u16()?;
 
         Ok(())
     }
@@ -24,7 +34,7 @@ impl Question {
     pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
         buffer.write_qname(&self.name)?;
         buffer.write_u16(self.qtype.to_num())?;
-        buffer.write_u16(1)?;
+        buffer.write_u16(self.qclass)?;
         Ok(())
     }
 }
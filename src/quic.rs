@@ -0,0 +1,489 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Connection, Endpoint, EndpointConfig, IdleTimeout, TokioRuntime, VarInt};
+
+use crate::{
+    buffer::BytePacketBuffer,
+    context::{ServerContext, Transport},
+    handler::{self, Client},
+    tls, PACKET_BUFFER_SIZE,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// RFC 9250 section 8.4 error codes.
+const DOQ_NO_ERROR: VarInt = VarInt::from_u32(0);
+const DOQ_PROTOCOL_ERROR: VarInt = VarInt::from_u32(2);
+const DOQ_REQUEST_CANCELLED: VarInt = VarInt::from_u32(3);
+const ALPN: &[u8] = b"doq";
+
+/// A QUIC server configuration offering `doq`, accepting 0-RTT queries
+/// from clients resuming a session.
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    idle_timeout: Duration,
+) -> Result<quinn::ServerConfig> {
+    let tls = tls::early_data_config(cert, key, &[ALPN])?;
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+    let mut transport = quinn::TransportConfig::default();
+    transport.max_idle_timeout(Some(IdleTimeout::try_from(idle_timeout)?));
+    config.transport_config(Arc::new(transport));
+    config.migration(true);
+    Ok(config)
+}
+
+/// Accepts DNS-over-QUIC connections on `socket`. Runs its own runtime and
+/// only returns if that fails.
+pub fn serve(
+    socket: UdpSocket,
+    config: quinn::ServerConfig,
+    ctx: Arc<ServerContext>,
+) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_name("doq")
+        .build()?;
+    let local = socket.local_addr()?;
+    runtime.block_on(async move {
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(config),
+            socket,
+            Arc::new(TokioRuntime),
+        )?;
+        while let Some(incoming) = endpoint.accept().await {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let addr = incoming.remote_address();
+                if let Err(e) = handle_connection(ctx, incoming, local).await {
+                    log::debug!("quic connection from {}: {}", addr, e);
+                }
+            });
+        }
+        Ok(())
+    })
+}
+
+async fn handle_connection(
+    ctx: Arc<ServerContext>,
+    incoming: quinn::Incoming,
+    local: SocketAddr,
+) -> Result<()> {
+    // A server can always take 0-RTT data; whether the client sent any is
+    // up to it.
+    let (conn, _) = match incoming.accept()?.into_0rtt() {
+        Ok(accepted) => accepted,
+        Err(_) => unreachable!("servers always accept 0-RTT"),
+    };
+    loop {
+        let (send, recv) = match conn.accept_bi().await {
+            Ok(stream) => stream,
+            Err(quinn::ConnectionError::ApplicationClosed(_))
+            | Err(quinn::ConnectionError::TimedOut) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let client = Client {
+            // Read per stream: the client may have migrated.
+            addr: conn.remote_address(),
+            local,
+            transport: Transport::Quic,
+        };
+        let ctx = ctx.clone();
+        let conn = conn.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_stream(ctx, &conn, client, send, recv).await {
+                log::debug!("quic query from {}: {}", client.addr, e);
+            }
+        });
+    }
+}
+
+/// One query per stream: the client sends a length-prefixed message and
+/// finishes its side, and the response goes back the same way.
+async fn handle_stream(
+    ctx: Arc<ServerContext>,
+    conn: &Connection,
+    client: Client,
+    mut send: quinn::SendStream,
+    mut recv: quinn::RecvStream,
+) -> Result<()> {
+    let data = recv.read_to_end(2 + PACKET_BUFFER_SIZE).await?;
+    let message = match unframe(&data) {
+        Some(message) if message.len() >= 2 && message[..2] == [0, 0] => message.to_vec(),
+        // RFC 9250 section 4.2.1: a non-zero ID or a bad length is a
+        // protocol error for the whole connection.
+        _ => {
+            conn.close(DOQ_PROTOCOL_ERROR, b"malformed query");
+            return Err("malformed query".into());
+        }
+    };
+    let response = tokio::task::spawn_blocking(move || {
+        BytePacketBuffer::from_bytes(&message)
+            .and_then(|mut buffer| handler::answer(&ctx, &mut buffer, client))
+            .map_err(|e| e.to_string())
+    })
+    .await?;
+    match response {
+        Ok(Some(response)) => {
+            send.write_all(&frame(&response)).await?;
+            send.finish()?;
+        }
+        Ok(None) => {
+            send.reset(DOQ_REQUEST_CANCELLED)?;
+        }
+        Err(e) => {
+            send.reset(DOQ_REQUEST_CANCELLED)?;
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+fn frame(message: &[u8]) -> Vec<u8> {
+    let mut data = (message.len() as u16).to_be_bytes().to_vec();
+    data.extend_from_slice(message);
+    data
+}
+
+/// The message in a stream holding exactly one length-prefixed message.
+fn unframe(data: &[u8]) -> Option<&[u8]> {
+    let len = u16::from_be_bytes([*data.first()?, *data.get(1)?]) as usize;
+    if data.len() == 2 + len {
+        Some(&data[2..])
+    } else {
+        None
+    }
+}
+
+/// QUIC connections to DoQ upstreams. Queries from the synchronous
+/// resolver are run on a runtime of its own; connections are kept open and
+/// shared, and sessions resumed with 0-RTT when the server allows it.
+pub struct QuicClient {
+    runtime: tokio::runtime::Runtime,
+    endpoints: Mutex<HashMap<bool, Endpoint>>,
+    connections: Mutex<HashMap<(SocketAddr, String), Connection>>,
+}
+
+impl QuicClient {
+    pub fn new() -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .thread_name("doq-client")
+            .build()?;
+        Ok(Self {
+            runtime,
+            endpoints: Mutex::new(HashMap::new()),
+            connections: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Sends `query` on a new stream to `server`, reusing an open
+    /// connection when there is one. `sent` is told the local address once
+    /// the query is on its way.
+    pub fn exchange(
+        &self,
+        server: SocketAddr,
+        server_name: &str,
+        tls: Arc<rustls::ClientConfig>,
+        query: &[u8],
+        timeout: Duration,
+        sent: impl FnOnce(SocketAddr),
+    ) -> Result<Vec<u8>> {
+        // DoQ queries always carry ID 0 (RFC 9250 section 4.2.1).
+        let mut query = query.to_vec();
+        query[..2].copy_from_slice(&[0, 0]);
+        let key = (server, server_name.to_string());
+        let endpoint = self.endpoint(server)?;
+        let local = endpoint.local_addr()?;
+        let mut sent = Some(sent);
+
+        let cached = self.connections.lock().unwrap().get(&key).cloned();
+        if let Some(conn) = cached {
+            let result = self.runtime.block_on(async {
+                let query = query_stream(&conn, &query, || {
+                    if let Some(sent) = sent.take() {
+                        sent(local)
+                    }
+                });
+                tokio::time::timeout(timeout, query).await
+            });
+            match result {
+                Ok(Ok(response)) => return Ok(response),
+                Ok(Err(e)) => log::debug!("quic connection to {} failed: {}", server, e),
+                Err(_) => return Err(timed_out()),
+            }
+        }
+
+        let connect = async {
+            let config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+            let connecting = endpoint.connect_with(config, server, server_name)?;
+            let mut notify = || {
+                if let Some(sent) = sent.take() {
+                    sent(local)
+                }
+            };
+            // Queries are safe to replay, so they may go out as 0-RTT data
+            // on a resumed session. If the server turns that down, the
+            // query is sent again once the handshake is done.
+            let (conn, response) = match connecting.into_0rtt() {
+                Ok((conn, accepted)) => match query_stream(&conn, &query, &mut notify).await {
+                    Ok(response) => (conn, response),
+                    Err(_) if !accepted.await => {
+                        let response = query_stream(&conn, &query, &mut notify).await?;
+                        (conn, response)
+                    }
+                    Err(e) => return Err(e),
+                },
+                Err(connecting) => {
+                    let conn = connecting.await?;
+                    let response = query_stream(&conn, &query, &mut notify).await?;
+                    (conn, response)
+                }
+            };
+            Ok::<_, Error>((conn, response))
+        };
+        let result = self
+            .runtime
+            .block_on(async { tokio::time::timeout(timeout, connect).await });
+        let (conn, response) = match result {
+            Ok(result) => result?,
+            Err(_) => return Err(timed_out()),
+        };
+        self.connections.lock().unwrap().insert(key, conn);
+        Ok(response)
+    }
+
+    /// Connections still open.
+    pub fn connections(&self) -> usize {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|_, conn| conn.close_reason().is_none());
+        connections.len()
+    }
+
+    fn endpoint(&self, server: SocketAddr) -> Result<Endpoint> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        if let Some(endpoint) = endpoints.get(&server.is_ipv4()) {
+            return Ok(endpoint.clone());
+        }
+        let bind = match server {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let _guard = self.runtime.enter();
+        let endpoint = Endpoint::client(bind)?;
+        endpoints.insert(server.is_ipv4(), endpoint.clone());
+        Ok(endpoint)
+    }
+}
+
+impl Drop for QuicClient {
+    fn drop(&mut self) {
+        for endpoint in self.endpoints.lock().unwrap().values() {
+            endpoint.close(DOQ_NO_ERROR, b"");
+        }
+    }
+}
+
+async fn query_stream(conn: &Connection, query: &[u8], sent: impl FnOnce()) -> Result<Vec<u8>> {
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_all(&frame(query)).await?;
+    send.finish()?;
+    sent();
+    let data = recv.read_to_end(2 + PACKET_BUFFER_SIZE).await?;
+    let message = unframe(&data).ok_or("Malformed DoQ response")?;
+    Ok(message.to_vec())
+}
+
+fn timed_out() -> Error {
+    std::io::Error::from(std::io::ErrorKind::TimedOut).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        enums::{QueryType, ResultCode},
+        packet::Packet,
+        question::Question,
+    };
+    use rustls::RootCertStore;
+    use rustls_pki_types::CertificateDer;
+    use std::path::PathBuf;
+
+    struct Server {
+        addr: SocketAddr,
+        ca: PathBuf,
+        client: quinn::ClientConfig,
+    }
+
+    fn start_server() -> Server {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("quic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (ca, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&ca, cert.cert.pem()).unwrap();
+        std::fs::write(&key, cert.key_pair.serialize_pem()).unwrap();
+        let config = Config::parse(
+            r#"
+            [acl]
+            recursion = []
+
+            [local]
+            records = ["a.test A 192.0.2.1", "b.test A 192.0.2.2", "www.a.test A 192.0.2.3"]
+            "#,
+        )
+        .unwrap();
+        let server = server_config(&ca, &key, Duration::from_secs(30)).unwrap();
+        let ctx = Arc::new(ServerContext::new(config).unwrap());
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || serve(socket, server, ctx).unwrap());
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(cert.cert.der().to_vec()))
+            .unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        tls.enable_early_data = true;
+        let client = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls).unwrap()));
+        Server { addr, ca, client }
+    }
+
+    fn query(id: u16, qname: &str) -> Vec<u8> {
+        let mut packet = Packet::new();
+        packet.header.id = id;
+        packet
+            .questions
+            .push(Question::new(qname.parse().unwrap(), QueryType::A));
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.buffer[..buffer.pos()].to_vec()
+    }
+
+    fn parse(data: &[u8]) -> Packet {
+        Packet::from_buffer(&mut BytePacketBuffer::from_bytes(data).unwrap()).unwrap()
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn framing() {
+        assert_eq!(unframe(&frame(b"abc")), Some(&b"abc"[..]));
+        assert_eq!(unframe(&[0, 3, 1, 2]), None);
+        assert_eq!(unframe(&[0, 1, 1, 2]), None);
+        assert_eq!(unframe(&[0]), None);
+    }
+
+    #[test]
+    fn one_stream_per_query() {
+        let server = start_server();
+        runtime().block_on(async {
+            let endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+            let conn = endpoint
+                .connect_with(server.client.clone(), server.addr, "localhost")
+                .unwrap()
+                .await
+                .unwrap();
+            let (a, b) = (query(0, "a.test"), query(0, "b.test"));
+            let (a, b) = tokio::join!(
+                query_stream(&conn, &a, || ()),
+                query_stream(&conn, &b, || ())
+            );
+            let (a, b) = (parse(&a.unwrap()), parse(&b.unwrap()));
+            assert_eq!(a.answers[0].domain(), &"a.test".parse().unwrap());
+            assert_eq!(b.answers[0].domain(), &"b.test".parse().unwrap());
+            assert_eq!(a.header.id, 0);
+
+            // A query with a non-zero ID is a protocol error.
+            assert!(query_stream(&conn, &query(1, "a.test"), || ())
+                .await
+                .is_err());
+            match conn.closed().await {
+                quinn::ConnectionError::ApplicationClosed(close) => {
+                    assert_eq!(close.error_code, DOQ_PROTOCOL_ERROR)
+                }
+                e => panic!("unexpected close: {}", e),
+            }
+        });
+    }
+
+    #[test]
+    fn resumed_sessions_use_0rtt() {
+        let server = start_server();
+        runtime().block_on(async {
+            let endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+            let connecting = endpoint
+                .connect_with(server.client.clone(), server.addr, "localhost")
+                .unwrap();
+            let conn = match connecting.into_0rtt() {
+                Ok(_) => panic!("0-RTT without a session to resume"),
+                Err(connecting) => connecting.await.unwrap(),
+            };
+            query_stream(&conn, &query(0, "a.test"), || ())
+                .await
+                .unwrap();
+            conn.close(DOQ_NO_ERROR, b"");
+
+            let connecting = endpoint
+                .connect_with(server.client.clone(), server.addr, "localhost")
+                .unwrap();
+            let (conn, accepted) = connecting.into_0rtt().ok().unwrap();
+            let response = query_stream(&conn, &query(0, "b.test"), || ())
+                .await
+                .unwrap();
+            assert!(accepted.await);
+            assert_eq!(parse(&response).answers.len(), 1);
+        });
+    }
+
+    #[test]
+    fn forwards_over_quic() {
+        let server = start_server();
+        let config = Config::parse(&format!(
+            r#"
+            [[forwarders]]
+            zone = "test"
+            servers = [{{ url = "quic://localhost:{}", bootstrap = ["127.0.0.1"], ca = {:?} }}]
+            "#,
+            server.addr.port(),
+            server.ca,
+        ))
+        .unwrap();
+        let ctx = ServerContext::new(config).unwrap();
+        let client = Client {
+            addr: "127.0.0.1:5300".parse().unwrap(),
+            local: "127.0.0.1:53".parse().unwrap(),
+            transport: Transport::Udp,
+        };
+        for (id, qname) in [(5, "a.test"), (6, "www.a.test")] {
+            let mut buffer = BytePacketBuffer::from_bytes(&query(id, qname)).unwrap();
+            let data = handler::answer(&ctx, &mut buffer, client).unwrap().unwrap();
+            let response = parse(&data);
+            assert_eq!(response.header.id, id);
+            assert_eq!(response.header.responce_code, ResultCode::NOERROR);
+            assert_eq!(response.answers.len(), 1);
+        }
+        assert_eq!(ctx.upstreams.quic_connections(), 1);
+    }
+}
@@ -1,55 +1,204 @@
 use std::net::{Ipv4Addr, Ipv6Addr};
 
-use crate::{buffer::BytePacketBuffer, enums::QueryType};
+use crate::{buffer::BytePacketBuffer, enums::QueryType, name::Name};
 
 type Error = Box<dyn std::error::Error>;
 type Result<T> = std::result::Result<T, Error>;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum DnsRecord {
     UNKNOWN {
-        domain: String,
+        domain: Name,
         qtype: u16,
-        data_len: u16,
+        data: Vec<u8>,
         ttl: u32,
     },
     A {
-        domain: String,
+        domain: Name,
         addr: Ipv4Addr,
         ttl: u32,
     },
     NS {
-        domain: String,
-        host: String,
+        domain: Name,
+        host: Name,
         ttl: u32,
     },
     CNAME {
-        domain: String,
-        host: String,
+        domain: Name,
+        host: Name,
+        ttl: u32,
+    },
+    SOA {
+        domain: Name,
+        mname: Name,
+        rname: Name,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    },
+    PTR {
+        domain: Name,
+        host: Name,
         ttl: u32,
     },
     MX {
-        domain: String,
+        domain: Name,
         priority: u16,
-        host: String,
+        host: Name,
         ttl: u32,
     },
     AAAA {
-        domain: String,
+        domain: Name,
         addr: Ipv6Addr,
         ttl: u32,
     },
+    /// The EDNS pseudo-record (RFC 6891), owned by the root. Its class is
+    /// the largest UDP message the sender takes, and its TTL holds the
+    /// upper bits of the RCODE, the EDNS version and flags.
+    OPT {
+        domain: Name,
+        payload_size: u16,
+        extended_rcode: u8,
+        version: u8,
+        flags: u16,
+        /// Option codes and their data, in order.
+        options: Vec<(u16, Vec<u8>)>,
+    },
+    /// A transaction signature (RFC 8945), owned by the name of the key.
+    /// It is only ever the last record of a message and has no TTL.
+    TSIG {
+        domain: Name,
+        algorithm: Name,
+        /// Seconds since the epoch, in 48 bits.
+        time_signed: u64,
+        fudge: u16,
+        mac: Vec<u8>,
+        original_id: u16,
+        error: u16,
+        other: Vec<u8>,
+    },
 }
 
 impl DnsRecord {
+    pub fn domain(&self) -> &Name {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::OPT { domain, .. }
+            | DnsRecord::TSIG { domain, .. } => domain,
+        }
+    }
+
+    pub fn qtype(&self) -> QueryType {
+        match self {
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::from_num(*qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::OPT { .. } => QueryType::OPT,
+            DnsRecord::TSIG { .. } => QueryType::TSIG,
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        match *self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. } => ttl,
+            DnsRecord::OPT { .. } | DnsRecord::TSIG { .. } => 0,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. } => *ttl = new_ttl,
+            DnsRecord::OPT { .. } | DnsRecord::TSIG { .. } => {}
+        }
+    }
+
     pub fn read(buffer: &mut BytePacketBuffer) -> Result<DnsRecord> {
-        let mut domain = String::new();
+        let mut domain = Name::root();
         buffer.read_qname(&mut domain)?;
         let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16()?;
+        let class = buffer.read_u16()?;
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
+
+        let data_start = buffer.pos();
+        let record = match qtype {
+            QueryType::OPT => Self::read_opt(buffer, domain, class, ttl, data_len)?,
+            _ => Self::read_data(buffer, domain, qtype, ttl, data_len)?,
+        };
+        if buffer.pos() != data_start + data_len as usize {
+            return Err(format!(
+                "RDATA of {:?} record does not match its length of {}",
+                qtype, data_len
+            )
+            .into());
+        }
+        Ok(record)
+    }
+
+    fn read_opt(
+        buffer: &mut BytePacketBuffer,
+        domain: Name,
+        class: u16,
+        ttl: u32,
+        data_len: u16,
+    ) -> Result<DnsRecord> {
+        let end = buffer.pos() + data_len as usize;
+        let mut options = Vec::new();
+        while buffer.pos() < end {
+            let code = buffer.read_u16()?;
+            let len = buffer.read_u16()? as usize;
+            let data = buffer.get_range(buffer.pos(), len)?.to_vec();
+            buffer.step(len)?;
+            options.push((code, data));
+        }
+        Ok(DnsRecord::OPT {
+            domain,
+            payload_size: class,
+            extended_rcode: (ttl >> 24) as u8,
+            version: (ttl >> 16) as u8,
+            flags: ttl as u16,
+            options,
+        })
+    }
+
+    fn read_data(
+        buffer: &mut BytePacketBuffer,
+        domain: Name,
+        qtype: QueryType,
+        ttl: u32,
+        data_len: u16,
+    ) -> Result<DnsRecord> {
         match qtype {
             QueryType::A => {
                 let raw_addr = buffer.read_u32()?;
@@ -57,7 +206,7 @@ impl DnsRecord {
                     ((raw_addr >> 24) & 0xFF) as u8,
                     ((raw_addr >> 16) & 0xFF) as u8,
                     ((raw_addr >> 8) & 0xFF) as u8,
-                    ((raw_addr >> 0) & 0xFF) as u8,
+                    (raw_addr & 0xFF) as u8,
                 );
                 Ok(DnsRecord::A { domain, addr, ttl })
             }
@@ -69,19 +218,19 @@ impl DnsRecord {
 
                 let addr = Ipv6Addr::new(
                     ((raw_addr1 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr1 >> 0) & 0xFFFF) as u16,
+                    (raw_addr1 & 0xFFFF) as u16,
                     ((raw_addr2 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr2 >> 0) & 0xFFFF) as u16,
+                    (raw_addr2 & 0xFFFF) as u16,
                     ((raw_addr3 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr3 >> 0) & 0xFFFF) as u16,
+                    (raw_addr3 & 0xFFFF) as u16,
                     ((raw_addr4 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr4 >> 0) & 0xFFFF) as u16,
+                    (raw_addr4 & 0xFFFF) as u16,
                 );
 
                 Ok(DnsRecord::AAAA { domain, addr, ttl })
             }
             QueryType::NS => {
-                let mut ns = String::new();
+                let mut ns = Name::root();
                 buffer.read_qname(&mut ns)?;
                 Ok(DnsRecord::NS {
                     domain,
@@ -90,7 +239,7 @@ impl DnsRecord {
                 })
             }
             QueryType::CNAME => {
-                let mut cname = String::new();
+                let mut cname = Name::root();
                 buffer.read_qname(&mut cname)?;
                 Ok(DnsRecord::CNAME {
                     domain,
@@ -98,9 +247,35 @@ impl DnsRecord {
                     ttl,
                 })
             }
+            QueryType::SOA => {
+                let mut mname = Name::root();
+                buffer.read_qname(&mut mname)?;
+                let mut rname = Name::root();
+                buffer.read_qname(&mut rname)?;
+                Ok(DnsRecord::SOA {
+                    domain,
+                    mname,
+                    rname,
+                    serial: buffer.read_u32()?,
+                    refresh: buffer.read_u32()?,
+                    retry: buffer.read_u32()?,
+                    expire: buffer.read_u32()?,
+                    minimum: buffer.read_u32()?,
+                    ttl,
+                })
+            }
+            QueryType::PTR => {
+                let mut ptr = Name::root();
+                buffer.read_qname(&mut ptr)?;
+                Ok(DnsRecord::PTR {
+                    domain,
+                    host: ptr,
+                    ttl,
+                })
+            }
             QueryType::MX => {
                 let priority = buffer.read_u16()?;
-                let mut mx = String::new();
+                let mut mx = Name::root();
                 buffer.read_qname(&mut mx)?;
                 Ok(DnsRecord::MX {
                     domain,
@@ -109,18 +284,47 @@ impl DnsRecord {
                     ttl,
                 })
             }
+            QueryType::TSIG => {
+                let mut algorithm = Name::root();
+                buffer.read_qname(&mut algorithm)?;
+                let time_signed =
+                    (u64::from(buffer.read_u16()?) << 32) | u64::from(buffer.read_u32()?);
+                let fudge = buffer.read_u16()?;
+                let mac_len = buffer.read_u16()? as usize;
+                let mac = buffer.get_range(buffer.pos(), mac_len)?.to_vec();
+                buffer.step(mac_len)?;
+                let original_id = buffer.read_u16()?;
+                let error = buffer.read_u16()?;
+                let other_len = buffer.read_u16()? as usize;
+                let other = buffer.get_range(buffer.pos(), other_len)?.to_vec();
+                buffer.step(other_len)?;
+                Ok(DnsRecord::TSIG {
+                    domain,
+                    algorithm,
+                    time_signed,
+                    fudge,
+                    mac,
+                    original_id,
+                    error,
+                    other,
+                })
+            }
 
-            QueryType::UNKNOWN(_) => {
+            // Transfer types only ever appear in questions, and OPT is read
+            // with its class.
+            QueryType::UNKNOWN(_) | QueryType::IXFR | QueryType::AXFR | QueryType::OPT => {
+                let data = buffer.get_range(buffer.pos(), data_len as usize)?.to_vec();
                 buffer.step(data_len as usize)?;
                 Ok(DnsRecord::UNKNOWN {
                     domain,
                     qtype: qtype.to_num(),
-                    data_len,
+                    data,
                     ttl,
                 })
             }
         }
     }
+
     pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
         let start_pos = buffer.pos();
         match *self {
@@ -177,6 +381,52 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
+            DnsRecord::SOA {
+                ref domain,
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                for value in [serial, refresh, retry, expire, minimum] {
+                    buffer.write_u32(value)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::PTR {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
             DnsRecord::MX {
                 ref domain,
                 priority,
@@ -211,8 +461,91 @@ impl DnsRecord {
                     buffer.write_u16(*octet)?;
                 }
             }
-            DnsRecord::UNKNOWN { .. } => {}
+            DnsRecord::OPT {
+                ref domain,
+                payload_size,
+                extended_rcode,
+                version,
+                flags,
+                ref options,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::OPT.to_num())?;
+                buffer.write_u16(payload_size)?;
+                buffer.write_u8(extended_rcode)?;
+                buffer.write_u8(version)?;
+                buffer.write_u16(flags)?;
+
+                let data = opt_data(options);
+                buffer.write_u16(data.len() as u16)?;
+                for b in &data {
+                    buffer.write_u8(*b)?;
+                }
+            }
+            DnsRecord::TSIG {
+                ref domain,
+                ref algorithm,
+                time_signed,
+                fudge,
+                ref mac,
+                original_id,
+                error,
+                ref other,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TSIG.to_num())?;
+                // Class ANY, TTL 0.
+                buffer.write_u16(255)?;
+                buffer.write_u32(0)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname_uncompressed(algorithm)?;
+                buffer.write_u16((time_signed >> 32) as u16)?;
+                buffer.write_u32(time_signed as u32)?;
+                buffer.write_u16(fudge)?;
+                buffer.write_u16(mac.len() as u16)?;
+                for b in mac {
+                    buffer.write_u8(*b)?;
+                }
+                buffer.write_u16(original_id)?;
+                buffer.write_u16(error)?;
+                buffer.write_u16(other.len() as u16)?;
+                for b in other {
+                    buffer.write_u8(*b)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::UNKNOWN {
+                ref domain,
+                qtype,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+                for b in data {
+                    buffer.write_u8(*b)?;
+                }
+            }
         }
         Ok(buffer.pos() - start_pos)
     }
 }
+
+/// The RDATA of an OPT record holding `options`.
+pub fn opt_data(options: &[(u16, Vec<u8>)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (code, value) in options {
+        data.extend_from_slice(&code.to_be_bytes());
+        data.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        data.extend_from_slice(value);
+    }
+    data
+}
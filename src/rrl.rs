@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{cidr, config::RateLimitConfig, enums::ResultCode, packet::Packet};
+
+/// What to do with a response after rate limiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Send,
+    Drop,
+    /// Send an empty, truncated response instead, so a real client retries
+    /// over TCP while a spoofed victim gets nothing worth amplifying.
+    Slip,
+}
+
+/// Responses are limited separately per kind, as in BIND.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ResponseKind {
+    Answer,
+    Nxdomain,
+    Error,
+}
+
+impl ResponseKind {
+    fn of(packet: &Packet) -> Self {
+        match packet.header.responce_code {
+            ResultCode::NOERROR => ResponseKind::Answer,
+            ResultCode::NXDOMAIN => ResponseKind::Nxdomain,
+            _ => ResponseKind::Error,
+        }
+    }
+}
+
+struct Bucket {
+    balance: f64,
+    last: Instant,
+    limited: u64,
+}
+
+/// Response Rate Limiting: every client netblock has a credit balance per
+/// response kind that refills at the configured rate per second, up to one
+/// second's worth. Each response costs one credit; once in debt (by at most
+/// `window` seconds' worth) further responses are dropped or slipped.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<(IpAddr, ResponseKind), Bucket>>,
+    last_cleanup: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            config: config.clone(),
+            buckets: Mutex::new(HashMap::new()),
+            last_cleanup: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn check(&self, client: IpAddr, response: &Packet, now: Instant) -> Action {
+        if self.config.exempt.iter().any(|cidr| cidr.contains(client)) {
+            return Action::Send;
+        }
+
+        let kind = ResponseKind::of(response);
+        let rate = match kind {
+            ResponseKind::Answer => self.config.responses_per_second,
+            ResponseKind::Nxdomain => self.config.nxdomains_per_second,
+            ResponseKind::Error => self.config.errors_per_second,
+        } as f64;
+        if rate == 0.0 {
+            return Action::Send;
+        }
+
+        self.cleanup(now);
+
+        let netblock = match client {
+            IpAddr::V4(_) => cidr::mask(client, self.config.ipv4_prefix_length),
+            IpAddr::V6(_) => cidr::mask(client, self.config.ipv6_prefix_length),
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry((netblock, kind)).or_insert(Bucket {
+            balance: rate,
+            last: now,
+            limited: 0,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        bucket.last = now;
+        let floor = -rate * self.config.window as f64;
+        bucket.balance = ((bucket.balance + elapsed * rate).min(rate) - 1.0).max(floor);
+        if bucket.balance >= 0.0 {
+            return Action::Send;
+        }
+
+        bucket.limited += 1;
+        let slip = self.config.slip as u64;
+        if slip > 0 && bucket.limited.is_multiple_of(slip) {
+            Action::Slip
+        } else {
+            Action::Drop
+        }
+    }
+
+    /// Forgets netblocks that have been quiet long enough to be back at full
+    /// credit, at most once a second.
+    fn cleanup(&self, now: Instant) {
+        let mut last_cleanup = self.last_cleanup.lock().unwrap();
+        if now.saturating_duration_since(*last_cleanup) < Duration::from_secs(1) {
+            return;
+        }
+        *last_cleanup = now;
+
+        let idle = Duration::from_secs(self.config.window as u64 + 1);
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| now.saturating_duration_since(bucket.last) < idle);
+    }
+}
+
+/// Turns `packet` into the minimal TC=1 reply sent for a slipped response.
+pub fn truncate(packet: &mut Packet) {
+    packet.header.truncated_message = true;
+    packet.answers.clear();
+    packet.authorities.clear();
+    packet.resources.clear();
+}
+
+/// Turns `packet` into the BADCOOKIE reply slipped instead to a client
+/// that sent a cookie (RFC 7873 section 5.2.3). It comes with a server
+/// cookie, and once the client sends that back it is not limited at all.
+pub fn bad_cookie(packet: &mut Packet) {
+    truncate(packet);
+    packet.header.truncated_message = false;
+    packet.header.responce_code = ResultCode::BADCOOKIE;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn limiter(slip: u32) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig {
+            responses_per_second: 2,
+            nxdomains_per_second: 1,
+            errors_per_second: 1,
+            window: 5,
+            slip,
+            exempt: vec!["127.0.0.0/8".parse().unwrap()],
+            ..RateLimitConfig::default()
+        })
+    }
+
+    fn response(code: ResultCode) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.responce_code = code;
+        packet
+    }
+
+    #[test]
+    fn limits_per_netblock_and_kind() {
+        let rrl = limiter(0);
+        let now = Instant::now();
+        let answer = response(ResultCode::NOERROR);
+
+        assert_eq!(rrl.check(ip("192.0.2.1"), &answer, now), Action::Send);
+        assert_eq!(rrl.check(ip("192.0.2.2"), &answer, now), Action::Send);
+        // Same /24, so it shares the budget.
+        assert_eq!(rrl.check(ip("192.0.2.3"), &answer, now), Action::Drop);
+        assert_eq!(rrl.check(ip("198.51.100.1"), &answer, now), Action::Send);
+
+        let nxdomain = response(ResultCode::NXDOMAIN);
+        assert_eq!(rrl.check(ip("192.0.2.1"), &nxdomain, now), Action::Send);
+        assert_eq!(rrl.check(ip("192.0.2.1"), &nxdomain, now), Action::Drop);
+        let servfail = response(ResultCode::SERVFAIL);
+        assert_eq!(rrl.check(ip("192.0.2.1"), &servfail, now), Action::Send);
+    }
+
+    #[test]
+    fn credit_refills_after_the_window() {
+        let rrl = limiter(0);
+        let now = Instant::now();
+        let answer = response(ResultCode::NOERROR);
+        for _ in 0..100 {
+            rrl.check(ip("192.0.2.1"), &answer, now);
+        }
+
+        // Flooding puts the netblock a full window in debt, which takes
+        // that long to pay back.
+        let later = now + Duration::from_secs(4);
+        assert_eq!(rrl.check(ip("192.0.2.1"), &answer, later), Action::Drop);
+        let later = later + Duration::from_secs(6);
+        assert_eq!(rrl.check(ip("192.0.2.1"), &answer, later), Action::Send);
+    }
+
+    #[test]
+    fn every_nth_limited_response_slips() {
+        let rrl = limiter(2);
+        let now = Instant::now();
+        let answer = response(ResultCode::NOERROR);
+        rrl.check(ip("192.0.2.1"), &answer, now);
+        rrl.check(ip("192.0.2.1"), &answer, now);
+
+        let actions: Vec<Action> = (0..4)
+            .map(|_| rrl.check(ip("192.0.2.1"), &answer, now))
+            .collect();
+        assert_eq!(
+            actions,
+            vec![Action::Drop, Action::Slip, Action::Drop, Action::Slip]
+        );
+    }
+
+    #[test]
+    fn exempt_clients_are_not_limited() {
+        let rrl = limiter(0);
+        let now = Instant::now();
+        let answer = response(ResultCode::NOERROR);
+        for _ in 0..10 {
+            assert_eq!(rrl.check(ip("127.0.0.1"), &answer, now), Action::Send);
+        }
+    }
+
+    #[test]
+    fn ipv6_clients_share_their_prefix() {
+        let rrl = limiter(0);
+        let now = Instant::now();
+        let answer = response(ResultCode::NOERROR);
+        rrl.check(ip("2001:db8:0:1::1"), &answer, now);
+        rrl.check(ip("2001:db8:0:2::1"), &answer, now);
+        assert_eq!(rrl.check(ip("2001:db8:0:3::1"), &answer, now), Action::Drop);
+        assert_eq!(rrl.check(ip("2001:db8:1::1"), &answer, now), Action::Send);
+    }
+}
@@ -0,0 +1,735 @@
+//! Secondary zones: copies of zones mastered elsewhere, kept current by
+//! asking a primary for its SOA when the refresh or retry timer runs out
+//! (RFC 1034 section 4.3.5) or a NOTIFY arrives (RFC 1996), and transferred
+//! by IXFR with AXFR as the fallback.
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::{
+    buffer::BytePacketBuffer,
+    config::ZoneConfig,
+    context::ServerContext,
+    enums::{OpCode, QueryType, ResultCode},
+    header::Header,
+    name::Name,
+    packet::Packet,
+    question::Question,
+    record::DnsRecord,
+    tsig::{Exchange, Key},
+    zone::{self, Delta, Zone, Zones},
+    PACKET_BUFFER_SIZE,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// How often the timers are checked, which is also the longest a NOTIFY
+/// waits to be acted on.
+const TICK: Duration = Duration::from_secs(1);
+/// Retry interval until there is a SOA to take it from.
+const INITIAL_RETRY: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// One secondary zone and its timers. The copy itself is served from the
+/// view's zones like any other.
+#[derive(Debug)]
+pub struct Secondary {
+    pub origin: Name,
+    primaries: Vec<SocketAddr>,
+    file: PathBuf,
+    /// Signs requests to the primaries, and NOTIFY has to be signed with it.
+    key: Option<Arc<Key>>,
+    timers: Mutex<Timers>,
+}
+
+#[derive(Debug)]
+struct Timers {
+    /// When to next ask a primary for its SOA.
+    refresh: Instant,
+    /// When the copy stops being served unless a primary confirms it first.
+    expire: Option<Instant>,
+}
+
+impl Secondary {
+    /// Sets up a secondary zone, along with the copy an earlier run saved
+    /// if it has not expired since. Either way the first tick refreshes it.
+    pub fn new(config: &ZoneConfig, key: Option<Arc<Key>>) -> (Self, Option<Zone>) {
+        let now = Instant::now();
+        let saved = saved_copy(config).filter(|(zone, age)| {
+            let fresh = *age < soa_timers(zone.soa()).expire;
+            if !fresh {
+                log::warn!("saved copy of zone {} has expired", config.name);
+            }
+            fresh
+        });
+        let expire = saved
+            .as_ref()
+            .map(|(zone, age)| now + soa_timers(zone.soa()).expire - *age);
+        let secondary = Secondary {
+            origin: config.name.clone(),
+            primaries: config.primaries.clone(),
+            file: config.file.clone(),
+            key,
+            timers: Mutex::new(Timers {
+                refresh: now,
+                expire,
+            }),
+        };
+        (secondary, saved.map(|(zone, _)| zone))
+    }
+
+    /// Whether a NOTIFY from `addr`, signed with `key`, comes from one of
+    /// the primaries.
+    pub fn accepts_notify(&self, addr: IpAddr, key: Option<&Name>) -> bool {
+        let signed = match &self.key {
+            Some(own) => key == Some(&own.name),
+            None => true,
+        };
+        signed && self.primaries.iter().any(|primary| primary.ip() == addr)
+    }
+
+    /// Makes the next tick refresh the zone.
+    pub fn refresh_soon(&self) {
+        self.timers.lock().unwrap().refresh = Instant::now();
+    }
+
+    /// Stops serving the zone once it has expired, and refreshes it when
+    /// the timer is due.
+    pub fn tick(&self, zones: &Zones, now: Instant) {
+        let due = {
+            let mut timers = self.timers.lock().unwrap();
+            if timers.expire.is_some_and(|expire| expire <= now) {
+                timers.expire = None;
+                if zones.remove(&self.origin).is_some() {
+                    log::warn!("zone {} expired and is no longer served", self.origin);
+                }
+            }
+            timers.refresh <= now
+        };
+        if due {
+            self.refresh(zones);
+        }
+    }
+
+    /// Asks each primary in turn until one answers, transferring the zone
+    /// if it has a newer serial.
+    pub fn refresh(&self, zones: &Zones) {
+        let current = zones.get(&self.origin);
+        for primary in &self.primaries {
+            match self.refresh_from(*primary, current.as_deref(), zones) {
+                Ok(soa) => {
+                    let timers = soa_timers(&soa);
+                    let now = Instant::now();
+                    *self.timers.lock().unwrap() = Timers {
+                        refresh: now + timers.refresh,
+                        expire: Some(now + timers.expire),
+                    };
+                    return;
+                }
+                Err(e) => log::warn!("refresh of zone {} from {}: {}", self.origin, primary, e),
+            }
+        }
+        let retry = current.map_or(INITIAL_RETRY, |zone| soa_timers(zone.soa()).retry);
+        self.timers.lock().unwrap().refresh = Instant::now() + retry;
+    }
+
+    /// Brings the zone up to date from `primary` and returns the SOA now
+    /// served.
+    fn refresh_from(
+        &self,
+        primary: SocketAddr,
+        current: Option<&Zone>,
+        zones: &Zones,
+    ) -> Result<DnsRecord> {
+        let key = self.key.as_ref();
+        let serial = query_serial(primary, &self.origin, key)?;
+        let zone = match current {
+            Some(zone) if !zone::is_newer_serial(serial, zone.serial()) => {
+                log::debug!(
+                    "zone {} is current at serial {}",
+                    self.origin,
+                    zone.serial()
+                );
+                return Ok(zone.soa().clone());
+            }
+            Some(zone) => transfer(primary, &self.origin, Some(zone), key).or_else(|e| {
+                log::info!(
+                    "IXFR of {} from {} failed, trying AXFR: {}",
+                    self.origin,
+                    primary,
+                    e
+                );
+                transfer(primary, &self.origin, None, key)
+            })?,
+            None => transfer(primary, &self.origin, None, key)?,
+        };
+        log::info!(
+            "transferred zone {} at serial {} from {}",
+            self.origin,
+            zone.serial(),
+            primary
+        );
+        // A copy that cannot be saved is still worth serving.
+        if let Err(e) = zone.save(&self.file) {
+            log::warn!("{}", e);
+        }
+        let soa = zone.soa().clone();
+        zones.replace(zone);
+        Ok(soa)
+    }
+}
+
+/// Keeps every secondary zone current, for the life of the server.
+pub fn run(ctx: &ServerContext) {
+    loop {
+        let now = Instant::now();
+        for view in &ctx.views {
+            for secondary in &view.secondaries {
+                secondary.tick(&view.zones, now);
+            }
+        }
+        thread::sleep(TICK);
+    }
+}
+
+/// The answer to a NOTIFY, which brings the refresh of the zone forward
+/// when it comes from one of its primaries, signed with the zone's key if
+/// it has one. `None` if the request is not a NOTIFY.
+pub fn notify(
+    ctx: &ServerContext,
+    req_buffer: &BytePacketBuffer,
+    client: IpAddr,
+    key: Option<&Name>,
+) -> Option<Packet> {
+    let mut buffer = BytePacketBuffer::from_bytes(&req_buffer.buffer[..req_buffer.len()]).ok()?;
+    let mut header = Header::new();
+    header.read(&mut buffer).ok()?;
+    if header.responce || header.operation_code != OpCode::NOTIFY {
+        return None;
+    }
+
+    let mut packet = Packet::new();
+    packet.header.id = header.id;
+    packet.header.operation_code = OpCode::NOTIFY;
+    packet.header.responce = true;
+    let mut question = Question::new(Name::root(), QueryType::UNKNOWN(0));
+    if header.questions != 1 || question.read(&mut buffer).is_err() {
+        packet.header.responce_code = ResultCode::FORMERR;
+        return Some(packet);
+    }
+    packet.questions.push(question.clone());
+
+    let secondaries: Vec<&Secondary> = ctx
+        .views
+        .iter()
+        .flat_map(|view| &view.secondaries)
+        .filter(|secondary| secondary.origin == question.name)
+        .collect();
+    if secondaries.is_empty() {
+        packet.header.responce_code = ResultCode::NOTAUTH;
+    } else if !secondaries
+        .iter()
+        .any(|secondary| secondary.accepts_notify(client, key))
+    {
+        log::info!("NOTIFY for {} from {} refused", question.name, client);
+        packet.header.responce_code = ResultCode::REFUSED;
+    } else {
+        log::info!("NOTIFY for {} from {}", question.name, client);
+        packet.header.authoritative_answer = true;
+        for secondary in secondaries {
+            secondary.refresh_soon();
+        }
+    }
+    Some(packet)
+}
+
+struct SoaTimers {
+    refresh: Duration,
+    retry: Duration,
+    expire: Duration,
+}
+
+fn soa_timers(soa: &DnsRecord) -> SoaTimers {
+    match soa {
+        DnsRecord::SOA {
+            refresh,
+            retry,
+            expire,
+            ..
+        } => SoaTimers {
+            refresh: Duration::from_secs(u64::from(*refresh)),
+            retry: Duration::from_secs(u64::from(*retry)),
+            expire: Duration::from_secs(u64::from(*expire)),
+        },
+        _ => unreachable!(),
+    }
+}
+
+fn soa_serial(record: &DnsRecord) -> Option<u32> {
+    match record {
+        DnsRecord::SOA { serial, .. } => Some(*serial),
+        _ => None,
+    }
+}
+
+/// The copy saved by an earlier run and how long ago it was saved.
+fn saved_copy(config: &ZoneConfig) -> Option<(Zone, Duration)> {
+    let modified = config
+        .file
+        .metadata()
+        .and_then(|meta| meta.modified())
+        .ok()?;
+    match Zone::load(&config.name, &config.file) {
+        Ok(zone) => Some((zone, modified.elapsed().unwrap_or_default())),
+        Err(e) => {
+            log::warn!("{}", e);
+            None
+        }
+    }
+}
+
+/// The serial of the zone on `primary`.
+fn query_serial(primary: SocketAddr, origin: &Name, key: Option<&Arc<Key>>) -> Result<u32> {
+    let mut stream = connect(primary)?;
+    let mut query = request(origin, QueryType::SOA);
+    let mut exchange = send(&mut stream, &mut query, key)?;
+    let response = receive(&mut stream, query.header.id, &mut exchange)?;
+    if response.header.responce_code != ResultCode::NOERROR {
+        return Err(format!("SOA query answered with {}", response.header.responce_code).into());
+    }
+    response
+        .answers
+        .iter()
+        .filter(|record| record.domain() == origin)
+        .find_map(soa_serial)
+        .ok_or_else(|| "no SOA in the answer".into())
+}
+
+/// Transfers the zone: by IXFR from the serial of `current` if there is
+/// one, otherwise by AXFR. Messages longer than this server's buffer
+/// cannot be read, so a primary has to keep them to 512 bytes.
+fn transfer(
+    primary: SocketAddr,
+    origin: &Name,
+    current: Option<&Zone>,
+    key: Option<&Arc<Key>>,
+) -> Result<Zone> {
+    let mut stream = connect(primary)?;
+    let mut query = match current {
+        Some(zone) => {
+            let mut query = request(origin, QueryType::IXFR);
+            query.authorities.push(zone.soa().clone());
+            query
+        }
+        None => request(origin, QueryType::AXFR),
+    };
+    let mut exchange = send(&mut stream, &mut query, key)?;
+    let mut records = Vec::new();
+    loop {
+        let response = receive(&mut stream, query.header.id, &mut exchange)?;
+        if response.header.responce_code != ResultCode::NOERROR {
+            return Err(format!(
+                "{} answered with {}",
+                query.questions[0].qtype, response.header.responce_code
+            )
+            .into());
+        }
+        if response.answers.is_empty() {
+            return Err("empty transfer message".into());
+        }
+        records.extend(response.answers);
+        if let Some(zone) = assemble(origin, current, &records)? {
+            if exchange
+                .as_ref()
+                .is_some_and(|exchange| !exchange.is_settled())
+            {
+                return Err("transfer ends with an unsigned message".into());
+            }
+            return Ok(zone);
+        }
+    }
+}
+
+/// The zone the records of a transfer describe, once they are complete.
+/// An IXFR answer is either the SOA alone when nothing changed, a list of
+/// changes between copies of the new SOA, or the whole zone like AXFR.
+fn assemble(origin: &Name, current: Option<&Zone>, records: &[DnsRecord]) -> Result<Option<Zone>> {
+    let serial = soa_serial(&records[0]).ok_or("transfer does not start with a SOA")?;
+    let later: Vec<(usize, u32)> = records
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter_map(|(i, record)| soa_serial(record).map(|serial| (i, serial)))
+        .collect();
+
+    let incremental = match current {
+        Some(zone) if records.len() == 1 && !zone::is_newer_serial(serial, zone.serial()) => {
+            return Ok(Some(zone.clone()));
+        }
+        Some(zone) if later.first().is_some_and(|(_, first)| *first != serial) => Some(zone),
+        _ => None,
+    };
+    // Changes come in pairs of old and new SOA, so the closing SOA is the
+    // first one with the new serial where an old one would be.
+    let end = match incremental {
+        Some(_) => later.iter().step_by(2).find(|(_, s)| *s == serial),
+        None => later.first(),
+    };
+    let end = match end {
+        Some((end, _)) => *end,
+        None => return Ok(None),
+    };
+    if end != records.len() - 1 {
+        return Err("records after the closing SOA".into());
+    }
+    match incremental {
+        Some(zone) => {
+            let mut zone = zone.clone();
+            for delta in deltas(&records[1..end])? {
+                zone = zone.apply(&delta)?;
+            }
+            Ok(Some(zone))
+        }
+        None => Zone::new(origin.clone(), records[..end].to_vec()).map(Some),
+    }
+}
+
+/// Splits the changes of an IXFR answer into old SOA, removals, new SOA
+/// and additions.
+fn deltas(mut records: &[DnsRecord]) -> Result<Vec<Delta>> {
+    let is_soa = |record: &DnsRecord| record.qtype() == QueryType::SOA;
+    let mut deltas = Vec::new();
+    while let Some((from, rest)) = records.split_first() {
+        let to = rest
+            .iter()
+            .position(is_soa)
+            .ok_or("IXFR change without a new SOA")?;
+        let next = rest[to + 1..]
+            .iter()
+            .position(is_soa)
+            .map_or(rest.len(), |i| to + 1 + i);
+        deltas.push(Delta {
+            from: from.clone(),
+            removed: rest[..to].to_vec(),
+            to: rest[to].clone(),
+            added: rest[to + 1..next].to_vec(),
+        });
+        records = &rest[next..];
+    }
+    Ok(deltas)
+}
+
+fn connect(primary: SocketAddr) -> Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&primary, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    Ok(stream)
+}
+
+fn request(origin: &Name, qtype: QueryType) -> Packet {
+    let mut id = [0; 2];
+    // An all-zero ID is still a valid one.
+    let _ = SystemRandom::new().fill(&mut id);
+    let mut packet = Packet::new();
+    packet.header.id = u16::from_be_bytes(id);
+    packet.questions.push(Question::new(origin.clone(), qtype));
+    packet
+}
+
+/// Sends a request, signed if there is a key, and returns the exchange
+/// its responses are checked against.
+fn send(
+    stream: &mut TcpStream,
+    packet: &mut Packet,
+    key: Option<&Arc<Key>>,
+) -> Result<Option<Exchange>> {
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer)?;
+    let mut data = buffer.get_range(0, buffer.pos())?.to_vec();
+    let exchange = match key {
+        Some(key) => {
+            let (exchange, signed) = Exchange::sign(key.clone(), &data, SystemTime::now())?;
+            data = signed;
+            Some(exchange)
+        }
+        None => None,
+    };
+    let mut message = (data.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(&data);
+    stream.write_all(&message)?;
+    Ok(exchange)
+}
+
+fn receive(stream: &mut TcpStream, id: u16, exchange: &mut Option<Exchange>) -> Result<Packet> {
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let len = u16::from_be_bytes(len) as usize;
+    if len > PACKET_BUFFER_SIZE {
+        return Err(format!("{} byte message is too large", len).into());
+    }
+    let mut buffer = BytePacketBuffer::new();
+    stream.read_exact(&mut buffer.buffer[..len])?;
+    buffer.set_len(len)?;
+    let packet = Packet::from_buffer(&mut buffer)?;
+    if !packet.header.responce || packet.header.id != id {
+        return Err("unexpected message".into());
+    }
+    if let Some(exchange) = exchange {
+        exchange.verify(&buffer.buffer[..len], SystemTime::now())?;
+    }
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        context::Transport,
+        handler::{self, Client},
+    };
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    fn version(serial: u32, expire: u32, hosts: usize) -> String {
+        let mut text = format!(
+            "$TTL 3600\n@ SOA ns1 hostmaster {} 7200 3600 {} 300\n@ NS ns1\nns1 A 192.0.2.53\n",
+            serial, expire
+        );
+        for i in 0..hosts {
+            text.push_str(&format!("host{} A 192.0.2.{}\n", i, i));
+        }
+        text
+    }
+
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("secondary-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const KEY: &str = r#"
+        [[keys]]
+        name = "xfr-key"
+        algorithm = "hmac-sha256"
+        secret = "eGZyLWtleSBzZWNyZXQ="
+        "#;
+
+    /// A primary for example.com on a local port, serving transfers to
+    /// 127.0.0.1.
+    fn primary(dir: &Path) -> (Arc<ServerContext>, SocketAddr) {
+        primary_with(dir, "allow_transfer = [\"127.0.0.1\"]")
+    }
+
+    fn primary_with(dir: &Path, acl: &str) -> (Arc<ServerContext>, SocketAddr) {
+        let path = dir.join("primary.zone");
+        std::fs::write(&path, version(1, 1209600, 30)).unwrap();
+        let config = Config::parse(&format!(
+            r#"
+            [[zones]]
+            name = "example.com"
+            file = {:?}
+
+            [[acl.zones]]
+            zone = "example.com"
+            {}
+
+            {}
+            "#,
+            path, acl, KEY
+        ))
+        .unwrap();
+        let ctx = Arc::new(ServerContext::new(config).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = ctx.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let ctx = server.clone();
+                let stream = stream.unwrap();
+                thread::spawn(move || {
+                    let _ = handler::handle_tcp_connection(&ctx, stream);
+                });
+            }
+        });
+        (ctx, addr)
+    }
+
+    fn secondary(file: &Path, primary: SocketAddr) -> ServerContext {
+        secondary_with(file, primary, "")
+    }
+
+    fn secondary_with(file: &Path, primary: SocketAddr, zone: &str) -> ServerContext {
+        let config = Config::parse(&format!(
+            r#"
+            [[zones]]
+            name = "example.com"
+            file = {:?}
+            primaries = ["{}"]
+            {}
+
+            {}
+            "#,
+            file, primary, zone, KEY
+        ))
+        .unwrap();
+        ServerContext::new(config).unwrap()
+    }
+
+    fn notify_from(ctx: &ServerContext, addr: &str) -> ResultCode {
+        notify_signed(ctx, addr, None)
+    }
+
+    fn notify_signed(ctx: &ServerContext, addr: &str, key: Option<&str>) -> ResultCode {
+        let mut request = Packet::new();
+        request.header.id = 99;
+        request.header.operation_code = OpCode::NOTIFY;
+        request
+            .questions
+            .push(Question::new(name("example.com"), QueryType::SOA));
+        let mut buffer = BytePacketBuffer::new();
+        request.write(&mut buffer).unwrap();
+        if let Some(key) = key {
+            let key = ctx.keys.get(&name(key)).unwrap();
+            let message = buffer.get_range(0, buffer.pos()).unwrap().to_vec();
+            let (_, signed) = Exchange::sign(key, &message, SystemTime::now()).unwrap();
+            buffer = BytePacketBuffer::from_bytes(&signed).unwrap();
+        }
+        buffer.seek(0).unwrap();
+        let client = Client {
+            addr: addr.parse().unwrap(),
+            local: "127.0.0.1:53".parse().unwrap(),
+            transport: Transport::Udp,
+        };
+        let data = handler::answer(ctx, &mut buffer, client).unwrap().unwrap();
+        let response =
+            Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&data).unwrap()).unwrap();
+        assert_eq!(response.header.operation_code, OpCode::NOTIFY);
+        assert_eq!(response.header.id, 99);
+        response.header.responce_code
+    }
+
+    #[test]
+    fn transfers_then_follows_notify_with_ixfr() {
+        let dir = temp_dir("notify");
+        let (primary_ctx, addr) = primary(&dir);
+        let file = dir.join("secondary.zone");
+        let ctx = secondary(&file, addr);
+        let view = &ctx.views[0];
+        assert!(view.zones.get(&name("example.com")).is_none());
+
+        view.secondaries[0].tick(&view.zones, Instant::now());
+        let zone = view.zones.get(&name("example.com")).unwrap();
+        assert_eq!(zone.serial(), 1);
+        assert_eq!(zone.records().count(), 33);
+        assert_eq!(Zone::load(&name("example.com"), &file).unwrap(), *zone);
+        // Not due again until the refresh timer runs out.
+        view.secondaries[0].tick(&view.zones, Instant::now());
+        assert_eq!(
+            primary_ctx
+                .metrics
+                .queries("SOA", "NOERROR", Transport::Tcp),
+            1
+        );
+
+        primary_ctx.views[0]
+            .zones
+            .replace(Zone::parse(&name("example.com"), &version(2, 1209600, 31)).unwrap());
+        assert_eq!(notify_from(&ctx, "192.0.2.9:53"), ResultCode::REFUSED);
+        view.secondaries[0].tick(&view.zones, Instant::now());
+        assert_eq!(view.zones.get(&name("example.com")).unwrap().serial(), 1);
+
+        assert_eq!(notify_from(&ctx, "127.0.0.1:53"), ResultCode::NOERROR);
+        view.secondaries[0].tick(&view.zones, Instant::now());
+        let zone = view.zones.get(&name("example.com")).unwrap();
+        assert_eq!(zone.serial(), 2);
+        assert_eq!(zone.records().count(), 34);
+        assert_eq!(
+            primary_ctx
+                .metrics
+                .queries("IXFR", "NOERROR", Transport::Tcp),
+            1
+        );
+        assert_eq!(
+            primary_ctx
+                .metrics
+                .queries("AXFR", "NOERROR", Transport::Tcp),
+            1
+        );
+        // And the change is journaled for this server's own secondaries.
+        assert_eq!(
+            view.zones.changes_since(&name("example.com"), 1).unwrap()[0]
+                .added
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn saved_copies_are_served_until_they_expire() {
+        let dir = temp_dir("expire");
+        let file = dir.join("secondary.zone");
+        std::fs::write(&file, version(5, 1, 0)).unwrap();
+        // Nothing listens on the primary's port.
+        let unreachable = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let ctx = secondary(&file, unreachable);
+        let view = &ctx.views[0];
+        assert_eq!(view.zones.get(&name("example.com")).unwrap().serial(), 5);
+
+        view.secondaries[0].tick(&view.zones, Instant::now());
+        assert!(view.zones.get(&name("example.com")).is_some());
+        view.secondaries[0].tick(&view.zones, Instant::now() + Duration::from_secs(2));
+        assert!(view.zones.get(&name("example.com")).is_none());
+
+        assert_eq!(notify_from(&ctx, "192.0.2.9:53"), ResultCode::REFUSED);
+        std::thread::sleep(Duration::from_millis(1100));
+        let ctx = secondary(&file, unreachable);
+        assert!(ctx.views[0].zones.get(&name("example.com")).is_none());
+    }
+
+    #[test]
+    fn signed_transfers_and_notify() {
+        let dir = temp_dir("tsig");
+        let (primary_ctx, addr) = primary_with(&dir, "allow_transfer_keys = [\"xfr-key\"]");
+        let file = dir.join("secondary.zone");
+        let unsigned = secondary(&file, addr);
+        unsigned.views[0].secondaries[0].refresh(&unsigned.views[0].zones);
+        assert!(unsigned.views[0].zones.get(&name("example.com")).is_none());
+
+        let ctx = secondary_with(&file, addr, "key = \"xfr-key\"");
+        let view = &ctx.views[0];
+        view.secondaries[0].refresh(&view.zones);
+        assert_eq!(view.zones.get(&name("example.com")).unwrap().serial(), 1);
+        assert_eq!(
+            primary_ctx
+                .metrics
+                .queries("AXFR", "NOERROR", Transport::Tcp),
+            1
+        );
+
+        assert_eq!(notify_from(&ctx, "127.0.0.1:53"), ResultCode::REFUSED);
+        assert_eq!(
+            notify_signed(&ctx, "127.0.0.1:53", Some("xfr-key")),
+            ResultCode::NOERROR
+        );
+        assert_eq!(
+            notify_signed(&ctx, "192.0.2.9:53", Some("xfr-key")),
+            ResultCode::REFUSED
+        );
+    }
+}
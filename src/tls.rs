@@ -0,0 +1,379 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustls::server::{ServerConfig, ServerConnection, ServerSessionMemoryCache};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+
+use crate::{
+    buffer::BytePacketBuffer,
+    context::{ServerContext, Transport},
+    handler::{self, Client},
+    PACKET_BUFFER_SIZE,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// Queries answered concurrently on one connection; further queries wait
+/// in the socket until one completes.
+const MAX_IN_FLIGHT: usize = 32;
+/// How long to wait for a worker before checking the socket again while
+/// queries are in flight.
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+const SESSION_CACHE_SIZE: usize = 1024;
+
+/// Builds a rustls configuration from PEM files, offering the `alpn`
+/// protocols, with stateful session resumption and TLS 1.3 tickets enabled.
+pub fn server_config(cert: &Path, key: &Path, alpn: &[&[u8]]) -> Result<Arc<ServerConfig>> {
+    let mut tls = base_config(cert, key, alpn)?;
+    tls.ticketer = rustls::crypto::ring::Ticketer::new()?;
+    Ok(Arc::new(tls))
+}
+
+/// Like `server_config`, but resuming sessions from the server-side cache
+/// only and accepting early data on them. rustls will not take 0-RTT data
+/// with stateless tickets, as it could not detect replays.
+pub fn early_data_config(cert: &Path, key: &Path, alpn: &[&[u8]]) -> Result<ServerConfig> {
+    let mut tls = base_config(cert, key, alpn)?;
+    tls.max_early_data_size = u32::MAX;
+    Ok(tls)
+}
+
+fn base_config(cert: &Path, key: &Path, alpn: &[&[u8]]) -> Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| format!("Cannot read certificates {}: {}", cert.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates in {}", cert.display()).into());
+    }
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| format!("Cannot read private key {}: {}", key.display(), e))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut tls = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    tls.session_storage = ServerSessionMemoryCache::new(SESSION_CACHE_SIZE);
+    tls.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(tls)
+}
+
+/// Accepts DNS-over-TLS connections, each served on its own thread.
+pub fn serve(listener: TcpListener, tls: Arc<ServerConfig>, ctx: Arc<ServerContext>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("tls accept: {}", e);
+                continue;
+            }
+        };
+        let ctx = ctx.clone();
+        let tls = tls.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(&ctx, tls, stream) {
+                log::debug!("tls: {}", e);
+            }
+        });
+    }
+}
+
+/// Serves one DoT connection: TCP-framed queries are read as they arrive
+/// and answered concurrently, each response written as soon as it is ready,
+/// so a slow lookup does not hold up the ones behind it. The connection is
+/// closed once the client is done or it has been idle too long.
+pub fn handle_connection(
+    ctx: &Arc<ServerContext>,
+    tls: Arc<ServerConfig>,
+    mut stream: TcpStream,
+) -> Result<()> {
+    let client = Client {
+        addr: stream.peer_addr()?,
+        local: stream.local_addr()?,
+        transport: Transport::Tls,
+    };
+    let idle_timeout = Duration::from_secs(
+        ctx.config
+            .tls
+            .as_ref()
+            .map_or(30, |tls| tls.idle_timeout_secs),
+    );
+    let mut conn = ServerConnection::new(tls)?;
+    let (responses, finished) = mpsc::channel::<Vec<Vec<u8>>>();
+    let mut received = Vec::new();
+    let mut in_flight = 0;
+    let mut last_activity = Instant::now();
+    let mut eof = false;
+
+    loop {
+        while let Ok(response) = finished.try_recv() {
+            in_flight -= 1;
+            last_activity = Instant::now();
+            for data in response {
+                write_message(&mut conn, &data)?;
+            }
+        }
+        while conn.wants_write() {
+            conn.write_tls(&mut stream)?;
+        }
+        if eof && in_flight == 0 {
+            return Ok(());
+        }
+
+        if in_flight == 0 {
+            let idle = idle_timeout.saturating_sub(last_activity.elapsed());
+            if idle.is_zero() {
+                log::debug!("closing idle tls connection from {}", client.addr);
+                conn.send_close_notify();
+                while conn.wants_write() {
+                    conn.write_tls(&mut stream)?;
+                }
+                return Ok(());
+            }
+            stream.set_read_timeout(Some(idle))?;
+        } else {
+            match finished.recv_timeout(POLL_INTERVAL) {
+                Ok(response) => {
+                    in_flight -= 1;
+                    last_activity = Instant::now();
+                    for data in response {
+                        write_message(&mut conn, &data)?;
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => unreachable!(),
+            }
+            stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        }
+        if eof || in_flight >= MAX_IN_FLIGHT {
+            continue;
+        }
+
+        match conn.read_tls(&mut stream) {
+            Ok(0) => eof = true,
+            Ok(_) => {
+                if let Err(e) = conn.process_new_packets() {
+                    // Send the alert explaining why before giving up.
+                    let _ = conn.write_tls(&mut stream);
+                    return Err(e.into());
+                }
+                eof = read_plaintext(&mut conn, &mut received)?;
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        while let Some(message) = take_message(&mut received)? {
+            in_flight += 1;
+            last_activity = Instant::now();
+            let ctx = ctx.clone();
+            let responses = responses.clone();
+            thread::spawn(move || {
+                let response = BytePacketBuffer::from_bytes(&message)
+                    .and_then(|mut buffer| handler::respond(&ctx, &mut buffer, client));
+                let response = response.unwrap_or_else(|e| {
+                    log::debug!("tls query from {}: {}", client.addr, e);
+                    Vec::new()
+                });
+                let _ = responses.send(response);
+            });
+        }
+    }
+}
+
+/// Moves decrypted bytes into `received`. Returns true once the client has
+/// sent close_notify.
+fn read_plaintext(conn: &mut ServerConnection, received: &mut Vec<u8>) -> Result<bool> {
+    let mut chunk = [0; 4096];
+    loop {
+        match conn.reader().read(&mut chunk) {
+            Ok(0) => return Ok(true),
+            Ok(n) => received.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(true),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Takes the next complete length-prefixed message off the front of
+/// `received`, if there is one.
+fn take_message(received: &mut Vec<u8>) -> Result<Option<Vec<u8>>> {
+    if received.len() < 2 {
+        return Ok(None);
+    }
+    let len = u16::from_be_bytes([received[0], received[1]]) as usize;
+    if len > PACKET_BUFFER_SIZE {
+        return Err(format!("{} byte message is too large", len).into());
+    }
+    if received.len() < 2 + len {
+        return Ok(None);
+    }
+    let message = received[2..2 + len].to_vec();
+    received.drain(..2 + len);
+    Ok(Some(message))
+}
+
+fn write_message(conn: &mut ServerConnection, data: &[u8]) -> Result<()> {
+    let mut message = (data.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(data);
+    conn.writer().write_all(&message)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        enums::{QueryType, ResultCode},
+        name::Name,
+        packet::Packet,
+        question::Question,
+    };
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use rustls_pki_types::ServerName;
+    use std::convert::TryFrom;
+
+    struct Server {
+        addr: std::net::SocketAddr,
+        root: CertificateDer<'static>,
+    }
+
+    fn start_server() -> Server {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+
+        let config = Config::parse(&format!(
+            r#"
+            [tls]
+            cert = {:?}
+            key = {:?}
+
+            [acl]
+            recursion = []
+
+            [local]
+            records = ["a.test A 192.0.2.1", "b.test A 192.0.2.2"]
+            "#,
+            dir.join("cert.pem"),
+            dir.join("key.pem")
+        ))
+        .unwrap();
+        let tls_config = config.tls.as_ref().unwrap();
+        let tls = server_config(&tls_config.cert, &tls_config.key, &[b"dot"]).unwrap();
+        let ctx = Arc::new(ServerContext::new(config).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, tls, ctx));
+        Server {
+            addr,
+            root: cert.cert.der().clone(),
+        }
+    }
+
+    fn client_config(root: &CertificateDer<'static>) -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        roots.add(root.clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"dot".to_vec()];
+        Arc::new(config)
+    }
+
+    fn connect(
+        server: &Server,
+        config: &Arc<ClientConfig>,
+    ) -> StreamOwned<ClientConnection, TcpStream> {
+        let name = ServerName::try_from("localhost").unwrap();
+        let conn = ClientConnection::new(config.clone(), name).unwrap();
+        StreamOwned::new(conn, TcpStream::connect(server.addr).unwrap())
+    }
+
+    fn send_query(stream: &mut impl Write, id: u16, qname: &str) {
+        let mut packet = Packet::new();
+        packet.header.id = id;
+        packet
+            .questions
+            .push(Question::new(qname.parse::<Name>().unwrap(), QueryType::A));
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let mut message = (buffer.pos() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(&buffer.buffer[..buffer.pos()]);
+        stream.write_all(&message).unwrap();
+    }
+
+    fn read_response(stream: &mut impl Read) -> Packet {
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).unwrap();
+        let mut data = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut data).unwrap();
+        Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&data).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn pipelined_queries_over_tls() {
+        let server = start_server();
+        let config = client_config(&server.root);
+        let mut stream = connect(&server, &config);
+
+        send_query(&mut stream, 1, "a.test");
+        send_query(&mut stream, 2, "b.test");
+        send_query(&mut stream, 3, "c.test");
+        let mut ids: Vec<u16> = (0..3)
+            .map(|_| {
+                let response = read_response(&mut stream);
+                let expected = match response.header.id {
+                    3 => ResultCode::REFUSED,
+                    _ => ResultCode::NOERROR,
+                };
+                assert_eq!(response.header.responce_code, expected);
+                response.header.id
+            })
+            .collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(stream.conn.alpn_protocol(), Some(&b"dot"[..]));
+    }
+
+    #[test]
+    fn sessions_resume() {
+        let server = start_server();
+        let config = client_config(&server.root);
+        for resumed in [false, true] {
+            let mut stream = connect(&server, &config);
+            send_query(&mut stream, 7, "a.test");
+            assert_eq!(read_response(&mut stream).header.id, 7);
+            let kind = stream.conn.handshake_kind().unwrap();
+            assert_eq!(kind == rustls::HandshakeKind::Resumed, resumed);
+        }
+    }
+
+    #[test]
+    fn messages_are_length_prefixed() {
+        let mut received = vec![0, 2, 0xAB, 0xCD, 0, 3, 1];
+        assert_eq!(take_message(&mut received).unwrap(), Some(vec![0xAB, 0xCD]));
+        assert_eq!(take_message(&mut received).unwrap(), None);
+        assert_eq!(received, vec![0, 3, 1]);
+        assert!(take_message(&mut vec![0xFF, 0xFF]).is_err());
+    }
+}
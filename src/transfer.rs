@@ -0,0 +1,497 @@
+//! Zone transfers: AXFR (RFC 5936) and IXFR (RFC 1995). A transfer is a run
+//! of messages framed by the zone's SOA, so it is only served on stream
+//! transports.
+
+use std::net::IpAddr;
+use std::time::{Instant, SystemTime};
+
+use crate::{
+    buffer::BytePacketBuffer,
+    context::ServerContext,
+    enums::{OpCode, QueryType, ResultCode},
+    handler::Client,
+    horizon::View,
+    name::Name,
+    packet::Packet,
+    querylog::QueryLogEntry,
+    record::DnsRecord,
+    tsig::{self, Signature},
+    zone::{self, Zone, Zones},
+    PACKET_BUFFER_SIZE,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+const CLASS_IN: u16 = 1;
+
+/// Parses `req_buffer` if it asks for a zone transfer. Anything else,
+/// including messages that do not parse, is left for the regular handler.
+pub fn request(req_buffer: &BytePacketBuffer) -> Option<Packet> {
+    let mut buffer = BytePacketBuffer::from_bytes(&req_buffer.buffer[..req_buffer.len()]).ok()?;
+    let request = Packet::from_buffer(&mut buffer).ok()?;
+    let is_transfer = !request.header.responce
+        && request.header.operation_code == OpCode::QUERY
+        && request.questions.len() == 1
+        && matches!(
+            request.questions[0].qtype,
+            QueryType::AXFR | QueryType::IXFR
+        );
+    if is_transfer {
+        Some(request)
+    } else {
+        None
+    }
+}
+
+/// Serves a transfer request, `request` parsed from `req_buffer`, and
+/// returns every message of the response, each one signed if the request
+/// was.
+pub fn serve(
+    ctx: &ServerContext,
+    req_buffer: &BytePacketBuffer,
+    request: &Packet,
+    client: Client,
+) -> Result<Vec<Vec<u8>>> {
+    let start = Instant::now();
+    let question = &request.questions[0];
+    let ip = client.addr.ip();
+    let message = &req_buffer.buffer[..req_buffer.len()];
+    let (signature, malformed) = match tsig::verify_request(&ctx.keys, message, SystemTime::now()) {
+        Ok(signature) => (signature, false),
+        Err(e) => {
+            log::debug!("bad TSIG record from {}: {}", client.addr, e);
+            (None, true)
+        }
+    };
+    let key = signature.as_ref().and_then(Signature::key);
+    let view = ctx.view(ip, key);
+
+    let records = match view.zones.get(&question.name) {
+        _ if malformed => Err(ResultCode::FORMERR),
+        _ if signature.is_some() && key.is_none() => Err(ResultCode::NOTAUTH),
+        _ if question.qclass != CLASS_IN => Err(ResultCode::NOTIMP),
+        None => Err(ResultCode::NOTAUTH),
+        Some(_) if !ctx.acl.may_transfer(ip, key, &question.name) => Err(ResultCode::REFUSED),
+        Some(zone) if question.qtype == QueryType::AXFR => Ok(axfr(&zone)),
+        Some(zone) => match client_serial(request) {
+            Some(serial) => Ok(ixfr(&view.zones, &zone, serial)),
+            None => Err(ResultCode::FORMERR),
+        },
+    };
+    let reserve = signature.as_ref().map_or(0, Signature::record_len);
+    let (rcode, answers, messages) = match records {
+        Ok(records) => {
+            let answers = records.len();
+            (
+                ResultCode::NOERROR,
+                answers,
+                pack(request, records, reserve)?,
+            )
+        }
+        Err(rcode) => {
+            log::info!(
+                "{} of {} for {} answered with {}",
+                question.qtype,
+                question.name,
+                client.addr,
+                rcode
+            );
+            let mut packet = response(request);
+            packet.header.responce_code = rcode;
+            (rcode, 0, vec![encode(&mut packet)?])
+        }
+    };
+    let messages = match &signature {
+        Some(signature) => tsig::sign_responses(signature, messages, SystemTime::now())?,
+        None => messages,
+    };
+    if rcode == ResultCode::NOERROR {
+        log::info!(
+            "{} of {} to {}: {} records in {} messages",
+            question.qtype,
+            question.name,
+            client.addr,
+            answers,
+            messages.len()
+        );
+    }
+
+    let latency = start.elapsed();
+    let qtype = question.qtype.to_string();
+    let rcode = rcode.to_string();
+    ctx.metrics
+        .query(Some(&qtype), &rcode, client.transport, latency);
+    if ctx.query_log.is_enabled() {
+        let mut entry = QueryLogEntry::new(client.addr, client.transport, rcode);
+        entry.view = Some(view.name.clone());
+        entry.qname = Some(question.name.to_string());
+        entry.qtype = Some(qtype);
+        entry.answers = answers;
+        entry.set_latency(latency);
+        ctx.query_log.log(&entry);
+    }
+    Ok(messages)
+}
+
+/// The answer to a transfer that arrived where only one message fits. An
+/// IXFR gets the current SOA alone, which tells the client to retry over
+/// TCP (RFC 1995 section 2); an AXFR is not served at all.
+pub fn single_message(
+    ctx: &ServerContext,
+    view: &View,
+    client: IpAddr,
+    key: Option<&Name>,
+    qname: &Name,
+    qtype: QueryType,
+) -> Packet {
+    let mut packet = Packet::new();
+    match view.zones.get(qname) {
+        None => packet.header.responce_code = ResultCode::NOTAUTH,
+        Some(_) if !ctx.acl.may_transfer(client, key, qname) => {
+            packet.header.responce_code = ResultCode::REFUSED
+        }
+        Some(zone) if qtype == QueryType::IXFR => {
+            packet.header.authoritative_answer = true;
+            packet.answers.push(zone.soa().clone());
+        }
+        Some(_) => packet.header.responce_code = ResultCode::NOTIMP,
+    }
+    packet
+}
+
+/// The whole zone between two copies of its SOA.
+fn axfr(zone: &Zone) -> Vec<DnsRecord> {
+    let soa = zone.soa();
+    let mut records = vec![soa.clone()];
+    records.extend(zone.records().filter(|record| *record != soa).cloned());
+    records.push(soa.clone());
+    records
+}
+
+/// The changes since `serial` from the journal: the current SOA, then each
+/// version's old SOA, removals, new SOA and additions, then the current SOA
+/// again. A client that is up to date gets the SOA alone, and one older
+/// than the journal gets the whole zone.
+fn ixfr(zones: &Zones, zone: &Zone, serial: u32) -> Vec<DnsRecord> {
+    if !zone::is_newer_serial(zone.serial(), serial) {
+        return vec![zone.soa().clone()];
+    }
+    let changes = match zones.changes_since(zone.origin(), serial) {
+        // The zone may have moved on since it was looked up.
+        Some(changes) if changes.last().map(|delta| delta.to_serial()) == Some(zone.serial()) => {
+            changes
+        }
+        _ => return axfr(zone),
+    };
+    let mut records = vec![zone.soa().clone()];
+    for delta in changes {
+        records.push(delta.from);
+        records.extend(delta.removed);
+        records.push(delta.to);
+        records.extend(delta.added);
+    }
+    records.push(zone.soa().clone());
+    records
+}
+
+/// The serial of the client's copy, from the SOA in the authority section.
+fn client_serial(request: &Packet) -> Option<u32> {
+    request.authorities.iter().find_map(|record| match record {
+        DnsRecord::SOA { serial, .. } => Some(*serial),
+        _ => None,
+    })
+}
+
+fn response(request: &Packet) -> Packet {
+    let mut packet = Packet::new();
+    packet.header.id = request.header.id;
+    packet.header.responce = true;
+    packet.questions = request.questions.clone();
+    packet
+}
+
+/// Fills messages with as many records as each holds, leaving `reserve`
+/// bytes for a signature. Only the first carries the question.
+fn pack(request: &Packet, records: Vec<DnsRecord>, reserve: usize) -> Result<Vec<Vec<u8>>> {
+    let mut messages = Vec::new();
+    let mut packet = response(request);
+    packet.header.authoritative_answer = true;
+    for record in records {
+        packet.answers.push(record);
+        if encode(&mut packet).is_ok_and(|message| message.len() + reserve <= PACKET_BUFFER_SIZE) {
+            continue;
+        }
+        let record = packet.answers.pop().unwrap();
+        if packet.answers.is_empty() {
+            return Err(format!("{} record does not fit in a message", record.qtype()).into());
+        }
+        messages.push(encode(&mut packet)?);
+        packet.questions.clear();
+        packet.answers = vec![record];
+    }
+    messages.push(encode(&mut packet)?);
+    Ok(messages)
+}
+
+fn encode(packet: &mut Packet) -> Result<Vec<u8>> {
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer)?;
+    Ok(buffer.get_range(0, buffer.pos())?.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, context::Transport, handler};
+    use std::net::Ipv4Addr;
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    fn version(serial: u32, hosts: usize) -> String {
+        let mut text = format!(
+            "$TTL 3600\n@ SOA ns1 hostmaster {} 7200 3600 1209600 300\n@ NS ns1\nns1 A 192.0.2.53\n",
+            serial
+        );
+        for i in 0..hosts {
+            text.push_str(&format!("host{} A 192.0.2.{}\n", i, i));
+        }
+        text
+    }
+
+    fn context(test: &str) -> ServerContext {
+        let dir = std::env::temp_dir().join(format!("transfer-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("example.com.zone");
+        std::fs::write(&path, version(1, 40)).unwrap();
+        let config = Config::parse(&format!(
+            r#"
+            [[zones]]
+            name = "example.com"
+            file = {:?}
+
+            [acl]
+            recursion = []
+
+            [[keys]]
+            name = "xfr-key"
+            algorithm = "hmac-sha384"
+            secret = "eGZyLWtleSBzZWNyZXQ="
+
+            [[acl.zones]]
+            zone = "example.com"
+            allow_transfer = ["192.0.2.0/24"]
+            allow_transfer_keys = ["xfr-key"]
+            "#,
+            path
+        ))
+        .unwrap();
+        ServerContext::new(config).unwrap()
+    }
+
+    fn client(addr: &str, transport: Transport) -> Client {
+        Client {
+            addr: addr.parse().unwrap(),
+            local: "192.0.2.1:53".parse().unwrap(),
+            transport,
+        }
+    }
+
+    fn request(qtype: QueryType, serial: Option<u32>) -> BytePacketBuffer {
+        let mut packet = Packet::new();
+        packet.header.id = 77;
+        packet
+            .questions
+            .push(crate::question::Question::new(name("example.com"), qtype));
+        if let Some(serial) = serial {
+            packet.authorities.push(DnsRecord::SOA {
+                domain: name("example.com"),
+                mname: name("ns1.example.com"),
+                rname: name("hostmaster.example.com"),
+                serial,
+                refresh: 0,
+                retry: 0,
+                expire: 0,
+                minimum: 0,
+                ttl: 0,
+            });
+        }
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.seek(0).unwrap();
+        buffer
+    }
+
+    fn transfer(
+        ctx: &ServerContext,
+        client: Client,
+        qtype: QueryType,
+        serial: Option<u32>,
+    ) -> Vec<Packet> {
+        handler::respond(ctx, &mut request(qtype, serial), client)
+            .unwrap()
+            .iter()
+            .map(|data| {
+                assert!(data.len() <= PACKET_BUFFER_SIZE);
+                Packet::from_buffer(&mut BytePacketBuffer::from_bytes(data).unwrap()).unwrap()
+            })
+            .collect()
+    }
+
+    fn answers(messages: &[Packet]) -> Vec<DnsRecord> {
+        messages.iter().flat_map(|m| m.answers.clone()).collect()
+    }
+
+    fn serials(records: &[DnsRecord]) -> Vec<u32> {
+        records
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::SOA { serial, .. } => Some(*serial),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn axfr_spans_messages_between_soas() {
+        let ctx = context("axfr");
+        let messages = transfer(
+            &ctx,
+            client("192.0.2.7:5300", Transport::Tcp),
+            QueryType::AXFR,
+            None,
+        );
+        assert!(messages.len() > 1);
+        assert_eq!(messages[0].questions.len(), 1);
+        assert!(messages[1..].iter().all(|m| m.questions.is_empty()));
+        assert!(messages
+            .iter()
+            .all(|m| m.header.id == 77 && m.header.authoritative_answer));
+
+        let records = answers(&messages);
+        // SOA, NS, ns1 and 40 hosts, then the SOA again.
+        assert_eq!(records.len(), 44);
+        assert_eq!(records[0].qtype(), QueryType::SOA);
+        assert_eq!(records[43], records[0]);
+        assert_eq!(serials(&records), vec![1, 1]);
+    }
+
+    #[test]
+    fn ixfr_sends_journaled_changes() {
+        let ctx = context("ixfr");
+        let tcp = client("192.0.2.7:5300", Transport::Tcp);
+        let zones = &ctx.view("192.0.2.7".parse().unwrap(), None).zones;
+        zones.replace(Zone::parse(&name("example.com"), &version(2, 41)).unwrap());
+        zones.replace(Zone::parse(&name("example.com"), &version(3, 39)).unwrap());
+
+        let records = answers(&transfer(&ctx, tcp, QueryType::IXFR, Some(1)));
+        assert_eq!(serials(&records), vec![3, 1, 2, 2, 3, 3]);
+        assert_eq!(
+            records[3],
+            DnsRecord::A {
+                domain: name("host40.example.com"),
+                addr: Ipv4Addr::new(192, 0, 2, 40),
+                ttl: 3600,
+            }
+        );
+        assert_eq!(records.len(), 9);
+
+        // Up to date: the SOA alone. Older than the journal: the whole zone.
+        let records = answers(&transfer(&ctx, tcp, QueryType::IXFR, Some(3)));
+        assert_eq!(serials(&records), vec![3]);
+        let records = answers(&transfer(&ctx, tcp, QueryType::IXFR, Some(0)));
+        assert_eq!(records.len(), 43);
+
+        let messages = transfer(&ctx, tcp, QueryType::IXFR, None);
+        assert_eq!(messages[0].header.responce_code, ResultCode::FORMERR);
+    }
+
+    #[test]
+    fn transfers_need_the_acl_and_a_stream() {
+        let ctx = context("acl");
+        let messages = transfer(
+            &ctx,
+            client("198.51.100.7:5300", Transport::Tcp),
+            QueryType::AXFR,
+            None,
+        );
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].header.responce_code, ResultCode::REFUSED);
+        assert!(messages[0].answers.is_empty());
+
+        let udp = client("192.0.2.7:5300", Transport::Udp);
+        let ask = |qtype| {
+            let data = handler::answer(&ctx, &mut request(qtype, Some(1)), udp)
+                .unwrap()
+                .unwrap();
+            Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&data).unwrap()).unwrap()
+        };
+        assert_eq!(
+            ask(QueryType::AXFR).header.responce_code,
+            ResultCode::NOTIMP
+        );
+        let response = ask(QueryType::IXFR);
+        assert_eq!(serials(&response.answers), vec![1]);
+    }
+
+    #[test]
+    fn signed_transfers_are_allowed_by_key() {
+        let ctx = context("tsig");
+        let outsider = client("198.51.100.7:5300", Transport::Tcp);
+        let key = ctx.keys.get(&name("xfr-key")).unwrap();
+        let unsigned = request(QueryType::AXFR, None);
+        let (mut exchange, signed) =
+            tsig::Exchange::sign(key, &unsigned.buffer[..unsigned.len()], SystemTime::now())
+                .unwrap();
+
+        let mut buffer = BytePacketBuffer::from_bytes(&signed).unwrap();
+        let messages = handler::respond(&ctx, &mut buffer, outsider).unwrap();
+        assert!(messages.len() > 1);
+        let mut records = Vec::new();
+        for data in &messages {
+            assert!(data.len() <= PACKET_BUFFER_SIZE);
+            exchange.verify(data, SystemTime::now()).unwrap();
+            let packet =
+                Packet::from_buffer(&mut BytePacketBuffer::from_bytes(data).unwrap()).unwrap();
+            assert_eq!(packet.header.responce_code, ResultCode::NOERROR);
+            assert_eq!(packet.resources[0].qtype(), QueryType::TSIG);
+            records.extend(packet.answers);
+        }
+        assert!(exchange.is_settled());
+        assert_eq!(records.len(), 44);
+
+        // A key the server does not have gets NOTAUTH, with BADKEY in the
+        // unsigned TSIG record.
+        let config = Config::parse(
+            "[[keys]]\nname = \"xfr-key\"\nalgorithm = \"hmac-sha256\"\nsecret = \"eGZyLWtleSBzZWNyZXQ=\"",
+        )
+        .unwrap();
+        let wrong = tsig::Keyring::new(&config.keys).unwrap();
+        let (mut exchange, signed) = tsig::Exchange::sign(
+            wrong.get(&name("xfr-key")).unwrap(),
+            &unsigned.buffer[..unsigned.len()],
+            SystemTime::now(),
+        )
+        .unwrap();
+        let mut buffer = BytePacketBuffer::from_bytes(&signed).unwrap();
+        let messages = handler::respond(&ctx, &mut buffer, outsider).unwrap();
+        assert_eq!(messages.len(), 1);
+        let packet =
+            Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&messages[0]).unwrap()).unwrap();
+        assert_eq!(packet.header.responce_code, ResultCode::NOTAUTH);
+        match &packet.resources[0] {
+            DnsRecord::TSIG { error, mac, .. } => {
+                assert_eq!(ResultCode::from_num(*error as u8), ResultCode::BADKEY);
+                assert!(mac.is_empty());
+            }
+            record => panic!("no TSIG record: {:?}", record),
+        }
+        assert!(exchange
+            .verify(&messages[0], SystemTime::now())
+            .unwrap_err()
+            .to_string()
+            .contains("BADKEY"));
+    }
+}
@@ -0,0 +1,640 @@
+//! Transaction signatures (RFC 8945): an HMAC, keyed with a secret two
+//! servers share, over the exact bytes of a message. Zone transfers, NOTIFY
+//! and updates are authenticated this way.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use ring::hmac;
+
+use crate::{
+    buffer::BytePacketBuffer,
+    config::{KeyConfig, TsigAlgorithm},
+    enums::{QueryType, ResultCode},
+    header::Header,
+    name::Name,
+    question::Question,
+    record::DnsRecord,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// How far apart the signer's clock and this server's may be, in seconds.
+const FUDGE: u16 = 300;
+/// Messages of a multi-message response that may go unsigned in a row.
+const MAX_UNSIGNED: usize = 99;
+const CLASS_ANY: u16 = 255;
+
+/// A named shared secret.
+#[derive(Debug)]
+pub struct Key {
+    pub name: Name,
+    algorithm: TsigAlgorithm,
+    secret: hmac::Key,
+}
+
+impl Key {
+    pub fn new(config: &KeyConfig) -> Result<Self> {
+        let secret = base64::engine::general_purpose::STANDARD
+            .decode(config.secret.trim())
+            .map_err(|e| format!("TSIG key {}: {}", config.name, e))?;
+        let algorithm = match config.algorithm {
+            TsigAlgorithm::HmacSha256 => hmac::HMAC_SHA256,
+            TsigAlgorithm::HmacSha384 => hmac::HMAC_SHA384,
+            TsigAlgorithm::HmacSha512 => hmac::HMAC_SHA512,
+        };
+        Ok(Key {
+            name: config.name.clone(),
+            algorithm: config.algorithm,
+            secret: hmac::Key::new(algorithm, &secret),
+        })
+    }
+
+    /// The name of the algorithm in TSIG records.
+    fn algorithm_name(&self) -> Name {
+        let name = match self.algorithm {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha384 => "hmac-sha384",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        };
+        name.parse().unwrap()
+    }
+
+    /// Wire length of a TSIG record signed with this key.
+    pub fn record_len(&self) -> usize {
+        let mac_len = self.secret.algorithm().digest_algorithm().output_len();
+        self.name.wire_len() + 10 + self.algorithm_name().wire_len() + 16 + mac_len
+    }
+}
+
+/// The configured keys, by name.
+#[derive(Debug, Default)]
+pub struct Keyring {
+    keys: HashMap<Name, Arc<Key>>,
+}
+
+impl Keyring {
+    pub fn new(configs: &[KeyConfig]) -> Result<Self> {
+        let mut keys = HashMap::new();
+        for config in configs {
+            if keys
+                .insert(config.name.clone(), Arc::new(Key::new(config)?))
+                .is_some()
+            {
+                return Err(format!("TSIG key {} is defined twice", config.name).into());
+            }
+        }
+        Ok(Keyring { keys })
+    }
+
+    pub fn get(&self, name: &Name) -> Option<Arc<Key>> {
+        self.keys.get(name).cloned()
+    }
+}
+
+/// The TSIG record of a request and what checking it found.
+#[derive(Debug)]
+pub struct Signature {
+    tsig: DnsRecord,
+    key: Option<Arc<Key>>,
+    /// NOERROR, or BADKEY, BADSIG or BADTIME for a request that is not
+    /// taken as signed.
+    pub error: ResultCode,
+}
+
+impl Signature {
+    /// The key the request was signed with, if the signature holds.
+    pub fn key(&self) -> Option<&Name> {
+        match &self.key {
+            Some(key) if self.error == ResultCode::NOERROR => Some(&key.name),
+            _ => None,
+        }
+    }
+
+    /// Room each message of the response needs for its TSIG record.
+    pub fn record_len(&self) -> usize {
+        match (&self.key, &self.tsig) {
+            (Some(key), _) => key.record_len(),
+            (
+                None,
+                DnsRecord::TSIG {
+                    domain, algorithm, ..
+                },
+            ) => domain.wire_len() + 10 + algorithm.wire_len() + 16,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Checks the TSIG record of a request. Unsigned requests give `None`, and
+/// a TSIG record that does not parse or is not the last record is an error.
+pub fn verify_request(
+    keys: &Keyring,
+    message: &[u8],
+    now: SystemTime,
+) -> Result<Option<Signature>> {
+    let (unsigned, tsig) = match split(message)? {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+    let (name, algorithm, time_signed, fudge, mac) = match &tsig {
+        DnsRecord::TSIG {
+            domain,
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            ..
+        } => (domain, algorithm, *time_signed, *fudge, mac),
+        _ => unreachable!(),
+    };
+    let key = keys
+        .get(name)
+        .filter(|key| key.algorithm_name() == *algorithm);
+    let error = match &key {
+        None => ResultCode::BADKEY,
+        Some(key) => {
+            let data = signed_data(None, &unsigned, &tsig, false);
+            if hmac::verify(&key.secret, &data, mac).is_err() {
+                ResultCode::BADSIG
+            } else if !in_time(time_signed, fudge, now) {
+                ResultCode::BADTIME
+            } else {
+                ResultCode::NOERROR
+            }
+        }
+    };
+    if error != ResultCode::NOERROR {
+        log::info!("TSIG check with key {} failed: {}", name, error);
+    }
+    Ok(Some(Signature { tsig, key, error }))
+}
+
+/// Signs the response to a request that carried `signature`. If the
+/// request failed its check, the response carries the error instead:
+/// unsigned for BADKEY and BADSIG, signed along with this server's time
+/// for BADTIME.
+pub fn sign_response(signature: &Signature, response: &[u8], now: SystemTime) -> Result<Vec<u8>> {
+    Ok(sign_responses(signature, vec![response.to_vec()], now)?.remove(0))
+}
+
+/// Signs each message of a multi-message response such as a zone
+/// transfer: the first as a single response would be, each later one over
+/// the MAC before it, itself and the time (RFC 8945 section 5.3.1).
+pub fn sign_responses(
+    signature: &Signature,
+    responses: Vec<Vec<u8>>,
+    now: SystemTime,
+) -> Result<Vec<Vec<u8>>> {
+    let (name, algorithm, request_time, request_mac) = match &signature.tsig {
+        DnsRecord::TSIG {
+            domain,
+            algorithm,
+            time_signed,
+            mac,
+            ..
+        } => (domain, algorithm, *time_signed, mac),
+        _ => unreachable!(),
+    };
+    let error = u16::from(signature.error.to_num());
+    let key = match &signature.key {
+        Some(key) if matches!(signature.error, ResultCode::NOERROR | ResultCode::BADTIME) => key,
+        _ => {
+            return responses
+                .iter()
+                .map(|response| {
+                    let tsig = record(name, algorithm, request_time, response, error, Vec::new());
+                    append(response, &tsig)
+                })
+                .collect();
+        }
+    };
+
+    let mut prior = request_mac.clone();
+    let mut signed = Vec::new();
+    for (i, response) in responses.iter().enumerate() {
+        let mut tsig = match signature.error {
+            ResultCode::BADTIME => record(
+                &key.name,
+                &key.algorithm_name(),
+                request_time,
+                response,
+                error,
+                secs(now).to_be_bytes()[2..].to_vec(),
+            ),
+            _ => record(
+                &key.name,
+                &key.algorithm_name(),
+                secs(now),
+                response,
+                error,
+                Vec::new(),
+            ),
+        };
+        let data = signed_data(Some(&prior), response, &tsig, i > 0);
+        prior = hmac::sign(&key.secret, &data).as_ref().to_vec();
+        if let DnsRecord::TSIG { mac, .. } = &mut tsig {
+            *mac = prior.clone();
+        }
+        signed.push(append(response, &tsig)?);
+    }
+    Ok(signed)
+}
+
+/// A request signed with a key, and the MAC its responses answer.
+#[derive(Debug)]
+pub struct Exchange {
+    key: Arc<Key>,
+    prior: Vec<u8>,
+    /// Unsigned messages since the last signed one, which the next
+    /// signature also covers.
+    unsigned: Vec<u8>,
+    unsigned_count: usize,
+    answered: bool,
+}
+
+impl Exchange {
+    /// Signs `request`, returning the exchange and the signed message.
+    pub fn sign(key: Arc<Key>, request: &[u8], now: SystemTime) -> Result<(Self, Vec<u8>)> {
+        let mut tsig = record(
+            &key.name,
+            &key.algorithm_name(),
+            secs(now),
+            request,
+            0,
+            Vec::new(),
+        );
+        let data = signed_data(None, request, &tsig, false);
+        let prior = hmac::sign(&key.secret, &data).as_ref().to_vec();
+        if let DnsRecord::TSIG { mac, .. } = &mut tsig {
+            *mac = prior.clone();
+        }
+        let signed = append(request, &tsig)?;
+        let exchange = Exchange {
+            key,
+            prior,
+            unsigned: Vec::new(),
+            unsigned_count: 0,
+            answered: false,
+        };
+        Ok((exchange, signed))
+    }
+
+    /// Checks the next response. After the first, up to 99 in a row may
+    /// be unsigned, but the last one has to be signed: see `is_settled`.
+    pub fn verify(&mut self, response: &[u8], now: SystemTime) -> Result<()> {
+        let (unsigned, tsig) = match split(response)? {
+            Some(split) => split,
+            None if self.answered && self.unsigned_count < MAX_UNSIGNED => {
+                self.unsigned.extend_from_slice(response);
+                self.unsigned_count += 1;
+                return Ok(());
+            }
+            None => return Err("response is not signed".into()),
+        };
+        let (name, algorithm, time_signed, fudge, mac, error) = match &tsig {
+            DnsRecord::TSIG {
+                domain,
+                algorithm,
+                time_signed,
+                fudge,
+                mac,
+                error,
+                ..
+            } => (domain, algorithm, *time_signed, *fudge, mac, *error),
+            _ => unreachable!(),
+        };
+        if error != 0 {
+            return Err(format!("TSIG error {}", ResultCode::from_num(error as u8)).into());
+        }
+        if *name != self.key.name || *algorithm != self.key.algorithm_name() {
+            return Err(format!("response signed with key {} instead", name).into());
+        }
+        let mut message = std::mem::take(&mut self.unsigned);
+        message.extend_from_slice(&unsigned);
+        let data = signed_data(Some(&self.prior), &message, &tsig, self.answered);
+        if hmac::verify(&self.key.secret, &data, mac).is_err() {
+            return Err("bad TSIG signature on response".into());
+        }
+        if !in_time(time_signed, fudge, now) {
+            return Err("TSIG time of response is outside the fudge".into());
+        }
+        self.prior = mac.clone();
+        self.unsigned_count = 0;
+        self.answered = true;
+        Ok(())
+    }
+
+    /// Whether the last response checked was signed.
+    pub fn is_settled(&self) -> bool {
+        self.answered && self.unsigned_count == 0
+    }
+}
+
+/// A TSIG record without its MAC, for the message `for_message`.
+fn record(
+    name: &Name,
+    algorithm: &Name,
+    time_signed: u64,
+    for_message: &[u8],
+    error: u16,
+    other: Vec<u8>,
+) -> DnsRecord {
+    DnsRecord::TSIG {
+        domain: name.clone(),
+        algorithm: algorithm.clone(),
+        time_signed,
+        fudge: FUDGE,
+        mac: Vec::new(),
+        original_id: u16::from_be_bytes([for_message[0], for_message[1]]),
+        error,
+        other,
+    }
+}
+
+/// What the MAC covers (RFC 8945 section 4.3): the MAC being answered,
+/// the message as it was before signing, and the TSIG fields, of which a
+/// later message of a multi-message response only includes the time.
+fn signed_data(
+    prior: Option<&[u8]>,
+    message: &[u8],
+    tsig: &DnsRecord,
+    timers_only: bool,
+) -> Vec<u8> {
+    let (name, algorithm, time_signed, fudge, error, other) = match tsig {
+        DnsRecord::TSIG {
+            domain,
+            algorithm,
+            time_signed,
+            fudge,
+            error,
+            other,
+            ..
+        } => (domain, algorithm, *time_signed, *fudge, *error, other),
+        _ => unreachable!(),
+    };
+    let mut data = Vec::new();
+    if let Some(prior) = prior {
+        data.extend_from_slice(&(prior.len() as u16).to_be_bytes());
+        data.extend_from_slice(prior);
+    }
+    data.extend_from_slice(message);
+    if !timers_only {
+        data.extend(canonical(name));
+        data.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend(canonical(algorithm));
+    }
+    data.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+    data.extend_from_slice(&fudge.to_be_bytes());
+    if !timers_only {
+        data.extend_from_slice(&error.to_be_bytes());
+        data.extend_from_slice(&(other.len() as u16).to_be_bytes());
+        data.extend_from_slice(other);
+    }
+    data
+}
+
+/// A name in lower case and uncompressed.
+fn canonical(name: &Name) -> Vec<u8> {
+    let mut wire = Vec::new();
+    for label in name.to_lowercase().labels() {
+        wire.push(label.len() as u8);
+        wire.extend_from_slice(label);
+    }
+    wire.push(0);
+    wire
+}
+
+/// Splits a signed message into the message as it was before signing and
+/// its TSIG record, which has to be the last one.
+fn split(message: &[u8]) -> Result<Option<(Vec<u8>, DnsRecord)>> {
+    let mut buffer = BytePacketBuffer::from_bytes(message)?;
+    let mut header = Header::new();
+    header.read(&mut buffer)?;
+    for _ in 0..header.questions {
+        Question::new(Name::root(), QueryType::UNKNOWN(0)).read(&mut buffer)?;
+    }
+    let records = usize::from(header.answers)
+        + usize::from(header.authoritative_entries)
+        + usize::from(header.resource_entries);
+    let mut start = None;
+    for i in 0..records {
+        let pos = buffer.pos();
+        let mut name = Name::root();
+        buffer.read_qname(&mut name)?;
+        let rtype = buffer.read_u16()?;
+        // Class and TTL.
+        buffer.step(6)?;
+        let len = buffer.read_u16()?;
+        buffer.step(usize::from(len))?;
+        if rtype == QueryType::TSIG.to_num() {
+            if i + 1 != records || header.resource_entries == 0 {
+                return Err("TSIG record is not the last one".into());
+            }
+            start = Some(pos);
+        }
+    }
+    let start = match start {
+        Some(start) => start,
+        None => return Ok(None),
+    };
+    buffer.seek(start)?;
+    let tsig = DnsRecord::read(&mut buffer)?;
+    let original_id = match &tsig {
+        DnsRecord::TSIG { original_id, .. } => *original_id,
+        _ => unreachable!(),
+    };
+    let mut unsigned = message[..start].to_vec();
+    unsigned[..2].copy_from_slice(&original_id.to_be_bytes());
+    unsigned[10..12].copy_from_slice(&(header.resource_entries - 1).to_be_bytes());
+    Ok(Some((unsigned, tsig)))
+}
+
+/// `message` with `tsig` added as its last additional record.
+fn append(message: &[u8], tsig: &DnsRecord) -> Result<Vec<u8>> {
+    if message.len() < 12 {
+        return Err("message too short to sign".into());
+    }
+    let mut buffer = BytePacketBuffer::new();
+    tsig.write(&mut buffer)?;
+    let mut signed = message.to_vec();
+    let additional = u16::from_be_bytes([signed[10], signed[11]]) + 1;
+    signed[10..12].copy_from_slice(&additional.to_be_bytes());
+    signed.extend_from_slice(buffer.get_range(0, buffer.pos())?);
+    Ok(signed)
+}
+
+fn in_time(time_signed: u64, fudge: u16, now: SystemTime) -> bool {
+    secs(now).abs_diff(time_signed) <= u64::from(fudge)
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{enums::OpCode, packet::Packet};
+    use std::time::Duration;
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    fn keyring() -> Keyring {
+        let config: crate::config::Config = crate::config::Config::parse(
+            r#"
+            [[keys]]
+            name = "xfr-key"
+            algorithm = "hmac-sha256"
+            secret = "c2VjcmV0IHNoYXJlZCBieSBwcmltYXJ5IGFuZCBzZWNvbmRhcnk="
+
+            [[keys]]
+            name = "other-key"
+            algorithm = "hmac-sha512"
+            secret = "b3RoZXI="
+            "#,
+        )
+        .unwrap();
+        Keyring::new(&config.keys).unwrap()
+    }
+
+    fn message(id: u16, response: bool) -> Vec<u8> {
+        let mut packet = Packet::new();
+        packet.header.id = id;
+        packet.header.responce = response;
+        packet.header.operation_code = OpCode::NOTIFY;
+        packet
+            .questions
+            .push(Question::new(name("example.com"), QueryType::SOA));
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.get_range(0, buffer.pos()).unwrap().to_vec()
+    }
+
+    fn tsig_error(message: &[u8]) -> u16 {
+        let packet =
+            Packet::from_buffer(&mut BytePacketBuffer::from_bytes(message).unwrap()).unwrap();
+        match packet.resources.last() {
+            Some(DnsRecord::TSIG { error, .. }) => *error,
+            record => panic!("no TSIG record: {:?}", record),
+        }
+    }
+
+    #[test]
+    fn requests_and_responses_are_signed_and_checked() {
+        let keys = keyring();
+        let key = keys.get(&name("XFR-KEY")).unwrap();
+        let now = SystemTime::now();
+        let (mut exchange, request) = Exchange::sign(key.clone(), &message(7, false), now).unwrap();
+        let parsed =
+            Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&request).unwrap()).unwrap();
+        assert_eq!(parsed.header.resource_entries, 1);
+        assert_eq!(parsed.resources[0].qtype(), QueryType::TSIG);
+
+        let signature = verify_request(&keys, &request, now).unwrap().unwrap();
+        assert_eq!(signature.error, ResultCode::NOERROR);
+        assert_eq!(signature.key(), Some(&name("xfr-key")));
+        assert!(verify_request(&keys, &message(7, false), now)
+            .unwrap()
+            .is_none());
+
+        let response = sign_response(&signature, &message(7, true), now).unwrap();
+        assert_eq!(
+            response.len(),
+            message(7, true).len() + signature.record_len()
+        );
+        exchange.verify(&response, now).unwrap();
+        assert!(exchange.is_settled());
+
+        // Any change to the signed bytes breaks the signature.
+        let mut tampered = request.clone();
+        tampered[2] ^= 0x01;
+        let signature = verify_request(&keys, &tampered, now).unwrap().unwrap();
+        assert_eq!(signature.error, ResultCode::BADSIG);
+        assert_eq!(signature.key(), None);
+        let response = sign_response(&signature, &message(7, true), now).unwrap();
+        assert_eq!(tsig_error(&response), 16);
+
+        let late = now + Duration::from_secs(u64::from(FUDGE) + 1);
+        let signature = verify_request(&keys, &request, late).unwrap().unwrap();
+        assert_eq!(signature.error, ResultCode::BADTIME);
+        let response = sign_response(&signature, &message(7, true), late).unwrap();
+        assert_eq!(tsig_error(&response), 18);
+        let (mut exchange, _) = Exchange::sign(key, &message(7, false), now).unwrap();
+        assert!(exchange.verify(&response, now).is_err());
+
+        let unknown = Keyring::default();
+        let signature = verify_request(&unknown, &request, now).unwrap().unwrap();
+        assert_eq!(signature.error, ResultCode::BADKEY);
+        assert_eq!(
+            tsig_error(&sign_response(&signature, &message(7, true), now).unwrap()),
+            17
+        );
+
+        // The TSIG record has to come last.
+        let mut packet =
+            Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&request).unwrap()).unwrap();
+        packet.resources.push(DnsRecord::A {
+            domain: name("example.com"),
+            addr: std::net::Ipv4Addr::LOCALHOST,
+            ttl: 60,
+        });
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        assert!(verify_request(&keys, buffer.get_range(0, buffer.pos()).unwrap(), now).is_err());
+    }
+
+    #[test]
+    fn each_message_of_a_transfer_is_chained_to_the_one_before() {
+        let keys = keyring();
+        let key = keys.get(&name("other-key")).unwrap();
+        let now = SystemTime::now();
+        let start = || Exchange::sign(key.clone(), &message(9, false), now).unwrap();
+        let (mut exchange, request) = start();
+        let signature = verify_request(&keys, &request, now).unwrap().unwrap();
+        let responses = sign_responses(&signature, vec![message(9, true); 3], now).unwrap();
+        for response in &responses {
+            exchange.verify(response, now).unwrap();
+        }
+        assert!(exchange.is_settled());
+
+        // Out of order, the chain breaks.
+        let (mut exchange, _) = start();
+        exchange.verify(&responses[0], now).unwrap();
+        assert!(exchange.verify(&responses[2], now).is_err());
+
+        // An unsigned message is covered by the next signature.
+        let (mut exchange, _) = start();
+        exchange.verify(&responses[0], now).unwrap();
+        exchange.verify(&message(9, true), now).unwrap();
+        assert!(!exchange.is_settled());
+        let prior = match split(&responses[0]).unwrap().unwrap().1 {
+            DnsRecord::TSIG { mac, .. } => mac,
+            _ => unreachable!(),
+        };
+        let mut tsig = record(
+            &key.name,
+            &key.algorithm_name(),
+            secs(now),
+            &message(9, true),
+            0,
+            Vec::new(),
+        );
+        let covered = [message(9, true), message(9, true)].concat();
+        let data = signed_data(Some(&prior), &covered, &tsig, true);
+        if let DnsRecord::TSIG { mac, .. } = &mut tsig {
+            *mac = hmac::sign(&key.secret, &data).as_ref().to_vec();
+        }
+        exchange
+            .verify(&append(&message(9, true), &tsig).unwrap(), now)
+            .unwrap();
+        assert!(exchange.is_settled());
+    }
+}
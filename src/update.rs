@@ -0,0 +1,691 @@
+//! Dynamic updates (RFC 2136). An UPDATE reuses the four sections of a
+//! message as zone, prerequisites, updates and additional data, and the
+//! class of each record says what it asks for, so the sections are read
+//! here rather than by `Packet`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+
+use crate::{
+    buffer::BytePacketBuffer,
+    context::ServerContext,
+    enums::{OpCode, QueryType, ResultCode},
+    header::Header,
+    horizon::View,
+    name::Name,
+    packet::Packet,
+    question::Question,
+    record::DnsRecord,
+    zone::{self, Zone},
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+const TYPE_ANY: u16 = 255;
+
+/// A prerequisite or update record. Those that only name an RRset or a
+/// name carry no RDATA.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    name: Name,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    record: Option<DnsRecord>,
+}
+
+/// An UPDATE message: the zone to change, what must hold first and the
+/// changes to make.
+#[derive(Debug)]
+struct Update {
+    zone: Question,
+    prerequisites: Vec<Entry>,
+    updates: Vec<Entry>,
+}
+
+/// The answer to an UPDATE, or `None` if the request is not one. `key` is
+/// the TSIG key the request was signed with. A change that gets through is
+/// applied to the zone in memory, journaled and announced to its
+/// secondaries.
+pub fn respond(
+    ctx: &ServerContext,
+    view: &View,
+    req_buffer: &BytePacketBuffer,
+    client: IpAddr,
+    key: Option<&Name>,
+) -> Option<Packet> {
+    let mut buffer = BytePacketBuffer::from_bytes(&req_buffer.buffer[..req_buffer.len()]).ok()?;
+    let mut header = Header::new();
+    header.read(&mut buffer).ok()?;
+    if header.responce || header.operation_code != OpCode::UPDATE {
+        return None;
+    }
+
+    let mut packet = Packet::new();
+    packet.header.id = header.id;
+    packet.header.operation_code = OpCode::UPDATE;
+    packet.header.responce = true;
+    let update = match parse(&mut buffer) {
+        Ok(update) => update,
+        Err(e) => {
+            log::debug!("malformed UPDATE from {}: {}", client, e);
+            packet.header.responce_code = ResultCode::FORMERR;
+            return Some(packet);
+        }
+    };
+    packet.questions.push(update.zone.clone());
+    packet.header.responce_code = apply(ctx, view, &update, client, key);
+    Some(packet)
+}
+
+fn apply(
+    ctx: &ServerContext,
+    view: &View,
+    update: &Update,
+    client: IpAddr,
+    key: Option<&Name>,
+) -> ResultCode {
+    let origin = &update.zone.name;
+    if update.zone.qtype != QueryType::SOA {
+        return ResultCode::FORMERR;
+    }
+    let primary = match view
+        .primaries
+        .iter()
+        .find(|primary| primary.origin == *origin)
+    {
+        Some(primary) => primary,
+        // Secondary zones change on their primary, not here.
+        None if view.zones.get(origin).is_some() => return ResultCode::REFUSED,
+        None => return ResultCode::NOTAUTH,
+    };
+    if !ctx.acl.may_update(client, key, origin) {
+        log::info!("UPDATE of {} from {} refused", origin, client);
+        return ResultCode::REFUSED;
+    }
+
+    let mut old_serial = 0;
+    let result = view.zones.modify(origin, |zone| {
+        old_serial = zone.serial();
+        check_prerequisites(zone, &update.prerequisites)?;
+        prescan(zone, &update.updates)?;
+        changed(zone, &update.updates)
+    });
+    match result {
+        None => ResultCode::NOTAUTH,
+        Some(Err(rcode)) => {
+            log::info!("UPDATE of {} from {} failed with {}", origin, client, rcode);
+            rcode
+        }
+        Some(Ok(zone)) => {
+            if zone.serial() != old_serial {
+                log::info!(
+                    "UPDATE of {} from {}: now serial {}",
+                    origin,
+                    client,
+                    zone.serial()
+                );
+                ctx.notifier
+                    .notify(&zone, &primary.also_notify, primary.key.clone());
+            }
+            ResultCode::NOERROR
+        }
+    }
+}
+
+fn parse(buffer: &mut BytePacketBuffer) -> Result<Update> {
+    buffer.seek(0)?;
+    let mut header = Header::new();
+    header.read(buffer)?;
+    if header.questions != 1 {
+        return Err(format!("{} zones in the zone section", header.questions).into());
+    }
+    let mut zone = Question::new(Name::root(), QueryType::UNKNOWN(0));
+    zone.read(buffer)?;
+    if zone.qclass != CLASS_IN {
+        return Err(format!("zone class {}", zone.qclass).into());
+    }
+    let prerequisites = (0..header.answers)
+        .map(|_| read_entry(buffer))
+        .collect::<Result<Vec<_>>>()?;
+    let updates = (0..header.authoritative_entries)
+        .map(|_| read_entry(buffer))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Update {
+        zone,
+        prerequisites,
+        updates,
+    })
+}
+
+/// Reads one record keeping its class, which `DnsRecord::read` drops, and
+/// allowing the empty RDATA that stands for a whole RRset.
+fn read_entry(buffer: &mut BytePacketBuffer) -> Result<Entry> {
+    let start = buffer.pos();
+    let mut name = Name::root();
+    buffer.read_qname(&mut name)?;
+    let rtype = buffer.read_u16()?;
+    let class = buffer.read_u16()?;
+    let ttl = buffer.read_u32()?;
+    let len = buffer.read_u16()?;
+    let record = if len == 0 {
+        None
+    } else {
+        buffer.seek(start)?;
+        Some(DnsRecord::read(buffer)?)
+    };
+    Ok(Entry {
+        name,
+        rtype,
+        class,
+        ttl,
+        record,
+    })
+}
+
+/// Types that only appear in questions or describe the message itself.
+fn is_meta(rtype: u16) -> bool {
+    rtype == 41 || (128..=255).contains(&rtype)
+}
+
+/// A record with its TTL cleared, for comparing RDATA.
+fn data(record: &DnsRecord) -> DnsRecord {
+    let mut record = record.clone();
+    record.set_ttl(0);
+    record
+}
+
+/// Checks every prerequisite against the zone as it is (RFC 2136 section
+/// 3.2). Either all hold or nothing changes.
+fn check_prerequisites(
+    zone: &Zone,
+    prerequisites: &[Entry],
+) -> std::result::Result<(), ResultCode> {
+    // RRsets that must exist with exactly these records.
+    let mut exact: BTreeMap<(Name, u16), BTreeSet<DnsRecord>> = BTreeMap::new();
+    for entry in prerequisites {
+        if !entry.name.is_subdomain_of(zone.origin()) {
+            return Err(ResultCode::NOTZONE);
+        }
+        let name_in_use = zone.records_at(&entry.name).next().is_some();
+        let rrset_exists = zone
+            .records_at(&entry.name)
+            .any(|record| record.qtype().to_num() == entry.rtype);
+        match (entry.class, &entry.record) {
+            _ if entry.ttl != 0 => return Err(ResultCode::FORMERR),
+            (CLASS_ANY, None) if entry.rtype == TYPE_ANY => {
+                if !name_in_use {
+                    return Err(ResultCode::NXDOMAIN);
+                }
+            }
+            (CLASS_ANY, None) => {
+                if !rrset_exists {
+                    return Err(ResultCode::NXRRSET);
+                }
+            }
+            (CLASS_NONE, None) if entry.rtype == TYPE_ANY => {
+                if name_in_use {
+                    return Err(ResultCode::YXDOMAIN);
+                }
+            }
+            (CLASS_NONE, None) => {
+                if rrset_exists {
+                    return Err(ResultCode::YXRRSET);
+                }
+            }
+            (CLASS_IN, Some(record)) => {
+                exact
+                    .entry((entry.name.clone(), entry.rtype))
+                    .or_default()
+                    .insert(data(record));
+            }
+            _ => return Err(ResultCode::FORMERR),
+        }
+    }
+    for ((name, rtype), expected) in exact {
+        let actual: BTreeSet<DnsRecord> = zone
+            .records_at(&name)
+            .filter(|record| record.qtype().to_num() == rtype)
+            .map(data)
+            .collect();
+        if actual != expected {
+            return Err(ResultCode::NXRRSET);
+        }
+    }
+    Ok(())
+}
+
+/// Rejects the whole update if any change is malformed (RFC 2136 section
+/// 3.4.1), before any of them is made.
+fn prescan(zone: &Zone, updates: &[Entry]) -> std::result::Result<(), ResultCode> {
+    for entry in updates {
+        if !entry.name.is_subdomain_of(zone.origin()) {
+            return Err(ResultCode::NOTZONE);
+        }
+        let valid = match entry.class {
+            CLASS_IN => entry.record.is_some() && !is_meta(entry.rtype),
+            CLASS_ANY => {
+                entry.ttl == 0
+                    && entry.record.is_none()
+                    && (entry.rtype == TYPE_ANY || !is_meta(entry.rtype))
+            }
+            CLASS_NONE => entry.ttl == 0 && entry.record.is_some() && !is_meta(entry.rtype),
+            _ => false,
+        };
+        if !valid {
+            return Err(ResultCode::FORMERR);
+        }
+    }
+    Ok(())
+}
+
+/// The zone with the updates made (RFC 2136 section 3.4.2) and, if that
+/// changed anything, a higher serial.
+fn changed(zone: &Zone, updates: &[Entry]) -> std::result::Result<Zone, ResultCode> {
+    let origin = zone.origin();
+    let soa = QueryType::SOA.to_num();
+    let ns = QueryType::NS.to_num();
+    let cname = QueryType::CNAME.to_num();
+    let mut records: Vec<DnsRecord> = zone.records().cloned().collect();
+
+    for entry in updates {
+        let name = &entry.name;
+        let at_apex = name == origin;
+        let rtype_of = |record: &DnsRecord| record.qtype().to_num();
+        match (entry.class, &entry.record) {
+            (CLASS_IN, Some(record)) if entry.rtype == soa => {
+                let newer = match record {
+                    DnsRecord::SOA { serial, .. } => {
+                        zone::is_newer_serial(*serial, current_serial(&records))
+                    }
+                    _ => false,
+                };
+                if at_apex && newer {
+                    records.retain(|r| rtype_of(r) != soa);
+                    records.push(record.clone());
+                }
+            }
+            (CLASS_IN, Some(record)) => {
+                let owned: Vec<u16> = records
+                    .iter()
+                    .filter(|r| r.domain() == name)
+                    .map(rtype_of)
+                    .collect();
+                // A CNAME shares its name with nothing else.
+                if entry.rtype == cname && owned.iter().any(|t| *t != cname) {
+                    continue;
+                }
+                if entry.rtype != cname && owned.contains(&cname) {
+                    continue;
+                }
+                let same = data(record);
+                records.retain(|r| {
+                    r.domain() != name
+                        || (entry.rtype == cname && rtype_of(r) != cname)
+                        || (entry.rtype != cname && data(r) != same)
+                });
+                records.push(record.clone());
+            }
+            (CLASS_ANY, None) if entry.rtype == TYPE_ANY => {
+                records.retain(|r| {
+                    r.domain() != name || (at_apex && (rtype_of(r) == soa || rtype_of(r) == ns))
+                });
+            }
+            (CLASS_ANY, None) => {
+                if at_apex && (entry.rtype == soa || entry.rtype == ns) {
+                    continue;
+                }
+                records.retain(|r| r.domain() != name || rtype_of(r) != entry.rtype);
+            }
+            (CLASS_NONE, Some(record)) => {
+                if entry.rtype == soa {
+                    continue;
+                }
+                let apex_ns = records
+                    .iter()
+                    .filter(|r| r.domain() == origin && rtype_of(r) == ns)
+                    .count();
+                if at_apex && entry.rtype == ns && apex_ns <= 1 {
+                    continue;
+                }
+                let gone = data(record);
+                records.retain(|r| r.domain() != name || data(r) != gone);
+            }
+            _ => return Err(ResultCode::FORMERR),
+        }
+    }
+
+    let mut new = Zone::new(origin.clone(), records).map_err(|_| ResultCode::SERVFAIL)?;
+    if new != *zone && new.serial() == zone.serial() {
+        let mut records: Vec<DnsRecord> = new.records().cloned().collect();
+        for record in &mut records {
+            if let DnsRecord::SOA { serial, .. } = record {
+                *serial = serial.wrapping_add(1);
+            }
+        }
+        new = Zone::new(origin.clone(), records).map_err(|_| ResultCode::SERVFAIL)?;
+    }
+    Ok(new)
+}
+
+fn current_serial(records: &[DnsRecord]) -> u32 {
+    records
+        .iter()
+        .find_map(|record| match record {
+            DnsRecord::SOA { serial, .. } => Some(*serial),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        context::Transport,
+        handler::{self, Client},
+        tsig::Exchange,
+    };
+    use std::net::Ipv4Addr;
+    use std::time::SystemTime;
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    enum Rdata {
+        Empty,
+        A(Ipv4Addr),
+        Host(&'static str),
+    }
+
+    struct Rr(&'static str, QueryType, u16, u32, Rdata);
+
+    fn context() -> ServerContext {
+        let dir = std::env::temp_dir().join(format!("update-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("example.com.zone");
+        std::fs::write(
+            &path,
+            "@ 3600 SOA ns1 hostmaster 1 7200 3600 1209600 300\n\
+             @ 3600 NS ns1\nns1 3600 A 192.0.2.53\nwww 3600 A 192.0.2.80\n",
+        )
+        .unwrap();
+        let config = Config::parse(&format!(
+            r#"
+            [[zones]]
+            name = "example.com"
+            file = {:?}
+
+            [acl]
+            recursion = []
+
+            [[keys]]
+            name = "ddns-key"
+            algorithm = "hmac-sha512"
+            secret = "ZGRucy1rZXkgc2VjcmV0"
+
+            [[acl.zones]]
+            zone = "example.com"
+            allow_update = ["192.0.2.10"]
+            allow_update_keys = ["ddns-key"]
+            "#,
+            path
+        ))
+        .unwrap();
+        ServerContext::new(config).unwrap()
+    }
+
+    fn send(
+        ctx: &ServerContext,
+        from: &str,
+        zone: &str,
+        prerequisites: &[Rr],
+        updates: &[Rr],
+    ) -> ResultCode {
+        send_signed(ctx, None, from, zone, prerequisites, updates)
+    }
+
+    fn send_signed(
+        ctx: &ServerContext,
+        key: Option<&str>,
+        from: &str,
+        zone: &str,
+        prerequisites: &[Rr],
+        updates: &[Rr],
+    ) -> ResultCode {
+        let mut header = Header::new();
+        header.id = 2136;
+        header.operation_code = OpCode::UPDATE;
+        header.questions = 1;
+        header.answers = prerequisites.len() as u16;
+        header.authoritative_entries = updates.len() as u16;
+        let mut buffer = BytePacketBuffer::new();
+        header.write(&mut buffer).unwrap();
+        Question::new(name(zone), QueryType::SOA)
+            .write(&mut buffer)
+            .unwrap();
+        for Rr(owner, rtype, class, ttl, rdata) in prerequisites.iter().chain(updates) {
+            buffer.write_qname(&name(owner)).unwrap();
+            buffer.write_u16(rtype.to_num()).unwrap();
+            buffer.write_u16(*class).unwrap();
+            buffer.write_u32(*ttl).unwrap();
+            let len_pos = buffer.pos();
+            buffer.write_u16(0).unwrap();
+            match rdata {
+                Rdata::Empty => {}
+                Rdata::A(addr) => addr
+                    .octets()
+                    .iter()
+                    .for_each(|b| buffer.write_u8(*b).unwrap()),
+                Rdata::Host(host) => buffer.write_qname(&name(host)).unwrap(),
+            }
+            let len = buffer.pos() - len_pos - 2;
+            buffer.set_u16(len_pos, len as u16).unwrap();
+        }
+        let len = buffer.pos();
+        buffer.set_len(len).unwrap();
+        if let Some(key) = key {
+            let key = ctx.keys.get(&name(key)).unwrap();
+            let (_, signed) =
+                Exchange::sign(key, &buffer.buffer[..len], SystemTime::now()).unwrap();
+            buffer = BytePacketBuffer::from_bytes(&signed).unwrap();
+        }
+        buffer.seek(0).unwrap();
+
+        let client = Client {
+            addr: from.parse().unwrap(),
+            local: "192.0.2.1:53".parse().unwrap(),
+            transport: Transport::Udp,
+        };
+        let data = handler::answer(ctx, &mut buffer, client).unwrap().unwrap();
+        let response =
+            Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&data).unwrap()).unwrap();
+        assert_eq!(response.header.id, 2136);
+        assert_eq!(response.header.operation_code, OpCode::UPDATE);
+        response.header.responce_code
+    }
+
+    fn addrs(ctx: &ServerContext, host: &str) -> Vec<Ipv4Addr> {
+        let zone = ctx.views[0].zones.get(&name("example.com")).unwrap();
+        zone.rrset(&name(host), QueryType::A)
+            .filter_map(|record| match record {
+                DnsRecord::A { addr, .. } => Some(*addr),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn serial(ctx: &ServerContext) -> u32 {
+        ctx.views[0]
+            .zones
+            .get(&name("example.com"))
+            .unwrap()
+            .serial()
+    }
+
+    #[test]
+    fn prerequisites_then_changes() {
+        let ctx = context();
+        let from = "192.0.2.10:5300";
+        let a = |n: u8| Rdata::A(Ipv4Addr::new(192, 0, 2, n));
+        let add_app = [Rr("app.example.com", QueryType::A, CLASS_IN, 300, a(1))];
+        let app_is_new = [Rr(
+            "app.example.com",
+            QueryType::UNKNOWN(TYPE_ANY),
+            CLASS_NONE,
+            0,
+            Rdata::Empty,
+        )];
+
+        assert_eq!(
+            send(&ctx, from, "example.com", &app_is_new, &add_app),
+            ResultCode::NOERROR
+        );
+        assert_eq!(
+            addrs(&ctx, "app.example.com"),
+            vec![Ipv4Addr::new(192, 0, 2, 1)]
+        );
+        assert_eq!(serial(&ctx), 2);
+        assert_eq!(
+            send(&ctx, from, "example.com", &app_is_new, &add_app),
+            ResultCode::YXDOMAIN
+        );
+        assert_eq!(serial(&ctx), 2);
+
+        // Swap www's address, but only if it still has the old one.
+        let www_is = [Rr("www.example.com", QueryType::A, CLASS_IN, 0, a(80))];
+        let swap = [
+            Rr("www.example.com", QueryType::A, CLASS_ANY, 0, Rdata::Empty),
+            Rr("www.example.com", QueryType::A, CLASS_IN, 300, a(81)),
+        ];
+        assert_eq!(
+            send(&ctx, from, "example.com", &www_is, &swap),
+            ResultCode::NOERROR
+        );
+        assert_eq!(
+            addrs(&ctx, "www.example.com"),
+            vec![Ipv4Addr::new(192, 0, 2, 81)]
+        );
+        assert_eq!(
+            send(&ctx, from, "example.com", &www_is, &swap),
+            ResultCode::NXRRSET
+        );
+        let missing = [Rr(
+            "gone.example.com",
+            QueryType::A,
+            CLASS_ANY,
+            0,
+            Rdata::Empty,
+        )];
+        assert_eq!(
+            send(&ctx, from, "example.com", &missing, &[]),
+            ResultCode::NXRRSET
+        );
+        assert_eq!(serial(&ctx), 3);
+
+        // Changes that would break the zone are skipped, not applied.
+        let breaking = [
+            Rr("example.com", QueryType::SOA, CLASS_ANY, 0, Rdata::Empty),
+            Rr(
+                "example.com",
+                QueryType::NS,
+                CLASS_NONE,
+                0,
+                Rdata::Host("ns1.example.com"),
+            ),
+            Rr(
+                "www.example.com",
+                QueryType::CNAME,
+                CLASS_IN,
+                300,
+                Rdata::Host("app.example.com"),
+            ),
+        ];
+        assert_eq!(
+            send(&ctx, from, "example.com", &[], &breaking),
+            ResultCode::NOERROR
+        );
+        assert_eq!(serial(&ctx), 3);
+
+        let delete = [Rr("app.example.com", QueryType::A, CLASS_NONE, 0, a(1))];
+        assert_eq!(
+            send(&ctx, from, "example.com", &[], &delete),
+            ResultCode::NOERROR
+        );
+        assert!(addrs(&ctx, "app.example.com").is_empty());
+        assert_eq!(serial(&ctx), 4);
+
+        let changes = ctx.views[0]
+            .zones
+            .changes_since(&name("example.com"), 1)
+            .unwrap();
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[1].removed.len(), 1);
+        assert_eq!(changes[1].added.len(), 1);
+    }
+
+    #[test]
+    fn malformed_and_unauthorized_updates() {
+        let ctx = context();
+        let add = [Rr(
+            "app.example.com",
+            QueryType::A,
+            CLASS_IN,
+            300,
+            Rdata::A(Ipv4Addr::LOCALHOST),
+        )];
+        assert_eq!(
+            send(&ctx, "192.0.2.11:5300", "example.com", &[], &add),
+            ResultCode::REFUSED
+        );
+        assert_eq!(
+            send(&ctx, "192.0.2.10:5300", "example.org", &[], &add),
+            ResultCode::NOTAUTH
+        );
+
+        let outside = [Rr(
+            "www.example.org",
+            QueryType::A,
+            CLASS_IN,
+            300,
+            Rdata::A(Ipv4Addr::LOCALHOST),
+        )];
+        assert_eq!(
+            send(&ctx, "192.0.2.10:5300", "example.com", &[], &outside),
+            ResultCode::NOTZONE
+        );
+        // Deleting an RRset takes no TTL and no RDATA.
+        let bad = [Rr(
+            "www.example.com",
+            QueryType::A,
+            CLASS_ANY,
+            300,
+            Rdata::Empty,
+        )];
+        assert_eq!(
+            send(&ctx, "192.0.2.10:5300", "example.com", &[], &bad),
+            ResultCode::FORMERR
+        );
+        assert_eq!(serial(&ctx), 1);
+
+        // The key stands in for the address.
+        assert_eq!(
+            send_signed(
+                &ctx,
+                Some("ddns-key"),
+                "192.0.2.11:5300",
+                "example.com",
+                &[],
+                &add
+            ),
+            ResultCode::NOERROR
+        );
+        assert_eq!(serial(&ctx), 2);
+    }
+}
@@ -0,0 +1,699 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::{
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+    SignatureScheme, StreamOwned,
+};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+
+use crate::{config::UpstreamConfig, context::Transport, quic::QuicClient, PACKET_BUFFER_SIZE};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// Idle connections older than this are dropped rather than reused; servers
+/// close idle DoT and DoH connections after a few seconds anyway.
+const MAX_IDLE: Duration = Duration::from_secs(10);
+const MAX_IDLE_PER_SERVER: usize = 4;
+const MAX_HTTP_HEAD: usize = 8192;
+const DNS_MESSAGE: &str = "application/dns-message";
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+/// Connections are only shared between upstreams with the same address and
+/// host name.
+type PoolKey = (Transport, SocketAddr, String);
+
+/// One address to send queries to, and how to reach it.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub transport: Transport,
+    pub addr: SocketAddr,
+    /// Sent in the HTTP Host header.
+    authority: String,
+    path: String,
+    server_name: Option<ServerName<'static>>,
+    tls: Option<Arc<ClientConfig>>,
+}
+
+impl Upstream {
+    pub fn udp(addr: SocketAddr) -> Self {
+        Self {
+            transport: Transport::Udp,
+            addr,
+            authority: addr.to_string(),
+            path: String::new(),
+            server_name: None,
+            tls: None,
+        }
+    }
+
+    /// One upstream per address the configured server is reachable at:
+    /// the URL's own address, or each bootstrap address when it names a
+    /// host.
+    pub fn from_config(config: &UpstreamConfig) -> Result<Vec<Upstream>> {
+        if let Ok(addr) = config.url.parse::<SocketAddr>() {
+            if !config.bootstrap.is_empty() || !config.spki.is_empty() || config.ca.is_some() {
+                return Err(
+                    format!("{}: options need a tls:// or https:// URL", config.url).into(),
+                );
+            }
+            return Ok(vec![Upstream::udp(addr)]);
+        }
+
+        let (transport, rest) = match config.url.split_once("://") {
+            Some(("tls", rest)) => (Transport::Tls, rest),
+            Some(("quic", rest)) => (Transport::Quic, rest),
+            Some(("https", rest)) => (Transport::Https, rest),
+            _ => return Err(format!("Unsupported upstream {:?}", config.url).into()),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        let path = match (transport, path) {
+            (Transport::Https, "") => "/dns-query",
+            (Transport::Https, path) => path,
+            (_, "") => "",
+            (_, _) => return Err(format!("{}: only https:// takes a path", config.url).into()),
+        };
+        let default_port = if transport == Transport::Https {
+            443
+        } else {
+            853
+        };
+        let (host, port) = split_port(authority, default_port)
+            .ok_or_else(|| format!("Invalid upstream {:?}", config.url))?;
+
+        let (server_name, addrs) = match host.parse::<IpAddr>() {
+            Ok(ip) => (ServerName::IpAddress(ip.into()), vec![ip]),
+            Err(_) => {
+                if config.bootstrap.is_empty() {
+                    return Err(
+                        format!("{}: a host name needs bootstrap addresses", config.url).into(),
+                    );
+                }
+                let name = ServerName::try_from(host.to_string())
+                    .map_err(|_| format!("Invalid host name in {:?}", config.url))?;
+                (name, config.bootstrap.clone())
+            }
+        };
+        let tls = client_config(config, transport)?;
+        Ok(addrs
+            .into_iter()
+            .map(|ip| Upstream {
+                transport,
+                addr: SocketAddr::new(ip, port),
+                authority: authority.to_string(),
+                path: path.to_string(),
+                server_name: Some(server_name.clone()),
+                tls: Some(tls.clone()),
+            })
+            .collect())
+    }
+}
+
+/// `host`, `host:port`, `[v6]` or `[v6]:port`.
+fn split_port(authority: &str, default_port: u16) -> Option<(&str, u16)> {
+    if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        let port = match rest.strip_prefix(':') {
+            Some(port) => port.parse().ok()?,
+            None if rest.is_empty() => default_port,
+            None => return None,
+        };
+        return Some((host, port));
+    }
+    match authority.split_once(':') {
+        Some((host, port)) => Some((host, port.parse().ok()?)),
+        None if !authority.is_empty() => Some((authority, default_port)),
+        None => None,
+    }
+}
+
+fn client_config(config: &UpstreamConfig, transport: Transport) -> Result<Arc<ClientConfig>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let mut tls = if config.spki.is_empty() {
+        let mut roots = RootCertStore::empty();
+        match &config.ca {
+            Some(path) => {
+                for cert in CertificateDer::pem_file_iter(path)
+                    .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?
+                {
+                    roots.add(cert?)?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    } else {
+        let pins = config
+            .spki
+            .iter()
+            .map(
+                |pin| match base64::engine::general_purpose::STANDARD.decode(pin) {
+                    Ok(digest) if digest.len() == 32 => Ok(digest),
+                    _ => Err(format!("Invalid SPKI pin {:?}", pin)),
+                },
+            )
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let verifier = PinnedKeys {
+            pins,
+            algorithms: provider.signature_verification_algorithms,
+        };
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth()
+    };
+    let alpn: &[u8] = match transport {
+        Transport::Https => b"http/1.1",
+        Transport::Quic => b"doq",
+        _ => b"dot",
+    };
+    tls.alpn_protocols = vec![alpn.to_vec()];
+    tls.enable_early_data = transport == Transport::Quic;
+    Ok(Arc::new(tls))
+}
+
+/// Accepts a server whose certificate carries one of the pinned public
+/// keys, whatever its name or issuer. The handshake signature still has to
+/// verify, which proves the server holds the key.
+#[derive(Debug)]
+struct PinnedKeys {
+    pins: Vec<Vec<u8>>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedKeys {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let spki = spki(end_entity).ok_or(rustls::Error::InvalidCertificate(
+            CertificateError::BadEncoding,
+        ))?;
+        let digest = ring::digest::digest(&ring::digest::SHA256, spki);
+        if self
+            .pins
+            .iter()
+            .any(|pin| pin.as_slice() == digest.as_ref())
+        {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// The DER SubjectPublicKeyInfo of an X.509 certificate: the seventh field
+/// of the TBSCertificate, counting the optional version.
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = der(cert)?;
+    let (_, tbs, _) = der(certificate)?;
+    let mut rest = tbs;
+    if rest.first() == Some(&0xa0) {
+        rest = der(rest)?.2;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        rest = der(rest)?.2;
+    }
+    let (element, _, _) = der(rest)?;
+    Some(element)
+}
+
+/// Splits the DER element at the front of `data` into the whole element,
+/// its contents and what follows it.
+fn der(data: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let first = *data.get(1)?;
+    let (header, len) = if first < 0x80 {
+        (2, first as usize)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let len = data
+            .get(2..2 + count)?
+            .iter()
+            .fold(0, |len, &byte| len << 8 | byte as usize);
+        (2 + count, len)
+    };
+    let end = header.checked_add(len)?;
+    if end > data.len() {
+        return None;
+    }
+    Some((&data[..end], &data[header..end], &data[end..]))
+}
+
+/// Sends a UDP query and waits for the reply. `sent` is told the local
+/// address once the query is on its way.
+pub fn udp_exchange(
+    upstream: &Upstream,
+    bind: SocketAddr,
+    query: &[u8],
+    timeout: Duration,
+    sent: impl FnOnce(SocketAddr),
+) -> Result<Vec<u8>> {
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.send_to(query, upstream.addr)?;
+    sent(socket.local_addr()?);
+    let mut response = vec![0; PACKET_BUFFER_SIZE];
+    let (len, _) = socket.recv_from(&mut response)?;
+    response.truncate(len);
+    Ok(response)
+}
+
+/// Idle TLS connections to DoT and DoH upstreams, reused across queries so
+/// that most lookups skip the handshake, and open DoQ connections.
+#[derive(Default)]
+pub struct Pool {
+    idle: Mutex<HashMap<PoolKey, Vec<(TlsStream, Instant)>>>,
+    /// Started on the first DoQ query.
+    quic: OnceLock<QuicClient>,
+}
+
+impl Pool {
+    /// Sends `query` to a DoT, DoH or DoQ upstream and returns the
+    /// response, on a pooled connection when one is available.
+    pub fn exchange(
+        &self,
+        upstream: &Upstream,
+        query: &[u8],
+        timeout: Duration,
+        sent: impl FnOnce(SocketAddr),
+    ) -> Result<Vec<u8>> {
+        if upstream.transport == Transport::Quic {
+            let tls = upstream.tls.clone().ok_or("Not a TLS upstream")?;
+            let server_name = upstream.server_name.as_ref().ok_or("Not a TLS upstream")?;
+            let quic = match self.quic.get() {
+                Some(quic) => quic,
+                None => {
+                    let quic = QuicClient::new()?;
+                    self.quic.get_or_init(|| quic)
+                }
+            };
+            return quic.exchange(
+                upstream.addr,
+                &server_name.to_str(),
+                tls,
+                query,
+                timeout,
+                sent,
+            );
+        }
+        let mut sent = Some(sent);
+        if let Some(mut stream) = self.take(upstream) {
+            let local = stream.sock.local_addr()?;
+            let result = send(upstream, &mut stream, query, || {
+                if let Some(sent) = sent.take() {
+                    sent(local)
+                }
+            });
+            match result {
+                Ok((response, reusable)) => {
+                    if reusable {
+                        self.put(upstream, stream);
+                    }
+                    return Ok(response);
+                }
+                // The server may have closed it while it sat in the pool.
+                Err(e) => log::debug!("pooled connection to {} failed: {}", upstream.addr, e),
+            }
+        }
+
+        let tls = upstream.tls.clone().ok_or("Not a TLS upstream")?;
+        let server_name = upstream.server_name.clone().ok_or("Not a TLS upstream")?;
+        let sock = TcpStream::connect_timeout(&upstream.addr, timeout)?;
+        sock.set_read_timeout(Some(timeout))?;
+        sock.set_write_timeout(Some(timeout))?;
+        sock.set_nodelay(true)?;
+        let local = sock.local_addr()?;
+        let mut stream = StreamOwned::new(ClientConnection::new(tls, server_name)?, sock);
+        let (response, reusable) = send(upstream, &mut stream, query, || {
+            if let Some(sent) = sent.take() {
+                sent(local)
+            }
+        })?;
+        if reusable {
+            self.put(upstream, stream);
+        }
+        Ok(response)
+    }
+
+    /// Connections waiting to be reused.
+    pub fn idle_connections(&self) -> usize {
+        self.idle.lock().unwrap().values().map(Vec::len).sum()
+    }
+
+    /// DoQ connections still open.
+    pub fn quic_connections(&self) -> usize {
+        self.quic.get().map_or(0, QuicClient::connections)
+    }
+
+    fn take(&self, upstream: &Upstream) -> Option<TlsStream> {
+        let mut idle = self.idle.lock().unwrap();
+        let streams = idle.get_mut(&key(upstream))?;
+        while let Some((stream, since)) = streams.pop() {
+            if since.elapsed() < MAX_IDLE {
+                return Some(stream);
+            }
+        }
+        None
+    }
+
+    fn put(&self, upstream: &Upstream, stream: TlsStream) {
+        let mut idle = self.idle.lock().unwrap();
+        let streams = idle.entry(key(upstream)).or_default();
+        streams.retain(|(_, since)| since.elapsed() < MAX_IDLE);
+        if streams.len() < MAX_IDLE_PER_SERVER {
+            streams.push((stream, Instant::now()));
+        }
+    }
+}
+
+fn key(upstream: &Upstream) -> PoolKey {
+    (
+        upstream.transport,
+        upstream.addr,
+        upstream.authority.clone(),
+    )
+}
+
+/// One query and response on an open connection. Also says whether the
+/// connection can carry another query.
+fn send(
+    upstream: &Upstream,
+    stream: &mut TlsStream,
+    query: &[u8],
+    sent: impl FnOnce(),
+) -> Result<(Vec<u8>, bool)> {
+    let mut message = Vec::new();
+    if upstream.transport == Transport::Https {
+        write!(
+            message,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nAccept: {}\r\nContent-Length: {}\r\n\r\n",
+            upstream.path,
+            upstream.authority,
+            DNS_MESSAGE,
+            DNS_MESSAGE,
+            query.len()
+        )?;
+    } else {
+        message.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    }
+    message.extend_from_slice(query);
+    stream.write_all(&message)?;
+    stream.flush()?;
+    sent();
+
+    if upstream.transport == Transport::Https {
+        return read_http_response(stream);
+    }
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response)?;
+    Ok((response, true))
+}
+
+fn read_http_response(stream: &mut TlsStream) -> Result<(Vec<u8>, bool)> {
+    let mut data = Vec::new();
+    let mut chunk = [0; 1024];
+    let end = loop {
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if data.len() > MAX_HTTP_HEAD {
+            return Err("HTTP response head too large".into());
+        }
+        let len = stream.read(&mut chunk)?;
+        if len == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        data.extend_from_slice(&chunk[..len]);
+    };
+
+    let head = String::from_utf8_lossy(&data[..end]).to_ascii_lowercase();
+    let mut lines = head.lines();
+    let status = lines.next().unwrap_or("");
+    let mut parts = status.split(' ');
+    let version = parts.next().unwrap_or("");
+    if parts.next() != Some("200") {
+        return Err(format!("Upstream answered {:?}", status).into());
+    }
+    let mut length = None;
+    let mut reusable = version == "http/1.1";
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+        match name {
+            "content-length" => length = Some(value.parse::<usize>()?),
+            "connection" if value == "close" => reusable = false,
+            "transfer-encoding" => return Err("Chunked HTTP responses are not supported".into()),
+            "content-type" if value != DNS_MESSAGE => {
+                return Err(format!("Upstream sent {}", value).into())
+            }
+            _ => {}
+        }
+    }
+    let length = length.ok_or("HTTP response has no Content-Length")?;
+    if length > PACKET_BUFFER_SIZE {
+        return Err(format!("{} byte response is too large", length).into());
+    }
+    let mut body = data.split_off(end + 4);
+    if body.len() > length {
+        return Err("HTTP response is longer than its Content-Length".into());
+    }
+    let start = body.len();
+    body.resize(length, 0);
+    stream.read_exact(&mut body[start..])?;
+    Ok((body, reusable))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer::BytePacketBuffer,
+        config::Config,
+        context::ServerContext,
+        doh,
+        enums::{QueryType, ResultCode},
+        handler::{self, Client},
+        packet::Packet,
+        question::Question,
+        tls,
+    };
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    fn upstreams(url: &str, bootstrap: &[&str]) -> Result<Vec<Upstream>> {
+        Upstream::from_config(&UpstreamConfig {
+            url: url.to_string(),
+            bootstrap: bootstrap.iter().map(|ip| ip.parse().unwrap()).collect(),
+            spki: Vec::new(),
+            ca: None,
+        })
+    }
+
+    #[test]
+    fn upstream_urls() {
+        let udp = upstreams("192.0.2.1:53", &[]).unwrap();
+        assert_eq!(udp[0].transport, Transport::Udp);
+        assert_eq!(udp[0].addr, "192.0.2.1:53".parse().unwrap());
+
+        let tls = upstreams("tls://1.1.1.1", &[]).unwrap();
+        assert_eq!(tls[0].transport, Transport::Tls);
+        assert_eq!(tls[0].addr, "1.1.1.1:853".parse().unwrap());
+        let tls = upstreams("tls://[2001:db8::1]:8853", &[]).unwrap();
+        assert_eq!(tls[0].addr, "[2001:db8::1]:8853".parse().unwrap());
+
+        let https = upstreams("https://dns.example/q", &["192.0.2.7", "2001:db8::7"]).unwrap();
+        assert_eq!(https.len(), 2);
+        assert_eq!(https[0].transport, Transport::Https);
+        assert_eq!(https[0].addr, "192.0.2.7:443".parse().unwrap());
+        assert_eq!(https[1].addr, "[2001:db8::7]:443".parse().unwrap());
+        assert_eq!(https[1].path, "/q");
+        assert_eq!(https[1].authority, "dns.example");
+        let https = upstreams("https://dns.example:8443", &["192.0.2.7"]).unwrap();
+        assert_eq!(https[0].path, "/dns-query");
+        assert_eq!(https[0].addr.port(), 8443);
+
+        assert!(upstreams("tls://dns.example", &[]).is_err());
+        assert!(upstreams("tls://1.1.1.1/path", &[]).is_err());
+        let quic = upstreams("quic://[2001:db8::1]", &[]).unwrap();
+        assert_eq!(quic[0].transport, Transport::Quic);
+        assert_eq!(quic[0].addr, "[2001:db8::1]:853".parse().unwrap());
+        assert!(upstreams("quic://1.1.1.1/path", &[]).is_err());
+        assert!(upstreams("udp://1.1.1.1", &[]).is_err());
+        assert!(upstreams("192.0.2.1:53", &["192.0.2.2"]).is_err());
+        assert!(upstreams("tls://1.1.1.1:x", &[]).is_err());
+        let pinned = UpstreamConfig {
+            url: "tls://1.1.1.1".to_string(),
+            bootstrap: Vec::new(),
+            spki: vec!["c2hvcnQ=".to_string()],
+            ca: Some(PathBuf::from("unused")),
+        };
+        assert!(Upstream::from_config(&pinned).is_err());
+    }
+
+    /// DoT and DoH servers answering `*.test` from local records, standing
+    /// in for public resolvers.
+    fn start_stand_ins() -> (SocketAddr, SocketAddr, PathBuf, String) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("upstream-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        let config = Config::parse(
+            r#"
+            [acl]
+            recursion = []
+
+            [local]
+            records = [
+                "a.test A 192.0.2.1",
+                "www.a.test A 192.0.2.11",
+                "b.test A 192.0.2.2",
+                "www.b.test A 192.0.2.12",
+                "c.test A 192.0.2.3",
+            ]
+            "#,
+        )
+        .unwrap();
+        let ctx = Arc::new(ServerContext::new(config).unwrap());
+
+        let tls = tls::server_config(&cert_path, &key_path, &[b"dot"]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dot = listener.local_addr().unwrap();
+        let dot_ctx = ctx.clone();
+        std::thread::spawn(move || tls::serve(listener, tls, dot_ctx));
+
+        let tls = tls::server_config(&cert_path, &key_path, &[b"h2", b"http/1.1"]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let doh = listener.local_addr().unwrap();
+        std::thread::spawn(move || doh::serve(listener, tls, ctx).unwrap());
+
+        let digest = ring::digest::digest(&ring::digest::SHA256, &cert.key_pair.public_key_der());
+        let pin = base64::engine::general_purpose::STANDARD.encode(digest);
+        (dot, doh, cert_path, pin)
+    }
+
+    fn resolve(ctx: &ServerContext, qname: &str) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.id = 77;
+        packet.header.recursion_desired = true;
+        packet
+            .questions
+            .push(Question::new(qname.parse().unwrap(), QueryType::A));
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.seek(0).unwrap();
+        let client = Client {
+            addr: "127.0.0.1:5300".parse().unwrap(),
+            local: "127.0.0.1:53".parse().unwrap(),
+            transport: Transport::Udp,
+        };
+        let data = handler::answer(ctx, &mut buffer, client).unwrap().unwrap();
+        Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&data).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn forwards_over_tls_and_https() {
+        let (dot, doh, ca, pin) = start_stand_ins();
+        let config = Config::parse(&format!(
+            r#"
+            [[forwarders]]
+            zone = "a.test"
+            servers = [{{ url = "tls://localhost:{}", bootstrap = ["127.0.0.1"], ca = {:?} }}]
+
+            [[forwarders]]
+            zone = "b.test"
+            servers = [{{ url = "https://localhost:{}/dns-query", bootstrap = ["127.0.0.1"], spki = [{:?}] }}]
+
+            [[forwarders]]
+            zone = "c.test"
+            servers = [{{ url = "tls://127.0.0.1:{}", spki = ["{}"] }}]
+            "#,
+            dot.port(),
+            ca,
+            doh.port(),
+            pin,
+            dot.port(),
+            base64::engine::general_purpose::STANDARD.encode([0; 32]),
+        ))
+        .unwrap();
+        let ctx = ServerContext::new(config).unwrap();
+
+        let response = resolve(&ctx, "a.test");
+        assert_eq!(response.header.responce_code, ResultCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+        let response = resolve(&ctx, "b.test");
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(ctx.upstreams.idle_connections(), 2);
+
+        // Both connections are reused, not reopened.
+        assert_eq!(resolve(&ctx, "www.a.test").answers.len(), 1);
+        assert_eq!(resolve(&ctx, "www.b.test").answers.len(), 1);
+        assert_eq!(ctx.upstreams.idle_connections(), 2);
+
+        // A certificate that does not match the pin is rejected.
+        let response = resolve(&ctx, "c.test");
+        assert_eq!(response.header.responce_code, ResultCode::SERVFAIL);
+    }
+
+    #[test]
+    fn public_key_of_a_certificate() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        assert_eq!(
+            spki(cert.cert.der()).unwrap(),
+            cert.key_pair.public_key_der().as_slice()
+        );
+        assert!(spki(&cert.cert.der()[..100]).is_none());
+        assert!(spki(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff]).is_none());
+    }
+}
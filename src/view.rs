@@ -0,0 +1,513 @@
+use std::fmt;
+
+use crate::{
+    buffer::BytePacketBuffer,
+    enums::{OpCode, QueryType, ResultCode},
+    name::{write_label, Name},
+    packet::Packet,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+const HEADER_SIZE: usize = 12;
+const MAX_JUMPS: usize = 5;
+
+/// Read-only view of a wire-format message that parses nothing up front.
+///
+/// Questions and records are decoded lazily by the iterators and names are
+/// compared in place, so filtering and lookups never touch the heap. Use
+/// `to_packet` when an owned `Packet` is needed.
+#[derive(Debug, Clone, Copy)]
+pub struct PacketView<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> PacketView<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self> {
+        if data.len() < HEADER_SIZE {
+            return Err("Message shorter than a header.".into());
+        }
+        Ok(Self { data })
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn id(&self) -> u16 {
+        read_u16(self.data, 0)
+    }
+
+    pub fn responce(&self) -> bool {
+        self.data[2] & 0x80 > 0
+    }
+
+    pub fn operation_code(&self) -> OpCode {
+        OpCode::from_num((self.data[2] >> 3) & 0x0F)
+    }
+
+    pub fn truncated_message(&self) -> bool {
+        self.data[2] & 0x02 > 0
+    }
+
+    pub fn responce_code(&self) -> ResultCode {
+        ResultCode::from_num(self.data[3] & 0x0F)
+    }
+
+    pub fn questions_count(&self) -> u16 {
+        read_u16(self.data, 4)
+    }
+
+    pub fn answers_count(&self) -> u16 {
+        read_u16(self.data, 6)
+    }
+
+    pub fn authorities_count(&self) -> u16 {
+        read_u16(self.data, 8)
+    }
+
+    pub fn resources_count(&self) -> u16 {
+        read_u16(self.data, 10)
+    }
+
+    pub fn questions(&self) -> Questions<'a> {
+        Questions {
+            data: self.data,
+            pos: HEADER_SIZE,
+            remaining: self.questions_count() as usize,
+        }
+    }
+
+    pub fn answers(&self) -> Records<'a> {
+        self.records_after(0, self.answers_count() as usize)
+    }
+
+    pub fn authorities(&self) -> Records<'a> {
+        let skip = self.answers_count() as usize;
+        self.records_after(skip, self.authorities_count() as usize)
+    }
+
+    pub fn resources(&self) -> Records<'a> {
+        let skip = self.answers_count() as usize + self.authorities_count() as usize;
+        self.records_after(skip, self.resources_count() as usize)
+    }
+
+    /// Every record of the answer, authority and additional sections in order.
+    pub fn records(&self) -> Records<'a> {
+        let total = self.answers_count() as usize
+            + self.authorities_count() as usize
+            + self.resources_count() as usize;
+        self.records_after(0, total)
+    }
+
+    pub fn to_packet(&self) -> Result<Packet> {
+        let mut buffer = BytePacketBuffer::from_bytes(self.data)?;
+        Packet::from_buffer(&mut buffer)
+    }
+
+    // Positions a record iterator after the questions and `skip` records.
+    // Parse errors on the way surface from the first call to `next`.
+    fn records_after(&self, skip: usize, count: usize) -> Records<'a> {
+        let mut questions = self.questions();
+        for question in questions.by_ref() {
+            if question.is_err() {
+                return Records::failed(self.data);
+            }
+        }
+
+        let mut records = Records {
+            data: self.data,
+            pos: questions.pos,
+            remaining: skip,
+        };
+        for record in records.by_ref() {
+            if record.is_err() {
+                return Records::failed(self.data);
+            }
+        }
+        records.remaining = count;
+        records
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QuestionRef<'a> {
+    pub name: NameRef<'a>,
+    pub qtype: QueryType,
+    pub qclass: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RecordRef<'a> {
+    pub name: NameRef<'a>,
+    pub qtype: QueryType,
+    pub class: u16,
+    pub ttl: u32,
+    data: &'a [u8],
+    rdata_start: usize,
+    rdata_len: usize,
+}
+
+impl<'a> RecordRef<'a> {
+    pub fn rdata(&self) -> &'a [u8] {
+        &self.data[self.rdata_start..self.rdata_start + self.rdata_len]
+    }
+
+    /// The name stored at the start of the RDATA, for NS and CNAME records.
+    pub fn rdata_name(&self) -> Option<NameRef<'a>> {
+        match self.qtype {
+            QueryType::NS | QueryType::CNAME => Some(NameRef {
+                data: self.data,
+                start: self.rdata_start,
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub struct Questions<'a> {
+    data: &'a [u8],
+    pos: usize,
+    remaining: usize,
+}
+
+impl<'a> Iterator for Questions<'a> {
+    type Item = Result<QuestionRef<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let result = (|| {
+            let name = NameRef::at(self.data, self.pos)?;
+            let pos = self.pos + name.wire_len()?;
+            let fixed = get(self.data, pos, 4)?;
+            self.pos = pos + 4;
+            Ok(QuestionRef {
+                name,
+                qtype: QueryType::from_num(read_u16(fixed, 0)),
+                qclass: read_u16(fixed, 2),
+            })
+        })();
+        if result.is_err() {
+            self.remaining = 0;
+        }
+        Some(result)
+    }
+}
+
+pub struct Records<'a> {
+    data: &'a [u8],
+    pos: usize,
+    remaining: usize,
+}
+
+impl<'a> Records<'a> {
+    fn failed(data: &'a [u8]) -> Self {
+        Records {
+            data,
+            pos: data.len() + 1,
+            remaining: 1,
+        }
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<RecordRef<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let result = (|| {
+            let name = NameRef::at(self.data, self.pos)?;
+            let pos = self.pos + name.wire_len()?;
+            let fixed = get(self.data, pos, 10)?;
+            let rdata_len = read_u16(fixed, 8) as usize;
+            get(self.data, pos + 10, rdata_len)?;
+            self.pos = pos + 10 + rdata_len;
+            Ok(RecordRef {
+                name,
+                qtype: QueryType::from_num(read_u16(fixed, 0)),
+                class: read_u16(fixed, 2),
+                ttl: (read_u16(fixed, 4) as u32) << 16 | read_u16(fixed, 6) as u32,
+                data: self.data,
+                rdata_start: pos + 10,
+                rdata_len,
+            })
+        })();
+        if result.is_err() {
+            self.remaining = 0;
+        }
+        Some(result)
+    }
+}
+
+/// A possibly compressed domain name inside a message, read on demand.
+#[derive(Debug, Clone, Copy)]
+pub struct NameRef<'a> {
+    data: &'a [u8],
+    start: usize,
+}
+
+impl<'a> NameRef<'a> {
+    fn at(data: &'a [u8], start: usize) -> Result<Self> {
+        let name = NameRef { data, start };
+        for label in name.labels() {
+            label?;
+        }
+        Ok(name)
+    }
+
+    /// Bytes the name occupies where it starts, up to and including the
+    /// terminating zero or the first compression pointer.
+    fn wire_len(&self) -> Result<usize> {
+        let mut pos = self.start;
+        loop {
+            let len = *self.data.get(pos).ok_or("End of buffer.")?;
+            if (len & 0xC0) == 0xC0 {
+                return Ok(pos + 2 - self.start);
+            }
+            pos += 1 + len as usize;
+            if len == 0 {
+                return Ok(pos - self.start);
+            }
+        }
+    }
+
+    pub fn labels(&self) -> Labels<'a> {
+        Labels {
+            data: self.data,
+            pos: self.start,
+            jumps: 0,
+            done: false,
+        }
+    }
+
+    /// Case-insensitive comparison with an owned name, without copying.
+    pub fn eq_name(&self, name: &Name) -> bool {
+        let mut expected = name.labels();
+        for label in self.labels() {
+            match (label, expected.next()) {
+                (Ok(label), Some(other)) if label.eq_ignore_ascii_case(other) => {}
+                _ => return false,
+            }
+        }
+        expected.next().is_none()
+    }
+
+    /// Case-insensitive check that this name is `zone` or lies below it.
+    pub fn is_subdomain_of(&self, zone: &Name) -> bool {
+        let own_labels = self.labels().count();
+        if own_labels < zone.label_count() {
+            return false;
+        }
+        self.labels()
+            .skip(own_labels - zone.label_count())
+            .zip(zone.labels())
+            .all(|(label, other)| match label {
+                Ok(label) => label.eq_ignore_ascii_case(other),
+                Err(_) => false,
+            })
+    }
+
+    pub fn to_name(&self) -> Result<Name> {
+        let mut name = Name::root();
+        for label in self.labels() {
+            name.push_label(label?.to_vec())?;
+        }
+        Ok(name)
+    }
+}
+
+impl PartialEq for NameRef<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        let mut theirs = other.labels();
+        for label in self.labels() {
+            match (label, theirs.next()) {
+                (Ok(a), Some(Ok(b))) if a.eq_ignore_ascii_case(b) => {}
+                _ => return false,
+            }
+        }
+        theirs.next().is_none()
+    }
+}
+
+impl fmt::Display for NameRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut labels = self.labels().peekable();
+        if labels.peek().is_none() {
+            return f.write_str(".");
+        }
+        for (i, label) in labels.enumerate() {
+            let label = label.map_err(|_| fmt::Error)?;
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            write_label(f, label)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Labels<'a> {
+    data: &'a [u8],
+    pos: usize,
+    jumps: usize,
+    done: bool,
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = Result<&'a [u8]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        loop {
+            let len = match self.data.get(self.pos) {
+                Some(len) => *len,
+                None => return self.fail("End of buffer.".into()),
+            };
+
+            if (len & 0xC0) == 0xC0 {
+                if self.jumps >= MAX_JUMPS {
+                    return self.fail(format!("Limit of {} jumps excceeded", MAX_JUMPS).into());
+                }
+                let b2 = match self.data.get(self.pos + 1) {
+                    Some(b2) => *b2 as usize,
+                    None => return self.fail("End of buffer.".into()),
+                };
+                self.pos = (((len as usize) ^ 0xC0) << 8) | b2;
+                self.jumps += 1;
+                continue;
+            }
+
+            if len == 0 {
+                self.done = true;
+                return None;
+            }
+
+            let start = self.pos + 1;
+            return match get(self.data, start, len as usize) {
+                Ok(label) => {
+                    self.pos = start + len as usize;
+                    Some(Ok(label))
+                }
+                Err(e) => self.fail(e),
+            };
+        }
+    }
+}
+
+impl<'a> Labels<'a> {
+    fn fail(&mut self, e: Error) -> Option<Result<&'a [u8]>> {
+        self.done = true;
+        Some(Err(e))
+    }
+}
+
+fn get(data: &[u8], start: usize, len: usize) -> Result<&[u8]> {
+    data.get(start..start + len)
+        .ok_or_else(|| "End of buffer.".into())
+}
+
+fn read_u16(data: &[u8], pos: usize) -> u16 {
+    ((data[pos] as u16) << 8) | data[pos + 1] as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    use crate::{question::Question, record::DnsRecord};
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    fn referral() -> Vec<u8> {
+        let mut packet = Packet::new();
+        packet.header.id = 77;
+        packet.header.responce = true;
+        packet
+            .questions
+            .push(Question::new(name("www.Example.com"), QueryType::A));
+        packet.authorities.push(DnsRecord::NS {
+            domain: name("example.com"),
+            host: name("ns1.example.com"),
+            ttl: 3600,
+        });
+        packet.resources.push(DnsRecord::A {
+            domain: name("ns1.example.com"),
+            addr: Ipv4Addr::new(192, 0, 2, 53),
+            ttl: 3600,
+        });
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.buffer[..buffer.pos()].to_vec()
+    }
+
+    #[test]
+    fn iterates_sections_lazily() {
+        let bytes = referral();
+        let view = PacketView::new(&bytes).unwrap();
+        assert_eq!(view.id(), 77);
+        assert!(view.responce());
+
+        let question = view.questions().next().unwrap().unwrap();
+        assert!(question.name.eq_name(&name("www.example.COM")));
+        assert!(!question.name.eq_name(&name("example.com")));
+        assert!(question.name.is_subdomain_of(&name("example.com")));
+        assert!(!question.name.is_subdomain_of(&name("ample.com")));
+        assert_eq!(
+            question.name.to_name().unwrap().to_string(),
+            "www.Example.com"
+        );
+        assert_eq!(question.qtype, QueryType::A);
+
+        assert_eq!(view.answers().count(), 0);
+        let ns = view.authorities().next().unwrap().unwrap();
+        assert_eq!(ns.qtype, QueryType::NS);
+        assert!(ns.rdata_name().unwrap().eq_name(&name("ns1.example.com")));
+
+        let glue = view.resources().next().unwrap().unwrap();
+        assert_eq!(glue.name, ns.rdata_name().unwrap());
+        assert_eq!(glue.rdata(), &[192, 0, 2, 53]);
+        assert_eq!(glue.name.to_string(), "ns1.example.com");
+        assert_eq!(view.records().count(), 2);
+    }
+
+    #[test]
+    fn converts_into_owned_packet() {
+        let bytes = referral();
+        let view = PacketView::new(&bytes).unwrap();
+        let mut buffer = BytePacketBuffer::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            view.to_packet().unwrap(),
+            Packet::from_buffer(&mut buffer).unwrap()
+        );
+    }
+
+    #[test]
+    fn malformed_records_are_errors() {
+        let bytes = referral();
+        let view = PacketView::new(&bytes[..bytes.len() - 2]).unwrap();
+        assert!(view.resources().next().unwrap().is_err());
+        assert!(view.authorities().next().unwrap().is_ok());
+
+        let mut looped = bytes[..12].to_vec();
+        looped.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1]);
+        let view = PacketView::new(&looped).unwrap();
+        assert!(view.questions().next().unwrap().is_err());
+        assert!(view.answers().next().unwrap().is_err());
+        assert!(PacketView::new(&bytes[..11]).is_err());
+    }
+}
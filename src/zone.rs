@@ -0,0 +1,621 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::{
+    enums::{QueryType, ResultCode},
+    name::Name,
+    packet::Packet,
+    record::DnsRecord,
+    zonefile,
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// CNAMEs followed inside a zone before giving up.
+const MAX_CNAME_CHAIN: usize = 8;
+/// Changes kept per zone for IXFR; older versions get a full transfer.
+const MAX_JOURNAL: usize = 64;
+
+/// True if SOA serial `serial` is later than `than` in sequence space
+/// arithmetic (RFC 1982), so serials may wrap around.
+pub fn is_newer_serial(serial: u32, than: u32) -> bool {
+    let distance = serial.wrapping_sub(than);
+    distance != 0 && distance < 1 << 31
+}
+
+/// An authoritative zone: every record at or below `origin`, in canonical
+/// order, with exactly one SOA at the apex.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Zone {
+    origin: Name,
+    records: BTreeMap<Name, Vec<DnsRecord>>,
+}
+
+impl Zone {
+    pub fn new(origin: Name, records: Vec<DnsRecord>) -> Result<Self> {
+        let mut zone = Zone {
+            origin,
+            records: BTreeMap::new(),
+        };
+        for record in records {
+            if !record.domain().is_subdomain_of(&zone.origin) {
+                return Err(format!("{} is outside zone {}", record.domain(), zone.origin).into());
+            }
+            let rrset = zone.records.entry(record.domain().clone()).or_default();
+            if !rrset.contains(&record) {
+                rrset.push(record);
+            }
+        }
+
+        let soas = zone.rrset(&zone.origin, QueryType::SOA).count();
+        if soas != 1 {
+            return Err(
+                format!("Zone {} has {} SOA records at its apex", zone.origin, soas).into(),
+            );
+        }
+        Ok(zone)
+    }
+
+    /// Reads a master file. Records without a TTL and with no `$TTL` before
+    /// them get the SOA minimum.
+    pub fn load(origin: &Name, path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read zone {}: {}", path.display(), e))?;
+        Self::parse(origin, &text)
+            .map_err(|e| format!("Invalid zone {}: {}", path.display(), e).into())
+    }
+
+    pub fn parse(origin: &Name, text: &str) -> Result<Self> {
+        let entries = zonefile::parse(text, origin)?;
+        let default_ttl = entries
+            .iter()
+            .find(|entry| entry.rtype == "SOA")
+            .and_then(|entry| entry.rdata.get(6))
+            .map(|minimum| zonefile::ttl(minimum))
+            .transpose()?
+            .unwrap_or(3600);
+        let records = entries
+            .iter()
+            .map(|entry| entry.to_record(default_ttl))
+            .collect::<Result<Vec<_>>>()?;
+        Zone::new(origin.clone(), records)
+    }
+
+    /// Writes the zone as a master file, replacing `path` only once the
+    /// whole file is written.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut text = format!("$ORIGIN {}.\n", self.origin);
+        for record in self.records() {
+            text.push_str(&zonefile::format(record));
+            text.push('\n');
+        }
+        let partial = path.with_extension("partial");
+        fs::write(&partial, text)
+            .and_then(|()| fs::rename(&partial, path))
+            .map_err(|e| format!("Cannot write zone {}: {}", path.display(), e).into())
+    }
+
+    /// The next version of the zone, with `delta` applied to this one.
+    pub fn apply(&self, delta: &Delta) -> Result<Zone> {
+        if delta.from_serial() != self.serial() {
+            return Err(format!(
+                "Changes to {} start at serial {}, not {}",
+                self.origin,
+                delta.from_serial(),
+                self.serial()
+            )
+            .into());
+        }
+        let mut records: Vec<DnsRecord> = self
+            .records()
+            .filter(|record| record.qtype() != QueryType::SOA && !delta.removed.contains(record))
+            .cloned()
+            .collect();
+        records.push(delta.to.clone());
+        records.extend(delta.added.iter().cloned());
+        Zone::new(self.origin.clone(), records)
+    }
+
+    pub fn origin(&self) -> &Name {
+        &self.origin
+    }
+
+    pub fn soa(&self) -> &DnsRecord {
+        self.rrset(&self.origin, QueryType::SOA).next().unwrap()
+    }
+
+    pub fn serial(&self) -> u32 {
+        soa_serial(self.soa())
+    }
+
+    /// Every record, apex first.
+    pub fn records(&self) -> impl Iterator<Item = &DnsRecord> {
+        self.records.values().flatten()
+    }
+
+    fn contains(&self, record: &DnsRecord) -> bool {
+        self.records
+            .get(record.domain())
+            .is_some_and(|rrset| rrset.contains(record))
+    }
+
+    pub fn rrset<'a>(
+        &'a self,
+        name: &Name,
+        qtype: QueryType,
+    ) -> impl Iterator<Item = &'a DnsRecord> {
+        self.records
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(move |record| record.qtype() == qtype)
+    }
+
+    /// Every record `name` owns.
+    pub fn records_at<'a>(&'a self, name: &Name) -> impl Iterator<Item = &'a DnsRecord> {
+        self.records.get(name).into_iter().flatten()
+    }
+
+    /// True if `name` owns records or has names below it (an empty
+    /// non-terminal). Canonical order puts a name's descendants right
+    /// after it.
+    fn exists(&self, name: &Name) -> bool {
+        self.records
+            .range(name.clone()..)
+            .next()
+            .is_some_and(|(owner, _)| owner.is_subdomain_of(name))
+    }
+
+    /// The SOA for the authority section of a negative answer, with the
+    /// negative caching TTL of RFC 2308.
+    fn negative_soa(&self) -> DnsRecord {
+        let mut soa = self.soa().clone();
+        if let DnsRecord::SOA { minimum, .. } = soa {
+            soa.set_ttl(soa.ttl().min(minimum));
+        }
+        soa
+    }
+
+    /// The authoritative answer for a name in this zone: records, a CNAME
+    /// chain, a referral to a delegated child, NODATA or NXDOMAIN.
+    pub fn lookup(&self, qname: &Name, qtype: QueryType) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.authoritative_answer = true;
+        let mut qname = qname.clone();
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            if let Some(cut) = self.delegation(&qname) {
+                // Only a referral when nothing was answered yet; a CNAME into
+                // a child zone is left for the client to follow.
+                if packet.answers.is_empty() {
+                    packet.header.authoritative_answer = false;
+                    packet.authorities = self.rrset(&cut, QueryType::NS).cloned().collect();
+                    packet.resources = self.glue(&packet.authorities);
+                }
+                return packet;
+            }
+
+            let rrset = match self.records.get(&qname) {
+                Some(rrset) => Some((rrset, None)),
+                None if self.exists(&qname) => None,
+                None => match self.wildcard(&qname) {
+                    Some(rrset) => Some((rrset, Some(qname.clone()))),
+                    None => {
+                        packet.header.responce_code = ResultCode::NXDOMAIN;
+                        packet.authorities.push(self.negative_soa());
+                        return packet;
+                    }
+                },
+            };
+            let (rrset, synthesized) = match rrset {
+                Some(rrset) => rrset,
+                None => {
+                    packet.authorities.push(self.negative_soa());
+                    return packet;
+                }
+            };
+            let owned = |record: &DnsRecord| {
+                let mut record = record.clone();
+                if let Some(owner) = &synthesized {
+                    set_domain(&mut record, owner.clone());
+                }
+                record
+            };
+
+            let matching: Vec<DnsRecord> = rrset
+                .iter()
+                .filter(|record| record.qtype() == qtype)
+                .map(owned)
+                .collect();
+            if !matching.is_empty() {
+                packet.answers.extend(matching);
+                return packet;
+            }
+            let cname = rrset
+                .iter()
+                .find(|record| record.qtype() == QueryType::CNAME);
+            match cname {
+                Some(cname) if qtype != QueryType::CNAME => {
+                    packet.answers.push(owned(cname));
+                    let target = match cname {
+                        DnsRecord::CNAME { host, .. } => host.clone(),
+                        _ => unreachable!(),
+                    };
+                    if !target.is_subdomain_of(&self.origin) {
+                        return packet;
+                    }
+                    qname = target;
+                }
+                _ => {
+                    packet.authorities.push(self.negative_soa());
+                    return packet;
+                }
+            }
+        }
+        packet
+    }
+
+    /// The highest zone cut between the apex and `qname`, if any.
+    fn delegation(&self, qname: &Name) -> Option<Name> {
+        let below_apex = qname.label_count().checked_sub(self.origin.label_count())?;
+        (1..=below_apex)
+            .map(|depth| {
+                let skip = qname.label_count() - self.origin.label_count() - depth;
+                Name::from_labels(qname.labels().skip(skip).map(|l| l.to_vec())).unwrap()
+            })
+            .find(|name| self.rrset(name, QueryType::NS).next().is_some())
+    }
+
+    /// Records of the `*` child of the closest existing ancestor (RFC 4592).
+    fn wildcard(&self, qname: &Name) -> Option<&Vec<DnsRecord>> {
+        let mut encloser = qname.parent();
+        while let Some(name) = encloser {
+            if self.exists(&name) {
+                return self.records.get(&name.child(b"*").ok()?);
+            }
+            encloser = name.parent();
+        }
+        None
+    }
+
+    /// In-zone addresses of the name servers in `ns`.
+    fn glue(&self, ns: &[DnsRecord]) -> Vec<DnsRecord> {
+        ns.iter()
+            .filter_map(|record| match record {
+                DnsRecord::NS { host, .. } => Some(host),
+                _ => None,
+            })
+            .flat_map(|host| {
+                self.rrset(host, QueryType::A)
+                    .chain(self.rrset(host, QueryType::AAAA))
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+fn soa_serial(soa: &DnsRecord) -> u32 {
+    match soa {
+        DnsRecord::SOA { serial, .. } => *serial,
+        _ => unreachable!(),
+    }
+}
+
+/// What changed between two versions of a zone, in the shape IXFR sends it
+/// (RFC 1995): the old SOA, the records removed, the new SOA, the records
+/// added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delta {
+    pub from: DnsRecord,
+    pub removed: Vec<DnsRecord>,
+    pub to: DnsRecord,
+    pub added: Vec<DnsRecord>,
+}
+
+impl Delta {
+    pub fn between(old: &Zone, new: &Zone) -> Self {
+        let changed = |a: &Zone, b: &Zone| {
+            a.records()
+                .filter(|record| record.qtype() != QueryType::SOA && !b.contains(record))
+                .cloned()
+                .collect()
+        };
+        Delta {
+            from: old.soa().clone(),
+            removed: changed(old, new),
+            to: new.soa().clone(),
+            added: changed(new, old),
+        }
+    }
+
+    pub fn from_serial(&self) -> u32 {
+        soa_serial(&self.from)
+    }
+
+    pub fn to_serial(&self) -> u32 {
+        soa_serial(&self.to)
+    }
+}
+
+fn set_domain(record: &mut DnsRecord, name: Name) {
+    match record {
+        DnsRecord::UNKNOWN { domain, .. }
+        | DnsRecord::A { domain, .. }
+        | DnsRecord::NS { domain, .. }
+        | DnsRecord::CNAME { domain, .. }
+        | DnsRecord::SOA { domain, .. }
+        | DnsRecord::PTR { domain, .. }
+        | DnsRecord::MX { domain, .. }
+        | DnsRecord::AAAA { domain, .. }
+        | DnsRecord::OPT { domain, .. }
+        | DnsRecord::TSIG { domain, .. } => *domain = name,
+    }
+}
+
+/// The zones one view is authoritative for. A zone is replaced as a whole,
+/// so lookups never see one half-changed, and each replacement with a newer
+/// serial is journaled for IXFR.
+#[derive(Debug, Default)]
+pub struct Zones {
+    zones: RwLock<BTreeMap<Name, Arc<Zone>>>,
+    journals: Mutex<BTreeMap<Name, Vec<Delta>>>,
+    /// Held while a new version is made and swapped in, so that changes
+    /// never overwrite each other.
+    writer: Mutex<()>,
+}
+
+impl Zones {
+    pub fn new(zones: Vec<Zone>) -> Self {
+        let zones = zones
+            .into_iter()
+            .map(|zone| (zone.origin.clone(), Arc::new(zone)))
+            .collect();
+        Self {
+            zones: RwLock::new(zones),
+            journals: Mutex::default(),
+            writer: Mutex::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.read().unwrap().is_empty()
+    }
+
+    /// The closest zone containing `qname`.
+    pub fn find(&self, qname: &Name) -> Option<Arc<Zone>> {
+        let zones = self.zones.read().unwrap();
+        let mut name = Some(qname.clone());
+        while let Some(candidate) = name {
+            if let Some(zone) = zones.get(&candidate) {
+                return Some(zone.clone());
+            }
+            name = candidate.parent();
+        }
+        None
+    }
+
+    pub fn get(&self, origin: &Name) -> Option<Arc<Zone>> {
+        self.zones.read().unwrap().get(origin).cloned()
+    }
+
+    /// Swaps in a new version of a zone. The journal only follows versions
+    /// that move the serial forward; anything else starts it over.
+    pub fn replace(&self, zone: Zone) {
+        let _writer = self.writer.lock().unwrap();
+        self.swap(zone);
+    }
+
+    /// Swaps in the version of a zone that `change` makes from the current
+    /// one, and returns the version now served. `None` if the zone is not
+    /// served.
+    pub fn modify<E>(
+        &self,
+        origin: &Name,
+        change: impl FnOnce(&Zone) -> std::result::Result<Zone, E>,
+    ) -> Option<std::result::Result<Arc<Zone>, E>> {
+        let _writer = self.writer.lock().unwrap();
+        let current = self.get(origin)?;
+        Some(change(&current).map(|zone| {
+            if zone == *current {
+                return current;
+            }
+            self.swap(zone);
+            self.get(origin).unwrap()
+        }))
+    }
+
+    fn swap(&self, zone: Zone) {
+        let mut zones = self.zones.write().unwrap();
+        let mut journals = self.journals.lock().unwrap();
+        let journal = journals.entry(zone.origin.clone()).or_default();
+        match zones.get(&zone.origin) {
+            Some(old) if is_newer_serial(zone.serial(), old.serial()) => {
+                journal.push(Delta::between(old, &zone));
+                if journal.len() > MAX_JOURNAL {
+                    journal.remove(0);
+                }
+            }
+            _ => journal.clear(),
+        }
+        zones.insert(zone.origin.clone(), Arc::new(zone));
+    }
+
+    /// Stops serving a zone, forgetting its journal.
+    pub fn remove(&self, origin: &Name) -> Option<Arc<Zone>> {
+        let _writer = self.writer.lock().unwrap();
+        let removed = self.zones.write().unwrap().remove(origin);
+        self.journals.lock().unwrap().remove(origin);
+        removed
+    }
+
+    /// The journaled changes that bring a copy of `origin` at `serial` up
+    /// to date, or `None` if the journal does not reach back that far.
+    pub fn changes_since(&self, origin: &Name, serial: u32) -> Option<Vec<Delta>> {
+        let journals = self.journals.lock().unwrap();
+        let journal = journals.get(origin)?;
+        let start = journal
+            .iter()
+            .position(|delta| delta.from_serial() == serial)?;
+        Some(journal[start..].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    fn zone() -> Zone {
+        Zone::parse(
+            &name("example.com"),
+            r#"
+$TTL 3600
+@        SOA   ns1 hostmaster 2024010101 7200 3600 1209600 300
+         NS    ns1
+ns1      A     192.0.2.53
+www      A     192.0.2.80
+alias    CNAME www
+outside  CNAME www.example.org.
+*.wild   A     192.0.2.99
+a.b.deep A     192.0.2.7
+sub      NS    ns.sub
+ns.sub   A     192.0.2.54
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn answers_and_negative_answers() {
+        let zone = zone();
+        assert_eq!(zone.serial(), 2024010101);
+
+        let packet = zone.lookup(&name("WWW.example.com"), QueryType::A);
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(
+            packet.answers,
+            vec![DnsRecord::A {
+                domain: name("www.example.com"),
+                addr: Ipv4Addr::new(192, 0, 2, 80),
+                ttl: 3600,
+            }]
+        );
+
+        let packet = zone.lookup(&name("www.example.com"), QueryType::MX);
+        assert_eq!(packet.header.responce_code, ResultCode::NOERROR);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.authorities[0].ttl(), 300);
+
+        let packet = zone.lookup(&name("missing.example.com"), QueryType::A);
+        assert_eq!(packet.header.responce_code, ResultCode::NXDOMAIN);
+        assert_eq!(packet.authorities[0].qtype(), QueryType::SOA);
+
+        // b.deep exists only as an empty non-terminal.
+        let packet = zone.lookup(&name("b.deep.example.com"), QueryType::A);
+        assert_eq!(packet.header.responce_code, ResultCode::NOERROR);
+        assert!(packet.answers.is_empty());
+    }
+
+    #[test]
+    fn cnames_wildcards_and_referrals() {
+        let zone = zone();
+        let packet = zone.lookup(&name("alias.example.com"), QueryType::A);
+        assert_eq!(packet.answers.len(), 2);
+        assert_eq!(packet.answers[1].domain(), &name("www.example.com"));
+
+        let packet = zone.lookup(&name("outside.example.com"), QueryType::A);
+        assert_eq!(packet.answers.len(), 1);
+
+        let packet = zone.lookup(&name("x.y.wild.example.com"), QueryType::A);
+        assert_eq!(packet.answers[0].domain(), &name("x.y.wild.example.com"));
+
+        let packet = zone.lookup(&name("host.sub.example.com"), QueryType::A);
+        assert!(!packet.header.authoritative_answer);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.authorities[0].qtype(), QueryType::NS);
+        assert_eq!(packet.resources[0].domain(), &name("ns.sub.example.com"));
+    }
+
+    #[test]
+    fn zones_need_one_soa_and_in_zone_records() {
+        assert!(Zone::parse(&name("example.com"), "www A 192.0.2.1").is_err());
+        assert!(Zone::parse(
+            &name("example.com"),
+            "@ SOA ns hm 1 2 3 4 5\nwww.example.org. A 192.0.2.1"
+        )
+        .is_err());
+
+        let zones = Zones::new(vec![zone()]);
+        assert!(zones.find(&name("a.b.example.com")).is_some());
+        assert!(zones.find(&name("example.org")).is_none());
+    }
+
+    #[test]
+    fn replacements_are_journaled() {
+        assert!(is_newer_serial(2, 1));
+        assert!(is_newer_serial(1, u32::MAX));
+        assert!(!is_newer_serial(1, 1));
+        assert!(!is_newer_serial(1, 2));
+
+        let zones = Zones::new(vec![zone()]);
+        let text = "@ SOA ns1 hostmaster {} 7200 3600 1209600 300\nwww A {}";
+        let version = |serial: u32, addr: &str| {
+            let text = text
+                .replacen("{}", &serial.to_string(), 1)
+                .replacen("{}", addr, 1);
+            Zone::parse(&name("example.com"), &text).unwrap()
+        };
+        zones.replace(version(2024010102, "192.0.2.80"));
+        zones.replace(version(2024010103, "192.0.2.81"));
+
+        let changes = zones
+            .changes_since(&name("example.com"), 2024010101)
+            .unwrap();
+        assert_eq!(changes.len(), 2);
+        // The first version dropped everything but www, at a new TTL.
+        assert_eq!(changes[0].removed.len(), 9);
+        assert_eq!(changes[0].added.len(), 1);
+        assert_eq!(changes[1].from_serial(), 2024010102);
+        assert_eq!(changes[1].to_serial(), 2024010103);
+        assert_eq!(
+            changes[1].added,
+            vec![DnsRecord::A {
+                domain: name("www.example.com"),
+                addr: Ipv4Addr::new(192, 0, 2, 81),
+                ttl: 300,
+            }]
+        );
+        assert!(zones.changes_since(&name("example.com"), 7).is_none());
+
+        // Going back in serial leaves nothing to send incrementally.
+        zones.replace(version(5, "192.0.2.80"));
+        assert!(zones
+            .changes_since(&name("example.com"), 2024010102)
+            .is_none());
+    }
+
+    #[test]
+    fn deltas_apply_and_zones_save() {
+        let old = zone();
+        let new = Zone::parse(
+            &name("example.com"),
+            "@ SOA ns1 hostmaster 2024010102 7200 3600 1209600 300\nns1 A 192.0.2.53\n@ NS ns1",
+        )
+        .unwrap();
+        let delta = Delta::between(&old, &new);
+        assert_eq!(old.apply(&delta).unwrap(), new);
+        assert!(new.apply(&delta).is_err());
+
+        let path = std::env::temp_dir().join(format!("zone-save-{}.zone", std::process::id()));
+        old.save(&path).unwrap();
+        assert_eq!(Zone::load(&name("example.com"), &path).unwrap(), old);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,614 @@
+use std::convert::TryFrom;
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use base64::engine::general_purpose;
+use base64::Engine;
+
+use crate::{
+    enums::ResultCode,
+    name::Name,
+    record::{opt_data, DnsRecord},
+};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// One resource record line of a master file (RFC 1035 §5), with the owner
+/// made absolute and the record type upper-cased. RDATA is left as tokens
+/// for the caller to interpret.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The `$ORIGIN` in effect, for relative names in the RDATA.
+    pub origin: Name,
+    pub owner: Name,
+    pub ttl: Option<u32>,
+    pub rtype: String,
+    pub rdata: Vec<String>,
+}
+
+const CLASSES: &[&str] = &["IN", "CH", "HS", "CS"];
+
+/// Parses master file text. Supports `$ORIGIN`, `$TTL`, `@`, relative names,
+/// blank owners (repeat the previous one), comments, quoted strings and
+/// parentheses spanning lines. Entries without a TTL get the last `$TTL`.
+pub fn parse(text: &str, origin: &Name) -> Result<Vec<Entry>> {
+    let mut origin = origin.clone();
+    let mut default_ttl = None;
+    let mut last_owner: Option<Name> = None;
+    let mut entries = Vec::new();
+
+    for (number, (starts_blank, tokens)) in logical_lines(text)?.into_iter().enumerate() {
+        let line_error = |e: Error| -> Error { format!("record {}: {}", number + 1, e).into() };
+        let mut tokens = tokens.into_iter().peekable();
+        let first = match tokens.peek() {
+            Some(first) => first.clone(),
+            None => continue,
+        };
+
+        match first.to_ascii_uppercase().as_str() {
+            "$ORIGIN" => {
+                tokens.next();
+                let value = tokens.next().ok_or("$ORIGIN without a name")?;
+                origin = name(&value, &origin).map_err(line_error)?;
+                continue;
+            }
+            "$TTL" => {
+                tokens.next();
+                let value = tokens.next().ok_or("$TTL without a value")?;
+                default_ttl = Some(ttl(&value).map_err(line_error)?);
+                continue;
+            }
+            directive if directive.starts_with('$') => {
+                return Err(format!("Unsupported directive {}", directive).into());
+            }
+            _ => {}
+        }
+
+        let owner = if starts_blank {
+            last_owner
+                .clone()
+                .ok_or_else(|| line_error("no previous owner".into()))?
+        } else {
+            let token = tokens.next().unwrap();
+            name(&token, &origin).map_err(line_error)?
+        };
+        last_owner = Some(owner.clone());
+
+        // TTL and class may come in either order, and both are optional.
+        let mut record_ttl = None;
+        let mut rtype = None;
+        for token in tokens.by_ref() {
+            let upper = token.to_ascii_uppercase();
+            if CLASSES.contains(&upper.as_str()) {
+                continue;
+            }
+            if record_ttl.is_none() && token.starts_with(|c: char| c.is_ascii_digit()) {
+                record_ttl = Some(ttl(&token).map_err(line_error)?);
+                continue;
+            }
+            rtype = Some(upper);
+            break;
+        }
+        let rtype = rtype.ok_or_else(|| line_error("missing record type".into()))?;
+
+        entries.push(Entry {
+            origin: origin.clone(),
+            owner,
+            ttl: record_ttl.or(default_ttl),
+            rtype,
+            rdata: tokens.collect(),
+        });
+    }
+    Ok(entries)
+}
+
+impl Entry {
+    /// Builds the record, using `default_ttl` if the entry has no TTL. Types
+    /// without their own variant are accepted in the RFC 3597 `\# len hex`
+    /// form, and TXT from its character strings.
+    pub fn to_record(&self, default_ttl: u32) -> Result<DnsRecord> {
+        let domain = self.owner.clone();
+        let record_ttl = self.ttl.unwrap_or(default_ttl);
+        let arg = |i: usize| -> Result<&str> {
+            self.rdata.get(i).map(|s| s.as_str()).ok_or_else(|| {
+                format!("{} record for {} is missing RDATA", self.rtype, self.owner).into()
+            })
+        };
+        let host = |i: usize| -> Result<Name> { name(arg(i)?, &self.origin) };
+
+        let record = match self.rtype.as_str() {
+            "A" => DnsRecord::A {
+                domain,
+                addr: arg(0)?.parse::<Ipv4Addr>()?,
+                ttl: record_ttl,
+            },
+            "AAAA" => DnsRecord::AAAA {
+                domain,
+                addr: arg(0)?.parse::<Ipv6Addr>()?,
+                ttl: record_ttl,
+            },
+            "NS" => DnsRecord::NS {
+                domain,
+                host: host(0)?,
+                ttl: record_ttl,
+            },
+            "CNAME" => DnsRecord::CNAME {
+                domain,
+                host: host(0)?,
+                ttl: record_ttl,
+            },
+            "SOA" => DnsRecord::SOA {
+                domain,
+                mname: host(0)?,
+                rname: host(1)?,
+                serial: arg(2)?.parse()?,
+                refresh: ttl(arg(3)?)?,
+                retry: ttl(arg(4)?)?,
+                expire: ttl(arg(5)?)?,
+                minimum: ttl(arg(6)?)?,
+                ttl: record_ttl,
+            },
+            "PTR" => DnsRecord::PTR {
+                domain,
+                host: host(0)?,
+                ttl: record_ttl,
+            },
+            "MX" => DnsRecord::MX {
+                domain,
+                priority: arg(0)?.parse()?,
+                host: host(1)?,
+                ttl: record_ttl,
+            },
+            "TXT" => {
+                let mut data = Vec::new();
+                for text in &self.rdata {
+                    let text = unescape(text)?;
+                    if text.len() > 255 {
+                        return Err(format!("TXT string for {} is too long", domain).into());
+                    }
+                    data.push(text.len() as u8);
+                    data.extend(text);
+                }
+                DnsRecord::UNKNOWN {
+                    domain,
+                    qtype: 16,
+                    data,
+                    ttl: record_ttl,
+                }
+            }
+            rtype => {
+                let qtype = match rtype.strip_prefix("TYPE").map(|n| n.parse::<u16>()) {
+                    Some(Ok(qtype)) => qtype,
+                    _ => return Err(format!("Unsupported record type {}", rtype).into()),
+                };
+                if arg(0)? != "\\#" {
+                    return Err(format!("{} RDATA must use the \\# form", rtype).into());
+                }
+                let len: usize = arg(1)?.parse()?;
+                let hex: String = self.rdata[2..].concat();
+                let data = (0..hex.len())
+                    .step_by(2)
+                    .map(|i| {
+                        hex.get(i..i + 2)
+                            .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                            .ok_or_else(|| format!("Invalid hex RDATA for {}", domain))
+                    })
+                    .collect::<std::result::Result<Vec<u8>, String>>()?;
+                if data.len() != len {
+                    return Err(format!("RDATA length of {} does not match", domain).into());
+                }
+                DnsRecord::UNKNOWN {
+                    domain,
+                    qtype,
+                    data,
+                    ttl: record_ttl,
+                }
+            }
+        };
+        Ok(record)
+    }
+}
+
+/// The master file line for `record`, with absolute names. Types without
+/// their own variant, TXT included, use the RFC 3597 `\# len hex` form.
+pub fn format(record: &DnsRecord) -> String {
+    let fqdn = |name: &Name| {
+        if name.is_root() {
+            ".".to_string()
+        } else {
+            format!("{}.", name)
+        }
+    };
+    let (rtype, rdata) = match record {
+        DnsRecord::A { addr, .. } => ("A".to_string(), addr.to_string()),
+        DnsRecord::AAAA { addr, .. } => ("AAAA".to_string(), addr.to_string()),
+        DnsRecord::NS { host, .. } => ("NS".to_string(), fqdn(host)),
+        DnsRecord::CNAME { host, .. } => ("CNAME".to_string(), fqdn(host)),
+        DnsRecord::PTR { host, .. } => ("PTR".to_string(), fqdn(host)),
+        DnsRecord::MX { priority, host, .. } => {
+            ("MX".to_string(), format!("{} {}", priority, fqdn(host)))
+        }
+        DnsRecord::SOA {
+            mname,
+            rname,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+            ..
+        } => (
+            "SOA".to_string(),
+            format!(
+                "{} {} {} {} {} {} {}",
+                fqdn(mname),
+                fqdn(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+        ),
+        DnsRecord::TSIG {
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+            ..
+        } => (
+            "TSIG".to_string(),
+            format!(
+                "{} {} {} {} {} {} {} {} {}",
+                fqdn(algorithm),
+                time_signed,
+                fudge,
+                mac.len(),
+                general_purpose::STANDARD.encode(mac),
+                original_id,
+                ResultCode::from_num(*error as u8),
+                other.len(),
+                general_purpose::STANDARD.encode(other)
+            )
+            .trim_end()
+            .to_string(),
+        ),
+        DnsRecord::OPT { options, .. } => ("TYPE41".to_string(), generic(&opt_data(options))),
+        DnsRecord::UNKNOWN { qtype, data, .. } => (format!("TYPE{}", qtype), generic(data)),
+    };
+    format!(
+        "{} {} {} {}",
+        fqdn(record.domain()),
+        record.ttl(),
+        rtype,
+        rdata
+    )
+}
+
+/// RDATA in the RFC 3597 `\# len hex` form.
+fn generic(data: &[u8]) -> String {
+    let hex: String = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("\\# {} {}", data.len(), hex).trim_end().to_string()
+}
+
+/// Resolves `\X` and `\DDD` escapes in a character string.
+fn unescape(text: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut bytes = text.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        let next = bytes.next().ok_or("Trailing backslash")?;
+        if next.is_ascii_digit() {
+            let digits = [next, bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)];
+            let value: u8 = std::str::from_utf8(&digits)
+                .ok()
+                .and_then(|d| d.parse().ok())
+                .ok_or("Invalid \\DDD escape")?;
+            out.push(value);
+        } else {
+            out.push(next);
+        }
+    }
+    Ok(out)
+}
+
+/// A possibly relative name from a master file: `@` is the origin, and
+/// names without a trailing dot are relative to it.
+pub fn name(token: &str, origin: &Name) -> Result<Name> {
+    if token == "@" {
+        return Ok(origin.clone());
+    }
+    if token.ends_with('.') && !token.ends_with("\\.") {
+        return token.parse();
+    }
+    let relative: Name = token.parse()?;
+    let mut labels: Vec<Vec<u8>> = relative.labels().map(|l| l.to_vec()).collect();
+    labels.extend(origin.labels().map(|l| l.to_vec()));
+    Name::from_labels(labels)
+}
+
+/// A TTL in seconds, or with BIND-style units such as `1h30m` or `2d`.
+pub fn ttl(token: &str) -> Result<u32> {
+    if let Ok(seconds) = token.parse() {
+        return Ok(seconds);
+    }
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in token.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return Err(format!("Invalid TTL {:?}", token).into()),
+        };
+        let value: u64 = number
+            .parse()
+            .map_err(|_| format!("Invalid TTL {:?}", token))?;
+        total += value * unit;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(format!("Invalid TTL {:?}", token).into());
+    }
+    u32::try_from(total).map_err(|_| format!("TTL {:?} is too large", token).into())
+}
+
+/// Splits text into logical lines of tokens, joining lines inside
+/// parentheses and dropping comments. The flag says whether the line began
+/// with whitespace, i.e. has no owner of its own.
+fn logical_lines(text: &str) -> Result<Vec<(bool, Vec<String>)>> {
+    let mut lines = Vec::new();
+    let mut tokens = Vec::new();
+    let mut starts_blank = false;
+    let mut depth = 0;
+
+    for line in text.lines() {
+        if depth == 0 {
+            starts_blank = line.starts_with([' ', '\t']);
+        }
+
+        let mut chars = line.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            match c {
+                ';' => break,
+                '(' => {
+                    depth += 1;
+                    chars.next();
+                }
+                ')' => {
+                    if depth == 0 {
+                        return Err("unbalanced ')'".into());
+                    }
+                    depth -= 1;
+                    chars.next();
+                }
+                c if c.is_whitespace() => {
+                    chars.next();
+                }
+                '"' => {
+                    chars.next();
+                    let mut token = String::new();
+                    let mut closed = false;
+                    while let Some(c) = chars.next() {
+                        match c {
+                            '\\' => {
+                                token.push(c);
+                                if let Some(escaped) = chars.next() {
+                                    token.push(escaped);
+                                }
+                            }
+                            '"' => {
+                                closed = true;
+                                break;
+                            }
+                            c => token.push(c),
+                        }
+                    }
+                    if !closed {
+                        return Err("unterminated quoted string".into());
+                    }
+                    tokens.push(token);
+                }
+                _ => {
+                    let mut token = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if c.is_whitespace() || c == ';' || c == '(' || c == ')' || c == '"' {
+                            break;
+                        }
+                        token.push(c);
+                        chars.next();
+                        if c == '\\' {
+                            if let Some(escaped) = chars.next() {
+                                token.push(escaped);
+                            }
+                        }
+                    }
+                    tokens.push(token);
+                }
+            }
+        }
+
+        if depth == 0 && !tokens.is_empty() {
+            lines.push((starts_blank, std::mem::take(&mut tokens)));
+        }
+    }
+    if depth != 0 {
+        return Err("unbalanced '('".into());
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_a_zone() {
+        let text = r#"
+$ORIGIN example.com.
+$TTL 1h
+@   IN  SOA ns1 hostmaster (
+            2024010101 ; serial
+            7200 3600 1209600 300 )
+    IN  NS  ns1
+ns1 300 IN A 192.0.2.53
+www     A   192.0.2.80 ; web
+mail.example.com. IN 60 MX 10 mail
+txt TXT "hello world" "a \"quoted\" string"
+"#;
+        let entries = parse(text, &Name::root()).unwrap();
+        assert_eq!(entries.len(), 6);
+
+        assert_eq!(entries[0].owner, name("example.com"));
+        assert_eq!(entries[0].rtype, "SOA");
+        assert_eq!(entries[0].ttl, Some(3600));
+        assert_eq!(entries[0].rdata.len(), 7);
+
+        assert_eq!(entries[1].owner, name("example.com"));
+        assert_eq!(entries[1].rtype, "NS");
+        assert_eq!(entries[2].ttl, Some(300));
+        assert_eq!(entries[3].owner, name("www.example.com"));
+        assert_eq!(entries[3].rdata, vec!["192.0.2.80"]);
+        assert_eq!(entries[4].ttl, Some(60));
+        assert_eq!(entries[4].rdata, vec!["10", "mail"]);
+        assert_eq!(
+            entries[5].rdata,
+            vec!["hello world", "a \\\"quoted\\\" string"]
+        );
+    }
+
+    #[test]
+    fn relative_names_use_the_origin() {
+        let origin = name("rpz.local");
+        assert_eq!(
+            super::name("*.ads", &origin).unwrap(),
+            name("*.ads.rpz.local")
+        );
+        assert_eq!(super::name("@", &origin).unwrap(), origin);
+        assert_eq!(
+            super::name("abs.example.", &origin).unwrap(),
+            name("abs.example")
+        );
+    }
+
+    #[test]
+    fn entries_become_records() {
+        let text = "$ORIGIN example.com.\n\
+                    mx 60 MX 10 mail\n\
+                    txt TXT \"v=spf1 -all\" x\n\
+                    raw TYPE65 \\# 2 0a0B\n\
+                    bad TYPE65 01\n";
+        let entries = parse(text, &Name::root()).unwrap();
+        assert_eq!(
+            entries[0].to_record(300).unwrap(),
+            DnsRecord::MX {
+                domain: name("mx.example.com"),
+                priority: 10,
+                host: name("mail.example.com"),
+                ttl: 60,
+            }
+        );
+        assert_eq!(
+            entries[1].to_record(300).unwrap(),
+            DnsRecord::UNKNOWN {
+                domain: name("txt.example.com"),
+                qtype: 16,
+                data: b"\x0bv=spf1 -all\x01x".to_vec(),
+                ttl: 300,
+            }
+        );
+        match entries[2].to_record(300).unwrap() {
+            DnsRecord::UNKNOWN { qtype, data, .. } => {
+                assert_eq!(qtype, 65);
+                assert_eq!(data, vec![0x0a, 0x0b]);
+            }
+            record => panic!("unexpected record {:?}", record),
+        }
+        assert!(entries[3].to_record(300).is_err());
+    }
+
+    #[test]
+    fn records_format_as_lines_that_parse_back() {
+        let records = vec![
+            DnsRecord::SOA {
+                domain: name("example.com"),
+                mname: name("ns1.example.com"),
+                rname: name("hostmaster.example.com"),
+                serial: 7,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+                ttl: 3600,
+            },
+            DnsRecord::MX {
+                domain: name("example.com"),
+                priority: 10,
+                host: name("mail.example.com"),
+                ttl: 60,
+            },
+            DnsRecord::UNKNOWN {
+                domain: name("txt.example.com"),
+                qtype: 16,
+                data: b"\x05hello".to_vec(),
+                ttl: 300,
+            },
+            DnsRecord::UNKNOWN {
+                domain: name("*.example.com"),
+                qtype: 65,
+                data: Vec::new(),
+                ttl: 300,
+            },
+        ];
+        assert_eq!(
+            format(&records[1]),
+            "example.com. 60 MX 10 mail.example.com."
+        );
+        assert_eq!(
+            format(&records[2]),
+            "txt.example.com. 300 TYPE16 \\# 6 0568656c6c6f"
+        );
+        let text: String = records.iter().map(|r| format(r) + "\n").collect();
+        let parsed = parse(&text, &Name::root())
+            .unwrap()
+            .iter()
+            .map(|entry| entry.to_record(0).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(parsed, records);
+    }
+
+    #[test]
+    fn ttl_units() {
+        assert_eq!(ttl("300").unwrap(), 300);
+        assert_eq!(ttl("1h30m").unwrap(), 5400);
+        assert_eq!(ttl("1W2d").unwrap(), 777600);
+        assert!(ttl("5x").is_err());
+        assert!(ttl("1h5").is_err());
+    }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        assert!(parse("a A (1.2.3.4", &Name::root()).is_err());
+        assert!(parse("a A 1.2.3.4 )", &Name::root()).is_err());
+        assert!(parse("  A 1.2.3.4", &Name::root()).is_err());
+        assert!(parse("$INCLUDE other.zone", &Name::root()).is_err());
+        assert!(parse("www 300", &Name::root()).is_err());
+    }
+}
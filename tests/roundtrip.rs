@@ -0,0 +1,256 @@
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use dns_server_rust::{
+    buffer::BytePacketBuffer,
+    enums::{OpCode, QueryType, ResultCode},
+    header::Header,
+    name::Name,
+    packet::Packet,
+    question::Question,
+    record::DnsRecord,
+};
+
+type SetFlag = fn(&mut Header);
+
+fn name(s: &str) -> Name {
+    s.parse().unwrap()
+}
+
+fn buffer_from(bytes: &[u8]) -> BytePacketBuffer {
+    BytePacketBuffer::from_bytes(bytes).unwrap()
+}
+
+fn written(buffer: &BytePacketBuffer) -> Vec<u8> {
+    buffer.buffer[..buffer.pos()].to_vec()
+}
+
+fn assert_packet_roundtrip(name: &str, bytes: &[u8]) {
+    let mut buffer = buffer_from(bytes);
+    let mut packet = Packet::from_buffer(&mut buffer).unwrap();
+    assert_eq!(buffer.pos(), bytes.len(), "{}: unparsed bytes", name);
+
+    let mut out = BytePacketBuffer::new();
+    packet.write(&mut out).unwrap();
+    assert_eq!(written(&out), bytes, "{}: re-encoding differs", name);
+
+    let mut reparsed_buffer = buffer_from(&written(&out));
+    let reparsed = Packet::from_buffer(&mut reparsed_buffer).unwrap();
+    assert_eq!(reparsed, packet, "{}: reparse differs", name);
+}
+
+#[test]
+fn header_flags_use_their_own_bits() {
+    let flags: [(SetFlag, [u8; 2]); 7] = [
+        (|h| h.recursion_desired = true, [0x01, 0x00]),
+        (|h| h.truncated_message = true, [0x02, 0x00]),
+        (|h| h.authoritative_answer = true, [0x04, 0x00]),
+        (|h| h.responce = true, [0x80, 0x00]),
+        (|h| h.checking_disabled = true, [0x00, 0x10]),
+        (|h| h.authed_data = true, [0x00, 0x20]),
+        (|h| h.recursion_available = true, [0x00, 0x80]),
+    ];
+
+    for (set, expected) in flags.iter() {
+        let mut header = Header::new();
+        set(&mut header);
+
+        let mut buffer = BytePacketBuffer::new();
+        header.write(&mut buffer).unwrap();
+        assert_eq!(&buffer.buffer[2..4], expected);
+
+        let mut read_back = Header::new();
+        buffer.seek(0).unwrap();
+        read_back.read(&mut buffer).unwrap();
+        assert_eq!(read_back, header);
+    }
+}
+
+#[test]
+fn header_roundtrip() {
+    let mut header = Header::new();
+    header.id = 0xBEEF;
+    header.recursion_desired = true;
+    header.authoritative_answer = true;
+    header.operation_code = OpCode::UPDATE;
+    header.responce = true;
+    header.responce_code = ResultCode::NOTZONE;
+    header.authed_data = true;
+    header.questions = 1;
+    header.answers = 2;
+    header.authoritative_entries = 3;
+    header.resource_entries = 4;
+
+    let mut buffer = BytePacketBuffer::new();
+    header.write(&mut buffer).unwrap();
+    let bytes = written(&buffer);
+    assert_eq!(
+        bytes,
+        [0xBE, 0xEF, 0xAD, 0x2A, 0, 1, 0, 2, 0, 3, 0, 4].to_vec()
+    );
+
+    let mut read_back = Header::new();
+    read_back.read(&mut buffer_from(&bytes)).unwrap();
+    assert_eq!(read_back, header);
+}
+
+#[test]
+fn every_rcode_roundtrips() {
+    for num in 0..16u8 {
+        let code = ResultCode::from_num(num);
+        assert_eq!(code.to_num(), num);
+
+        let mut header = Header::new();
+        header.responce_code = code;
+        let mut buffer = BytePacketBuffer::new();
+        header.write(&mut buffer).unwrap();
+
+        let mut read_back = Header::new();
+        buffer.seek(0).unwrap();
+        read_back.read(&mut buffer).unwrap();
+        assert_eq!(read_back.responce_code, code);
+    }
+    assert_eq!(ResultCode::from_num(11), ResultCode::UNKNOWN(11));
+}
+
+#[test]
+fn question_roundtrip() {
+    let questions = [
+        ("google.com", QueryType::A, 1),
+        ("Mixed.Case.Example", QueryType::AAAA, 1),
+        ("version.bind", QueryType::UNKNOWN(16), 3),
+        ("example.org", QueryType::UNKNOWN(255), 255),
+    ];
+
+    for (qname, qtype, qclass) in questions.iter() {
+        let mut question = Question::new(name(qname), *qtype);
+        question.qclass = *qclass;
+
+        let mut buffer = BytePacketBuffer::new();
+        question.write(&mut buffer).unwrap();
+        let bytes = written(&buffer);
+
+        let mut read_back = Question::new(Name::root(), QueryType::UNKNOWN(0));
+        read_back.read(&mut buffer_from(&bytes)).unwrap();
+        assert_eq!(read_back, question);
+
+        let mut rewritten = BytePacketBuffer::new();
+        read_back.write(&mut rewritten).unwrap();
+        assert_eq!(written(&rewritten), bytes);
+    }
+}
+
+#[test]
+fn record_roundtrip() {
+    let records = vec![
+        DnsRecord::A {
+            domain: name("example.com"),
+            addr: Ipv4Addr::new(93, 184, 216, 34),
+            ttl: 3600,
+        },
+        DnsRecord::NS {
+            domain: name("example.com"),
+            host: name("a.iana-servers.net"),
+            ttl: 86400,
+        },
+        DnsRecord::CNAME {
+            domain: name("www.example.com"),
+            host: name("example.com"),
+            ttl: 300,
+        },
+        DnsRecord::SOA {
+            domain: name("example.com"),
+            mname: name("ns.icann.org"),
+            rname: name("noc.dns.icann.org"),
+            serial: 2024010101,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 3600,
+            ttl: 3600,
+        },
+        DnsRecord::PTR {
+            domain: name("34.216.184.93.in-addr.arpa"),
+            host: name("example.com"),
+            ttl: 3600,
+        },
+        DnsRecord::MX {
+            domain: name("example.com"),
+            priority: 10,
+            host: name("mail.example.com"),
+            ttl: 300,
+        },
+        DnsRecord::AAAA {
+            domain: name("example.com"),
+            addr: "2606:2800:220:1:248:1893:25c8:1946"
+                .parse::<Ipv6Addr>()
+                .unwrap(),
+            ttl: 3600,
+        },
+        DnsRecord::UNKNOWN {
+            domain: name("example.com"),
+            qtype: 16,
+            data: b"\x0bv=spf1 -all".to_vec(),
+            ttl: 3600,
+        },
+    ];
+
+    for record in records {
+        let mut buffer = BytePacketBuffer::new();
+        let size = record.write(&mut buffer).unwrap();
+        let bytes = written(&buffer);
+        assert_eq!(size, bytes.len());
+
+        let read_back = DnsRecord::read(&mut buffer_from(&bytes)).unwrap();
+        assert_eq!(read_back, record);
+
+        let mut rewritten = BytePacketBuffer::new();
+        read_back.write(&mut rewritten).unwrap();
+        assert_eq!(written(&rewritten), bytes);
+    }
+}
+
+#[test]
+fn bundled_captures_roundtrip() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    for name in ["query_packet.txt", "response_packet.txt"].iter() {
+        let bytes = fs::read(root.join(name)).unwrap();
+        assert_packet_roundtrip(name, &bytes);
+    }
+}
+
+#[test]
+fn response_capture_decodes() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let bytes = fs::read(root.join("response_packet.txt")).unwrap();
+    let packet = Packet::from_buffer(&mut buffer_from(&bytes)).unwrap();
+
+    assert_eq!(packet.header.id, 0x2ABA);
+    assert!(packet.header.responce);
+    assert!(packet.header.recursion_desired);
+    assert!(packet.header.recursion_available);
+    assert!(!packet.header.authoritative_answer);
+    assert_eq!(packet.questions[0].name, name("google.com"));
+    assert_eq!(
+        packet.answers,
+        vec![DnsRecord::A {
+            domain: name("google.com"),
+            addr: Ipv4Addr::new(172, 217, 175, 110),
+            ttl: 190,
+        }]
+    );
+}
+
+#[test]
+fn corpus_roundtrip() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let mut count = 0;
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let bytes = fs::read(&path).unwrap();
+        assert_packet_roundtrip(&path.display().to_string(), &bytes);
+        count += 1;
+    }
+    assert!(count > 0);
+}